@@ -0,0 +1,169 @@
+// A headless client for a musicbox server. Prints every event the server sends
+// and optionally runs a script of commands.
+//
+// Scripts contain one instruction per line:
+//
+//     # Comments and blank lines are ignored.
+//     sleep 500
+//     NextTrack
+//     {"type": "StartPlaylist", "name": "red", "force": false}
+//
+// `sleep` waits for the given number of milliseconds, a bare name sends a
+// command with no arguments and anything else is parsed as a JSON command.
+
+use std::fs::read_to_string;
+use std::process::exit;
+use std::time::Duration;
+
+use clap::{load_yaml, App};
+use futures::future::{select, Either};
+use futures::sink::{Sink, SinkExt};
+use futures::stream::StreamExt;
+use musicbox_protocol::{Command, MessageFromClient, MessageToClient};
+use serde_json::{from_str, json, to_string};
+use tokio::time::delay_for;
+use tokio_tungstenite::connect_async;
+use tokio_tungstenite::tungstenite::{Error, Message};
+
+enum Step {
+    Sleep(Duration),
+    Send(Command),
+}
+
+fn parse_script(script: &str) -> Result<Vec<Step>, String> {
+    let mut steps = Vec::new();
+
+    for (number, line) in script.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let step = if let Some(millis) = line.strip_prefix("sleep ") {
+            millis
+                .trim()
+                .parse::<u64>()
+                .map(|millis| Step::Sleep(Duration::from_millis(millis)))
+                .map_err(|e| e.to_string())
+        } else if line.starts_with('{') {
+            from_str(line).map(Step::Send).map_err(|e| e.to_string())
+        } else {
+            serde_json::from_value(json!({ "type": line }))
+                .map(Step::Send)
+                .map_err(|e| e.to_string())
+        };
+
+        steps.push(step.map_err(|e| format!("Line {}: {}", number + 1, e))?);
+    }
+
+    Ok(steps)
+}
+
+async fn run_script<S>(sink: &mut S, steps: &[Step], repeat: u32) -> Result<(), String>
+where
+    S: Sink<Message, Error = Error> + Unpin,
+{
+    let mut iteration = 0;
+    while repeat == 0 || iteration < repeat {
+        for step in steps {
+            match step {
+                Step::Sleep(duration) => delay_for(*duration).await,
+                Step::Send(command) => {
+                    let message = MessageFromClient::Command {
+                        command: command.clone(),
+                    };
+                    let text = to_string(&message).map_err(|e| e.to_string())?;
+                    sink.send(Message::text(text))
+                        .await
+                        .map_err(|e| e.to_string())?;
+                }
+            }
+        }
+        iteration += 1;
+    }
+
+    Ok(())
+}
+
+async fn run(
+    server: &str,
+    steps: Option<Vec<Step>>,
+    repeat: u32,
+    quiet: bool,
+) -> Result<(), String> {
+    let (socket, _) = connect_async(server)
+        .await
+        .map_err(|e| format!("Unable to connect to {}: {}", server, e))?;
+    let (mut sink, stream) = socket.split();
+
+    let printer = stream.for_each(|message| {
+        match message {
+            Ok(Message::Text(text)) => {
+                if !quiet {
+                    match from_str::<MessageToClient>(&text) {
+                        Ok(MessageToClient::Connected { device, .. }) => {
+                            println!("Connected to {} ({})", device.name, device.id)
+                        }
+                        Ok(MessageToClient::Event { event, .. }) => println!("{:?}", event),
+                        Ok(MessageToClient::Response { id, response }) => {
+                            println!("Response {}: {}", id, response)
+                        }
+                        Ok(MessageToClient::Unknown) | Err(_) => println!("{}", text),
+                    }
+                }
+            }
+            Ok(_) => (),
+            Err(e) => eprintln!("Connection error: {}", e),
+        }
+        futures::future::ready(())
+    });
+
+    match steps {
+        Some(steps) => {
+            let script = Box::pin(run_script(&mut sink, &steps, repeat));
+            match select(script, Box::pin(printer)).await {
+                Either::Left((result, _)) => result,
+                Either::Right(_) => Err(String::from("The server closed the connection.")),
+            }
+        }
+        None => {
+            printer.await;
+            Ok(())
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let yaml = load_yaml!("cli.yml");
+    let matches = App::from_yaml(yaml).get_matches();
+
+    let server = matches.value_of("server").unwrap();
+    let quiet = matches.is_present("quiet");
+    let repeat = match matches.value_of("repeat").unwrap().parse::<u32>() {
+        Ok(r) => r,
+        Err(e) => {
+            eprintln!("Invalid repeat count: {}", e);
+            exit(1);
+        }
+    };
+
+    let steps = match matches.value_of("script") {
+        Some(path) => match read_to_string(path)
+            .map_err(|e| e.to_string())
+            .and_then(|s| parse_script(&s))
+        {
+            Ok(steps) => Some(steps),
+            Err(e) => {
+                eprintln!("Unable to load script {}: {}", path, e);
+                exit(1);
+            }
+        },
+        None => None,
+    };
+
+    if let Err(e) = run(server, steps, repeat, quiet).await {
+        eprintln!("{}", e);
+        exit(1);
+    }
+}
@@ -0,0 +1,616 @@
+//! The types that make up the musicbox wire protocol.
+//!
+//! The protocol version is bumped whenever a change is made that an existing
+//! client would not be able to understand. Adding new commands, events or
+//! fields is not considered a breaking change so clients should ignore
+//! anything they do not recognise. Unrecognised variants are read as
+//! `Unknown`.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+pub const PROTOCOL_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum Command {
+    PreviousTrack,
+    RestartTrack,
+    // Adds the playing track to the favorites playlist.
+    FavoriteTrack,
+    // Skips the playing track and leaves it out of playlists from then on.
+    BlockTrack,
+    UnblockTrack {
+        id: String,
+    },
+    // Runs the command here and on the other music boxes in the group.
+    GroupCommand {
+        command: Box<Command>,
+    },
+    NextTrack,
+    PlayPause,
+    VolumeUp,
+    VolumeDown,
+    Seek {
+        millis: i64,
+    },
+    StartPlaylist {
+        name: String,
+        force: bool,
+    },
+    // Starts the playlist that the track is in from that track.
+    PlayTrack {
+        id: String,
+    },
+    // Adds a track from any playlist to the queue before another track in
+    // the queue, or at the end.
+    AddToQueue {
+        id: String,
+        before: Option<String>,
+    },
+    // Moves a track in the queue before another, or to the end.
+    MoveInQueue {
+        id: String,
+        before: Option<String>,
+    },
+    RemoveFromQueue {
+        id: String,
+    },
+    Shutdown,
+    Reload,
+    Status,
+    PlayTestSound,
+    PowerLost,
+    PowerOff,
+    // Percent from -100 (left only) to 100 (right only).
+    SetBalance {
+        balance: i32,
+    },
+    // Interrupts the playlist to play a single file. When ducking the
+    // playlist continues at a lower volume instead of pausing.
+    PlayFile {
+        path: String,
+        #[serde(default)]
+        duck: bool,
+    },
+    // A key on a numeric keypad, "0" to "9", "*" or "#".
+    Keypad {
+        key: char,
+    },
+    // Checks for a newer release and installs it.
+    Update,
+    // Shuts down and starts again.
+    Restart,
+    // Turns guest mode, with its playback limits, on or off.
+    SetGuestMode {
+        enabled: bool,
+    },
+    // Lifts the quiet hours volume limit until the current quiet hours end.
+    OverrideQuietHours {
+        enabled: bool,
+    },
+    // Switches to a profile, or to no profile if the name is missing.
+    SelectProfile {
+        name: Option<String>,
+    },
+    // Blinks the LED of the named playlist, or of the download cache if the
+    // name is "cache", to find where it is on the box.
+    IdentifyLed {
+        name: String,
+    },
+    // Scans a single stored playlist for changes to its tracks.
+    RescanPlaylist {
+        name: String,
+    },
+    // Bookmarks the position in the playing track. Unnamed bookmarks are
+    // numbered.
+    SetBookmark {
+        name: Option<String>,
+    },
+    // Seeks the playing track to one of its bookmarks.
+    JumpToBookmark {
+        name: String,
+    },
+    // Records a message from the microphone until StopRecording or the
+    // maximum length.
+    StartRecording,
+    StopRecording,
+    // A command from a newer version of the protocol.
+    #[serde(other)]
+    Unknown,
+}
+
+// Where a command came from.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum Origin {
+    Gpio {
+        pin: u8,
+    },
+    Keyboard,
+    Input {
+        device: String,
+    },
+    Signal {
+        signal: i32,
+    },
+    WebClient {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        address: Option<String>,
+    },
+    // Another music box in the same group.
+    Group {
+        device: String,
+    },
+    // Generated by the music box itself.
+    Internal,
+    #[serde(other)]
+    Unknown,
+}
+
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Origin::Gpio { pin } => write!(f, "GPIO pin {}", pin),
+            Origin::Keyboard => f.write_str("keyboard"),
+            Origin::Input { device } => write!(f, "input device {}", device),
+            Origin::Signal { signal } => write!(f, "signal {}", signal),
+            Origin::WebClient {
+                address: Some(address),
+            } => write!(f, "web client {}", address),
+            Origin::WebClient { address: None } => f.write_str("web client"),
+            Origin::Group { device } => write!(f, "group member {}", device),
+            Origin::Internal => f.write_str("music box"),
+            Origin::Unknown => f.write_str("unknown"),
+        }
+    }
+}
+
+// How trusted the source of a command has to be for it to be accepted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Authorization {
+    // Controlling what is playing.
+    Playback,
+    // Managing the music box.
+    Admin,
+    // Affecting the host system or the daemon itself.
+    System,
+}
+
+impl Command {
+    // The authorization needed to issue this command.
+    pub fn authorization(&self) -> Authorization {
+        match self {
+            Command::PreviousTrack
+            | Command::RestartTrack
+            | Command::FavoriteTrack
+            | Command::BlockTrack
+            | Command::NextTrack
+            | Command::PlayPause
+            | Command::VolumeUp
+            | Command::VolumeDown
+            | Command::Seek { .. }
+            | Command::StartPlaylist { .. }
+            | Command::PlayTrack { .. }
+            | Command::AddToQueue { .. }
+            | Command::MoveInQueue { .. }
+            | Command::RemoveFromQueue { .. }
+            | Command::Keypad { .. }
+            | Command::SelectProfile { .. }
+            | Command::SetBookmark { .. }
+            | Command::JumpToBookmark { .. }
+            | Command::StartRecording
+            | Command::StopRecording
+            | Command::Status => Authorization::Playback,
+            Command::Reload
+            | Command::PlayTestSound
+            | Command::SetBalance { .. }
+            | Command::PlayFile { .. }
+            | Command::SetGuestMode { .. }
+            | Command::OverrideQuietHours { .. }
+            | Command::IdentifyLed { .. }
+            | Command::RescanPlaylist { .. }
+            | Command::UnblockTrack { .. } => Authorization::Admin,
+            Command::GroupCommand { command } => command.authorization(),
+            Command::Shutdown
+            | Command::PowerLost
+            | Command::PowerOff
+            | Command::Update
+            | Command::Restart
+            | Command::Unknown => Authorization::System,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum Event {
+    // The queue or a stored playlist changed. Edits to the queue list their
+    // changes, otherwise clients should fetch the state again.
+    PlaylistUpdated {
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        changes: Vec<QueueChange>,
+    },
+    // A stored playlist was scanned for its tracks.
+    PlaylistScanned {
+        name: String,
+        result: ScanResult,
+    },
+    PlaybackStarted,
+    PlaybackPaused,
+    PlaybackUnpaused,
+    PlaybackEnded,
+    PlaybackPosition {
+        duration: Duration,
+    },
+    // The player says it is playing but the position hasn't moved for a
+    // while.
+    PlaybackStalled,
+    CommandHandled {
+        command: Command,
+        origin: Origin,
+    },
+    // A command that was seen in dry run mode but not run.
+    CommandSkipped {
+        command: Command,
+        origin: Origin,
+    },
+    Error {
+        message: String,
+    },
+    // A playlist was started that doesn't exist or has no tracks. Names the
+    // playlist that was started instead, if any.
+    PlaylistUnavailable {
+        name: String,
+        fallback: Option<String>,
+    },
+    // The removable media holding a playlist's tracks was removed or put back.
+    PlaylistMediaChanged {
+        name: String,
+        present: bool,
+    },
+    ConnectivityChanged {
+        online: bool,
+    },
+    // The system clock jumped by this many seconds, usually because it was
+    // set from the network.
+    ClockChanged {
+        offset: i64,
+    },
+    // Names the configured audio output now in use, missing when none of them
+    // work and the system default is used.
+    AudioOutputChanged {
+        name: Option<String>,
+    },
+    // The audio output stopped working during playback.
+    AudioOutputFailed,
+    InterruptionStarted,
+    FileFinished {
+        path: String,
+    },
+    InterruptionEnded,
+    // A check for a new release finished. Names the release if one was
+    // installed, the music box restarts into it.
+    UpdateFinished {
+        installed: Option<String>,
+    },
+    Shutdown,
+    // An event from a newer version of the protocol.
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum Request {}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum MessageFromClient {
+    Command { command: Command },
+    Request { id: u32, request: Request },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum MessageToClient {
+    // The first message on a connection. Events missed since the sequence
+    // number the client asked to resume from are only replayed if resumed is
+    // true, otherwise the client should fetch the full state again.
+    Connected {
+        session: String,
+        resumed: bool,
+        // The music box the events come from.
+        #[serde(default)]
+        device: DeviceInfo,
+    },
+    Event {
+        event: Event,
+        // Clients reconnect with the last sequence number they saw.
+        #[serde(default)]
+        sequence: u64,
+    },
+    Response {
+        id: u32,
+        response: Value,
+    },
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VersionInfo {
+    pub protocol: u32,
+    pub server: String,
+    #[serde(default)]
+    pub device: DeviceInfo,
+}
+
+// What is known about the library of tracks.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LibraryStats {
+    pub playlists: usize,
+    pub tracks: usize,
+    pub favorites: usize,
+    pub blocked: usize,
+    // Files that couldn't be probed.
+    pub unreadable: usize,
+}
+
+// Enough to tell whether music boxes are running the same build and
+// configuration, for managing several of them.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SystemReport {
+    pub protocol: u32,
+    pub server: String,
+    pub device: DeviceInfo,
+    // The optional build features that are enabled.
+    pub features: Vec<String>,
+    // A SHA-256 hash of the hardware config, before secrets are filled in.
+    pub config_hash: String,
+    pub library: LibraryStats,
+}
+
+// Tells music boxes apart when there are several on the network. The ID never
+// changes, the name is chosen by the owner.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeviceInfo {
+    pub id: String,
+    pub name: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TrackInfo {
+    pub id: String,
+    pub title: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub artist: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub album: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration: Option<Duration>,
+}
+
+// A named position in a track.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Bookmark {
+    pub name: String,
+    pub position: Duration,
+}
+
+// A file, or the whole playlist, that couldn't be scanned.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ScanError {
+    pub path: String,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ScanResult {
+    pub tracks: usize,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<ScanError>,
+}
+
+// A change to the queue. Indexes are positions in the queue as each change
+// is applied in turn.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum QueueChange {
+    Inserted { index: usize, track: TrackInfo },
+    Moved { from: usize, to: usize },
+    Removed { index: usize },
+}
+
+// The tracks queued to play and the position of the playing track.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Queue {
+    pub tracks: Vec<TrackInfo>,
+    pub position: Option<usize>,
+}
+
+// A track that matched a search and the playlist it is in.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SearchResult {
+    pub playlist: String,
+    pub track: TrackInfo,
+}
+
+// A page of the tracks that matched a search.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SearchResults {
+    // The number of matches across every page.
+    pub total: usize,
+    pub offset: usize,
+    pub results: Vec<SearchResult>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PlaylistInfo {
+    pub name: String,
+    pub tracks: Vec<TrackInfo>,
+    // Set while the removable media holding the playlist's tracks is missing.
+    #[serde(default)]
+    pub media_missing: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PlayState {
+    pub position: usize,
+    pub duration: Duration,
+    pub paused: bool,
+}
+
+// The outcome of one command in a batch.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum CommandResult {
+    Accepted,
+    Rejected { reason: String },
+    // Nothing in a batch runs if any of its commands are rejected.
+    Skipped,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HistoryEntry {
+    pub command: Command,
+    pub origin: Origin,
+    // Milliseconds since the unix epoch.
+    pub timestamp: u64,
+}
+
+// The results of analysing a track's audio.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TrackAnalysis {
+    // The average loudness in dB relative to full scale.
+    pub loudness: f64,
+    // The loudest point in dB relative to full scale.
+    pub peak: f64,
+    pub duration: Duration,
+    // The peak level of evenly spaced sections of the track, from 0 to 255.
+    pub waveform: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AnalysisProgress {
+    // Tracks waiting to be analysed, including any being analysed now.
+    pub queued: usize,
+    pub completed: usize,
+    pub paused: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CurrentDownload {
+    pub name: String,
+    // In bytes.
+    pub received: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DownloadProgress {
+    // Tracks waiting to be downloaded, including any downloading now.
+    pub queued: usize,
+    pub completed: usize,
+    // Downloads that were given up on after retrying.
+    pub failed: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub current: Option<CurrentDownload>,
+}
+
+// What has been seen on a GPIO pin, for diagnosing wiring problems.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PinStatus {
+    pub pin: u8,
+    pub high: bool,
+    // The number of changes to each level.
+    pub rising: u64,
+    pub falling: u64,
+    // Milliseconds since the unix epoch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_event: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GuestState {
+    // Seconds of playback left today, if there is a daily limit.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub remaining: Option<u64>,
+    // Set during quiet hours when nothing can play.
+    pub quiet: bool,
+}
+
+// How much a button has been used, counted across restarts.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ButtonUsage {
+    pub presses: u64,
+    // Level changes that were ignored as bounce.
+    pub bounces: u64,
+    // Set when the button has recently bounced far more than normal, which
+    // happens as a switch wears out.
+    #[serde(default)]
+    pub worn: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StateSnapshot {
+    pub stored_playlists: HashMap<String, PlaylistInfo>,
+    pub playlist: Vec<TrackInfo>,
+    pub play_state: Option<PlayState>,
+    pub volume: f64,
+    #[serde(default)]
+    pub balance: f64,
+    #[serde(default)]
+    pub analysis: AnalysisProgress,
+    #[serde(default)]
+    pub downloads: DownloadProgress,
+    // Set while the network is down and only cached tracks can play.
+    #[serde(default)]
+    pub offline: bool,
+    // Present while guest mode is on.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub guest: Option<GuestState>,
+    // The loudest the volume can be set while quiet hours or the profile
+    // limit it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_volume: Option<f64>,
+    // The selected profile.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub profile: Option<String>,
+    // Problems found by the self test at startup.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub self_test_failures: Vec<String>,
+    // Keyed by GPIO pin.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub buttons: HashMap<u8, ButtonUsage>,
+    // The configured audio output in use.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub audio_output: Option<String>,
+}
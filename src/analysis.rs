@@ -0,0 +1,404 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::metadata;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use futures::channel::mpsc::{unbounded, UnboundedSender};
+use futures::stream::StreamExt;
+use glib::object::{Cast, ObjectExt};
+use glib::value::Value;
+use glib::ValueArray;
+use gstreamer::message::MessageView;
+use gstreamer::{
+    parse_launch, Bin, ClockTime, ElementExt, GstBinExt, MessageType, State, Structure,
+};
+use musicbox_protocol::{AnalysisProgress, TrackAnalysis};
+use serde::{Deserialize, Serialize};
+use serde_json::{from_slice, to_vec};
+use tokio::fs::{read, write};
+use tokio::sync::watch;
+use tokio::task::spawn_blocking;
+use tracing::{debug, info, warn};
+
+use crate::appstate::MutableAppState;
+use crate::error::{ErrorExt, MusicResult, VoidResult};
+use crate::track::Track;
+
+const ANALYSIS_FILE: &str = "analysis.json";
+// How often the level element reports, in nanoseconds.
+const LEVEL_INTERVAL: u64 = 100_000_000;
+const WAVEFORM_POINTS: usize = 100;
+// Anything quieter than this, in dB, is treated as silence.
+const SILENCE: f64 = -60.0;
+
+fn default_concurrency() -> usize {
+    1
+}
+
+fn default_pause_during_playback() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AnalysisConfig {
+    // How many tracks to analyse at once.
+    #[serde(default = "default_concurrency")]
+    pub concurrency: usize,
+
+    // Analysis is decoding audio so on a small board it can interrupt
+    // playback.
+    #[serde(default = "default_pause_during_playback")]
+    pub pause_during_playback: bool,
+}
+
+impl Default for AnalysisConfig {
+    fn default() -> AnalysisConfig {
+        AnalysisConfig {
+            concurrency: default_concurrency(),
+            pause_during_playback: default_pause_during_playback(),
+        }
+    }
+}
+
+// Resizes a waveform to the given number of points, keeping the peak of each
+// section.
+pub fn resample_waveform(waveform: &[u8], points: usize) -> Vec<u8> {
+    if waveform.is_empty() {
+        return Vec::new();
+    }
+
+    (0..points)
+        .map(|i| {
+            let start = i * waveform.len() / points;
+            let end = ((i + 1) * waveform.len() / points).max(start + 1);
+            waveform[start..end].iter().cloned().max().unwrap_or(0)
+        })
+        .collect()
+}
+
+fn db_to_power(db: f64) -> f64 {
+    10f64.powf(db / 10.0)
+}
+
+fn channel_levels(structure: &Structure, field: &str) -> Vec<f64> {
+    match structure.get::<ValueArray>(field) {
+        Ok(Some(levels)) => levels
+            .iter()
+            .filter_map(|value| value.get::<f64>().ok().and_then(|level| level))
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+// Accumulates the messages from a level element.
+#[derive(Default)]
+struct Levels {
+    power: f64,
+    peaks: Vec<f64>,
+}
+
+impl Levels {
+    fn record(&mut self, structure: &Structure) {
+        let rms = channel_levels(structure, "rms");
+        let peak = channel_levels(structure, "peak");
+        if rms.is_empty() || peak.is_empty() {
+            return;
+        }
+
+        self.power += rms.iter().map(|db| db_to_power(*db)).sum::<f64>() / rms.len() as f64;
+        self.peaks
+            .push(peak.into_iter().fold(f64::NEG_INFINITY, f64::max));
+    }
+
+    fn analysis(self) -> TrackAnalysis {
+        let count = self.peaks.len();
+        let loudness = if count > 0 {
+            (10.0 * (self.power / count as f64).log10()).max(SILENCE)
+        } else {
+            SILENCE
+        };
+
+        let chunk = count.div_ceil(WAVEFORM_POINTS).max(1);
+        let waveform = self
+            .peaks
+            .chunks(chunk)
+            .map(|levels| {
+                let peak = levels.iter().cloned().fold(SILENCE, f64::max);
+                ((peak - SILENCE) / -SILENCE * 255.0).clamp(0.0, 255.0) as u8
+            })
+            .collect();
+
+        TrackAnalysis {
+            loudness,
+            peak: self.peaks.iter().cloned().fold(SILENCE, f64::max),
+            duration: Duration::from_nanos(count as u64 * LEVEL_INTERVAL),
+            waveform,
+        }
+    }
+}
+
+// Decodes the whole track as fast as possible. This blocks until complete.
+fn analyze(path: &Path) -> MusicResult<TrackAnalysis> {
+    gstreamer::init().prefix("Unable to initialize gstreamer")?;
+
+    let pipeline = parse_launch(&format!(
+        "filesrc name=source ! decodebin ! audioconvert ! level interval={} ! fakesink sync=false",
+        LEVEL_INTERVAL
+    ))
+    .prefix("Unable to create analysis pipeline")?;
+    pipeline
+        .clone()
+        .dynamic_cast::<Bin>()
+        .map_err(|_| String::from("Analysis pipeline is not a bin."))?
+        .get_by_name("source")
+        .ok_or_else(|| String::from("Unable to get analysis source."))?
+        .set_property("location", &Value::from(&path.display().to_string()))
+        .prefix("Unable to load source file")?;
+
+    let bus = pipeline
+        .get_bus()
+        .ok_or_else(|| String::from("Unable to get analysis bus."))?;
+    pipeline
+        .set_state(State::Playing)
+        .prefix("Unable to start analysis")?;
+
+    let mut levels = Levels::default();
+    let result = loop {
+        let message = match bus.timed_pop_filtered(
+            ClockTime::none(),
+            &[MessageType::Eos, MessageType::Error, MessageType::Element],
+        ) {
+            Some(message) => message,
+            None => break Ok(()),
+        };
+
+        match message.view() {
+            MessageView::Eos(_) => break Ok(()),
+            MessageView::Error(e) => break Err(format!("Analysis failed: {}", e.get_error())),
+            MessageView::Element(element) => {
+                if let Some(structure) = element.get_structure() {
+                    if structure.get_name() == "level" {
+                        levels.record(structure);
+                    }
+                }
+            }
+            _ => (),
+        }
+    };
+
+    pipeline
+        .set_state(State::Null)
+        .prefix("Unable to stop analysis")?;
+    result.map(|()| levels.analysis())
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CacheEntry {
+    modified: SystemTime,
+    size: u64,
+    analysis: TrackAnalysis,
+}
+
+fn file_version(path: &Path) -> Option<(SystemTime, u64)> {
+    let file = metadata(path).ok()?;
+    Some((
+        file.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+        file.len(),
+    ))
+}
+
+struct Results {
+    file: PathBuf,
+    entries: HashMap<PathBuf, CacheEntry>,
+    queued: HashSet<PathBuf>,
+    completed: usize,
+    paused: bool,
+}
+
+impl Results {
+    fn is_current(&self, path: &Path) -> bool {
+        match (self.entries.get(path), file_version(path)) {
+            (Some(entry), Some((modified, size))) => {
+                entry.modified == modified && entry.size == size
+            }
+            _ => false,
+        }
+    }
+
+    fn progress(&self) -> AnalysisProgress {
+        AnalysisProgress {
+            queued: self.queued.len(),
+            completed: self.completed,
+            paused: self.paused,
+        }
+    }
+}
+
+// Analyses tracks in the background and caches the results. Entries are only
+// considered valid while the file's modification time and size are unchanged.
+#[derive(Clone)]
+pub struct Analyzer {
+    results: Arc<Mutex<Results>>,
+    jobs: UnboundedSender<PathBuf>,
+    pause: Arc<watch::Sender<bool>>,
+    pause_during_playback: bool,
+    state: MutableAppState,
+}
+
+impl Analyzer {
+    pub async fn new(data_dir: &Path, config: &AnalysisConfig, state: MutableAppState) -> Analyzer {
+        let file = data_dir.join(ANALYSIS_FILE);
+
+        let entries = match read(&file).await {
+            Ok(data) => match from_slice(&data) {
+                Ok(entries) => entries,
+                Err(e) => {
+                    warn!("Discarding unreadable track analysis: {}", e);
+                    HashMap::new()
+                }
+            },
+            Err(_) => HashMap::new(),
+        };
+
+        let (jobs, receiver) = unbounded::<PathBuf>();
+        let (pause, paused) = watch::channel(false);
+
+        let analyzer = Analyzer {
+            results: Arc::new(Mutex::new(Results {
+                file,
+                entries,
+                queued: HashSet::new(),
+                completed: 0,
+                paused: false,
+            })),
+            jobs,
+            pause: Arc::new(pause),
+            pause_during_playback: config.pause_during_playback,
+            state,
+        };
+
+        let worker = analyzer.clone();
+        tokio::spawn(
+            receiver
+                .map(move |path| {
+                    let mut paused = paused.clone();
+                    async move {
+                        // Wait here rather than in the queue so a job that has
+                        // started finishes.
+                        while *paused.borrow() {
+                            if paused.recv().await.is_none() {
+                                break;
+                            }
+                        }
+
+                        debug!("Analysing {}.", path.display());
+                        let target = path.clone();
+                        let result = spawn_blocking(move || analyze(&target)).await;
+                        (path, result)
+                    }
+                })
+                .buffer_unordered(config.concurrency.max(1))
+                .for_each(move |(path, result)| {
+                    let mut worker = worker.clone();
+                    async move {
+                        let analysis = match result {
+                            Ok(Ok(analysis)) => Some(analysis),
+                            Ok(Err(e)) => {
+                                warn!("Failed to analyse {}: {}", path.display(), e);
+                                None
+                            }
+                            Err(e) => {
+                                warn!("Failed to analyse {}: {}", path.display(), e);
+                                None
+                            }
+                        };
+
+                        worker.finished(&path, analysis);
+                        worker.save().await.log().drop();
+                    }
+                }),
+        );
+
+        analyzer
+    }
+
+    // Adds any of the tracks that haven't been analysed to the queue.
+    pub fn queue(&mut self, tracks: &[Track]) {
+        let mut results = self.results.lock().unwrap();
+        let mut count = 0;
+        for track in tracks {
+            let path = track.path();
+            // Tracks from storage backends can't be analysed until they are
+            // downloaded.
+            if !path.exists() || results.queued.contains(&path) || results.is_current(&path) {
+                continue;
+            }
+
+            results.queued.insert(path.clone());
+            self.jobs.unbounded_send(path).drop();
+            count += 1;
+        }
+
+        if count > 0 {
+            info!("Queued {} tracks for analysis.", count);
+            self.state.set_analysis_progress(results.progress());
+        }
+    }
+
+    fn finished(&mut self, path: &Path, analysis: Option<TrackAnalysis>) {
+        let mut results = self.results.lock().unwrap();
+        results.queued.remove(path);
+        results.completed += 1;
+
+        if let (Some(analysis), Some((modified, size))) = (analysis, file_version(path)) {
+            results.entries.insert(
+                path.to_owned(),
+                CacheEntry {
+                    modified,
+                    size,
+                    analysis,
+                },
+            );
+        }
+
+        self.state.set_analysis_progress(results.progress());
+    }
+
+    async fn save(&self) -> VoidResult {
+        let (file, data) = {
+            let results = self.results.lock().unwrap();
+            (
+                results.file.clone(),
+                to_vec(&results.entries).prefix("Unable to serialize track analysis")?,
+            )
+        };
+
+        write(&file, data)
+            .await
+            .prefix("Unable to write track analysis")
+    }
+
+    pub fn analysis(&self, path: &Path) -> Option<TrackAnalysis> {
+        let results = self.results.lock().unwrap();
+        results
+            .entries
+            .get(path)
+            .map(|entry| entry.analysis.clone())
+    }
+
+    // Pauses analysis while something is playing, if so configured.
+    pub fn set_playing(&mut self, playing: bool) {
+        let paused = playing && self.pause_during_playback;
+        let mut results = self.results.lock().unwrap();
+        if results.paused == paused {
+            return;
+        }
+
+        results.paused = paused;
+        self.pause.broadcast(paused).drop();
+        self.state.set_analysis_progress(results.progress());
+    }
+}
@@ -1,26 +1,64 @@
-use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, TryLockError};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+use musicbox_protocol::{
+    AnalysisProgress, ButtonUsage, Command, DownloadProgress, GuestState, HistoryEntry, Origin,
+    PlayState, Queue, StateSnapshot,
+};
 use serde::{Serialize, Serializer};
 
+#[cfg(feature = "rpi")]
+use crate::hardware::gpio::led::LED;
 use crate::playlist::StoredPlaylist;
 use crate::track::Track;
 
-#[derive(Serialize)]
-pub struct PlayState {
-    position: usize,
-    duration: Duration,
-    paused: bool,
-}
+// How many handled commands are remembered.
+const HISTORY_LENGTH: usize = 50;
 
-#[derive(Serialize)]
-#[serde(rename_all = "camelCase")]
 struct InnerState {
     stored_playlists: HashMap<String, StoredPlaylist>,
     playlist: Vec<Track>,
     play_state: Option<PlayState>,
     volume: f64,
+    balance: f64,
+    history: VecDeque<HistoryEntry>,
+    analysis: AnalysisProgress,
+    downloads: DownloadProgress,
+    offline: bool,
+    guest: Option<GuestState>,
+    max_volume: Option<f64>,
+    profile: Option<String>,
+    self_test_failures: Vec<String>,
+    buttons: HashMap<u8, ButtonUsage>,
+    audio_output: Option<String>,
+    // Changes whenever anything in the snapshot changes.
+    version: u64,
+}
+
+impl InnerState {
+    fn snapshot(&self) -> StateSnapshot {
+        StateSnapshot {
+            stored_playlists: self
+                .stored_playlists
+                .iter()
+                .map(|(name, playlist)| (name.clone(), playlist.info()))
+                .collect(),
+            playlist: self.playlist.iter().map(Track::info).collect(),
+            play_state: self.play_state.clone(),
+            volume: self.volume,
+            balance: self.balance,
+            analysis: self.analysis.clone(),
+            downloads: self.downloads.clone(),
+            offline: self.offline,
+            guest: self.guest.clone(),
+            max_volume: self.max_volume,
+            profile: self.profile.clone(),
+            self_test_failures: self.self_test_failures.clone(),
+            buttons: self.buttons.clone(),
+            audio_output: self.audio_output.clone(),
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -28,12 +66,169 @@ pub struct AppState {
     state: Arc<Mutex<InnerState>>,
 }
 
+impl AppState {
+    pub fn track(&self, id: &str) -> Option<Track> {
+        let state = self.state.lock().unwrap();
+        state
+            .stored_playlists
+            .values()
+            .find_map(|playlist| playlist.track(id))
+            .or_else(|| {
+                state
+                    .playlist
+                    .iter()
+                    .find(|track| track.id() == id)
+                    .cloned()
+            })
+    }
+
+    pub fn queue(&self) -> Queue {
+        let state = self.state.lock().unwrap();
+        Queue {
+            tracks: state.playlist.iter().map(Track::info).collect(),
+            position: state.play_state.as_ref().map(|state| state.position),
+        }
+    }
+
+    pub fn is_queued(&self, id: &str) -> bool {
+        self.state
+            .lock()
+            .unwrap()
+            .playlist
+            .iter()
+            .any(|track| track.id() == id)
+    }
+
+    pub fn has_stored_playlist(&self, name: &str) -> bool {
+        self.state
+            .lock()
+            .unwrap()
+            .stored_playlists
+            .contains_key(name)
+    }
+
+    pub fn stored_playlist_count(&self) -> usize {
+        self.state.lock().unwrap().stored_playlists.len()
+    }
+
+    // The most recently handled commands, oldest first.
+    pub fn history(&self) -> Vec<HistoryEntry> {
+        self.state.lock().unwrap().history.iter().cloned().collect()
+    }
+
+    // The playing track, the stored playlist it is from and how far through
+    // it playback is.
+    pub fn now_playing(&self) -> Option<(Track, Option<String>, PlayState)> {
+        let state = self.state.lock().unwrap();
+        let play_state = state.play_state.clone()?;
+        let track = state.playlist.get(play_state.position)?.clone();
+        let playlist = state
+            .stored_playlists
+            .values()
+            .find(|playlist| playlist.equals(&state.playlist))
+            .map(StoredPlaylist::name);
+        Some((track, playlist, play_state))
+    }
+
+    // Tracks in the stored playlists whose title or artist contains every
+    // word of the query, ignoring case. Playlists are searched in name order
+    // so results stay in the same order between pages.
+    pub fn search(&self, query: &str) -> Vec<(String, Track)> {
+        let words: Vec<String> = query.split_whitespace().map(str::to_lowercase).collect();
+        if words.is_empty() {
+            return Vec::new();
+        }
+
+        let state = self.state.lock().unwrap();
+        let mut names: Vec<&String> = state.stored_playlists.keys().collect();
+        names.sort();
+
+        let mut results = Vec::new();
+        for name in names {
+            for track in state.stored_playlists[name].tracks() {
+                let info = track.info();
+                let text = format!(
+                    "{} {}",
+                    info.title.to_lowercase(),
+                    info.artist.unwrap_or_default().to_lowercase()
+                );
+                if words.iter().all(|word| text.contains(word.as_str())) {
+                    results.push((name.clone(), track));
+                }
+            }
+        }
+
+        results
+    }
+
+    #[cfg(feature = "rpi")]
+    pub fn update_leds(&self) {
+        let state = self.state.lock().unwrap();
+        let unpaused = match state.play_state {
+            Some(ref play_state) => !play_state.paused,
+            None => false,
+        };
+
+        for playlist in state.stored_playlists.values() {
+            playlist.update_led(unpaused && playlist.equals(&state.playlist));
+        }
+    }
+
+    // The LED of the named playlist.
+    #[cfg(feature = "rpi")]
+    pub fn led(&self, name: &str) -> Option<LED> {
+        self.state
+            .lock()
+            .unwrap()
+            .stored_playlists
+            .get(name)
+            .and_then(|playlist| playlist.led().cloned())
+    }
+
+    #[cfg(feature = "rpi")]
+    pub fn leds(&self) -> Vec<LED> {
+        self.state
+            .lock()
+            .unwrap()
+            .stored_playlists
+            .values()
+            .filter_map(|playlist| playlist.led().cloned())
+            .collect()
+    }
+}
+
+impl AppState {
+    pub fn snapshot(&self) -> StateSnapshot {
+        self.state.lock().unwrap().snapshot()
+    }
+
+    pub fn version(&self) -> u64 {
+        self.state.lock().unwrap().version
+    }
+
+    // Gives up rather than waiting if the state is locked, for use while
+    // panicking when the thread holding the lock may never release it.
+    pub fn try_snapshot(&self) -> Option<StateSnapshot> {
+        match self.state.try_lock() {
+            Ok(state) => Some(state.snapshot()),
+            Err(TryLockError::Poisoned(e)) => Some(e.into_inner().snapshot()),
+            Err(TryLockError::WouldBlock) => None,
+        }
+    }
+
+    // The snapshot along with the version it was taken at.
+    pub fn versioned_snapshot(&self) -> (u64, StateSnapshot) {
+        let state = self.state.lock().unwrap();
+        (state.version, state.snapshot())
+    }
+}
+
 impl Serialize for AppState {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: Serializer,
     {
-        self.state.lock().unwrap().serialize(serializer)
+        self.snapshot().serialize(serializer)
     }
 }
 
@@ -55,6 +250,18 @@ impl MutableAppState {
                 playlist: Default::default(),
                 play_state: None,
                 volume: 0.0,
+                balance: 0.0,
+                history: VecDeque::with_capacity(HISTORY_LENGTH),
+                analysis: Default::default(),
+                downloads: Default::default(),
+                offline: false,
+                guest: None,
+                max_volume: None,
+                profile: None,
+                self_test_failures: Vec::new(),
+                buttons: HashMap::new(),
+                audio_output: None,
+                version: 0,
             })),
         }
     }
@@ -73,8 +280,79 @@ impl MutableAppState {
         self.state.lock().unwrap().volume
     }
 
+    // Changes the state and bumps its version.
+    fn modify<F, R>(&mut self, f: F) -> R
+    where
+        F: FnOnce(&mut InnerState) -> R,
+    {
+        let mut state = self.state.lock().unwrap();
+        state.version += 1;
+        f(&mut state)
+    }
+
     pub fn set_volume(&mut self, volume: f64) {
-        self.state.lock().unwrap().volume = volume
+        self.modify(|state| state.volume = volume)
+    }
+
+    pub fn set_balance(&mut self, balance: f64) {
+        self.modify(|state| state.balance = balance)
+    }
+
+    pub fn set_analysis_progress(&mut self, progress: AnalysisProgress) {
+        self.modify(|state| state.analysis = progress)
+    }
+
+    pub fn set_download_progress(&mut self, progress: DownloadProgress) {
+        self.modify(|state| state.downloads = progress)
+    }
+
+    pub fn set_offline(&mut self, offline: bool) {
+        self.modify(|state| state.offline = offline)
+    }
+
+    pub fn set_max_volume(&mut self, max_volume: Option<f64>) {
+        if self.state.lock().unwrap().max_volume != max_volume {
+            self.modify(|state| state.max_volume = max_volume)
+        }
+    }
+
+    pub fn set_profile(&mut self, profile: Option<String>) {
+        self.modify(|state| state.profile = profile)
+    }
+
+    pub fn set_self_test_failures(&mut self, failures: Vec<String>) {
+        self.modify(|state| state.self_test_failures = failures)
+    }
+
+    pub fn set_button_usage(&mut self, pin: u8, usage: ButtonUsage) {
+        self.modify(|state| state.buttons.insert(pin, usage));
+    }
+
+    pub fn set_audio_output(&mut self, name: Option<String>) {
+        self.modify(|state| state.audio_output = name)
+    }
+
+    pub fn set_guest(&mut self, guest: Option<GuestState>) {
+        if self.state.lock().unwrap().guest != guest {
+            self.modify(|state| state.guest = guest)
+        }
+    }
+
+    pub fn record_command(&mut self, command: &Command, origin: &Origin) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_millis() as u64)
+            .unwrap_or_default();
+
+        let mut state = self.state.lock().unwrap();
+        if state.history.len() == HISTORY_LENGTH {
+            state.history.pop_front();
+        }
+        state.history.push_back(HistoryEntry {
+            command: command.clone(),
+            origin: origin.clone(),
+            timestamp,
+        });
     }
 
     pub fn paused(&self) -> Option<bool> {
@@ -87,10 +365,11 @@ impl MutableAppState {
     }
 
     pub fn set_paused(&mut self, paused: bool) {
-        let mut state = self.state.lock().unwrap();
-        if let Some(ref mut play_state) = state.play_state {
-            play_state.paused = paused;
-        }
+        self.modify(|state| {
+            if let Some(ref mut play_state) = state.play_state {
+                play_state.paused = paused;
+            }
+        })
     }
 
     pub fn playback_position(&self) -> Option<usize> {
@@ -111,13 +390,22 @@ impl MutableAppState {
             .map(|state| state.duration)
     }
 
+    pub fn set_playback_duration(&mut self, duration: Duration) {
+        self.modify(|state| {
+            if let Some(ref mut play_state) = state.play_state {
+                play_state.duration = duration;
+            }
+        })
+    }
+
     pub fn set_playback_position(&mut self, position: Option<usize>) {
-        let mut state = self.state.lock().unwrap();
-        state.play_state = position.map(|position| PlayState {
-            position,
-            duration: Default::default(),
-            paused: false,
-        });
+        self.modify(|state| {
+            state.play_state = position.map(|position| PlayState {
+                position,
+                duration: Default::default(),
+                paused: false,
+            })
+        })
     }
 
     pub fn is_playing_playlist(&self, name: &str) -> bool {
@@ -133,6 +421,20 @@ impl MutableAppState {
         }
     }
 
+    pub fn stored_playlist_names(&self) -> Vec<String> {
+        self.state
+            .lock()
+            .unwrap()
+            .stored_playlists
+            .keys()
+            .cloned()
+            .collect()
+    }
+
+    pub fn set_stored_playlist(&mut self, playlist: StoredPlaylist) {
+        self.modify(|state| state.stored_playlists.insert(playlist.name(), playlist));
+    }
+
     pub fn stored_playlist(&self, name: &str) -> Option<StoredPlaylist> {
         self.state
             .lock()
@@ -143,6 +445,22 @@ impl MutableAppState {
     }
 
     pub fn set_playlist(&mut self, tracks: Vec<Track>) {
-        self.state.lock().unwrap().playlist = tracks;
+        self.modify(|state| state.playlist = tracks)
+    }
+
+    // Replaces the queue after an edit, moving the playing track to its new
+    // position or stopping it if it was removed.
+    pub fn set_queue(&mut self, tracks: Vec<Track>, position: Option<usize>) {
+        self.modify(|state| {
+            state.playlist = tracks;
+            match position {
+                Some(position) => {
+                    if let Some(ref mut play_state) = state.play_state {
+                        play_state.position = position;
+                    }
+                }
+                None => state.play_state = None,
+            }
+        })
     }
 }
@@ -0,0 +1,191 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use serde::Deserialize;
+use tracing::error;
+
+use crate::schedule::{TimeOfDay, TimeWindow};
+use crate::sounds::Sound;
+
+fn default_duck_volume() -> f64 {
+    0.3
+}
+
+fn default_ramp_start() -> f64 {
+    0.05
+}
+
+fn default_quiet_volume() -> f64 {
+    0.3
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type")]
+pub enum FilterConfig {
+    // Gains in dB for each of the ten bands from 29Hz to 15kHz.
+    Equalizer { bands: Vec<f64> },
+    // Hard limits the output at the threshold, between 0 and 1.
+    Limiter { threshold: f64 },
+    MonoDownmix,
+    // Changes the playback speed without changing the pitch.
+    Speed { rate: f64 },
+    // Any other gstreamer element description.
+    Element { description: String },
+}
+
+impl FilterConfig {
+    fn description(&self) -> String {
+        match self {
+            FilterConfig::Equalizer { bands } => {
+                let mut description = String::from("equalizer-10bands");
+                for (i, gain) in bands.iter().enumerate().take(10) {
+                    description.push_str(&format!(" band{}={}", i, gain));
+                }
+                description
+            }
+            FilterConfig::Limiter { threshold } => format!(
+                "audiodynamic mode=compressor characteristics=hard-knee ratio=0 threshold={}",
+                threshold
+            ),
+            FilterConfig::MonoDownmix => String::from("audio/x-raw,channels=1"),
+            FilterConfig::Speed { rate } => format!("pitch tempo={}", rate),
+            FilterConfig::Element { description } => description.clone(),
+        }
+    }
+}
+
+// Somewhere to send audio, a USB DAC or the headphone jack for example.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OutputConfig {
+    pub name: String,
+    // The gstreamer sink, "alsasink device=hw:1" for example.
+    pub sink: String,
+}
+
+// Raises the volume gradually when a playlist starts.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VolumeRampConfig {
+    // In milliseconds.
+    pub duration: u64,
+
+    // The volume that playback starts at, between 0 and 1.
+    #[serde(default = "default_ramp_start")]
+    pub start: f64,
+}
+
+// How a change in volume is announced, for music boxes with nothing to show
+// the volume on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum VolumeFeedback {
+    // A blip that gets higher and louder as the volume goes up.
+    Tone,
+    // The new volume is spoken as a percentage once it stops changing.
+    Speech,
+}
+
+// Limits the volume at times when loud music would disturb anyone.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QuietHoursConfig {
+    #[serde(flatten)]
+    pub window: TimeWindow,
+
+    // The loudest the volume can be set, between 0 and 1.
+    #[serde(default = "default_quiet_volume")]
+    pub max_volume: f64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AudioConfig {
+    // Named lists of filters that are inserted between decoding and output.
+    #[serde(default)]
+    pub pipelines: HashMap<String, Vec<FilterConfig>>,
+
+    // Outputs in order of preference. Playback uses the first that works and
+    // moves on to the next if it fails. The system default is used when
+    // there are none or none of them work.
+    #[serde(default)]
+    pub outputs: Vec<OutputConfig>,
+
+    // The pipeline used by playlists that don't name their own.
+    pub default_pipeline: Option<String>,
+
+    // From -1 (left only) to 1 (right only).
+    #[serde(default)]
+    pub balance: f64,
+
+    // The fraction of the volume that the playlist is lowered to while an
+    // announcement plays over it.
+    #[serde(default = "default_duck_volume")]
+    pub duck_volume: f64,
+
+    pub ramp: Option<VolumeRampConfig>,
+
+    #[serde(default)]
+    pub quiet_hours: Vec<QuietHoursConfig>,
+
+    pub volume_feedback: Option<VolumeFeedback>,
+
+    // Files to play in place of the built in feedback tones. These are kept
+    // ready to play so they are heard as soon as a button is pressed.
+    #[serde(default)]
+    pub sounds: HashMap<Sound, PathBuf>,
+}
+
+impl Default for AudioConfig {
+    fn default() -> AudioConfig {
+        AudioConfig {
+            pipelines: HashMap::new(),
+            outputs: Vec::new(),
+            default_pipeline: None,
+            balance: 0.0,
+            duck_volume: default_duck_volume(),
+            ramp: None,
+            quiet_hours: Vec::new(),
+            volume_feedback: None,
+            sounds: HashMap::new(),
+        }
+    }
+}
+
+impl AudioConfig {
+    // The loudest the volume may be at the given time, None outside of quiet
+    // hours.
+    pub fn max_volume(&self, time: TimeOfDay) -> Option<f64> {
+        self.quiet_hours
+            .iter()
+            .filter(|quiet| quiet.window.contains(time))
+            .map(|quiet| quiet.max_volume)
+            .fold(None, |max, volume| match max {
+                Some(max) if max < volume => Some(max),
+                _ => Some(volume),
+            })
+    }
+
+    // Builds the gstreamer description of the named pipeline, or the default
+    // pipeline if no name is given.
+    pub fn filters(&self, name: Option<&str>) -> Option<String> {
+        let name = name.or(self.default_pipeline.as_deref())?;
+        let filters = match self.pipelines.get(name) {
+            Some(filters) => filters,
+            None => {
+                error!("Unknown audio pipeline {}.", name);
+                return None;
+            }
+        };
+
+        if filters.is_empty() {
+            return None;
+        }
+
+        let mut parts = vec![String::from("audioconvert")];
+        for filter in filters {
+            parts.push(filter.description());
+            parts.push(String::from("audioconvert"));
+        }
+        Some(parts.join(" ! "))
+    }
+}
@@ -0,0 +1,62 @@
+use serde::Deserialize;
+use tracing::warn;
+
+pub use musicbox_protocol::Authorization;
+
+use crate::events::Command;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AuthConfig {
+    // Web clients that connect with this token may issue admin commands.
+    pub admin_token: Option<String>,
+
+    // Allows web admins and hardware controls with the key switch on to issue
+    // system commands. The console and signals can always issue them.
+    #[serde(default)]
+    pub allow_system: bool,
+}
+
+impl AuthConfig {
+    // The most a trusted remote or hardware source may be granted.
+    pub fn elevated(&self) -> Authorization {
+        if self.allow_system {
+            Authorization::System
+        } else {
+            Authorization::Admin
+        }
+    }
+
+    // The authorization for a web client that presented the given token.
+    pub fn for_token(&self, token: Option<&str>) -> Authorization {
+        match (&self.admin_token, token) {
+            (Some(expected), Some(token)) if expected == token => self.elevated(),
+            _ => Authorization::Playback,
+        }
+    }
+}
+
+// Decides what a command source is currently allowed to do.
+pub trait Authorizer: Send + Sync {
+    fn authorization(&self) -> Authorization;
+}
+
+impl Authorizer for Authorization {
+    fn authorization(&self) -> Authorization {
+        *self
+    }
+}
+
+// Checks that a command is allowed, logging when it is not.
+pub fn is_authorized(source: &str, granted: Authorization, command: &Command) -> bool {
+    let needed = command.authorization();
+    if needed > granted {
+        warn!(
+            "Rejected {:?} from {}, it needs {:?} authorization but only has {:?}.",
+            command, source, needed, granted
+        );
+        false
+    } else {
+        true
+    }
+}
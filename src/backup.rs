@@ -0,0 +1,246 @@
+use std::collections::HashMap;
+use std::fs::{create_dir_all, read, read_dir, remove_file, write};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{from_slice, to_vec, to_vec_pretty, Value};
+use tracing::{info, warn};
+
+use crate::assets::Config;
+use crate::error::{ErrorExt, MusicResult, VoidResult};
+
+const BACKUP_VERSION: u32 = 1;
+
+// The state files in the data directory that are backed up.
+const STATE_FILES: &[&str] = &[
+    "resume.json",
+    "library.json",
+    "analysis.json",
+    "guest.json",
+    "profiles.json",
+    "plays.jsonl",
+];
+
+// A restore that is applied the next time the music box starts.
+const STAGED_RESTORE: &str = "restore.json";
+
+// Everything needed to set up another music box the same way, apart from the
+// tracks themselves.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Backup {
+    pub version: u32,
+    // The hardware configuration the backup was made with.
+    pub config: Value,
+    // The contents of each state file. JSON lines files are kept as text.
+    pub state: HashMap<String, Value>,
+    // The track files in each playlist directory.
+    pub playlists: HashMap<String, Vec<String>>,
+}
+
+fn config() -> MusicResult<Value> {
+    let data = Config::get("hw_config.json")
+        .ok_or_else(|| String::from("Could not load hardware config."))?;
+    from_slice(&data).prefix("Failed to parse hardware config")
+}
+
+fn list_files(dir: &Path) -> MusicResult<Vec<String>> {
+    let mut files = Vec::new();
+    for entry in read_dir(dir).prefix("Unable to read playlist directory")? {
+        let entry = entry.as_err()?;
+        if entry.file_type().as_err()?.is_file() {
+            files.push(entry.file_name().to_string_lossy().to_string());
+        }
+    }
+    files.sort();
+
+    Ok(files)
+}
+
+fn playlists_dir(data_dir: &Path) -> PathBuf {
+    data_dir.join("playlists")
+}
+
+fn is_json_lines(name: &str) -> bool {
+    name.ends_with(".jsonl")
+}
+
+fn read_state(data_dir: &Path) -> HashMap<String, Value> {
+    let mut state = HashMap::new();
+    for name in STATE_FILES {
+        if let Ok(data) = read(data_dir.join(name)) {
+            let value = if is_json_lines(name) {
+                String::from_utf8(data)
+                    .map(Value::String)
+                    .map_err(|e| e.to_string())
+            } else {
+                from_slice(&data).map_err(|e| e.to_string())
+            };
+
+            match value {
+                Ok(value) => {
+                    state.insert(name.to_string(), value);
+                }
+                Err(e) => warn!("Not backing up unreadable {}: {}", name, e),
+            }
+        }
+    }
+    state
+}
+
+fn write_state(data_dir: &Path, state: &HashMap<String, Value>) -> VoidResult {
+    for (name, value) in state {
+        if !STATE_FILES.contains(&name.as_str()) {
+            warn!("Ignoring unknown state file {} in the backup.", name);
+            continue;
+        }
+
+        let data = match value {
+            Value::String(text) if is_json_lines(name) => text.clone().into_bytes(),
+            value => to_vec(value).prefix("Unable to serialize state")?,
+        };
+        write(data_dir.join(name), data).format(|e| format!("Unable to write {}: {}", name, e))?;
+    }
+    Ok(())
+}
+
+pub fn create(data_dir: &Path) -> MusicResult<Backup> {
+    let state = read_state(data_dir);
+
+    let mut playlists = HashMap::new();
+    if let Ok(entries) = read_dir(playlists_dir(data_dir)) {
+        for entry in entries {
+            let entry = entry.as_err()?;
+            if entry.file_type().as_err()?.is_dir() {
+                playlists.insert(
+                    entry.file_name().to_string_lossy().to_string(),
+                    list_files(&entry.path())?,
+                );
+            }
+        }
+    }
+
+    Ok(Backup {
+        version: BACKUP_VERSION,
+        config: config()?,
+        state,
+        playlists,
+    })
+}
+
+// Writes the backed up state into the data directory. This should only
+// happen while the music box isn't running or it will overwrite the state.
+pub fn restore(data_dir: &Path, backup: &Backup) -> VoidResult {
+    if backup.version > BACKUP_VERSION {
+        return Err(format!(
+            "Backup version {} is newer than this music box supports.",
+            backup.version
+        ));
+    }
+
+    // The configuration is built into the binary so can't be restored here.
+    if config()? != backup.config {
+        warn!("The backup was made with a different hardware configuration.");
+    }
+
+    write_state(data_dir, &backup.state)?;
+
+    for (name, tracks) in &backup.playlists {
+        if name.contains('/') || name == "." || name == ".." {
+            warn!("Ignoring invalid playlist name {} in the backup.", name);
+            continue;
+        }
+
+        let dir = playlists_dir(data_dir).join(name);
+        create_dir_all(&dir).prefix("Unable to create playlist directory")?;
+
+        let present = list_files(&dir)?;
+        let missing = tracks
+            .iter()
+            .filter(|track| !present.contains(track))
+            .count();
+        if missing > 0 {
+            warn!(
+                "{} of the {} tracks in the {} playlist need to be copied over.",
+                missing,
+                tracks.len(),
+                name
+            );
+        }
+    }
+
+    info!("Restored backup.");
+    Ok(())
+}
+
+// Saves a backup to restore the next time the music box starts.
+pub fn stage(data_dir: &Path, backup: &Backup) -> VoidResult {
+    let data = to_vec(backup).prefix("Unable to serialize backup")?;
+    write(data_dir.join(STAGED_RESTORE), data).prefix("Unable to save backup")
+}
+
+// Restores any backup that was staged before the music box last stopped.
+pub fn restore_staged(data_dir: &Path) -> VoidResult {
+    let file = data_dir.join(STAGED_RESTORE);
+    let data = match read(&file) {
+        Ok(data) => data,
+        Err(_) => return Ok(()),
+    };
+
+    // Removed first so a bad backup can't stop the music box from starting.
+    remove_file(&file).prefix("Unable to remove staged backup")?;
+    let backup: Backup = from_slice(&data).prefix("Unable to parse staged backup")?;
+    restore(data_dir, &backup)
+}
+
+pub fn export(data_dir: &Path, file: &Path) -> VoidResult {
+    let data = to_vec_pretty(&create(data_dir)?).prefix("Unable to serialize backup")?;
+    write(file, data).prefix("Unable to write backup")
+}
+
+pub fn import(data_dir: &Path, file: &Path) -> VoidResult {
+    let data = read(file).prefix("Unable to read backup")?;
+    let backup: Backup = from_slice(&data).prefix("Unable to parse backup")?;
+    restore(data_dir, &backup)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env::temp_dir;
+    use std::fs::{read_to_string, remove_dir_all};
+    use std::process;
+
+    use super::*;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = temp_dir().join(format!("musicbox-{}-{}", name, process::id()));
+        create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn json_lines_round_trip() {
+        let plays = "{\"path\":\"a.mp3\"}\n{\"path\":\"b.mp3\"}\n";
+        let source = test_dir("backup-source");
+        write(source.join("plays.jsonl"), plays).unwrap();
+        write(source.join("guest.json"), "{\"enabled\":true}").unwrap();
+
+        let state = read_state(&source);
+        assert_eq!(
+            state.get("plays.jsonl"),
+            Some(&Value::String(plays.to_owned()))
+        );
+        assert!(state.contains_key("guest.json"));
+
+        let target = test_dir("backup-target");
+        write_state(&target, &state).unwrap();
+        assert_eq!(read_to_string(target.join("plays.jsonl")).unwrap(), plays);
+        assert_eq!(
+            from_slice::<Value>(&read(target.join("guest.json")).unwrap()).unwrap(),
+            from_slice::<Value>(b"{\"enabled\":true}").unwrap()
+        );
+
+        remove_dir_all(source).unwrap();
+        remove_dir_all(target).unwrap();
+    }
+}
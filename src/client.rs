@@ -0,0 +1,69 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use musicbox_protocol::{CommandResult, ScanResult};
+use reqwest::{Client, StatusCode, Url};
+
+use crate::error::{ErrorExt, MusicResult};
+use crate::hw_config::HwConfig;
+
+// Talks to the API of the music box running from the same data directory, for
+// command line tools.
+pub struct LocalClient {
+    base: Url,
+    token: Option<String>,
+    client: Client,
+}
+
+impl LocalClient {
+    // Prefers the control server, which accepts commands even when the main
+    // server is read only.
+    pub fn new(hw_config: &HwConfig) -> MusicResult<LocalClient> {
+        let mut server: SocketAddr = hw_config.control_server.unwrap_or(hw_config.server);
+        if server.ip().is_unspecified() {
+            server.set_ip(match server.ip() {
+                IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+                IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+            });
+        }
+
+        Ok(LocalClient {
+            base: Url::parse(&format!("http://{}/api/", server))
+                .prefix("Invalid server address")?,
+            token: hw_config.auth.admin_token.clone(),
+            client: Client::new(),
+        })
+    }
+
+    fn url(&self, segments: &[&str]) -> MusicResult<Url> {
+        let mut url = self.base.clone();
+        url.path_segments_mut()
+            .map_err(|_| String::from("Invalid server address."))?
+            .pop_if_empty()
+            .extend(segments);
+        if let Some(ref token) = self.token {
+            url.query_pairs_mut().append_pair("token", token);
+        }
+        Ok(url)
+    }
+
+    pub async fn rescan(&self, playlist: &str) -> MusicResult<ScanResult> {
+        let response = self
+            .client
+            .post(self.url(&["playlists", playlist, "rescan"])?)
+            .send()
+            .await
+            .prefix("Unable to reach the music box")?;
+
+        match response.status() {
+            StatusCode::OK => response
+                .json()
+                .await
+                .prefix("Unable to parse the scan result"),
+            StatusCode::BAD_REQUEST => match response.json().await {
+                Ok(CommandResult::Rejected { reason }) => Err(reason),
+                _ => Err(String::from("The music box rejected the rescan.")),
+            },
+            status => Err(format!("The music box responded with {}.", status)),
+        }
+    }
+}
@@ -0,0 +1,122 @@
+use std::time::{Duration, Instant, SystemTime};
+
+use serde::Deserialize;
+use tokio::process::Command;
+use tokio::sync::watch::{channel, Receiver};
+use tokio::time::delay_for;
+use tracing::{info, warn};
+
+use crate::events::{Event, MessageSender};
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+fn default_wait_for_sync() -> bool {
+    true
+}
+
+fn default_step_threshold() -> u64 {
+    60
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ClockConfig {
+    // Holds back scheduled syncs and update checks until the clock has been
+    // set from the network. Without a real time clock the time is wrong after
+    // a power cut until then.
+    #[serde(default = "default_wait_for_sync")]
+    pub wait_for_sync: bool,
+
+    // In seconds. Larger jumps in the clock are reported so anything
+    // scheduled can be rescheduled.
+    #[serde(default = "default_step_threshold")]
+    pub step_threshold: u64,
+}
+
+impl Default for ClockConfig {
+    fn default() -> ClockConfig {
+        ClockConfig {
+            wait_for_sync: default_wait_for_sync(),
+            step_threshold: default_step_threshold(),
+        }
+    }
+}
+
+// Asks systemd whether the clock has been synchronized. If that isn't
+// possible there is no way to tell so the clock is trusted.
+async fn is_synchronized() -> bool {
+    match Command::new("timedatectl")
+        .args(&["show", "--property=NTPSynchronized", "--value"])
+        .output()
+        .await
+    {
+        Ok(output) if output.status.success() => {
+            String::from_utf8_lossy(&output.stdout).trim() == "yes"
+        }
+        _ => {
+            warn!("Unable to tell whether the clock is synchronized, assuming it is.");
+            true
+        }
+    }
+}
+
+// How far the clock has moved from where it should be, in seconds.
+fn offset(expected: SystemTime, now: SystemTime) -> i64 {
+    match now.duration_since(expected) {
+        Ok(ahead) => ahead.as_secs() as i64,
+        Err(behind) => -(behind.duration().as_secs() as i64),
+    }
+}
+
+#[derive(Clone)]
+pub struct Clock {
+    synchronized: Receiver<bool>,
+}
+
+impl Clock {
+    pub fn is_synchronized(&self) -> bool {
+        *self.synchronized.borrow()
+    }
+
+    // Waits until the clock can be trusted.
+    pub async fn synchronized(&self) {
+        let mut receiver = self.synchronized.clone();
+        while !*receiver.borrow() {
+            if receiver.recv().await.is_none() {
+                return;
+            }
+        }
+    }
+}
+
+// Periodically checks whether the clock has been synchronized and sends an
+// event whenever it jumps, which happens when it is first set from the network.
+pub fn monitor_clock(config: ClockConfig, sender: MessageSender<Event>) -> Clock {
+    let (synchronized, receiver) = channel(!config.wait_for_sync);
+
+    tokio::spawn(async move {
+        let mut waiting = config.wait_for_sync;
+        let mut last = (Instant::now(), SystemTime::now());
+        loop {
+            if waiting && is_synchronized().await {
+                info!("The clock is synchronized.");
+                waiting = false;
+                synchronized.broadcast(true).ok();
+            }
+
+            delay_for(CHECK_INTERVAL).await;
+
+            let now = (Instant::now(), SystemTime::now());
+            let offset = offset(last.1 + (now.0 - last.0), now.1);
+            if offset.abs() as u64 >= config.step_threshold {
+                warn!("The clock changed by {}s.", offset);
+                sender.send(Event::ClockChanged { offset }.into());
+            }
+            last = now;
+        }
+    });
+
+    Clock {
+        synchronized: receiver,
+    }
+}
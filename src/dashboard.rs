@@ -0,0 +1,223 @@
+use std::collections::VecDeque;
+use std::io::{stdout, Write};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crossterm::cursor::{Hide, MoveTo, Show};
+use crossterm::event::KeyEvent;
+use crossterm::style::Print;
+use crossterm::terminal::{size, Clear, ClearType, EnterAlternateScreen, LeaveAlternateScreen};
+use crossterm::QueueableCommand;
+use futures::future::{ready, BoxFuture, FutureExt};
+use serde::Deserialize;
+use time::Time;
+
+use crate::appstate::AppState;
+use crate::error::{ErrorExt, VoidResult};
+use crate::events::{Event, Message};
+use crate::sinks::EventSink;
+use crate::term_logger;
+
+const RECENT_EVENTS: usize = 10;
+const BAR_WIDTH: usize = 40;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DashboardConfig {
+    // Switches between the dashboard and the log.
+    #[serde(deserialize_with = "crate::hardware::keyboard::deserialize_key")]
+    pub key: KeyEvent,
+
+    // Shows the dashboard instead of the log at startup.
+    #[serde(default)]
+    pub show: bool,
+}
+
+fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    format!("{}:{:02}", seconds / 60, seconds % 60)
+}
+
+fn progress_bar(position: Duration, length: Option<Duration>) -> String {
+    let filled = match length {
+        Some(length) if length.as_millis() > 0 => {
+            (position.as_millis() * BAR_WIDTH as u128 / length.as_millis()) as usize
+        }
+        _ => 0,
+    }
+    .min(BAR_WIDTH);
+
+    format!("[{}{}]", "#".repeat(filled), "-".repeat(BAR_WIDTH - filled))
+}
+
+struct Inner {
+    shown: bool,
+    recent: VecDeque<String>,
+}
+
+// A live view of what the music box is doing for when it is running in a
+// terminal. The log is hidden while the dashboard is shown.
+#[derive(Clone)]
+pub struct Dashboard {
+    state: AppState,
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl Dashboard {
+    pub fn new(state: AppState) -> Dashboard {
+        Dashboard {
+            state,
+            inner: Arc::new(Mutex::new(Inner {
+                shown: false,
+                recent: VecDeque::with_capacity(RECENT_EVENTS),
+            })),
+        }
+    }
+
+    pub fn toggle(&self) -> VoidResult {
+        let mut inner = self.inner.lock().unwrap();
+        let mut output = stdout();
+
+        inner.shown = !inner.shown;
+        if inner.shown {
+            term_logger::pause(true);
+            output.queue(EnterAlternateScreen).as_err()?;
+            output.queue(Hide).as_err()?;
+            self.draw(&inner)
+        } else {
+            output.queue(Show).as_err()?;
+            output.queue(LeaveAlternateScreen).as_err()?;
+            output.flush().as_err()?;
+            term_logger::pause(false);
+            Ok(())
+        }
+    }
+
+    // Redraws the dashboard if it is showing, after the terminal is resized.
+    pub fn refresh(&self) -> VoidResult {
+        let inner = self.inner.lock().unwrap();
+        if inner.shown {
+            self.draw(&inner)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn lines(&self, inner: &Inner) -> Vec<String> {
+        let snapshot = self.state.snapshot();
+        let mut lines = vec![String::from("Music Box"), String::new()];
+
+        match snapshot.play_state {
+            Some(ref play_state) => {
+                let track = snapshot.playlist.get(play_state.position);
+                let title = match track {
+                    Some(track) => match track.artist {
+                        Some(ref artist) => format!("{} - {}", artist, track.title),
+                        None => track.title.clone(),
+                    },
+                    None => String::new(),
+                };
+                let length = track.and_then(|track| track.duration);
+
+                lines.push(format!(
+                    "{} {}",
+                    if play_state.paused {
+                        "Paused: "
+                    } else {
+                        "Playing:"
+                    },
+                    title
+                ));
+                lines.push(format!(
+                    "{} {} / {}",
+                    progress_bar(play_state.duration, length),
+                    format_duration(play_state.duration),
+                    length
+                        .map(format_duration)
+                        .unwrap_or_else(|| String::from("?")),
+                ));
+                lines.push(format!(
+                    "Track {} of {}",
+                    play_state.position + 1,
+                    snapshot.playlist.len()
+                ));
+            }
+            None => lines.push(String::from("Stopped")),
+        }
+
+        lines.push(format!("Volume:  {:.0}%", snapshot.volume * 100.0));
+        if let Some(max_volume) = snapshot.max_volume {
+            lines.push(format!("Limit:   {:.0}%", max_volume * 100.0));
+        }
+
+        // Shows the playlist LEDs as they would be lit.
+        let playing = matches!(snapshot.play_state, Some(ref play_state) if !play_state.paused);
+        let mut playlists: Vec<_> = snapshot.stored_playlists.values().collect();
+        playlists.sort_by(|a, b| a.name.cmp(&b.name));
+        lines.push(String::new());
+        lines.push(String::from("Playlists:"));
+        for playlist in playlists {
+            let lit = playing && playlist.tracks == snapshot.playlist;
+            lines.push(format!(
+                "  ({}) {}",
+                if lit { "*" } else { " " },
+                playlist.name
+            ));
+        }
+
+        lines.push(String::new());
+        lines.push(String::from("Recent events:"));
+        lines.extend(inner.recent.iter().map(|event| format!("  {}", event)));
+
+        lines
+    }
+
+    fn draw(&self, inner: &Inner) -> VoidResult {
+        let (columns, rows) = size().as_err()?;
+        let mut output = stdout();
+
+        for (row, line) in self.lines(inner).iter().take(rows as usize).enumerate() {
+            output.queue(MoveTo(0, row as u16)).as_err()?;
+            output
+                .queue(Print(
+                    line.chars().take(columns as usize).collect::<String>(),
+                ))
+                .as_err()?;
+            output.queue(Clear(ClearType::UntilNewLine)).as_err()?;
+        }
+        output.queue(Clear(ClearType::FromCursorDown)).as_err()?;
+
+        output.flush().as_err()
+    }
+}
+
+impl EventSink for Dashboard {
+    fn name(&self) -> &str {
+        "dashboard"
+    }
+
+    fn handle<'a>(&'a mut self, event: &'a Message<Event>) -> BoxFuture<'a, VoidResult> {
+        let result = match event.payload {
+            Event::PlaybackPosition { .. } => self.refresh(),
+            // Puts the log back so anything after shutdown can be seen.
+            Event::Shutdown if self.inner.lock().unwrap().shown => self.toggle(),
+            ref payload => {
+                let mut inner = self.inner.lock().unwrap();
+                if inner.recent.len() == RECENT_EVENTS {
+                    inner.recent.pop_front();
+                }
+                inner
+                    .recent
+                    .push_back(format!("{} {:?}", Time::now().format("%H:%M:%S"), payload));
+
+                if inner.shown {
+                    self.draw(&inner)
+                } else {
+                    Ok(())
+                }
+            }
+        };
+
+        ready(result).boxed()
+    }
+}
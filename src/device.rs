@@ -0,0 +1,40 @@
+use std::fs::{read_to_string, write};
+use std::path::Path;
+
+use musicbox_protocol::DeviceInfo;
+use ring::rand::{SecureRandom, SystemRandom};
+use tracing::info;
+
+use crate::error::{ErrorExt, MusicResult};
+
+const ID_FILE: &str = "device-id";
+const DEFAULT_NAME: &str = "Music Box";
+
+fn generate_id() -> MusicResult<String> {
+    let mut bytes = [0; 8];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .map_err(|_| String::from("Unable to generate a device ID."))?;
+    Ok(bytes.iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+// Identifies this music box to clients and other music boxes. The ID is made
+// on the first run and kept in the data directory so it survives restarts and
+// updates, the name comes from the configuration.
+pub fn load(data_dir: &Path, name: Option<String>) -> MusicResult<DeviceInfo> {
+    let file = data_dir.join(ID_FILE);
+    let id = match read_to_string(&file) {
+        Ok(id) if !id.trim().is_empty() => id.trim().to_owned(),
+        _ => {
+            let id = generate_id()?;
+            write(&file, &id).prefix("Unable to write device ID")?;
+            info!("Generated device ID {}.", id);
+            id
+        }
+    };
+
+    Ok(DeviceInfo {
+        id,
+        name: name.unwrap_or_else(|| DEFAULT_NAME.to_owned()),
+    })
+}
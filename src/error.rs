@@ -1,7 +1,7 @@
 use std::borrow::Borrow;
 use std::fmt::Display;
 
-use log::error;
+use tracing::error;
 
 pub type MusicResult<T> = Result<T, String>;
 pub type VoidResult = MusicResult<()>;
@@ -1,57 +1,75 @@
+use std::collections::VecDeque;
 use std::convert::Infallible;
 use std::pin::Pin;
 use std::sync::{Arc, Mutex};
 use std::task::{Context, Poll, Waker};
-use std::time::{Duration, Instant};
+use std::time::Instant;
 
 use futures::sink::Sink;
 use futures::stream::{FusedStream, Stream};
-use serde::{Deserialize, Serialize};
-
-#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
-#[serde(tag = "type")]
-pub enum Command {
-    PreviousTrack,
-    NextTrack,
-    PlayPause,
-    VolumeUp,
-    VolumeDown,
-    StartPlaylist { name: String, force: bool },
-    Shutdown,
-    Reload,
-    Status,
-}
-
-#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
-#[serde(tag = "type")]
-pub enum Event {
-    PlaylistUpdated,
-    PlaybackStarted,
-    PlaybackPaused,
-    PlaybackUnpaused,
-    PlaybackEnded,
-    PlaybackPosition { duration: Duration },
-    Shutdown,
+
+pub use musicbox_protocol::{Command, Event, Origin};
+
+// Ordering guarantees for messages:
+//
+// * Every message passed to a sender is given a sequence number that is unique
+//   and increasing across all senders attached to the same set of receivers.
+// * Each receiver sees messages in sequence order (FIFO), no message is ever
+//   delivered before one that was sent earlier.
+// * A message may replace the most recently queued message in a receiver's
+//   queue if `Payload::replaces` allows it. For events this only happens for
+//   consecutive `PlaybackPosition` events so a slow receiver always gets the
+//   latest position but never sees a position after a later event.
+// * A receiver from `receiver_since` first sees the kept messages sent after
+//   its sequence number, then every later message like any other receiver.
+pub trait Payload: Clone {
+    fn replaces(&self, _queued: &Self) -> bool {
+        false
+    }
+}
+
+impl Payload for Command {}
+
+impl Payload for Event {
+    fn replaces(&self, queued: &Self) -> bool {
+        match (self, queued) {
+            (Event::PlaybackPosition { .. }, Event::PlaybackPosition { .. }) => true,
+            _ => false,
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
 pub struct Message<T> {
     pub payload: T,
     pub instant: Instant,
+    pub sequence: u64,
+    pub origin: Origin,
+    // When the source passed the message on, which for a button is after
+    // debouncing the press at the instant above.
+    pub emitted: Option<Instant>,
 }
 
 impl<T> Message<T> {
     pub fn new(instant: Instant, payload: T) -> Message<T> {
-        Message { instant, payload }
+        Message {
+            instant,
+            payload,
+            sequence: 0,
+            origin: Origin::Internal,
+            emitted: None,
+        }
+    }
+
+    pub fn with_origin(mut self, origin: Origin) -> Message<T> {
+        self.origin = origin;
+        self
     }
 }
 
 impl<T> From<T> for Message<T> {
     fn from(payload: T) -> Message<T> {
-        Message {
-            payload,
-            instant: Instant::now(),
-        }
+        Message::new(Instant::now(), payload)
     }
 }
 
@@ -69,49 +87,165 @@ impl<T> Default for Channel<T> {
     }
 }
 
+impl<T> Channel<T>
+where
+    T: Payload,
+{
+    fn push(&mut self, message: Message<T>) {
+        match self.messages.last_mut() {
+            Some(last) if message.payload.replaces(&last.payload) => *last = message,
+            _ => self.messages.push(message),
+        }
+
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+struct Channels<T> {
+    next_sequence: u64,
+    channels: Vec<Arc<Mutex<Channel<T>>>>,
+    // Recently sent messages so a new receiver can carry on from where an
+    // earlier one stopped. Coalesced in the same way as receivers' queues.
+    history: VecDeque<Message<T>>,
+    history_length: usize,
+}
+
+impl<T> Channels<T> {
+    fn new(channels: Vec<Arc<Mutex<Channel<T>>>>) -> Channels<T> {
+        Channels {
+            next_sequence: 1,
+            channels,
+            history: VecDeque::new(),
+            history_length: 0,
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct MessageSender<T>
 where
-    T: Clone,
+    T: Payload,
 {
-    channels: Arc<Mutex<Vec<Arc<Mutex<Channel<T>>>>>>,
+    channels: Arc<Mutex<Channels<T>>>,
 }
 
 impl<T> MessageSender<T>
 where
-    T: Clone,
+    T: Payload,
 {
     pub fn new() -> MessageSender<T> {
         MessageSender {
-            channels: Arc::new(Mutex::new(Vec::new())),
+            channels: Arc::new(Mutex::new(Channels::new(Vec::new()))),
+        }
+    }
+
+    // Keeps up to this many of the most recently sent messages for
+    // `receiver_since`.
+    pub fn with_history(length: usize) -> MessageSender<T> {
+        let mut channels = Channels::new(Vec::new());
+        channels.history_length = length;
+        MessageSender {
+            channels: Arc::new(Mutex::new(channels)),
         }
     }
 
     pub fn send(&self, message: Message<T>) {
-        let channels = self.channels.lock().unwrap();
-        for mut channel in channels.iter().map(|c| c.lock().unwrap()) {
-            channel.messages.push(message.clone());
-            if let Some(waker) = channel.waker.take() {
-                waker.wake();
+        self.send_all(vec![message]);
+    }
+
+    // Queues the messages together so no other message can arrive between
+    // them.
+    pub fn send_all(&self, messages: Vec<Message<T>>) {
+        // The sequence number is assigned while holding the lock so sequence
+        // order always matches the order messages are queued in.
+        let mut channels = self.channels.lock().unwrap();
+        for mut message in messages {
+            message.sequence = channels.next_sequence;
+            channels.next_sequence += 1;
+
+            for mut channel in channels.channels.iter().map(|c| c.lock().unwrap()) {
+                channel.push(message.clone());
+            }
+
+            if channels.history_length > 0 {
+                match channels.history.back_mut() {
+                    Some(last) if message.payload.replaces(&last.payload) => *last = message,
+                    _ => {
+                        if channels.history.len() >= channels.history_length {
+                            channels.history.pop_front();
+                        }
+                        channels.history.push_back(message);
+                    }
+                }
             }
         }
     }
 
+    // The kept messages, oldest first.
+    pub fn history(&self) -> Vec<Message<T>> {
+        self.channels
+            .lock()
+            .unwrap()
+            .history
+            .iter()
+            .cloned()
+            .collect()
+    }
+
     pub fn receiver(&self) -> MessageReceiver<T> {
         let mut channels = self.channels.lock().unwrap();
         let channel = Arc::new(Mutex::new(Default::default()));
-        channels.push(channel.clone());
+        channels.channels.push(channel.clone());
 
         MessageReceiver {
             channels: self.channels.clone(),
             channel,
         }
     }
+
+    // A receiver that starts with the kept messages sent after the given
+    // sequence number. Returns None if any of those messages are no longer
+    // kept, or the sequence number was never used.
+    pub fn receiver_since(&self, sequence: u64) -> Option<MessageReceiver<T>> {
+        let mut channels = self.channels.lock().unwrap();
+        if sequence >= channels.next_sequence {
+            return None;
+        }
+
+        // Until the history fills up nothing has been dropped from it.
+        let complete = channels.history.len() < channels.history_length;
+        let oldest = channels
+            .history
+            .front()
+            .map(|message| message.sequence)
+            .unwrap_or(channels.next_sequence);
+        if !complete && sequence + 1 < oldest {
+            return None;
+        }
+
+        let channel = Arc::new(Mutex::new(Channel {
+            messages: channels
+                .history
+                .iter()
+                .filter(|message| message.sequence > sequence)
+                .cloned()
+                .collect(),
+            waker: None,
+        }));
+        channels.channels.push(channel.clone());
+
+        Some(MessageReceiver {
+            channels: self.channels.clone(),
+            channel,
+        })
+    }
 }
 
 impl<T> Default for MessageSender<T>
 where
-    T: Clone,
+    T: Payload,
 {
     fn default() -> Self {
         MessageSender::new()
@@ -120,7 +254,7 @@ where
 
 impl<T> Sink<Message<T>> for MessageSender<T>
 where
-    T: Clone,
+    T: Payload,
 {
     type Error = Infallible;
 
@@ -144,15 +278,15 @@ where
 
 pub struct MessageReceiver<T>
 where
-    T: Clone,
+    T: Payload,
 {
-    channels: Arc<Mutex<Vec<Arc<Mutex<Channel<T>>>>>>,
+    channels: Arc<Mutex<Channels<T>>>,
     channel: Arc<Mutex<Channel<T>>>,
 }
 
 impl<T> MessageReceiver<T>
 where
-    T: Clone,
+    T: Payload,
 {
     pub fn new() -> MessageReceiver<T> {
         let channel = Arc::new(Mutex::new(Default::default()));
@@ -160,7 +294,7 @@ where
         vec.push(channel.clone());
 
         MessageReceiver {
-            channels: Arc::new(Mutex::new(vec)),
+            channels: Arc::new(Mutex::new(Channels::new(vec))),
             channel,
         }
     }
@@ -174,7 +308,7 @@ where
 
 impl<T> Default for MessageReceiver<T>
 where
-    T: Clone,
+    T: Payload,
 {
     fn default() -> Self {
         MessageReceiver::new()
@@ -183,12 +317,12 @@ where
 
 impl<T> Clone for MessageReceiver<T>
 where
-    T: Clone,
+    T: Payload,
 {
     fn clone(&self) -> MessageReceiver<T> {
         let mut channels = self.channels.lock().unwrap();
         let channel = Arc::new(Mutex::new(Default::default()));
-        channels.push(channel.clone());
+        channels.channels.push(channel.clone());
 
         MessageReceiver {
             channels: self.channels.clone(),
@@ -199,22 +333,23 @@ where
 
 impl<T> Drop for MessageReceiver<T>
 where
-    T: Clone,
+    T: Payload,
 {
     fn drop(&mut self) {
         let mut channels = self.channels.lock().unwrap();
-        for (i, ref channel) in channels.iter().enumerate() {
-            if Arc::ptr_eq(channel, &self.channel) {
-                channels.remove(i);
-                return;
-            }
+        if let Some(i) = channels
+            .channels
+            .iter()
+            .position(|channel| Arc::ptr_eq(channel, &self.channel))
+        {
+            channels.channels.remove(i);
         }
     }
 }
 
 impl<T> Stream for MessageReceiver<T>
 where
-    T: Clone,
+    T: Payload,
 {
     type Item = Message<T>;
 
@@ -235,9 +370,197 @@ where
 
 impl<T> FusedStream for MessageReceiver<T>
 where
-    T: Clone,
+    T: Payload,
 {
     fn is_terminated(&self) -> bool {
         false
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::future::FutureExt;
+    use futures::stream::StreamExt;
+
+    use super::*;
+
+    fn position(secs: u64) -> Message<Event> {
+        Event::PlaybackPosition {
+            duration: Duration::from_secs(secs),
+        }
+        .into()
+    }
+
+    fn drain(receiver: &mut MessageReceiver<Event>) -> Vec<Message<Event>> {
+        let mut messages = Vec::new();
+        while let Some(Some(message)) = receiver.next().now_or_never() {
+            messages.push(message);
+        }
+        messages
+    }
+
+    fn payloads(messages: &[Message<Event>]) -> Vec<Event> {
+        messages.iter().map(|m| m.payload.clone()).collect()
+    }
+
+    #[test]
+    fn fifo_per_listener() {
+        let sender = MessageSender::new();
+        let mut first = sender.receiver();
+        let mut second = sender.receiver();
+
+        sender.send(Event::PlaybackStarted.into());
+        sender.send(Event::PlaybackPaused.into());
+        sender.send(Event::PlaybackUnpaused.into());
+
+        let expected = vec![
+            Event::PlaybackStarted,
+            Event::PlaybackPaused,
+            Event::PlaybackUnpaused,
+        ];
+        assert_eq!(payloads(&drain(&mut first)), expected);
+        assert_eq!(payloads(&drain(&mut second)), expected);
+    }
+
+    #[test]
+    fn sequence_numbers_increase() {
+        let mut receiver = MessageReceiver::new();
+        let first = receiver.sender();
+        let second = receiver.sender();
+
+        first.send(Event::PlaybackStarted.into());
+        second.send(Event::PlaybackPaused.into());
+        first.send(Event::PlaybackEnded.into());
+
+        let sequences: Vec<u64> = drain(&mut receiver).iter().map(|m| m.sequence).collect();
+        assert_eq!(sequences, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn consecutive_positions_coalesce() {
+        let mut receiver = MessageReceiver::new();
+        let sender = receiver.sender();
+
+        sender.send(Event::PlaybackStarted.into());
+        sender.send(position(1));
+        sender.send(position(2));
+        sender.send(position(3));
+
+        let messages = drain(&mut receiver);
+        assert_eq!(
+            payloads(&messages),
+            vec![
+                Event::PlaybackStarted,
+                Event::PlaybackPosition {
+                    duration: Duration::from_secs(3)
+                },
+            ]
+        );
+        assert_eq!(messages[1].sequence, 4);
+    }
+
+    #[test]
+    fn resumes_after_sequence() {
+        let sender = MessageSender::with_history(10);
+
+        sender.send(Event::PlaybackStarted.into());
+        sender.send(Event::PlaybackPaused.into());
+        sender.send(Event::PlaybackUnpaused.into());
+
+        let mut receiver = sender.receiver_since(1).unwrap();
+        sender.send(Event::PlaybackEnded.into());
+
+        let messages = drain(&mut receiver);
+        assert_eq!(
+            payloads(&messages),
+            vec![
+                Event::PlaybackPaused,
+                Event::PlaybackUnpaused,
+                Event::PlaybackEnded,
+            ]
+        );
+        assert_eq!(messages[0].sequence, 2);
+    }
+
+    #[test]
+    fn cannot_resume_past_history() {
+        let sender = MessageSender::with_history(2);
+
+        sender.send(Event::PlaybackStarted.into());
+        sender.send(Event::PlaybackPaused.into());
+        sender.send(Event::PlaybackUnpaused.into());
+
+        assert!(sender.receiver_since(0).is_none());
+        assert!(sender.receiver_since(1).is_some());
+        assert!(sender.receiver_since(4).is_none());
+        assert!(MessageSender::<Event>::new().receiver_since(0).is_some());
+    }
+
+    #[test]
+    fn history_keeps_latest() {
+        let sender = MessageSender::with_history(2);
+
+        sender.send(Event::PlaybackStarted.into());
+        sender.send(Event::PlaybackPaused.into());
+        sender.send(Event::PlaybackUnpaused.into());
+
+        assert_eq!(
+            payloads(&sender.history()),
+            vec![Event::PlaybackPaused, Event::PlaybackUnpaused]
+        );
+        assert!(MessageSender::<Event>::new().history().is_empty());
+    }
+
+    #[test]
+    fn positions_never_follow_later_events() {
+        let mut receiver = MessageReceiver::new();
+        let sender = receiver.sender();
+
+        sender.send(position(1));
+        sender.send(Event::PlaybackEnded.into());
+        sender.send(position(2));
+
+        assert_eq!(
+            payloads(&drain(&mut receiver)),
+            vec![
+                Event::PlaybackPosition {
+                    duration: Duration::from_secs(1)
+                },
+                Event::PlaybackEnded,
+                Event::PlaybackPosition {
+                    duration: Duration::from_secs(2)
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn commands_never_coalesce() {
+        let mut receiver = MessageReceiver::new();
+        let sender = receiver.sender();
+
+        sender.send(Command::VolumeUp.into());
+        sender.send(Command::VolumeUp.into());
+
+        let mut count = 0;
+        while let Some(Some(_)) = receiver.next().now_or_never() {
+            count += 1;
+        }
+        assert_eq!(count, 2);
+    }
+
+    #[test]
+    fn dropped_receivers_stop_receiving() {
+        let sender: MessageSender<Event> = MessageSender::new();
+        let receiver = sender.receiver();
+        let mut kept = receiver.clone();
+        drop(receiver);
+
+        sender.send(Event::PlaybackStarted.into());
+
+        assert_eq!(sender.channels.lock().unwrap().channels.len(), 1);
+        assert_eq!(payloads(&drain(&mut kept)), vec![Event::PlaybackStarted]);
+    }
+}
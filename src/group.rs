@@ -0,0 +1,398 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::str;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use libmdns::{Responder, Service};
+use musicbox_protocol::DeviceInfo;
+use reqwest::Client;
+use ring::hmac;
+use serde::Deserialize;
+use serde_json::to_vec;
+use tokio::net::UdpSocket;
+use tokio::time::{delay_for, timeout};
+use tracing::{debug, info, warn};
+
+use crate::error::{ErrorExt, MusicResult};
+use crate::events::{Command, Origin};
+use crate::network::local_address;
+
+const SERVICE_TYPE: &str = "_musicbox._tcp";
+const SERVICE_QUERY: &str = "_musicbox._tcp.local";
+const MDNS_ADDRESS: &str = "224.0.0.251:5353";
+
+// How long to wait for answers to a query.
+const BROWSE_TIME: Duration = Duration::from_secs(2);
+const BROWSE_INTERVAL: Duration = Duration::from_secs(60);
+
+const RECORD_TXT: u16 = 16;
+const RECORD_PTR: u16 = 12;
+// Asks for answers to be sent straight back rather than multicast.
+const CLASS_IN_UNICAST: u16 = 0x8001;
+
+// Commands sent to the rest of the group are signed in these headers.
+pub const DEVICE_HEADER: &str = "x-musicbox-device";
+pub const TIMESTAMP_HEADER: &str = "x-musicbox-timestamp";
+pub const SIGNATURE_HEADER: &str = "x-musicbox-signature";
+
+// Signed commands older than this, in seconds, are refused so they can't be
+// replayed later.
+const MAX_SIGNATURE_AGE: u64 = 30;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GroupConfig {
+    // Music boxes with the same group name find each other on the network.
+    pub name: String,
+
+    // Shared by every member of the group to sign the commands they send each
+    // other. It is never sent over the network so it should not be the admin
+    // token.
+    pub secret: String,
+
+    // The device ids of the other music boxes in the group. Others that
+    // advertise the group name are ignored.
+    #[serde(default)]
+    pub members: Vec<String>,
+
+    // Starting a playlist here starts it on the rest of the group too.
+    #[serde(default)]
+    pub mirror: bool,
+}
+
+fn query_packet() -> Vec<u8> {
+    // A header with a single question.
+    let mut packet = vec![0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+    for label in SERVICE_QUERY.split('.') {
+        packet.push(label.len() as u8);
+        packet.extend_from_slice(label.as_bytes());
+    }
+    packet.push(0);
+    packet.extend_from_slice(&RECORD_PTR.to_be_bytes());
+    packet.extend_from_slice(&CLASS_IN_UNICAST.to_be_bytes());
+    packet
+}
+
+fn read_u16(packet: &[u8], offset: usize) -> Option<u16> {
+    let bytes = packet.get(offset..offset + 2)?;
+    Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+// Returns the offset just past the name starting at the offset.
+fn skip_name(packet: &[u8], mut offset: usize) -> Option<usize> {
+    loop {
+        let length = *packet.get(offset)?;
+        if length == 0 {
+            return Some(offset + 1);
+        }
+        // A pointer to the rest of the name elsewhere in the packet.
+        if length & 0xc0 == 0xc0 {
+            return Some(offset + 2);
+        }
+        offset += 1 + length as usize;
+    }
+}
+
+fn parse_txt(data: &[u8]) -> HashMap<String, String> {
+    let mut values = HashMap::new();
+    let mut offset = 0;
+    while let Some(length) = data.get(offset) {
+        let end = offset + 1 + *length as usize;
+        if let Some(entry) = data.get(offset + 1..end) {
+            let entry = String::from_utf8_lossy(entry);
+            if let Some(index) = entry.find('=') {
+                values.insert(entry[..index].to_owned(), entry[index + 1..].to_owned());
+            }
+        }
+        offset = end;
+    }
+    values
+}
+
+// The contents of every TXT record in a response.
+fn txt_records(packet: &[u8]) -> Option<Vec<HashMap<String, String>>> {
+    let questions = read_u16(packet, 4)?;
+    let records = u32::from(read_u16(packet, 6)?)
+        + u32::from(read_u16(packet, 8)?)
+        + u32::from(read_u16(packet, 10)?);
+
+    let mut offset = 12;
+    for _ in 0..questions {
+        offset = skip_name(packet, offset)? + 4;
+    }
+
+    let mut txts = Vec::new();
+    for _ in 0..records {
+        offset = skip_name(packet, offset)?;
+        let kind = read_u16(packet, offset)?;
+        let length = read_u16(packet, offset + 8)? as usize;
+        let data = packet.get(offset + 10..offset + 10 + length)?;
+        if kind == RECORD_TXT {
+            txts.push(parse_txt(data));
+        }
+        offset += 10 + length;
+    }
+
+    Some(txts)
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or_default()
+}
+
+fn from_hex(text: &str) -> Option<Vec<u8>> {
+    text.as_bytes()
+        .chunks(2)
+        .map(|pair| match str::from_utf8(pair) {
+            Ok(pair) if pair.len() == 2 => u8::from_str_radix(pair, 16).ok(),
+            _ => None,
+        })
+        .collect()
+}
+
+// Signs and checks the commands sent between members of the group. Each
+// signature covers the sender, the recipient and the time so it can't be
+// replayed to another member or much later.
+#[derive(Clone)]
+pub struct GroupKey {
+    key: hmac::Key,
+    device: String,
+    members: Vec<String>,
+}
+
+impl GroupKey {
+    pub fn new(config: &GroupConfig, device: &DeviceInfo) -> GroupKey {
+        GroupKey {
+            key: hmac::Key::new(hmac::HMAC_SHA256, config.secret.as_bytes()),
+            device: device.id.clone(),
+            members: config.members.clone(),
+        }
+    }
+
+    fn signed(sender: &str, recipient: &str, timestamp: u64, body: &[u8]) -> Vec<u8> {
+        let mut data = format!("{}\n{}\n{}\n", sender, recipient, timestamp).into_bytes();
+        data.extend_from_slice(body);
+        data
+    }
+
+    fn sign(&self, recipient: &str, timestamp: u64, body: &[u8]) -> String {
+        hmac::sign(
+            &self.key,
+            &GroupKey::signed(&self.device, recipient, timestamp, body),
+        )
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+    }
+
+    // Checks that a command sent to this music box came from a member.
+    pub fn verify(&self, sender: &str, timestamp: &str, signature: &str, body: &[u8]) -> bool {
+        if !self.members.iter().any(|member| member == sender) {
+            return false;
+        }
+
+        let timestamp = match timestamp.parse::<u64>() {
+            Ok(timestamp) => timestamp,
+            Err(_) => return false,
+        };
+        let now = unix_time();
+        if now.max(timestamp) - now.min(timestamp) > MAX_SIGNATURE_AGE {
+            return false;
+        }
+
+        match from_hex(signature) {
+            Some(signature) => hmac::verify(
+                &self.key,
+                &GroupKey::signed(sender, &self.device, timestamp, body),
+                &signature,
+            )
+            .is_ok(),
+            None => false,
+        }
+    }
+}
+
+// Another music box advertising the group.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Peer {
+    id: String,
+    address: SocketAddr,
+}
+
+// Asks the network for music boxes and returns those in the group.
+async fn browse(group: &str) -> MusicResult<Vec<Peer>> {
+    let mut socket = UdpSocket::bind("0.0.0.0:0")
+        .await
+        .prefix("Unable to open mDNS socket")?;
+    socket
+        .send_to(&query_packet(), MDNS_ADDRESS)
+        .await
+        .prefix("Unable to send mDNS query")?;
+
+    let deadline = Instant::now() + BROWSE_TIME;
+    let mut peers = Vec::new();
+    let mut buffer = [0; 9000];
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let length = match timeout(remaining, socket.recv_from(&mut buffer)).await {
+            Ok(Ok((length, _))) => length,
+            Ok(Err(e)) => return Err(format!("Unable to read mDNS response: {}", e)),
+            Err(_) => break,
+        };
+
+        for txt in txt_records(&buffer[..length]).unwrap_or_default() {
+            if txt.get("group").map(String::as_str) != Some(group) {
+                continue;
+            }
+
+            let ip = txt.get("ip").and_then(|ip| ip.parse::<IpAddr>().ok());
+            let port = txt.get("port").and_then(|port| port.parse::<u16>().ok());
+            if let (Some(id), Some(ip), Some(port)) = (txt.get("id"), ip, port) {
+                let peer = Peer {
+                    id: id.clone(),
+                    address: SocketAddr::new(ip, port),
+                };
+                if !peers.contains(&peer) {
+                    peers.push(peer);
+                }
+            }
+        }
+    }
+
+    Ok(peers)
+}
+
+// Music boxes on the same network that can be asked to do the same thing as
+// this one. Only the configured members are sent commands.
+pub struct Group {
+    config: GroupConfig,
+    key: GroupKey,
+    client: Client,
+    peers: Arc<Mutex<Vec<Peer>>>,
+    _service: Service,
+    _responder: Responder,
+}
+
+impl Group {
+    pub fn new(
+        config: GroupConfig,
+        server: &SocketAddr,
+        device: &DeviceInfo,
+    ) -> MusicResult<Group> {
+        let ip = local_address(server)
+            .ok_or_else(|| String::from("Unable to determine the local address for the group."))?;
+
+        let responder = Responder::new().prefix("Unable to start mDNS responder")?;
+        let txt = [
+            format!("group={}", config.name),
+            format!("ip={}", ip),
+            format!("port={}", server.port()),
+            format!("id={}", device.id),
+            format!("name={}", device.name),
+        ];
+        let txt: Vec<&str> = txt.iter().map(String::as_str).collect();
+        let service = responder.register(
+            SERVICE_TYPE.to_owned(),
+            // Dots would split the name into labels.
+            format!("{} {}", device.name.replace('.', "-"), device.id),
+            server.port(),
+            &txt,
+        );
+        info!("Joined group {}.", config.name);
+        if config.members.is_empty() {
+            warn!(
+                "No members are configured for group {}, commands will not be shared.",
+                config.name
+            );
+        }
+
+        Ok(Group {
+            key: GroupKey::new(&config, device),
+            config,
+            client: Client::new(),
+            peers: Default::default(),
+            _service: service,
+            _responder: responder,
+        })
+    }
+
+    // Keeps looking for the other members of the group.
+    pub fn start(&self) {
+        let name = self.config.name.clone();
+        let members = self.config.members.clone();
+        let found = self.peers.clone();
+        tokio::spawn(async move {
+            loop {
+                match browse(&name).await {
+                    Ok(mut peers) => {
+                        peers.retain(|peer| members.contains(&peer.id));
+                        debug!("Found group members {:?}.", peers);
+                        *found.lock().unwrap() = peers;
+                    }
+                    Err(e) => warn!("Unable to look for group members: {}", e),
+                }
+                delay_for(BROWSE_INTERVAL).await;
+            }
+        });
+    }
+
+    pub fn key(&self) -> GroupKey {
+        self.key.clone()
+    }
+
+    pub fn mirrors(&self) -> bool {
+        self.config.mirror
+    }
+
+    // Commands that came from another member of the group aren't passed on
+    // again.
+    pub fn is_member(&self, origin: &Origin) -> bool {
+        matches!(origin, Origin::Group { .. })
+    }
+
+    // For commands that this music box has already sent to the group.
+    pub fn origin(&self) -> Origin {
+        Origin::Group {
+            device: self.key.device.clone(),
+        }
+    }
+
+    // Sends a command to every other member of the group.
+    pub fn send(&self, command: &Command) {
+        let body = match to_vec(&[command]) {
+            Ok(body) => body,
+            Err(e) => {
+                warn!("Unable to encode a command for the group: {}", e);
+                return;
+            }
+        };
+
+        for peer in self.peers.lock().unwrap().iter() {
+            let timestamp = unix_time();
+            let request = self
+                .client
+                .post(&format!("http://{}/api/group/commands", peer.address))
+                .header("Content-Type", "application/json")
+                .header(DEVICE_HEADER, &self.key.device)
+                .header(TIMESTAMP_HEADER, timestamp.to_string())
+                .header(SIGNATURE_HEADER, self.key.sign(&peer.id, timestamp, &body))
+                .body(body.clone());
+
+            let peer = peer.address;
+            tokio::spawn(async move {
+                if let Err(e) = request
+                    .send()
+                    .await
+                    .and_then(|response| response.error_for_status())
+                {
+                    warn!("Unable to send a command to group member {}: {}", peer, e);
+                }
+            });
+        }
+    }
+}
@@ -0,0 +1,236 @@
+use std::fs::{read, write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use musicbox_protocol::GuestState;
+use serde::{Deserialize, Serialize};
+use serde_json::{from_slice, to_vec};
+use time::OffsetDateTime;
+use tracing::{info, warn};
+
+use crate::auth::{Authorization, Authorizer};
+use crate::error::{ErrorExt, VoidResult};
+use crate::schedule::{TimeOfDay, TimeWindow};
+
+const GUEST_FILE: &str = "guest.json";
+// How often the usage is saved while it is being counted.
+const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+fn default_warning() -> u64 {
+    5
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GuestConfig {
+    // Minutes of playback allowed each day while in guest mode.
+    pub daily_limit: Option<u64>,
+
+    // Times of day when nothing plays in guest mode.
+    #[serde(default)]
+    pub quiet_hours: Vec<TimeWindow>,
+
+    // Minutes of warning given before playback is stopped.
+    #[serde(default = "default_warning")]
+    pub warning: u64,
+
+    // Turns guest mode on whenever the key switch is off.
+    #[serde(default)]
+    pub key_switch: bool,
+
+    // Speaks the warning over the playlist.
+    #[serde(default)]
+    pub announce: bool,
+
+    // The playlist whose LED blinks the warning.
+    pub led: Option<String>,
+}
+
+// Persisted so restarting doesn't reset the allowance.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Usage {
+    enabled: bool,
+    day: String,
+    used: Duration,
+}
+
+fn today() -> String {
+    OffsetDateTime::now_local().date().to_string()
+}
+
+// Limits how much and when the music box can play, like screen time limits.
+pub struct GuestMode {
+    config: Option<GuestConfig>,
+    switch: Option<Arc<dyn Authorizer>>,
+    file: PathBuf,
+    usage: Usage,
+    // When playback was last counted, if it was playing.
+    counted: Option<Instant>,
+    // When the usage was last saved while counting.
+    saved: Option<Instant>,
+    warned: bool,
+}
+
+impl GuestMode {
+    pub fn new(config: Option<GuestConfig>, data_dir: &Path) -> GuestMode {
+        let file = data_dir.join(GUEST_FILE);
+        let usage = match read(&file) {
+            Ok(data) => from_slice(&data)
+                .format_log(|e| format!("Discarding unreadable guest mode state: {}", e))
+                .unwrap_or_default(),
+            Err(_) => Default::default(),
+        };
+
+        GuestMode {
+            config,
+            switch: None,
+            file,
+            usage,
+            counted: None,
+            saved: None,
+            warned: false,
+        }
+    }
+
+    pub fn config(&self) -> Option<&GuestConfig> {
+        self.config.as_ref()
+    }
+
+    // Lets the key switch turn on guest mode.
+    #[cfg(feature = "rpi")]
+    pub fn set_switch(&mut self, switch: Arc<dyn Authorizer>) {
+        self.switch = Some(switch);
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        let config = match self.config {
+            Some(ref config) => config,
+            None => return false,
+        };
+
+        let switched = match self.switch {
+            Some(ref switch) if config.key_switch => {
+                switch.authorization() == Authorization::Playback
+            }
+            _ => false,
+        };
+
+        self.usage.enabled || switched
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        if self.config.is_none() {
+            warn!("Guest mode is not configured.");
+            return;
+        }
+
+        info!(
+            "Guest mode {}.",
+            if enabled { "enabled" } else { "disabled" }
+        );
+        self.usage.enabled = enabled;
+        self.save().log().drop();
+    }
+
+    // Adds the time played since the last call to the day's usage. Should be
+    // called regularly and whenever playback starts or stops. The usage is
+    // saved now and then so a crash or power cut loses little of it.
+    pub fn count(&mut self, playing: bool) {
+        let now = Instant::now();
+        let day = today();
+        let changed = if day != self.usage.day {
+            self.usage.day = day;
+            self.usage.used = Duration::from_secs(0);
+            true
+        } else if let (Some(last), true) = (self.counted, self.is_enabled()) {
+            self.usage.used += now.saturating_duration_since(last);
+            true
+        } else {
+            false
+        };
+
+        self.counted = if playing { Some(now) } else { None };
+
+        let due = match self.saved {
+            Some(saved) => now.saturating_duration_since(saved) >= SAVE_INTERVAL,
+            None => true,
+        };
+        if changed && due {
+            self.saved = Some(now);
+            self.save().log().drop();
+        }
+    }
+
+    // The playback allowed for the rest of the day.
+    fn remaining(&self, config: &GuestConfig) -> Option<Duration> {
+        config.daily_limit.map(|limit| {
+            Duration::from_secs(limit * 60)
+                .checked_sub(self.usage.used)
+                .unwrap_or_default()
+        })
+    }
+
+    fn is_quiet(&self, config: &GuestConfig, now: TimeOfDay) -> bool {
+        config.quiet_hours.iter().any(|window| window.contains(now))
+    }
+
+    // How long playback can continue for, None if there is no limit.
+    pub fn time_left(&self) -> Option<Duration> {
+        let config = match self.config {
+            Some(ref config) if self.is_enabled() => config,
+            _ => return None,
+        };
+
+        let now = TimeOfDay::now();
+        if self.is_quiet(config, now) {
+            return Some(Duration::from_secs(0));
+        }
+
+        config
+            .quiet_hours
+            .iter()
+            .map(|window| window.starts_in(now))
+            .chain(self.remaining(config))
+            .min()
+    }
+
+    pub fn is_blocked(&self) -> bool {
+        self.time_left() == Some(Duration::from_secs(0))
+    }
+
+    // True the first time playback gets within the warning period of being
+    // stopped.
+    pub fn should_warn(&mut self) -> bool {
+        let warning = match self.config {
+            Some(ref config) => Duration::from_secs(config.warning * 60),
+            None => return false,
+        };
+
+        match self.time_left() {
+            Some(left) if left <= warning => !std::mem::replace(&mut self.warned, true),
+            _ => {
+                self.warned = false;
+                false
+            }
+        }
+    }
+
+    pub fn state(&self) -> Option<GuestState> {
+        let config = match self.config {
+            Some(ref config) if self.is_enabled() => config,
+            _ => return None,
+        };
+
+        Some(GuestState {
+            remaining: self.remaining(config).map(|left| left.as_secs()),
+            quiet: self.is_quiet(config, TimeOfDay::now()),
+        })
+    }
+
+    pub fn save(&self) -> VoidResult {
+        let data = to_vec(&self.usage).prefix("Unable to serialize guest mode state")?;
+        write(&self.file, data).prefix("Unable to write guest mode state")
+    }
+}
@@ -1,4 +1,22 @@
+use std::fs::read_to_string;
+
+#[cfg(feature = "input")]
+pub mod input;
 pub mod keyboard;
 
 #[cfg(feature = "rpi")]
 pub mod gpio;
+
+const MODEL_FILE: &str = "/proc/device-tree/model";
+
+// The board's model from the device tree. Missing on most desktops.
+pub fn board_model() -> Option<String> {
+    let model = read_to_string(MODEL_FILE).ok()?;
+    Some(model.trim_end_matches('\0').trim().to_owned())
+}
+
+// The GPIO pins are only used on a Raspberry Pi so the same binary can also
+// run on a desktop.
+pub fn is_raspberry_pi() -> bool {
+    matches!(board_model(), Some(model) if model.starts_with("Raspberry Pi"))
+}
@@ -1,15 +1,69 @@
+use std::future::Future;
+use std::pin::Pin as StdPin;
+use std::sync::RwLock;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use futures::channel::mpsc::{unbounded, UnboundedReceiver};
+use futures::stream::Stream;
 use lazy_static::lazy_static;
-use rppal::gpio::{Gpio, Level, PullUpDown};
-use serde::Deserialize;
+use rppal::gpio::{Gpio, InputPin, Level, Pin, PullUpDown, Trigger};
+use serde::{Deserialize, Deserializer, Serialize};
+use tokio::time::{delay_until, Delay};
+use tracing::{error, info, warn};
+
+use crate::error::{ErrorExt, MusicResult};
+use crate::hardware::gpio::recording::Recorder;
+use crate::hardware::is_raspberry_pi;
 
 pub mod button;
+pub mod encoder;
+pub mod key_switch;
 pub mod led;
+pub mod monitor;
+pub mod power;
+pub mod recording;
+pub mod usage;
 
 lazy_static! {
-    pub static ref GPIO: Gpio = Gpio::new().unwrap();
+    static ref DEFAULTS: RwLock<GpioDefaults> = RwLock::new(GpioDefaults::default());
+    static ref GPIO: Option<Gpio> = if !is_raspberry_pi() {
+        info!("Not running on a Raspberry Pi, continuing without hardware controls.");
+        None
+    } else {
+        match Gpio::new() {
+            Ok(gpio) => Some(gpio),
+            Err(e) => {
+                warn!(
+                    "GPIO is unavailable, continuing without hardware controls: {}",
+                    e
+                );
+                None
+            }
+        }
+    };
 }
 
-#[derive(Deserialize)]
+// Hardware setup should be skipped rather than failing when this is false.
+pub fn is_available() -> bool {
+    GPIO.is_some()
+}
+
+pub fn get_pin(pin: u8) -> MusicResult<Pin> {
+    let gpio = GPIO
+        .as_ref()
+        .ok_or_else(|| String::from("GPIO is unavailable."))?;
+
+    match gpio.get(pin) {
+        Ok(p) => Ok(p),
+        Err(e) => {
+            error!("Failed to get pin {}: {}", pin, e);
+            Err(e.to_string())
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
 #[serde(remote = "PullUpDown")]
 pub enum PullUpDownDef {
     Off,
@@ -17,9 +71,209 @@ pub enum PullUpDownDef {
     PullUp,
 }
 
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize)]
 #[serde(remote = "Level")]
 pub enum LevelDef {
     Low,
     High,
 }
+
+#[derive(Deserialize)]
+struct PullUpDownWrapper(#[serde(with = "PullUpDownDef")] PullUpDown);
+
+#[derive(Deserialize)]
+struct LevelWrapper(#[serde(with = "LevelDef")] Level);
+
+// For config fields that fall back to the defaults when omitted.
+pub fn optional_pull_up_down<'de, D>(deserializer: D) -> Result<Option<PullUpDown>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(Option::<PullUpDownWrapper>::deserialize(deserializer)?.map(|wrapper| wrapper.0))
+}
+
+pub fn optional_level<'de, D>(deserializer: D) -> Result<Option<Level>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(Option::<LevelWrapper>::deserialize(deserializer)?.map(|wrapper| wrapper.0))
+}
+
+fn default_button_kind() -> PullUpDown {
+    PullUpDown::PullUp
+}
+
+fn default_button_on() -> Level {
+    Level::Low
+}
+
+fn default_led_on() -> Level {
+    Level::High
+}
+
+// Used for buttons and LEDs that don't say how they are wired. By default
+// buttons connect the pin to ground, pulled up when released, and LEDs light
+// when the pin is high.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GpioDefaults {
+    #[serde(with = "PullUpDownDef", default = "default_button_kind")]
+    pub button_kind: PullUpDown,
+
+    #[serde(with = "LevelDef", default = "default_button_on")]
+    pub button_on: Level,
+
+    // Low for LEDs wired between the pin and the supply.
+    #[serde(with = "LevelDef", default = "default_led_on")]
+    pub led_on: Level,
+}
+
+impl Default for GpioDefaults {
+    fn default() -> GpioDefaults {
+        GpioDefaults {
+            button_kind: default_button_kind(),
+            button_on: default_button_on(),
+            led_on: default_led_on(),
+        }
+    }
+}
+
+// Must be called before any buttons or LEDs are created.
+pub fn set_defaults(defaults: GpioDefaults) {
+    *DEFAULTS.write().unwrap() = defaults;
+}
+
+pub fn defaults() -> GpioDefaults {
+    *DEFAULTS.read().unwrap()
+}
+
+// A raw change in level seen on an input pin.
+#[derive(Debug, Clone, Copy)]
+pub struct PinEvent {
+    pub pin: u8,
+    pub level: Level,
+    pub instant: Instant,
+}
+
+pub fn get_input_pin(pin: u8, kind: PullUpDown) -> MusicResult<InputPin> {
+    let pin = get_pin(pin)?;
+
+    let input = match kind {
+        PullUpDown::PullUp => pin.into_input_pullup(),
+        PullUpDown::PullDown => pin.into_input_pulldown(),
+        PullUpDown::Off => pin.into_input(),
+    };
+    monitor::observe(input.pin(), input.read());
+    Ok(input)
+}
+
+// A stream of every level change on an input pin. The interrupt is removed
+// when the stream is dropped.
+pub struct PinEvents {
+    _pin: InputPin,
+    receiver: UnboundedReceiver<PinEvent>,
+}
+
+impl PinEvents {
+    pub fn new(pin: u8, kind: PullUpDown, recorder: Option<Recorder>) -> MusicResult<PinEvents> {
+        let mut input = get_input_pin(pin, kind)?;
+        let (sender, receiver) = unbounded();
+
+        let result = input.set_async_interrupt(Trigger::Both, move |level: Level| {
+            let event = PinEvent {
+                pin,
+                level,
+                instant: Instant::now(),
+            };
+
+            if let Some(ref recorder) = recorder {
+                recorder.record(&event);
+            }
+            monitor::record(&event);
+
+            sender.unbounded_send(event).drop();
+        });
+
+        if let Err(e) = result {
+            error!("Failed to attach interrupt for pin {}: {}", pin, e);
+            return Err(e.to_string());
+        }
+
+        Ok(PinEvents {
+            _pin: input,
+            receiver,
+        })
+    }
+}
+
+impl Stream for PinEvents {
+    type Item = PinEvent;
+
+    fn poll_next(mut self: StdPin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        StdPin::new(&mut self.receiver).poll_next(cx)
+    }
+}
+
+// Drops pulses shorter than the threshold, which are electrical noise rather
+// than anything a person could do. Each change is held back for the threshold
+// and discarded along with the change back if the level returns in that time.
+pub struct GlitchFilter<S> {
+    events: S,
+    threshold: Duration,
+    pending: Option<(PinEvent, Delay)>,
+}
+
+impl<S> GlitchFilter<S>
+where
+    S: Stream<Item = PinEvent> + Unpin,
+{
+    pub fn new(events: S, threshold: Duration) -> GlitchFilter<S> {
+        GlitchFilter {
+            events,
+            threshold,
+            pending: None,
+        }
+    }
+
+    fn hold(&mut self, event: PinEvent) {
+        let delay = delay_until((event.instant + self.threshold).into());
+        self.pending = Some((event, delay));
+    }
+}
+
+impl<S> Stream for GlitchFilter<S>
+where
+    S: Stream<Item = PinEvent> + Unpin,
+{
+    type Item = PinEvent;
+
+    fn poll_next(mut self: StdPin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        loop {
+            match StdPin::new(&mut self.events).poll_next(cx) {
+                Poll::Ready(Some(event)) => match self.pending.take() {
+                    Some((pending, delay)) if pending.level == event.level => {
+                        // A change back was missed, the pending change stands.
+                        self.pending = Some((pending, delay));
+                    }
+                    Some((pending, _))
+                        if event.instant.duration_since(pending.instant) < self.threshold => {}
+                    Some((pending, _)) => {
+                        self.hold(event);
+                        return Poll::Ready(Some(pending));
+                    }
+                    None => self.hold(event),
+                },
+                Poll::Ready(None) => return Poll::Ready(self.pending.take().map(|(e, _)| e)),
+                Poll::Pending => break,
+            }
+        }
+
+        if let Some((_, ref mut delay)) = self.pending {
+            if StdPin::new(delay).poll(cx).is_ready() {
+                return Poll::Ready(self.pending.take().map(|(e, _)| e));
+            }
+        }
+
+        Poll::Pending
+    }
+}
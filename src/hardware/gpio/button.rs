@@ -1,77 +1,510 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::path::PathBuf;
+use std::pin::Pin as StdPin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use futures::channel::mpsc::unbounded;
 use futures::future::ready;
-use futures::stream::{Stream, StreamExt};
-use log::{debug, error};
-use rpi_async::gpio::{ButtonEvent, InputPinEvents};
+use futures::stream::{select_all, Stream, StreamExt};
 use rppal::gpio::{Level, PullUpDown};
 use serde::Deserialize;
+use tokio::time::{delay_until, Delay};
+use tracing::debug;
+
+use crate::auth::Authorizer;
+use crate::error::{ErrorExt, MusicResult};
+use crate::events::{Command, Message, Origin};
+use crate::hardware::gpio::recording::{replay, Recorder};
+use crate::hardware::gpio::usage::ButtonCounters;
+use crate::hardware::gpio::{
+    defaults, is_available, optional_level, optional_pull_up_down, GlitchFilter, PinEvent,
+    PinEvents,
+};
+use crate::options::Options;
+use crate::sources::{CommandSource, CommandStream};
 
-use crate::error::{MusicResult, VoidResult};
-use crate::events::{Command, Message};
-use crate::hardware::gpio::{LevelDef, PullUpDownDef, GPIO};
-use crate::musicbox::MusicBox;
+fn default_debounce() -> u64 {
+    50
+}
+
+fn default_hold_duration() -> u64 {
+    3000
+}
+
+// A different command for when the button is held down.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ButtonHoldConfig {
+    // In milliseconds.
+    #[serde(default = "default_hold_duration")]
+    pub duration: u64,
+
+    pub command: Command,
+}
 
 #[derive(Debug, Clone, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct ButtonConfig {
     pub pin: u8,
 
-    #[serde(with = "PullUpDownDef")]
-    pub kind: PullUpDown,
+    // The GPIO defaults are used when these are omitted.
+    #[serde(default, deserialize_with = "optional_pull_up_down")]
+    pub kind: Option<PullUpDown>,
 
-    #[serde(with = "LevelDef")]
-    pub on: Level,
+    #[serde(default, deserialize_with = "optional_level")]
+    pub on: Option<Level>,
 
     pub command: Command,
+
+    // Level changes that revert within this many microseconds are ignored.
+    pub glitch_filter: Option<u64>,
+
+    // Milliseconds after a press or release during which further changes are
+    // treated as bounce.
+    #[serde(default = "default_debounce")]
+    pub debounce: u64,
+
+    // When set the button's command is sent when it is released rather than
+    // when it is pressed, so that it can tell if the button is being held.
+    pub hold: Option<ButtonHoldConfig>,
+
+    // Sent when the button is released, for things that happen while the
+    // button is held down. Not used with a hold command.
+    pub release: Option<Command>,
 }
 
-pub struct Buttons;
+impl ButtonConfig {
+    pub fn kind(&self) -> PullUpDown {
+        self.kind.unwrap_or_else(|| defaults().button_kind)
+    }
 
-impl Buttons {
-    pub fn init(music_box: &mut MusicBox, buttons: &Vec<ButtonConfig>) -> VoidResult {
-        for config in buttons {
-            music_box.add_command_stream(Buttons::new(config.to_owned())?);
+    pub fn on(&self) -> Level {
+        self.on.unwrap_or_else(|| defaults().button_on)
+    }
+}
+
+// A press or release of a button.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct ButtonChange {
+    pressed: bool,
+    instant: Instant,
+}
+
+// Turns the raw level changes of a pin into presses. Any change that happens
+// too soon after the last accepted change is considered to be bounce, but the
+// last of those is kept so the button settles on the level the pin was left at
+// once the debounce window has passed.
+struct Debouncer {
+    pin: u8,
+    on: Level,
+    level: Level,
+    debounce: Duration,
+    last_change: Option<Instant>,
+    pending: Option<PinEvent>,
+    counters: ButtonCounters,
+}
+
+impl Debouncer {
+    fn new(config: &ButtonConfig, counters: ButtonCounters) -> Debouncer {
+        Debouncer {
+            pin: config.pin,
+            on: config.on(),
+            level: !config.on(),
+            debounce: Duration::from_millis(config.debounce),
+            last_change: None,
+            pending: None,
+            counters,
+        }
+    }
+
+    // Returns the press or release if this event is one.
+    fn change(&mut self, event: PinEvent) -> Option<ButtonChange> {
+        if let Some(last) = self.last_change {
+            if event.instant.duration_since(last) < self.debounce {
+                if event.level != self.level {
+                    self.counters.bounce(self.pin);
+                }
+                self.pending = Some(event);
+                return None;
+            }
+        }
+
+        self.pending = None;
+        self.accept(event)
+    }
+
+    // When the pin changed during the debounce window this is when the window
+    // ends and `settle` should be called.
+    fn settle_at(&self) -> Option<Instant> {
+        match (self.pending, self.last_change) {
+            (Some(_), Some(last)) => Some(last + self.debounce),
+            _ => None,
+        }
+    }
+
+    // Returns the press or release if the pin was left at a different level by
+    // the changes during the debounce window.
+    fn settle(&mut self) -> Option<ButtonChange> {
+        self.pending.take().and_then(|event| self.accept(event))
+    }
+
+    fn accept(&mut self, event: PinEvent) -> Option<ButtonChange> {
+        if event.level == self.level {
+            return None;
+        }
+
+        self.level = event.level;
+        self.last_change = Some(event.instant);
+
+        let pressed = event.level == self.on;
+        if pressed {
+            self.counters.press(self.pin);
+        }
+        Some(ButtonChange {
+            pressed,
+            instant: event.instant,
+        })
+    }
+}
+
+// The debounced presses and releases of a button.
+struct Debounced<S> {
+    events: S,
+    debouncer: Debouncer,
+    settle: Option<Delay>,
+}
+
+impl<S> Debounced<S>
+where
+    S: Stream<Item = PinEvent> + Unpin,
+{
+    fn new(events: S, config: &ButtonConfig, counters: ButtonCounters) -> Debounced<S> {
+        Debounced {
+            events,
+            debouncer: Debouncer::new(config, counters),
+            settle: None,
+        }
+    }
+}
+
+impl<S> Stream for Debounced<S>
+where
+    S: Stream<Item = PinEvent> + Unpin,
+{
+    type Item = ButtonChange;
+
+    fn poll_next(mut self: StdPin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        loop {
+            match StdPin::new(&mut self.events).poll_next(cx) {
+                Poll::Ready(Some(event)) => {
+                    if let Some(change) = self.debouncer.change(event) {
+                        self.settle = None;
+                        return Poll::Ready(Some(change));
+                    }
+
+                    if self.settle.is_none() {
+                        self.settle = self.debouncer.settle_at().map(|at| delay_until(at.into()));
+                    }
+                }
+                Poll::Ready(None) => return Poll::Ready(self.debouncer.settle()),
+                Poll::Pending => break,
+            }
         }
 
-        Ok(())
+        if let Some(ref mut delay) = self.settle {
+            if StdPin::new(delay).poll(cx).is_ready() {
+                self.settle = None;
+                if let Some(change) = self.debouncer.settle() {
+                    return Poll::Ready(Some(change));
+                }
+            }
+        }
+
+        Poll::Pending
     }
+}
+
+// The presses of a button that has a hold command. Releasing the button sends
+// its normal command unless it was held down long enough for the hold command
+// to have been sent.
+struct HoldButton<S> {
+    changes: S,
+    command: Command,
+    hold: ButtonHoldConfig,
+    origin: Origin,
+    pressed: Option<(Instant, Delay)>,
+}
+
+impl<S> HoldButton<S>
+where
+    S: Stream<Item = ButtonChange> + Unpin,
+{
+    fn new(changes: S, config: ButtonConfig, hold: ButtonHoldConfig) -> HoldButton<S> {
+        HoldButton {
+            changes,
+            command: config.command,
+            hold,
+            origin: Origin::Gpio { pin: config.pin },
+            pressed: None,
+        }
+    }
+
+    fn message(&self, instant: Instant, command: &Command) -> Message<Command> {
+        Message::new(instant, command.clone()).with_origin(self.origin.clone())
+    }
+}
 
-    fn new(config: ButtonConfig) -> MusicResult<impl Stream<Item = Message<Command>>> {
+impl<S> Stream for HoldButton<S>
+where
+    S: Stream<Item = ButtonChange> + Unpin,
+{
+    type Item = Message<Command>;
+
+    fn poll_next(mut self: StdPin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        loop {
+            match StdPin::new(&mut self.changes).poll_next(cx) {
+                Poll::Ready(Some(change)) if change.pressed => {
+                    let held = change.instant + Duration::from_millis(self.hold.duration);
+                    self.pressed = Some((change.instant, delay_until(held.into())));
+                }
+                Poll::Ready(Some(_)) => {
+                    if let Some((instant, _)) = self.pressed.take() {
+                        return Poll::Ready(Some(self.message(instant, &self.command)));
+                    }
+                }
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => break,
+            }
+        }
+
+        if let Some((_, ref mut delay)) = self.pressed {
+            if StdPin::new(delay).poll(cx).is_ready() {
+                self.pressed = None;
+                return Poll::Ready(Some(self.message(Instant::now(), &self.hold.command)));
+            }
+        }
+
+        Poll::Pending
+    }
+}
+
+pub struct Buttons {
+    buttons: Vec<ButtonConfig>,
+    record: Option<PathBuf>,
+    replay: Option<PathBuf>,
+    authorizer: Arc<dyn Authorizer>,
+    counters: ButtonCounters,
+}
+
+impl Buttons {
+    pub fn new(
+        buttons: Vec<ButtonConfig>,
+        options: &Options,
+        authorizer: Arc<dyn Authorizer>,
+        counters: ButtonCounters,
+    ) -> Buttons {
+        Buttons {
+            buttons,
+            record: options.record_gpio.clone(),
+            replay: options.replay_gpio.clone(),
+            authorizer,
+            counters,
+        }
+    }
+
+    fn button<S>(config: ButtonConfig, events: S, counters: ButtonCounters) -> CommandStream
+    where
+        S: Stream<Item = PinEvent> + Send + 'static,
+    {
         debug!(
-            "Creating event button for pin {}, type {}, on level: {}, command {:?}",
-            config.pin, config.kind, config.on, config.command
+            "Creating event button for pin {}, type {}, on level: {}, debounce: {}ms, command {:?}",
+            config.pin,
+            config.kind(),
+            config.on(),
+            config.debounce,
+            config.command
         );
-        let pin = match GPIO.get(config.pin) {
-            Ok(p) => p,
-            Err(e) => {
-                error!("Failed to get pin {}: {}", config.pin, e);
-                return Err(e.to_string());
+
+        let events = match config.glitch_filter {
+            Some(micros) => {
+                GlitchFilter::new(events.boxed(), Duration::from_micros(micros)).boxed()
             }
+            None => events.boxed(),
         };
 
-        let input = match config.kind {
-            PullUpDown::PullUp => pin.into_input_pullup(),
-            PullUpDown::PullDown => pin.into_input_pulldown(),
-            PullUpDown::Off => pin.into_input(),
-        };
+        let changes = Debounced::new(events, &config, counters);
+        if let Some(hold) = config.hold.clone() {
+            return Box::pin(HoldButton::new(changes, config, hold));
+        }
 
-        let events = match input.button_events(config.on, None) {
-            Ok(e) => e,
-            Err(e) => {
-                error!("Failed to open button stream for pin {}: {}", config.pin, e);
-                return Err(e.to_string());
+        let origin = Origin::Gpio { pin: config.pin };
+        Box::pin(changes.filter_map(move |change| {
+            let command = if change.pressed {
+                Some(config.command.clone())
+            } else {
+                config.release.clone()
+            };
+            ready(
+                command.map(|command| {
+                    Message::new(change.instant, command).with_origin(origin.clone())
+                }),
+            )
+        }))
+    }
+}
+
+impl CommandSource for Buttons {
+    fn name(&self) -> &str {
+        "buttons"
+    }
+
+    fn authorizer(&self) -> Arc<dyn Authorizer> {
+        self.authorizer.clone()
+    }
+
+    fn commands(&mut self) -> MusicResult<CommandStream> {
+        let mut streams = Vec::new();
+
+        if let Some(ref path) = self.replay {
+            let mut pins = HashMap::new();
+            for config in &self.buttons {
+                let (sender, receiver) = unbounded();
+                pins.insert(config.pin, sender);
+                streams.push(Buttons::button(
+                    config.to_owned(),
+                    receiver,
+                    self.counters.clone(),
+                ));
             }
+
+            replay(path, pins)?;
+        } else if is_available() {
+            let recorder = match self.record {
+                Some(ref path) => Some(Recorder::new(path)?),
+                None => None,
+            };
+
+            for config in &self.buttons {
+                let events = PinEvents::new(config.pin, config.kind(), recorder.clone())?;
+                streams.push(Buttons::button(
+                    config.to_owned(),
+                    events,
+                    self.counters.clone(),
+                ));
+            }
+        }
+
+        Ok(Box::pin(select_all(streams)))
+    }
+    fn shutdown(&mut self) {
+        self.counters.save().log().drop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env::temp_dir;
+    use std::process;
+
+    use futures::channel::mpsc::unbounded;
+
+    use super::*;
+    use crate::appstate::MutableAppState;
+    use crate::metrics::Metrics;
+
+    fn config() -> ButtonConfig {
+        ButtonConfig {
+            pin: 5,
+            kind: Some(PullUpDown::PullUp),
+            on: Some(Level::Low),
+            command: Command::PlayPause,
+            glitch_filter: None,
+            debounce: 50,
+            hold: None,
+            release: None,
+        }
+    }
+
+    fn counters() -> ButtonCounters {
+        let dir = temp_dir().join(format!("musicbox-button-{}", process::id()));
+        ButtonCounters::load(&dir, MutableAppState::new(Vec::new()), Metrics::default())
+    }
+
+    fn event(level: Level, instant: Instant) -> PinEvent {
+        PinEvent {
+            pin: 5,
+            level,
+            instant,
+        }
+    }
+
+    fn change(pressed: bool, instant: Instant) -> Option<ButtonChange> {
+        Some(ButtonChange { pressed, instant })
+    }
+
+    #[test]
+    fn short_tap_settles_released() {
+        let mut debouncer = Debouncer::new(&config(), counters());
+        let start = Instant::now();
+        let released = start + Duration::from_millis(20);
+
+        assert_eq!(
+            debouncer.change(event(Level::Low, start)),
+            change(true, start)
+        );
+        assert_eq!(debouncer.change(event(Level::High, released)), None);
+        assert_eq!(
+            debouncer.settle_at(),
+            Some(start + Duration::from_millis(50))
+        );
+        assert_eq!(debouncer.settle(), change(false, released));
+
+        let again = start + Duration::from_millis(100);
+        assert_eq!(
+            debouncer.change(event(Level::Low, again)),
+            change(true, again)
+        );
+    }
+
+    #[test]
+    fn bounce_settles_on_last_level() {
+        let mut debouncer = Debouncer::new(&config(), counters());
+        let start = Instant::now();
+
+        assert_eq!(
+            debouncer.change(event(Level::Low, start)),
+            change(true, start)
+        );
+        for (millis, level) in [(2, Level::High), (4, Level::Low)].iter() {
+            let instant = start + Duration::from_millis(*millis);
+            assert_eq!(debouncer.change(event(*level, instant)), None);
+        }
+        assert_eq!(debouncer.settle(), None);
+        assert_eq!(debouncer.settle_at(), None);
+    }
+
+    #[tokio::test]
+    async fn short_tap_does_not_hold() {
+        let (sender, receiver) = unbounded();
+        let mut config = config();
+        let hold = ButtonHoldConfig {
+            duration: 100,
+            command: Command::NextTrack,
         };
+        config.hold = Some(hold.clone());
+        let changes = Debounced::new(receiver, &config, counters());
+        let mut button = HoldButton::new(changes, config, hold);
 
-        let pin: u8 = config.pin;
-        Ok(events.filter_map(move |r| {
-            ready(match r {
-                Ok(ButtonEvent::Click(i)) => Some(Message::new(i, config.command.clone())),
-                Err(e) => {
-                    error!("Failure while polling button on pin {}: {}", pin, e);
-                    None
-                }
-                _ => None,
-            })
-        }))
+        let start = Instant::now();
+        sender.unbounded_send(event(Level::Low, start)).unwrap();
+        sender
+            .unbounded_send(event(Level::High, start + Duration::from_millis(10)))
+            .unwrap();
+
+        let message = button.next().await.unwrap();
+        assert_eq!(message.payload, Command::PlayPause);
     }
 }
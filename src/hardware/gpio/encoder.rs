@@ -0,0 +1,286 @@
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use futures::stream::{select_all, Stream};
+use rppal::gpio::{InputPin, Level, PullUpDown, Trigger};
+use serde::Deserialize;
+use tracing::{debug, error};
+
+use crate::auth::Authorizer;
+use crate::error::{ErrorExt, MusicResult};
+use crate::events::{Command, Message, Origin};
+use crate::hardware::gpio::{
+    get_input_pin, is_available, monitor, LevelDef, PinEvent, PullUpDownDef,
+};
+use crate::sources::{CommandSource, CommandStream};
+
+// Quarter steps for each transition of the pins, indexed by the previous and
+// new states. Only one pin changes at a time so anything else is a missed
+// edge and counts for nothing.
+const TRANSITIONS: [i32; 16] = [0, -1, 1, 0, 1, 0, 0, -1, -1, 0, 0, 1, 0, 1, -1, 0];
+
+fn default_step() -> u64 {
+    1000
+}
+
+fn default_acceleration() -> u64 {
+    4
+}
+
+fn default_acceleration_window() -> u64 {
+    60
+}
+
+fn default_debounce() -> u64 {
+    2
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ModifierConfig {
+    pub pin: u8,
+
+    #[serde(with = "PullUpDownDef")]
+    pub kind: PullUpDown,
+
+    #[serde(with = "LevelDef")]
+    pub on: Level,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EncoderConfig {
+    pub pin_a: u8,
+    pub pin_b: u8,
+
+    #[serde(with = "PullUpDownDef")]
+    pub kind: PullUpDown,
+
+    // If present the encoder only seeks while this button is held.
+    pub modifier: Option<ModifierConfig>,
+
+    // Milliseconds to seek for each detent.
+    #[serde(default = "default_step")]
+    pub step: u64,
+
+    // Detents closer together than the window (in milliseconds) multiply the
+    // step by the acceleration factor.
+    #[serde(default = "default_acceleration")]
+    pub acceleration: u64,
+    #[serde(default = "default_acceleration_window")]
+    pub acceleration_window: u64,
+
+    // Edges on a pin within this many milliseconds of the last are contact
+    // bounce.
+    #[serde(default = "default_debounce")]
+    pub debounce: u64,
+}
+
+fn pin_state(a: Level, b: Level) -> usize {
+    (((a == Level::High) as usize) << 1) | (b == Level::High) as usize
+}
+
+// Decodes the quadrature signal from the two pins into detents, shared by the
+// interrupts on both.
+struct Decoder {
+    pins: [u8; 2],
+    levels: [Level; 2],
+    last_edges: [Option<Instant>; 2],
+    debounce: Duration,
+    state: usize,
+    // The state the encoder rests in at every detent.
+    rest: usize,
+    // Quarter steps turned since the last detent.
+    position: i32,
+
+    modifier: Option<(InputPin, Level)>,
+    step: u64,
+    accelerated_step: u64,
+    window: Duration,
+    last_step: Option<Instant>,
+    origin: Origin,
+    sender: UnboundedSender<Message<Command>>,
+}
+
+impl Decoder {
+    // Returns 1 for a clockwise detent and -1 for anticlockwise, once the
+    // encoder is back at rest.
+    fn decode(&mut self, pin: usize, level: Level, now: Instant) -> Option<i64> {
+        self.levels[pin] = level;
+        let bounced = matches!(
+            self.last_edges[pin],
+            Some(last) if now.duration_since(last) < self.debounce
+        );
+        self.last_edges[pin] = Some(now);
+        if bounced {
+            return None;
+        }
+
+        let state = pin_state(self.levels[0], self.levels[1]);
+        self.position += TRANSITIONS[(self.state << 2) | state];
+        self.state = state;
+        if state != self.rest {
+            return None;
+        }
+
+        // A detent is four quarter steps but a missed edge shouldn't lose it.
+        let position = self.position;
+        self.position = 0;
+        match position {
+            position if position >= 2 => Some(1),
+            position if position <= -2 => Some(-1),
+            _ => None,
+        }
+    }
+
+    fn edge(&mut self, pin: usize, level: Level) {
+        let now = Instant::now();
+        monitor::record(&PinEvent {
+            pin: self.pins[pin],
+            level,
+            instant: now,
+        });
+
+        let direction = match self.decode(pin, level, now) {
+            Some(direction) => direction,
+            None => return,
+        };
+
+        if let Some((ref pin, on)) = self.modifier {
+            let level = pin.read();
+            monitor::observe(pin.pin(), level);
+            if level != on {
+                return;
+            }
+        }
+
+        let step = match self.last_step {
+            Some(last) if now.duration_since(last) < self.window => self.accelerated_step,
+            _ => self.step,
+        };
+        self.last_step = Some(now);
+
+        self.sender
+            .unbounded_send(
+                Message::new(
+                    now,
+                    Command::Seek {
+                        millis: direction * step as i64,
+                    },
+                )
+                .with_origin(self.origin.clone()),
+            )
+            .drop();
+    }
+}
+
+// A rotary encoder that generates seek commands. The interrupts are attached
+// to the pins and so they must be kept alive for as long as the stream is.
+pub struct Encoder {
+    _pins: [InputPin; 2],
+    receiver: UnboundedReceiver<Message<Command>>,
+}
+
+impl Encoder {
+    fn new(config: EncoderConfig) -> MusicResult<Encoder> {
+        debug!(
+            "Creating encoder for pins {} and {}, type {}, step {}ms",
+            config.pin_a, config.pin_b, config.kind, config.step
+        );
+
+        let mut pin_a = get_input_pin(config.pin_a, config.kind)?;
+        let mut pin_b = get_input_pin(config.pin_b, config.kind)?;
+        let modifier = match config.modifier {
+            Some(ref modifier) => Some((get_input_pin(modifier.pin, modifier.kind)?, modifier.on)),
+            None => None,
+        };
+
+        let (sender, receiver) = unbounded();
+        let levels = [pin_a.read(), pin_b.read()];
+        let state = pin_state(levels[0], levels[1]);
+        let decoder = Arc::new(Mutex::new(Decoder {
+            pins: [config.pin_a, config.pin_b],
+            levels,
+            last_edges: [None, None],
+            debounce: Duration::from_millis(config.debounce),
+            state,
+            rest: state,
+            position: 0,
+            modifier,
+            step: config.step,
+            accelerated_step: config.step * config.acceleration,
+            window: Duration::from_millis(config.acceleration_window),
+            last_step: None,
+            origin: Origin::Gpio { pin: config.pin_a },
+            sender,
+        }));
+
+        for (index, pin) in [&mut pin_a, &mut pin_b].iter_mut().enumerate() {
+            let decoder = decoder.clone();
+            let result = pin.set_async_interrupt(Trigger::Both, move |level: Level| {
+                decoder.lock().unwrap().edge(index, level)
+            });
+
+            if let Err(e) = result {
+                error!(
+                    "Failed to attach interrupt for encoder on pin {}: {}",
+                    pin.pin(),
+                    e
+                );
+                return Err(e.to_string());
+            }
+        }
+
+        Ok(Encoder {
+            _pins: [pin_a, pin_b],
+            receiver,
+        })
+    }
+}
+
+impl Stream for Encoder {
+    type Item = Message<Command>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.receiver).poll_next(cx)
+    }
+}
+
+pub struct Encoders {
+    encoders: Vec<EncoderConfig>,
+    authorizer: Arc<dyn Authorizer>,
+}
+
+impl Encoders {
+    pub fn new(encoders: Vec<EncoderConfig>, authorizer: Arc<dyn Authorizer>) -> Encoders {
+        Encoders {
+            encoders,
+            authorizer,
+        }
+    }
+}
+
+impl CommandSource for Encoders {
+    fn name(&self) -> &str {
+        "encoders"
+    }
+
+    fn authorizer(&self) -> Arc<dyn Authorizer> {
+        self.authorizer.clone()
+    }
+
+    fn commands(&mut self) -> MusicResult<CommandStream> {
+        let mut streams = Vec::new();
+
+        if is_available() {
+            for config in &self.encoders {
+                streams.push(Encoder::new(config.to_owned())?);
+            }
+        }
+
+        Ok(Box::pin(select_all(streams)))
+    }
+}
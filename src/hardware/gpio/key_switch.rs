@@ -0,0 +1,57 @@
+use std::sync::Mutex;
+
+use rppal::gpio::{InputPin, Level, PullUpDown};
+use serde::Deserialize;
+use tracing::debug;
+
+use crate::auth::{Authorization, Authorizer};
+use crate::error::MusicResult;
+use crate::hardware::gpio::{get_input_pin, monitor, LevelDef, PullUpDownDef};
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct KeySwitchConfig {
+    pub pin: u8,
+
+    #[serde(with = "PullUpDownDef")]
+    pub kind: PullUpDown,
+
+    #[serde(with = "LevelDef")]
+    pub on: Level,
+}
+
+// Hardware controls are limited to playback commands unless the key switch is
+// turned on.
+pub struct KeySwitch {
+    pin: Mutex<InputPin>,
+    on: Level,
+    elevated: Authorization,
+}
+
+impl KeySwitch {
+    pub fn new(config: &KeySwitchConfig, elevated: Authorization) -> MusicResult<KeySwitch> {
+        debug!(
+            "Creating key switch for pin {}, type {}, on level: {}",
+            config.pin, config.kind, config.on
+        );
+
+        Ok(KeySwitch {
+            pin: Mutex::new(get_input_pin(config.pin, config.kind)?),
+            on: config.on,
+            elevated,
+        })
+    }
+}
+
+impl Authorizer for KeySwitch {
+    fn authorization(&self) -> Authorization {
+        let pin = self.pin.lock().unwrap();
+        let level = pin.read();
+        monitor::observe(pin.pin(), level);
+        if level == self.on {
+            self.elevated
+        } else {
+            Authorization::Playback
+        }
+    }
+}
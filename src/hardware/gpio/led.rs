@@ -1,59 +1,302 @@
+use std::fmt;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, TryLockError, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use futures::future::{ready, BoxFuture, FutureExt};
+use lazy_static::lazy_static;
 use rppal::gpio::{Level, OutputPin};
 
-use log::{debug, error};
 use serde::Deserialize;
+use tracing::debug;
+
+use crate::appstate::AppState;
+use crate::error::{MusicResult, VoidResult};
+use crate::events::{Event, Message};
+use crate::hardware::gpio::{defaults, get_pin, monitor, optional_level};
+use crate::sinks::EventSink;
+
+const PULSE_INTERVAL: u64 = 500;
+const CODE_BLINK: u64 = 250;
+const CODE_GAP: u64 = 1000;
+// Between the codes when several are shown one after another.
+const CODE_SEPARATOR: u64 = 3000;
+const IDENTIFY_BLINK: u64 = 100;
+
+lazy_static! {
+    // Every LED made so they can all be turned off after a panic.
+    static ref LEDS: Mutex<Vec<Weak<Mutex<Inner>>>> = Mutex::new(Vec::new());
+}
 
-use crate::error::MusicResult;
-use crate::hardware::gpio::{LevelDef, GPIO};
+// Turns off every LED still in use. This runs while panicking so it never
+// waits for a lock that the panicking thread may hold.
+pub fn all_off() {
+    let leds = match LEDS.try_lock() {
+        Ok(leds) => leds,
+        Err(TryLockError::Poisoned(e)) => e.into_inner(),
+        Err(TryLockError::WouldBlock) => return,
+    };
+
+    for inner in leds.iter().filter_map(Weak::upgrade) {
+        match inner.try_lock() {
+            Ok(mut inner) => inner.write(false),
+            Err(TryLockError::Poisoned(e)) => e.into_inner().write(false),
+            Err(TryLockError::WouldBlock) => (),
+        }
+    }
+}
 
 #[derive(Debug, Clone, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct LEDConfig {
     pub pin: u8,
 
-    #[serde(with = "LevelDef")]
-    pub on: Level,
+    // The GPIO default is used when this is omitted.
+    #[serde(default, deserialize_with = "optional_level")]
+    pub on: Option<Level>,
 }
 
-pub struct LED {
+impl LEDConfig {
+    pub fn on(&self) -> Level {
+        self.on.unwrap_or_else(|| defaults().led_on)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Mode {
+    Off,
+    On,
+    Pulse,
+}
+
+struct Inner {
     pin: OutputPin,
     on: Level,
+    mode: Mode,
+    lit: bool,
+    // While a blink code is showing mode changes are recorded but not applied.
+    busy: bool,
+    // Dropping this stops the pulse thread.
+    _pulse: Sender<()>,
+}
+
+impl Inner {
+    fn write(&mut self, lit: bool) {
+        self.lit = lit;
+        let level = if lit { self.on } else { !self.on };
+        self.pin.write(level);
+        monitor::observe(self.pin.pin(), level);
+    }
+
+    fn apply_mode(&mut self) {
+        match self.mode {
+            Mode::Off => self.write(false),
+            Mode::On | Mode::Pulse => self.write(true),
+        }
+    }
+
+    fn set_mode(&mut self, mode: Mode) {
+        if self.mode == mode {
+            return;
+        }
+
+        self.mode = mode;
+        if !self.busy {
+            self.apply_mode();
+        }
+    }
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        self.write(false);
+    }
+}
+
+// LEDs can be shared, the pin is released and turned off when the last clone
+// is dropped.
+#[derive(Clone)]
+pub struct LED {
+    inner: Arc<Mutex<Inner>>,
 }
 
 impl LED {
     pub fn new(config: &LEDConfig) -> MusicResult<LED> {
         debug!(
             "Creating LED for pin {}, on level: {}",
-            config.pin, config.on
+            config.pin,
+            config.on()
         );
 
-        let pin = match GPIO.get(config.pin) {
-            Ok(p) => p,
-            Err(e) => {
-                error!("Failed to get pin {}: {}", config.pin, e);
-                return Err(e.to_string());
-            }
-        };
+        let pin = get_pin(config.pin)?;
+        let (pulse, stop) = channel();
 
-        let mut led = LED {
+        let mut inner = Inner {
             pin: pin.into_output(),
-            on: config.on,
+            on: config.on(),
+            mode: Mode::Off,
+            lit: false,
+            busy: false,
+            _pulse: pulse,
         };
-        led.off();
+        inner.write(false);
+
+        let led = LED {
+            inner: Arc::new(Mutex::new(inner)),
+        };
+
+        let weak = Arc::downgrade(&led.inner);
+        {
+            let mut leds = LEDS.lock().unwrap();
+            leds.retain(|led| led.strong_count() > 0);
+            leds.push(weak.clone());
+        }
+        thread::spawn(move || LED::pulse_thread(weak, stop));
+
         Ok(led)
     }
 
-    pub fn on(&mut self) {
-        self.pin.write(self.on);
+    // Runs until the LED is dropped, which disconnects the channel.
+    fn pulse_thread(inner: Weak<Mutex<Inner>>, stop: Receiver<()>) {
+        let interval = Duration::from_millis(PULSE_INTERVAL);
+        while let Err(RecvTimeoutError::Timeout) = stop.recv_timeout(interval) {
+            if let Some(inner) = inner.upgrade() {
+                let mut inner = inner.lock().unwrap();
+                if inner.mode == Mode::Pulse && !inner.busy {
+                    let lit = !inner.lit;
+                    inner.write(lit);
+                }
+            }
+        }
     }
 
-    pub fn off(&mut self) {
-        self.pin.write(!self.on);
+    pub fn on(&self) {
+        self.inner.lock().unwrap().set_mode(Mode::On);
+    }
+
+    pub fn off(&self) {
+        self.inner.lock().unwrap().set_mode(Mode::Off);
+    }
+
+    pub fn pulse(&self) {
+        self.inner.lock().unwrap().set_mode(Mode::Pulse);
+    }
+
+    // Lights the LED for the duration, then returns it to its normal state.
+    pub fn flash(&self, duration: Duration) {
+        let inner = self.inner.clone();
+        thread::spawn(move || {
+            {
+                let mut inner = inner.lock().unwrap();
+                inner.busy = true;
+                inner.write(true);
+            }
+            thread::sleep(duration);
+
+            let mut inner = inner.lock().unwrap();
+            inner.busy = false;
+            inner.apply_mode();
+        });
+    }
+
+    // Blinks rapidly for the duration so the LED stands out from the others,
+    // then returns it to its normal state.
+    pub fn identify(&self, duration: Duration) {
+        let inner = self.inner.clone();
+        thread::spawn(move || {
+            inner.lock().unwrap().busy = true;
+
+            let start = Instant::now();
+            let mut lit = false;
+            while start.elapsed() < duration {
+                lit = !lit;
+                inner.lock().unwrap().write(lit);
+                thread::sleep(Duration::from_millis(IDENTIFY_BLINK));
+            }
+
+            let mut inner = inner.lock().unwrap();
+            inner.busy = false;
+            inner.apply_mode();
+        });
+    }
+
+    // Blinks each digit in turn, a zero is shown as ten blinks. The LED returns
+    // to its normal state once the code is complete.
+    pub fn blink_code(&self, digits: Vec<u8>) {
+        self.blink_codes(vec![digits]);
+    }
+
+    // Blinks several codes with a longer gap between each of them.
+    pub fn blink_codes(&self, codes: Vec<Vec<u8>>) {
+        let inner = self.inner.clone();
+        thread::spawn(move || {
+            let write = |lit: bool| inner.lock().unwrap().write(lit);
+            let pause = |millis: u64| thread::sleep(Duration::from_millis(millis));
+
+            inner.lock().unwrap().busy = true;
+            write(false);
+            pause(CODE_GAP);
+
+            for (index, digits) in codes.into_iter().enumerate() {
+                if index > 0 {
+                    pause(CODE_SEPARATOR);
+                }
+
+                for digit in digits {
+                    let count = if digit == 0 { 10 } else { digit };
+                    for _ in 0..count {
+                        write(true);
+                        pause(CODE_BLINK);
+                        write(false);
+                        pause(CODE_BLINK);
+                    }
+                    pause(CODE_GAP);
+                }
+            }
+
+            let mut inner = inner.lock().unwrap();
+            inner.busy = false;
+            inner.apply_mode();
+        });
     }
 }
 
-impl Drop for LED {
-    fn drop(&mut self) {
-        self.off();
+impl fmt::Debug for LED {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "LED")
+    }
+}
+
+// Keeps the playlist LEDs in sync with what is playing.
+pub struct PlaylistLEDs {
+    state: AppState,
+}
+
+impl PlaylistLEDs {
+    pub fn new(state: AppState) -> PlaylistLEDs {
+        PlaylistLEDs { state }
+    }
+}
+
+impl EventSink for PlaylistLEDs {
+    fn name(&self) -> &str {
+        "playlist LEDs"
+    }
+
+    fn accepts(&self, event: &Event) -> bool {
+        match event {
+            Event::PlaylistUpdated { .. }
+            | Event::PlaybackStarted
+            | Event::PlaybackPaused
+            | Event::PlaybackUnpaused
+            | Event::PlaybackEnded => true,
+            _ => false,
+        }
+    }
+
+    fn handle<'a>(&'a mut self, _event: &'a Message<Event>) -> BoxFuture<'a, VoidResult> {
+        self.state.update_leds();
+        ready(Ok(())).boxed()
     }
 }
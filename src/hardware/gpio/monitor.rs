@@ -0,0 +1,77 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use lazy_static::lazy_static;
+use musicbox_protocol::PinStatus;
+use rppal::gpio::Level;
+
+use crate::hardware::gpio::PinEvent;
+
+#[derive(Default)]
+struct Monitor {
+    pins: BTreeMap<u8, PinStatus>,
+    listeners: Vec<UnboundedSender<PinStatus>>,
+}
+
+lazy_static! {
+    // Everything seen on the pins in use, for diagnosing wiring problems.
+    static ref MONITOR: Mutex<Monitor> = Default::default();
+}
+
+fn update<F>(pin: u8, f: F)
+where
+    F: FnOnce(&mut PinStatus),
+{
+    let mut monitor = MONITOR.lock().unwrap();
+    let status = monitor.pins.entry(pin).or_insert_with(|| PinStatus {
+        pin,
+        ..Default::default()
+    });
+
+    let previous = status.clone();
+    f(status);
+    if *status == previous {
+        return;
+    }
+
+    let status = status.clone();
+    monitor
+        .listeners
+        .retain(|listener| listener.unbounded_send(status.clone()).is_ok());
+}
+
+// Notes the level of a pin that was read or written.
+pub fn observe(pin: u8, level: Level) {
+    update(pin, |status| status.high = level == Level::High);
+}
+
+// Notes a change in level seen by an interrupt.
+pub fn record(event: &PinEvent) {
+    let timestamp = (SystemTime::now() - event.instant.elapsed())
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64;
+
+    update(event.pin, |status| {
+        status.high = event.level == Level::High;
+        if status.high {
+            status.rising += 1;
+        } else {
+            status.falling += 1;
+        }
+        status.last_event = Some(timestamp);
+    });
+}
+
+pub fn snapshot() -> Vec<PinStatus> {
+    MONITOR.lock().unwrap().pins.values().cloned().collect()
+}
+
+// Receives the new status of each pin whenever it changes.
+pub fn subscribe() -> UnboundedReceiver<PinStatus> {
+    let (sender, receiver) = unbounded();
+    MONITOR.lock().unwrap().listeners.push(sender);
+    receiver
+}
@@ -0,0 +1,79 @@
+use std::sync::Arc;
+
+use futures::future::ready;
+use futures::stream::{empty, StreamExt};
+use rppal::gpio::{Level, PullUpDown};
+use serde::Deserialize;
+use tracing::debug;
+
+use crate::auth::{Authorization, Authorizer};
+use crate::error::MusicResult;
+use crate::events::{Command, Message, Origin};
+use crate::hardware::gpio::{is_available, LevelDef, PinEvents, PullUpDownDef};
+use crate::sources::{CommandSource, CommandStream};
+
+fn default_grace() -> u64 {
+    2000
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PowerLossConfig {
+    pub pin: u8,
+
+    #[serde(with = "PullUpDownDef")]
+    pub kind: PullUpDown,
+
+    // The level the pin goes to when external power is lost.
+    #[serde(with = "LevelDef")]
+    pub on: Level,
+
+    // Milliseconds that the backup power can be relied on for.
+    #[serde(default = "default_grace")]
+    pub grace: u64,
+}
+
+// Watches the power loss signal from a UPS or supercap circuit.
+pub struct PowerLoss {
+    config: PowerLossConfig,
+}
+
+impl PowerLoss {
+    pub fn new(config: PowerLossConfig) -> PowerLoss {
+        PowerLoss { config }
+    }
+}
+
+impl CommandSource for PowerLoss {
+    fn name(&self) -> &str {
+        "power loss detection"
+    }
+
+    fn commands(&mut self) -> MusicResult<CommandStream> {
+        if !is_available() {
+            return Ok(Box::pin(empty()));
+        }
+
+        debug!(
+            "Watching for power loss on pin {}, type {}, on level: {}",
+            self.config.pin, self.config.kind, self.config.on
+        );
+
+        let on = self.config.on;
+        let pin = self.config.pin;
+        let events = PinEvents::new(self.config.pin, self.config.kind, None)?;
+        Ok(Box::pin(
+            events
+                .filter(move |event| ready(event.level == on))
+                .take(1)
+                .map(move |event| {
+                    Message::new(event.instant, Command::PowerLost)
+                        .with_origin(Origin::Gpio { pin })
+                }),
+        ))
+    }
+
+    fn authorizer(&self) -> Arc<dyn Authorizer> {
+        Arc::new(Authorization::System)
+    }
+}
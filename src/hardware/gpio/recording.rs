@@ -0,0 +1,100 @@
+use std::collections::HashMap;
+use std::fs::{read_to_string, File};
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::channel::mpsc::UnboundedSender;
+use rppal::gpio::Level;
+use serde::{Deserialize, Serialize};
+use serde_json::{from_str, to_string};
+use tokio::time::delay_until;
+use tracing::{info, warn};
+
+use crate::error::{ErrorExt, MusicResult, VoidResult};
+use crate::hardware::gpio::{LevelDef, PinEvent};
+
+// Recordings are stored as one JSON object per line.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RecordedEvent {
+    pin: u8,
+    #[serde(with = "LevelDef")]
+    level: Level,
+    // Time since the recording started.
+    micros: u64,
+}
+
+// Records raw pin events to a file. Clones all write to the same file.
+#[derive(Clone)]
+pub struct Recorder {
+    start: Instant,
+    file: Arc<Mutex<File>>,
+}
+
+impl Recorder {
+    pub fn new(path: &Path) -> MusicResult<Recorder> {
+        let file = File::create(path).prefix("Unable to create GPIO recording")?;
+        info!("Recording GPIO events to {}.", path.display());
+
+        Ok(Recorder {
+            start: Instant::now(),
+            file: Arc::new(Mutex::new(file)),
+        })
+    }
+
+    pub fn record(&self, event: &PinEvent) {
+        let recorded = RecordedEvent {
+            pin: event.pin,
+            level: event.level,
+            micros: event.instant.duration_since(self.start).as_micros() as u64,
+        };
+
+        let result = to_string(&recorded)
+            .as_err()
+            .and_then(|line| writeln!(self.file.lock().unwrap(), "{}", line).as_err());
+        if let Err(e) = result {
+            warn!("Failed to record GPIO event: {}", e);
+        }
+    }
+}
+
+// Feeds the events in a recording to the senders for each pin with the same
+// timing as when they were recorded.
+pub fn replay(path: &Path, pins: HashMap<u8, UnboundedSender<PinEvent>>) -> VoidResult {
+    let events = read_to_string(path)
+        .prefix("Unable to read GPIO recording")?
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| from_str::<RecordedEvent>(line).prefix("Invalid GPIO recording"))
+        .collect::<MusicResult<Vec<RecordedEvent>>>()?;
+
+    info!(
+        "Replaying {} GPIO events from {}.",
+        events.len(),
+        path.display()
+    );
+
+    tokio::spawn(async move {
+        let start = Instant::now();
+        for recorded in events {
+            let instant = start + Duration::from_micros(recorded.micros);
+            delay_until(instant.into()).await;
+
+            match pins.get(&recorded.pin) {
+                Some(sender) => sender
+                    .unbounded_send(PinEvent {
+                        pin: recorded.pin,
+                        level: recorded.level,
+                        instant,
+                    })
+                    .drop(),
+                None => warn!("Recording contains unknown pin {}.", recorded.pin),
+            }
+        }
+        info!("GPIO replay complete.");
+    });
+
+    Ok(())
+}
@@ -0,0 +1,107 @@
+use std::collections::HashMap;
+use std::fs::{read, write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use musicbox_protocol::ButtonUsage;
+use serde_json::{from_slice, to_vec};
+use tracing::warn;
+
+use crate::appstate::MutableAppState;
+use crate::error::{ErrorExt, VoidResult};
+use crate::metrics::Metrics;
+
+const USAGE_FILE: &str = "buttons.json";
+// A button's bounce rate is checked each time it has been pressed this many
+// more times.
+const WEAR_CHECK_PRESSES: u64 = 50;
+// A switch in good condition rarely bounces more than once or twice per press.
+const WEAR_BOUNCE_RATE: u64 = 5;
+
+#[derive(Default)]
+struct Recent {
+    presses: u64,
+    bounces: u64,
+}
+
+struct Inner {
+    file: PathBuf,
+    usage: HashMap<u8, ButtonUsage>,
+    // Counts since the last wear check.
+    recent: HashMap<u8, Recent>,
+    state: MutableAppState,
+    metrics: Metrics,
+}
+
+// Counts the presses and bounces of every button. Persisted so the counts
+// cover the life of the switches.
+#[derive(Clone)]
+pub struct ButtonCounters {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl ButtonCounters {
+    pub fn load(data_dir: &Path, mut state: MutableAppState, metrics: Metrics) -> ButtonCounters {
+        let file = data_dir.join(USAGE_FILE);
+        let usage: HashMap<u8, ButtonUsage> = match read(&file) {
+            Ok(data) => from_slice(&data)
+                .format_log(|e| format!("Discarding unreadable button usage: {}", e))
+                .unwrap_or_default(),
+            Err(_) => Default::default(),
+        };
+
+        for (pin, usage) in usage.iter() {
+            state.set_button_usage(*pin, usage.clone());
+        }
+
+        ButtonCounters {
+            inner: Arc::new(Mutex::new(Inner {
+                file,
+                usage,
+                recent: HashMap::new(),
+                state,
+                metrics,
+            })),
+        }
+    }
+
+    // The state is only updated on presses, bounces can come in bursts.
+    pub fn press(&self, pin: u8) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.metrics.increment(&format!("button{}.presses", pin));
+
+        let inner = &mut *inner;
+        let usage = inner.usage.entry(pin).or_default();
+        let recent = inner.recent.entry(pin).or_default();
+        usage.presses += 1;
+        recent.presses += 1;
+
+        if recent.presses >= WEAR_CHECK_PRESSES {
+            usage.worn = recent.bounces >= recent.presses * WEAR_BOUNCE_RATE;
+            if usage.worn {
+                warn!(
+                    "The button on pin {} bounced {} times in its last {} presses, the switch may be wearing out.",
+                    pin, recent.bounces, recent.presses
+                );
+            }
+            *recent = Recent::default();
+        }
+
+        inner.state.set_button_usage(pin, usage.clone());
+    }
+
+    pub fn bounce(&self, pin: u8) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.metrics.increment(&format!("button{}.bounces", pin));
+
+        let inner = &mut *inner;
+        inner.usage.entry(pin).or_default().bounces += 1;
+        inner.recent.entry(pin).or_default().bounces += 1;
+    }
+
+    pub fn save(&self) -> VoidResult {
+        let inner = self.inner.lock().unwrap();
+        let data = to_vec(&inner.usage).prefix("Unable to serialize button usage")?;
+        write(&inner.file, data).prefix("Unable to write button usage")
+    }
+}
@@ -0,0 +1,160 @@
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use evdev::{Device, EventType};
+use futures::channel::mpsc::{unbounded, UnboundedSender};
+use serde::Deserialize;
+use tracing::{debug, error, warn};
+
+use crate::error::{ErrorExt, MusicResult};
+use crate::events::{Command, Message, Origin};
+use crate::sources::{CommandSource, CommandStream};
+
+// The value of a key event when the key is first pressed, as opposed to
+// released or repeating.
+const KEY_PRESSED: i32 = 1;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InputKeyConfig {
+    // The Linux key code, as listed in linux/input-event-codes.h.
+    pub code: u16,
+    pub command: Command,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InputDeviceConfig {
+    // Ideally a stable path from /dev/input/by-id.
+    pub device: PathBuf,
+
+    // Takes exclusive use of the device so its keys don't also reach the
+    // console.
+    #[serde(default)]
+    pub grab: bool,
+
+    pub keys: Vec<InputKeyConfig>,
+}
+
+type SharedSender = Arc<Mutex<Option<UnboundedSender<Message<Command>>>>>;
+
+fn read_device(mut device: Device, config: InputDeviceConfig, sender: SharedSender) {
+    let origin = Origin::Input {
+        device: config.device.display().to_string(),
+    };
+
+    loop {
+        let events = match device.fetch_events() {
+            Ok(events) => events,
+            Err(e) => {
+                error!(
+                    "Failed to read from input device {}: {}",
+                    config.device.display(),
+                    e
+                );
+                return;
+            }
+        };
+
+        for event in events {
+            if event.event_type() != EventType::KEY || event.value() != KEY_PRESSED {
+                continue;
+            }
+
+            let command = match config.keys.iter().find(|key| key.code == event.code()) {
+                Some(key) => key.command.clone(),
+                None => {
+                    debug!("Ignoring unmapped key code {}.", event.code());
+                    continue;
+                }
+            };
+
+            if let Some(ref sender) = *sender.lock().unwrap() {
+                sender
+                    .unbounded_send(Message::from(command).with_origin(origin.clone()))
+                    .drop();
+            }
+        }
+    }
+}
+
+// Reads key presses directly from input devices such as USB keypads and
+// remotes. Unlike the keyboard this works without a terminal.
+pub struct InputDevices {
+    devices: Vec<InputDeviceConfig>,
+    // Reads block so the threads are started once and kept across reloads,
+    // each new command stream replaces the sender they use.
+    sender: SharedSender,
+    started: bool,
+}
+
+impl InputDevices {
+    pub fn new(devices: Vec<InputDeviceConfig>) -> InputDevices {
+        InputDevices {
+            devices,
+            sender: Arc::new(Mutex::new(None)),
+            started: false,
+        }
+    }
+
+    fn start(&self) {
+        for config in &self.devices {
+            let mut device = match Device::open(&config.device) {
+                Ok(device) => device,
+                Err(e) => {
+                    warn!(
+                        "Unable to open input device {}: {}",
+                        config.device.display(),
+                        e
+                    );
+                    continue;
+                }
+            };
+
+            debug!(
+                "Reading from input device {} ({}).",
+                config.device.display(),
+                device.name().unwrap_or("unnamed")
+            );
+
+            if config.grab {
+                device
+                    .grab()
+                    .format_log(|e| {
+                        format!(
+                            "Unable to grab input device {}: {}",
+                            config.device.display(),
+                            e
+                        )
+                    })
+                    .drop();
+            }
+
+            let config = config.clone();
+            let sender = self.sender.clone();
+            thread::spawn(move || read_device(device, config, sender));
+        }
+    }
+}
+
+impl CommandSource for InputDevices {
+    fn name(&self) -> &str {
+        "input devices"
+    }
+
+    fn commands(&mut self) -> MusicResult<CommandStream> {
+        if !self.started {
+            self.start();
+            self.started = true;
+        }
+
+        let (sender, receiver) = unbounded();
+        *self.sender.lock().unwrap() = Some(sender);
+        Ok(Box::pin(receiver))
+    }
+
+    fn shutdown(&mut self) {
+        *self.sender.lock().unwrap() = None;
+    }
+}
@@ -1,33 +1,251 @@
-use crossterm::event::{Event, EventStream, KeyEvent};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crossterm::event::{Event, EventStream, KeyCode, KeyEvent, KeyModifiers};
 use futures::future::ready;
-use futures::stream::{Stream, StreamExt};
+use futures::stream::StreamExt;
+
+use serde::de::Error;
+use serde::{Deserialize, Deserializer};
+
+use crate::auth::{Authorization, Authorizer};
+use crate::dashboard::Dashboard;
+use crate::error::{ErrorExt, MusicResult};
+use crate::events::{Command, Message, Origin};
+use crate::sources::{CommandSource, CommandStream};
 
-use serde::Deserialize;
+// Parses a key along with any modifiers held with it, like "g", "left" or
+// "ctrl+shift+x".
+fn parse_key(text: &str) -> MusicResult<KeyEvent> {
+    // The key is last and may itself be "+".
+    let (held, key) = if text == "+" {
+        ("", "+")
+    } else if let Some(held) = text.strip_suffix("++") {
+        (held, "+")
+    } else {
+        match text.rfind('+') {
+            Some(index) => (&text[..index], &text[index + 1..]),
+            None => ("", text),
+        }
+    };
 
-use crate::events::{Command, Message};
+    let mut modifiers = KeyModifiers::empty();
+    for modifier in held.split('+').filter(|modifier| !modifier.is_empty()) {
+        match modifier.to_lowercase().as_str() {
+            "ctrl" | "control" => modifiers.insert(KeyModifiers::CONTROL),
+            "alt" => modifiers.insert(KeyModifiers::ALT),
+            "shift" => modifiers.insert(KeyModifiers::SHIFT),
+            _ => {
+                return Err(format!(
+                    "Unknown modifier '{}' in key '{}'.",
+                    modifier, text
+                ))
+            }
+        }
+    }
+
+    let mut chars = key.chars();
+    let code = match (chars.next(), chars.next()) {
+        // The terminal reports shifted letters as upper case with shift held.
+        (Some(c), None) if c.is_alphabetic() => {
+            if c.is_uppercase() || modifiers.contains(KeyModifiers::SHIFT) {
+                modifiers.insert(KeyModifiers::SHIFT);
+                KeyCode::Char(c.to_uppercase().next().unwrap_or(c))
+            } else {
+                KeyCode::Char(c)
+            }
+        }
+        (Some(c), None) => KeyCode::Char(c),
+        _ => match key.to_lowercase().as_str() {
+            "space" => KeyCode::Char(' '),
+            "enter" => KeyCode::Enter,
+            "tab" => KeyCode::Tab,
+            "backspace" => KeyCode::Backspace,
+            "esc" | "escape" => KeyCode::Esc,
+            "left" => KeyCode::Left,
+            "right" => KeyCode::Right,
+            "up" => KeyCode::Up,
+            "down" => KeyCode::Down,
+            "home" => KeyCode::Home,
+            "end" => KeyCode::End,
+            "pageup" => KeyCode::PageUp,
+            "pagedown" => KeyCode::PageDown,
+            "delete" => KeyCode::Delete,
+            "insert" => KeyCode::Insert,
+            name => match name.strip_prefix('f').and_then(|n| n.parse::<u8>().ok()) {
+                Some(n) if (1..=12).contains(&n) => KeyCode::F(n),
+                _ => return Err(format!("Unknown key '{}'.", text)),
+            },
+        },
+    };
+
+    Ok(KeyEvent { code, modifiers })
+}
+
+// Keys are either written out as a crossterm key event or as a string.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum KeyDef {
+    Named(String),
+    Event(KeyEvent),
+}
+
+impl KeyDef {
+    fn key(self) -> MusicResult<KeyEvent> {
+        match self {
+            KeyDef::Named(text) => parse_key(&text),
+            KeyDef::Event(key) => Ok(key),
+        }
+    }
+}
+
+pub fn deserialize_key<'de, D>(deserializer: D) -> Result<KeyEvent, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    KeyDef::deserialize(deserializer)?
+        .key()
+        .map_err(D::Error::custom)
+}
+
+fn deserialize_keys<'de, D>(deserializer: D) -> Result<Vec<KeyEvent>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Vec::<KeyDef>::deserialize(deserializer)?
+        .into_iter()
+        .map(|def| def.key().map_err(D::Error::custom))
+        .collect()
+}
 
 #[derive(Debug, Clone, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct KeyConfig {
+    #[serde(deserialize_with = "deserialize_key")]
     pub key: KeyEvent,
+    // Keys that must follow the first to make a sequence, each pressed within
+    // the sequence timeout of the one before.
+    #[serde(default, deserialize_with = "deserialize_keys")]
+    pub then: Vec<KeyEvent>,
     pub command: Command,
 }
 
-pub struct Keyboard;
+impl KeyConfig {
+    fn matches(&self, keys: &[KeyEvent]) -> bool {
+        keys.split_first()
+            .map(|(first, rest)| *first == self.key && rest == self.then.as_slice())
+            .unwrap_or(false)
+    }
+
+    fn starts_with(&self, keys: &[KeyEvent]) -> bool {
+        match keys.split_first() {
+            Some((first, rest)) => {
+                *first == self.key && rest.len() < self.then.len() && self.then.starts_with(rest)
+            }
+            None => false,
+        }
+    }
+}
+
+// Tracks the keys pressed so far in a sequence. A binding runs as soon as its
+// keys are complete so a key bound on its own can't also start a sequence.
+struct KeySequence {
+    keys: Vec<KeyConfig>,
+    timeout: Duration,
+    pressed: Vec<KeyEvent>,
+    last_press: Instant,
+}
+
+impl KeySequence {
+    fn press(&mut self, key: KeyEvent) -> Option<&KeyConfig> {
+        if self.last_press.elapsed() > self.timeout {
+            self.pressed.clear();
+        }
+        self.last_press = Instant::now();
+        self.pressed.push(key);
+
+        if let Some(index) = self
+            .keys
+            .iter()
+            .position(|config| config.matches(&self.pressed))
+        {
+            self.pressed.clear();
+            return Some(&self.keys[index]);
+        }
+
+        if self
+            .keys
+            .iter()
+            .any(|config| config.starts_with(&self.pressed))
+        {
+            return None;
+        }
+
+        // The sequence went nowhere but the last key may start another.
+        let abandoned = self.pressed.len() > 1;
+        self.pressed.clear();
+        if abandoned {
+            self.press(key)
+        } else {
+            None
+        }
+    }
+}
+
+pub struct Keyboard {
+    keys: Vec<KeyConfig>,
+    sequence_timeout: Duration,
+    // The key that shows and hides the dashboard.
+    dashboard: Option<(KeyEvent, Dashboard)>,
+}
 
 impl Keyboard {
-    pub fn init(keys: Vec<KeyConfig>) -> impl Stream<Item = Message<Command>> {
-        EventStream::new().filter_map(move |event| {
-            ready(match event {
-                Ok(Event::Key(k)) => Keyboard::generate_command(k, &keys),
-                _ => None,
-            })
-        })
+    pub fn new(keys: Vec<KeyConfig>, sequence_timeout: Duration) -> Keyboard {
+        Keyboard {
+            keys,
+            sequence_timeout,
+            dashboard: None,
+        }
+    }
+
+    pub fn set_dashboard(&mut self, key: KeyEvent, dashboard: Dashboard) {
+        self.dashboard = Some((key, dashboard));
+    }
+}
+
+impl CommandSource for Keyboard {
+    fn name(&self) -> &str {
+        "keyboard"
+    }
+
+    // Only someone at the console can use the keyboard.
+    fn authorizer(&self) -> Arc<dyn Authorizer> {
+        Arc::new(Authorization::System)
     }
 
-    fn generate_command(key: KeyEvent, keys: &[KeyConfig]) -> Option<Message<Command>> {
-        keys.iter()
-            .find(|config| config.key == key)
-            .map(|config| config.command.to_owned().into())
+    fn commands(&mut self) -> MusicResult<CommandStream> {
+        let mut sequence = KeySequence {
+            keys: self.keys.clone(),
+            timeout: self.sequence_timeout,
+            pressed: Vec::new(),
+            last_press: Instant::now(),
+        };
+        let dashboard = self.dashboard.clone();
+        Ok(Box::pin(EventStream::new().filter_map(move |event| {
+            ready(match (event, &dashboard) {
+                (Ok(Event::Key(k)), Some((key, dashboard))) if k == *key => {
+                    dashboard.toggle().log().drop();
+                    None
+                }
+                (Ok(Event::Resize(_, _)), Some((_, dashboard))) => {
+                    dashboard.refresh().log().drop();
+                    None
+                }
+                (Ok(Event::Key(k)), _) => sequence.press(k).map(|config| {
+                    Message::from(config.command.to_owned()).with_origin(Origin::Keyboard)
+                }),
+                _ => None,
+            })
+        })))
     }
 }
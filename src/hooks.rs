@@ -0,0 +1,319 @@
+use std::io;
+use std::mem;
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+use std::ptr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use futures::future::{BoxFuture, FutureExt};
+use musicbox_protocol::{DeviceInfo, StateSnapshot};
+use serde::Deserialize;
+use tokio::task::spawn_blocking;
+use tracing::{debug, warn};
+
+use crate::appstate::AppState;
+use crate::error::{ErrorExt, MusicResult, VoidResult};
+use crate::events::{Event, Message};
+use crate::sinks::EventSink;
+
+const POLL_INTERVAL: u64 = 100;
+
+fn default_timeout() -> u64 {
+    10
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum HookEvent {
+    TrackStarted,
+    PlaylistStarted,
+    Shutdown,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HookConfig {
+    pub event: HookEvent,
+
+    // The command to run. "{event}", "{playlist}", "{position}", "{title}",
+    // "{artist}", "{album}", "{deviceId}" and "{deviceName}" in the arguments
+    // are replaced.
+    pub command: Vec<String>,
+
+    // Seconds before the command is killed.
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HooksConfig {
+    // The user and group ids that commands run as, so they don't get the
+    // music box's access to the hardware. The group defaults to the user's
+    // primary group and supplementary groups are always dropped.
+    pub user: Option<u32>,
+    pub group: Option<u32>,
+
+    #[serde(default)]
+    pub hooks: Vec<HookConfig>,
+}
+
+// The values that can be used in a hook's arguments.
+#[derive(Debug, Default)]
+struct HookValues {
+    device: DeviceInfo,
+    playlist: String,
+    position: String,
+    title: String,
+    artist: String,
+    album: String,
+}
+
+impl HookValues {
+    fn new(snapshot: &StateSnapshot, playlist: Option<&str>, device: &DeviceInfo) -> HookValues {
+        let device = device.clone();
+        let play_state = match snapshot.play_state {
+            Some(ref play_state) => play_state,
+            None => {
+                return HookValues {
+                    device,
+                    ..Default::default()
+                }
+            }
+        };
+
+        let track = snapshot.playlist.get(play_state.position);
+        HookValues {
+            device,
+            playlist: playlist.unwrap_or_default().to_owned(),
+            position: (play_state.position + 1).to_string(),
+            title: track.map(|track| track.title.clone()).unwrap_or_default(),
+            artist: track
+                .and_then(|track| track.artist.clone())
+                .unwrap_or_default(),
+            album: track
+                .and_then(|track| track.album.clone())
+                .unwrap_or_default(),
+        }
+    }
+
+    fn value(&self, event: HookEvent, name: &str) -> Option<String> {
+        Some(match name {
+            "event" => format!("{:?}", event),
+            "playlist" => self.playlist.clone(),
+            "position" => self.position.clone(),
+            "title" => self.title.clone(),
+            "artist" => self.artist.clone(),
+            "album" => self.album.clone(),
+            "deviceId" => self.device.id.clone(),
+            "deviceName" => self.device.name.clone(),
+            _ => return None,
+        })
+    }
+
+    // Replaces placeholders in a single pass so values that look like
+    // placeholders are left alone.
+    fn fill(&self, event: HookEvent, arg: &str) -> String {
+        let mut filled = String::with_capacity(arg.len());
+        let mut rest = arg;
+        while let Some(start) = rest.find('{') {
+            filled.push_str(&rest[..start]);
+            let placeholder = &rest[start..];
+            match placeholder.find('}').and_then(|end| {
+                self.value(event, &placeholder[1..end])
+                    .map(|value| (end, value))
+            }) {
+                Some((end, value)) => {
+                    filled.push_str(&value);
+                    rest = &placeholder[end + 1..];
+                }
+                None => {
+                    filled.push('{');
+                    rest = &placeholder[1..];
+                }
+            }
+        }
+        filled.push_str(rest);
+        filled
+    }
+}
+
+fn primary_group(user: u32) -> MusicResult<u32> {
+    let mut passwd: libc::passwd = unsafe { mem::zeroed() };
+    let mut buffer = vec![0; 16384];
+    let mut result = ptr::null_mut();
+    let code = unsafe {
+        libc::getpwuid_r(
+            user,
+            &mut passwd,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut result,
+        )
+    };
+
+    if code != 0 || result.is_null() {
+        Err(format!(
+            "Unable to find the primary group of user {}.",
+            user
+        ))
+    } else {
+        Ok(passwd.pw_gid)
+    }
+}
+
+fn check(result: libc::c_int) -> io::Result<()> {
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+// Switches the command to the user and group. The supplementary groups have
+// to be dropped first, while the music box still has the permission to.
+fn run_as(command: &mut Command, user: u32, group: u32) {
+    unsafe {
+        command.pre_exec(move || {
+            check(libc::setgroups(0, ptr::null()))?;
+            check(libc::setgid(group))?;
+            check(libc::setuid(user))
+        });
+    }
+}
+
+// Blocks until the command exits, killing it if it runs for too long.
+fn run_command(mut command: Command, timeout: Duration) -> VoidResult {
+    let mut child = command.spawn().prefix("Unable to run hook")?;
+    let deadline = Instant::now() + timeout;
+
+    loop {
+        if let Some(status) = child.try_wait().prefix("Unable to wait for hook")? {
+            return if status.success() {
+                Ok(())
+            } else {
+                Err(format!("Hook failed with {}.", status))
+            };
+        }
+
+        if Instant::now() >= deadline {
+            child.kill().drop();
+            child.wait().drop();
+            return Err(format!("Hook took longer than {}s.", timeout.as_secs()));
+        }
+
+        thread::sleep(Duration::from_millis(POLL_INTERVAL));
+    }
+}
+
+// Runs external commands when things happen, for integrations the music box
+// doesn't support itself.
+pub struct Hooks {
+    config: HooksConfig,
+    // The user and group that commands run as.
+    ids: Option<(u32, u32)>,
+    state: AppState,
+    device: DeviceInfo,
+    // The stored playlist that was last seen playing.
+    playlist: Option<String>,
+}
+
+impl Hooks {
+    pub fn new(config: HooksConfig, state: AppState, device: DeviceInfo) -> MusicResult<Hooks> {
+        let ids = match (config.user, config.group) {
+            (Some(user), Some(group)) => Some((user, group)),
+            (Some(user), None) => Some((user, primary_group(user)?)),
+            (None, Some(_)) => return Err(String::from("Hooks need a user to run as a group.")),
+            (None, None) => None,
+        };
+
+        Ok(Hooks {
+            config,
+            ids,
+            state,
+            device,
+            playlist: None,
+        })
+    }
+
+    fn playing_playlist(snapshot: &StateSnapshot) -> Option<String> {
+        snapshot
+            .stored_playlists
+            .values()
+            .find(|playlist| playlist.tracks == snapshot.playlist)
+            .map(|playlist| playlist.name.clone())
+    }
+
+    fn commands(&self, event: HookEvent, values: &HookValues) -> Vec<(Command, Duration)> {
+        self.config
+            .hooks
+            .iter()
+            .filter(|hook| hook.event == event)
+            .filter_map(|hook| {
+                let args: Vec<String> = hook
+                    .command
+                    .iter()
+                    .map(|arg| values.fill(event, arg))
+                    .collect();
+                let (program, args) = args.split_first()?;
+
+                let mut command = Command::new(program);
+                command.args(args);
+                if let Some((user, group)) = self.ids {
+                    run_as(&mut command, user, group);
+                }
+
+                Some((command, Duration::from_secs(hook.timeout)))
+            })
+            .collect()
+    }
+}
+
+impl EventSink for Hooks {
+    fn name(&self) -> &str {
+        "hooks"
+    }
+
+    fn accepts(&self, event: &Event) -> bool {
+        matches!(event, Event::PlaybackStarted | Event::Shutdown)
+    }
+
+    fn handle<'a>(&'a mut self, event: &'a Message<Event>) -> BoxFuture<'a, VoidResult> {
+        let snapshot = self.state.snapshot();
+        let mut events = Vec::new();
+
+        if event.payload == Event::Shutdown {
+            events.push(HookEvent::Shutdown);
+        } else {
+            let playlist = Hooks::playing_playlist(&snapshot);
+            let restarted =
+                matches!(snapshot.play_state, Some(ref play_state) if play_state.position == 0);
+            if playlist.is_some() && (playlist != self.playlist || restarted) {
+                events.push(HookEvent::PlaylistStarted);
+            }
+            self.playlist = playlist;
+            events.push(HookEvent::TrackStarted);
+        }
+
+        let values = HookValues::new(&snapshot, self.playlist.as_deref(), &self.device);
+        let commands: Vec<(Command, Duration)> = events
+            .into_iter()
+            .flat_map(|event| self.commands(event, &values))
+            .collect();
+
+        async move {
+            for (command, timeout) in commands {
+                debug!("Running hook {:?}.", command);
+                let result = spawn_blocking(move || run_command(command, timeout))
+                    .await
+                    .as_err()
+                    .and_then(|result| result);
+                if let Err(e) = result {
+                    warn!("{}", e);
+                }
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+}
@@ -1,25 +1,173 @@
 use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 
+use ring::digest::{digest, SHA256};
 use serde::Deserialize;
-use serde_json::from_slice;
+use serde_json::{from_slice, from_value, Value};
 
 use crate::assets::Config;
 use crate::error::{ErrorExt, MusicResult};
+use crate::secrets;
+
+fn default_language() -> String {
+    String::from(crate::i18n::DEFAULT_LANGUAGE)
+}
+
+fn default_handler_budget() -> u64 {
+    250
+}
+
+fn default_previous_track_threshold() -> u64 {
+    2
+}
+
+fn default_favorites_playlist() -> String {
+    String::from("favorites")
+}
+
+fn default_key_sequence_timeout() -> u64 {
+    1000
+}
+
+fn default_power_off_command() -> Vec<String> {
+    vec![String::from("systemctl"), String::from("poweroff")]
+}
 
 #[derive(Debug, Clone, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct HwConfig {
+    // Shown to clients and on the network to tell music boxes apart.
+    pub name: Option<String>,
     pub server: SocketAddr,
+    // Stops the web API on the main server from accepting commands.
+    #[serde(default)]
+    pub read_only: bool,
+    // An additional server, normally on localhost, that always accepts
+    // commands.
+    pub control_server: Option<SocketAddr>,
+    // A directory of web app files that are served instead of the built in
+    // ones, allowing the web app to be updated without rebuilding.
+    pub webapp_dir: Option<PathBuf>,
+    // The language used for spoken and displayed strings.
+    #[serde(default = "default_language")]
+    pub language: String,
+    #[serde(default)]
+    pub audio: crate::audio::AudioConfig,
+    // What is kept of the log for the logs API.
+    #[serde(default)]
+    pub logging: crate::logs::LogConfig,
+    #[serde(default)]
+    pub analysis: crate::analysis::AnalysisConfig,
+    #[serde(default)]
+    pub announce: crate::network::AnnounceConfig,
+    #[serde(default)]
+    pub connectivity: crate::network::ConnectivityConfig,
+    #[serde(default)]
+    pub clock: crate::clock::ClockConfig,
+    // Other music boxes on the network that can play the same thing.
+    pub group: Option<crate::group::GroupConfig>,
+    // Brings up an access point with a setup page when there is no network.
+    pub provisioning: Option<crate::provisioning::ProvisioningConfig>,
+    #[serde(default)]
+    pub auth: crate::auth::AuthConfig,
+    #[serde(default)]
+    pub speech: crate::speech::SpeechConfig,
+    #[serde(default)]
+    pub cache: crate::storage::CacheConfig,
     pub keyboard: Vec<crate::hardware::keyboard::KeyConfig>,
+    // Milliseconds allowed between the keys of a keyboard sequence.
+    #[serde(default = "default_key_sequence_timeout")]
+    pub key_sequence_timeout: u64,
+    // A status screen that can replace the log when running in a terminal.
+    pub dashboard: Option<crate::dashboard::DashboardConfig>,
+    #[serde(default)]
+    pub keypad: crate::keypad::KeypadConfig,
+    // Profiles for each of the people sharing the music box.
+    #[serde(default)]
+    pub profiles: Vec<crate::profile::ProfileConfig>,
+    // Daily playback limits and quiet hours for guests.
+    pub guest: Option<crate::guest::GuestConfig>,
+    #[cfg(feature = "input")]
+    #[serde(default)]
+    pub input_devices: Vec<crate::hardware::input::InputDeviceConfig>,
+    // How buttons and LEDs are wired unless their own config says otherwise.
+    #[cfg(feature = "rpi")]
+    #[serde(default)]
+    pub gpio: crate::hardware::gpio::GpioDefaults,
     #[cfg(feature = "rpi")]
     pub buttons: Vec<crate::hardware::gpio::button::ButtonConfig>,
+    #[cfg(feature = "rpi")]
+    #[serde(default)]
+    pub encoders: Vec<crate::hardware::gpio::encoder::EncoderConfig>,
+    #[cfg(feature = "rpi")]
+    pub power_loss: Option<crate::hardware::gpio::power::PowerLossConfig>,
+    #[cfg(feature = "rpi")]
+    pub key_switch: Option<crate::hardware::gpio::key_switch::KeySwitchConfig>,
     pub playlists: Vec<crate::playlist::PlaylistConfig>,
+    // Recording messages from a microphone into one of the playlists.
+    pub messages: Option<crate::messages::MessagesConfig>,
+    // Playlists that play a random selection of tracks from other playlists.
+    #[serde(default)]
+    pub surprises: Vec<crate::surprise::SurpriseConfig>,
+    #[serde(default)]
+    pub end_of_playlist: crate::playlist::EndOfPlaylist,
+    // A playlist of the tracks from every other playlist that have been
+    // marked as favorites.
+    #[serde(default = "default_favorites_playlist")]
+    pub favorites_playlist: String,
+    // Seconds into a track after which PreviousTrack restarts it rather than
+    // going back to the track before.
+    #[serde(default = "default_previous_track_threshold")]
+    pub previous_track_threshold: u64,
+    // Started instead of a playlist that is missing or empty.
+    pub fallback_playlist: Option<String>,
+    // Where to find new releases.
+    pub update: Option<crate::update::UpdateConfig>,
+    // When playlists from storage backends are synced and updates are checked
+    // for. Storage is synced at the start of each window.
+    #[serde(default)]
+    pub sync_windows: crate::schedule::SyncWindows,
+    // External commands run when tracks or playlists start.
+    #[serde(default)]
+    pub hooks: crate::hooks::HooksConfig,
+    #[serde(default)]
+    pub crash_recovery: crate::resume::CrashRecoveryConfig,
+    // Restarts or skips a track whose playback silently stops moving.
+    #[serde(default)]
+    pub watchdog: crate::watchdog::WatchdogConfig,
+    // Flashes the LEDs, checks the buttons, plays a tone and connects to the
+    // web server at startup, reporting anything that fails.
+    #[serde(default)]
+    pub self_test: bool,
+    #[serde(default = "default_power_off_command")]
+    pub power_off_command: Vec<String>,
+    // Commands and events that take longer than this many milliseconds to
+    // handle, or wait longer than this to be handled, are logged.
+    #[serde(default = "default_handler_budget")]
+    pub handler_budget: u64,
+    // Logs how long each stage of handling every button press took.
+    #[serde(default)]
+    pub debug_latency: bool,
+    // A hash of the config file so differences between music boxes can be
+    // spotted.
+    #[serde(skip)]
+    pub hash: String,
 }
 
 impl HwConfig {
-    pub fn load() -> MusicResult<HwConfig> {
-        Config::get("hw_config.json")
-            .ok_or_else(|| String::from("Could not load hardware config."))
-            .and_then(|slice| from_slice(&slice).prefix("Failed to parse hardware config"))
+    // Any string in the config can be a reference to a secret in the data
+    // directory, written as {"secret": "name"}.
+    pub fn load(data_dir: &Path) -> MusicResult<HwConfig> {
+        let slice = Config::get("hw_config.json")
+            .ok_or_else(|| String::from("Could not load hardware config."))?;
+        let mut config: Value = from_slice(&slice).prefix("Failed to parse hardware config")?;
+        secrets::resolve(&mut config, data_dir)?;
+        let mut config: HwConfig = from_value(config).prefix("Failed to parse hardware config")?;
+        config.hash = digest(&SHA256, &slice)
+            .as_ref()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        Ok(config)
     }
 }
@@ -0,0 +1,148 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use tracing::warn;
+
+pub const DEFAULT_LANGUAGE: &str = "en";
+
+type Table = &'static [(&'static str, &'static str)];
+
+// The bundled translations, keyed by language code. The first is the fallback
+// for languages and strings that are missing.
+const TRANSLATIONS: &[(&str, Table)] = &[
+    (
+        "en",
+        &[
+            ("now-playing", "Now playing"),
+            ("error", "Music box error"),
+            ("not-authorized", "Not authorized."),
+            ("read-only", "The API is read only."),
+            ("text-not-utf8", "Text must be UTF-8."),
+            ("speech-failed", "Unable to synthesize speech."),
+            ("unknown-playlist", "Unknown playlist {name}."),
+            ("unknown-track", "Unknown track {id}."),
+            ("unknown-led", "Unknown LED {name}."),
+            ("unknown-command", "Unknown command."),
+            ("unplayable-file", "{path} is not in a playlist."),
+            ("group-relay", "Group commands can't be passed on again."),
+            ("not-queued", "Track {id} is not queued."),
+            (
+                "media-missing",
+                "Insert the card or USB stick for playlist {name}.",
+            ),
+            ("guest-warning", "The music will stop in {minutes} minutes."),
+            ("volume-level", "Volume {percent} percent."),
+            ("nothing-playing", "Nothing playing"),
+            ("paused", "paused"),
+        ],
+    ),
+    (
+        "de",
+        &[
+            ("now-playing", "Jetzt läuft"),
+            ("error", "Fehler in der Musikbox"),
+            ("not-authorized", "Nicht berechtigt."),
+            ("read-only", "Die API ist schreibgeschützt."),
+            ("text-not-utf8", "Der Text muss UTF-8 sein."),
+            ("speech-failed", "Die Sprachausgabe ist fehlgeschlagen."),
+            ("unknown-playlist", "Unbekannte Playlist {name}."),
+            ("unknown-track", "Unbekannter Titel {id}."),
+            ("unknown-led", "Unbekannte LED {name}."),
+            ("unknown-command", "Unbekannter Befehl."),
+            ("unplayable-file", "{path} ist in keiner Playlist."),
+            (
+                "group-relay",
+                "Gruppenbefehle können nicht weitergegeben werden.",
+            ),
+            ("not-queued", "Titel {id} ist nicht in der Warteschlange."),
+            (
+                "media-missing",
+                "Bitte die Karte oder den USB-Stick für die Playlist {name} einstecken.",
+            ),
+            ("guest-warning", "Die Musik stoppt in {minutes} Minuten."),
+            ("volume-level", "Lautstärke {percent} Prozent."),
+            ("nothing-playing", "Es läuft nichts"),
+            ("paused", "pausiert"),
+        ],
+    ),
+    (
+        "fr",
+        &[
+            ("now-playing", "En cours de lecture"),
+            ("error", "Erreur de la boîte à musique"),
+            ("not-authorized", "Non autorisé."),
+            ("read-only", "L'API est en lecture seule."),
+            ("text-not-utf8", "Le texte doit être en UTF-8."),
+            ("speech-failed", "La synthèse vocale a échoué."),
+            ("unknown-playlist", "Playlist inconnue {name}."),
+            ("unknown-track", "Morceau inconnu {id}."),
+            ("unknown-led", "LED inconnue {name}."),
+            ("unknown-command", "Commande inconnue."),
+            ("unplayable-file", "{path} n'est dans aucune playlist."),
+            (
+                "group-relay",
+                "Les commandes de groupe ne peuvent pas être retransmises.",
+            ),
+            (
+                "not-queued",
+                "Le morceau {id} n'est pas dans la file d'attente.",
+            ),
+            (
+                "media-missing",
+                "Insérez la carte ou la clé USB de la playlist {name}.",
+            ),
+            (
+                "guest-warning",
+                "La musique s'arrêtera dans {minutes} minutes.",
+            ),
+            ("volume-level", "Volume {percent} pour cent."),
+            ("nothing-playing", "Rien en cours de lecture"),
+            ("paused", "en pause"),
+        ],
+    ),
+];
+
+// Index into TRANSLATIONS of the selected language.
+static LANGUAGE: AtomicUsize = AtomicUsize::new(0);
+
+fn lookup(table: Table, key: &str) -> Option<&'static str> {
+    table
+        .iter()
+        .find(|(k, _)| *k == key)
+        .map(|(_, value)| *value)
+}
+
+// Selects the language used for all strings. Unknown languages fall back to
+// the default.
+pub fn set_language(language: &str) {
+    match TRANSLATIONS.iter().position(|(code, _)| *code == language) {
+        Some(index) => LANGUAGE.store(index, Ordering::SeqCst),
+        None => {
+            warn!(
+                "No translations for language {}, using {}.",
+                language, DEFAULT_LANGUAGE
+            );
+            LANGUAGE.store(0, Ordering::SeqCst);
+        }
+    }
+}
+
+pub fn language() -> &'static str {
+    TRANSLATIONS[LANGUAGE.load(Ordering::SeqCst)].0
+}
+
+// The translated string for a key. Missing keys return the key itself so the
+// mistake is visible.
+pub fn tr(key: &str) -> String {
+    let (_, table) = TRANSLATIONS[LANGUAGE.load(Ordering::SeqCst)];
+    lookup(table, key)
+        .or_else(|| lookup(TRANSLATIONS[0].1, key))
+        .unwrap_or(key)
+        .to_owned()
+}
+
+// Like `tr` but replaces "{name}" style placeholders with the given values.
+pub fn tr_with(key: &str, values: &[(&str, &str)]) -> String {
+    values.iter().fold(tr(key), |text, (name, value)| {
+        text.replace(&format!("{{{}}}", name), value)
+    })
+}
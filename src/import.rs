@@ -0,0 +1,156 @@
+use std::fs::{canonicalize, copy, create_dir_all, metadata, read_dir, remove_file};
+use std::path::{Path, PathBuf};
+
+use gstreamer::{init, parse_launch};
+use tokio::task::spawn_blocking;
+use tracing::{info, warn};
+
+use crate::error::{ErrorExt, MusicResult, VoidResult};
+use crate::library::Library;
+use crate::player::play_to_end;
+use crate::storage::is_track;
+
+// Playlists only play mp3 files so anything else has to be transcoded.
+const TRACK_EXTENSION: &str = "mp3";
+
+#[derive(Debug, Clone)]
+pub struct ImportOptions {
+    // A file or a directory of files to import.
+    pub source: PathBuf,
+    pub playlist: String,
+    // Converts files that playlists can't play instead of skipping them.
+    pub transcode: bool,
+    // In kbit/s.
+    pub bitrate: u32,
+}
+
+// Every file in the source, including those in subdirectories, in name order.
+fn source_files(source: &Path) -> MusicResult<Vec<PathBuf>> {
+    if !source.is_dir() {
+        return Ok(vec![source.to_owned()]);
+    }
+
+    let mut files = Vec::new();
+    let mut entries: Vec<PathBuf> = read_dir(source)
+        .prefix("Unable to read import directory")?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .collect();
+    entries.sort();
+
+    for path in entries {
+        if path.is_dir() {
+            files.extend(source_files(&path)?);
+        } else {
+            files.push(path);
+        }
+    }
+
+    Ok(files)
+}
+
+// Lowercases the name and replaces anything other than letters and numbers
+// with single dashes so names work on any filesystem and in URLs.
+fn normalize_name(name: &str) -> String {
+    let mut normalized = String::with_capacity(name.len());
+    for c in name.chars().flat_map(char::to_lowercase) {
+        if c.is_alphanumeric() {
+            normalized.push(c);
+        } else if !normalized.is_empty() && !normalized.ends_with('-') {
+            normalized.push('-');
+        }
+    }
+
+    let normalized = normalized.trim_end_matches('-');
+    if normalized.is_empty() {
+        String::from("track")
+    } else {
+        normalized.to_owned()
+    }
+}
+
+// A path in the playlist directory that doesn't already exist.
+fn target_path(dir: &Path, source: &Path) -> PathBuf {
+    let stem = source
+        .file_stem()
+        .map(|stem| normalize_name(&stem.to_string_lossy()))
+        .unwrap_or_else(|| String::from("track"));
+
+    let mut target = dir.join(format!("{}.{}", stem, TRACK_EXTENSION));
+    let mut count = 1;
+    while target.exists() {
+        count += 1;
+        target = dir.join(format!("{}-{}.{}", stem, count, TRACK_EXTENSION));
+    }
+    target
+}
+
+// Blocks until the file has been converted.
+fn transcode(source: &Path, target: &Path, bitrate: u32) -> VoidResult {
+    init().prefix("Unable to initialize gstreamer")?;
+
+    let pipeline = parse_launch(&format!(
+        "filesrc location=\"{}\" ! decodebin ! audioconvert ! audioresample ! lamemp3enc target=bitrate cbr=true bitrate={} ! id3v2mux ! filesink location=\"{}\"",
+        source.display(),
+        bitrate,
+        target.display()
+    ))
+    .prefix("Unable to create transcoding pipeline")?;
+
+    play_to_end(&pipeline, "transcoding")
+}
+
+// Copies tracks into a playlist's directory and adds them to the library so
+// the music box doesn't have to probe them when it next starts.
+pub async fn import_tracks(data_dir: &Path, options: &ImportOptions) -> VoidResult {
+    let dir = data_dir.join("playlists").join(&options.playlist);
+    create_dir_all(&dir).prefix("Unable to create playlist directory")?;
+
+    let mut library = Library::load(data_dir).await;
+    let (mut imported, mut skipped) = (0, 0);
+
+    for source in source_files(&options.source)? {
+        let supported = is_track(&source.to_string_lossy());
+        if !supported && !options.transcode {
+            warn!("Skipping {} as it is not an mp3 file.", source.display());
+            skipped += 1;
+            continue;
+        }
+
+        let target = target_path(&dir, &source);
+        let result = if supported {
+            copy(&source, &target)
+                .map(|_| ())
+                .prefix("Unable to copy track")
+        } else {
+            info!("Transcoding {}.", source.display());
+            let (from, to, bitrate) = (source.clone(), target.clone(), options.bitrate);
+            spawn_blocking(move || transcode(&from, &to, bitrate))
+                .await
+                .as_err()
+                .and_then(|result| result)
+        };
+
+        if let Err(e) = result {
+            warn!("Unable to import {}: {}", source.display(), e);
+            remove_file(&target).drop();
+            skipped += 1;
+            continue;
+        }
+
+        // Playlists look tracks up by their canonical path.
+        let path = canonicalize(&target).prefix("Unable to resolve imported track")?;
+        let file = metadata(&path).prefix("Unable to read imported track")?;
+        library.metadata(&path, &file).await;
+        info!("Imported {} as {}.", source.display(), target.display());
+        imported += 1;
+    }
+
+    library.save().await?;
+    info!(
+        "Imported {} tracks into the {} playlist, skipped {}.",
+        imported, options.playlist, skipped
+    );
+
+    Ok(())
+}
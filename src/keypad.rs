@@ -0,0 +1,112 @@
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+
+fn default_timeout() -> u64 {
+    3000
+}
+
+fn default_playlist_digits() -> usize {
+    1
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct KeypadConfig {
+    // The playlists selected by number, the first is number 1.
+    #[serde(default)]
+    pub playlists: Vec<String>,
+
+    // Milliseconds after the last key press that a partly entered number is
+    // forgotten.
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+
+    // Numbers with up to this many digits select a playlist, longer numbers
+    // select a track in the current playlist. A leading 0 always selects a
+    // track.
+    #[serde(default = "default_playlist_digits")]
+    pub playlist_digits: usize,
+}
+
+impl Default for KeypadConfig {
+    fn default() -> KeypadConfig {
+        KeypadConfig {
+            playlists: Vec::new(),
+            timeout: default_timeout(),
+            playlist_digits: default_playlist_digits(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum KeypadAction {
+    // A digit was added to the number.
+    Digit,
+    // The number was thrown away.
+    Cleared,
+    StartPlaylist(String),
+    // The position in the playlist, starting at 0.
+    PlayTrack(usize),
+    Invalid,
+}
+
+// Collects key presses into numbers. Digits are entered followed by "#" to
+// select, "*" clears the number.
+pub struct Keypad {
+    config: KeypadConfig,
+    digits: String,
+    last_press: Option<Instant>,
+}
+
+impl Keypad {
+    pub fn new(config: KeypadConfig) -> Keypad {
+        Keypad {
+            config,
+            digits: String::new(),
+            last_press: None,
+        }
+    }
+
+    pub fn press(&mut self, key: char, instant: Instant) -> KeypadAction {
+        if let Some(last) = self.last_press {
+            if instant.saturating_duration_since(last) > Duration::from_millis(self.config.timeout)
+            {
+                self.digits.clear();
+            }
+        }
+        self.last_press = Some(instant);
+
+        match key {
+            '0'..='9' => {
+                self.digits.push(key);
+                KeypadAction::Digit
+            }
+            '*' => {
+                self.digits.clear();
+                KeypadAction::Cleared
+            }
+            '#' => {
+                let digits = std::mem::take(&mut self.digits);
+                self.select(&digits)
+            }
+            _ => KeypadAction::Invalid,
+        }
+    }
+
+    fn select(&self, digits: &str) -> KeypadAction {
+        let number = match digits.parse::<usize>() {
+            Ok(number) if number > 0 => number,
+            _ => return KeypadAction::Invalid,
+        };
+
+        if digits.len() <= self.config.playlist_digits && !digits.starts_with('0') {
+            match self.config.playlists.get(number - 1) {
+                Some(name) => KeypadAction::StartPlaylist(name.clone()),
+                None => KeypadAction::Invalid,
+            }
+        } else {
+            KeypadAction::PlayTrack(number - 1)
+        }
+    }
+}
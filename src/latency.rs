@@ -0,0 +1,89 @@
+use std::collections::VecDeque;
+use std::time::Instant;
+
+use tracing::info;
+
+use crate::events::{Command, Message, Origin};
+use crate::metrics::Metrics;
+
+// How many recent presses the percentiles are taken from.
+const WINDOW: usize = 100;
+const PERCENTILES: [usize; 3] = [50, 90, 99];
+
+const STAGES: [&str; 4] = ["debounce", "dispatch", "action", "total"];
+
+fn millis(from: Instant, to: Instant) -> u64 {
+    to.saturating_duration_since(from).as_millis() as u64
+}
+
+fn percentile(sorted: &[u64], percent: usize) -> u64 {
+    if sorted.is_empty() {
+        return 0;
+    }
+    sorted[(sorted.len() - 1) * percent / 100]
+}
+
+// Measures the time from a button's interrupt to the player acting on the
+// command, in stages: debouncing the press, waiting to be handled and
+// handling. Percentiles of each stage over recent presses are kept in the
+// metrics.
+pub struct LatencyTracker {
+    metrics: Metrics,
+    // Logs the stages of every press.
+    debug: bool,
+    samples: Vec<VecDeque<u64>>,
+}
+
+impl LatencyTracker {
+    pub fn new(metrics: Metrics, debug: bool) -> LatencyTracker {
+        LatencyTracker {
+            metrics,
+            debug,
+            samples: STAGES
+                .iter()
+                .map(|_| VecDeque::with_capacity(WINDOW))
+                .collect(),
+        }
+    }
+
+    // Records a command that started being handled at dispatched and was
+    // acted on at acted. Only presses of buttons are measured.
+    pub fn record(&mut self, command: &Message<Command>, dispatched: Instant, acted: Instant) {
+        if !matches!(command.origin, Origin::Gpio { .. } | Origin::Input { .. }) {
+            return;
+        }
+
+        let pressed = command.instant;
+        let emitted = command.emitted.unwrap_or(dispatched);
+        let stages = [
+            millis(pressed, emitted),
+            millis(emitted, dispatched),
+            millis(dispatched, acted),
+            millis(pressed, acted),
+        ];
+
+        if self.debug {
+            info!(
+                "{:?} from {} took {}ms: debounce {}ms, dispatch {}ms, action {}ms.",
+                command.payload, command.origin, stages[3], stages[0], stages[1], stages[2]
+            );
+        }
+
+        for ((stage, samples), value) in STAGES.iter().zip(&mut self.samples).zip(&stages) {
+            if samples.len() >= WINDOW {
+                samples.pop_front();
+            }
+            samples.push_back(*value);
+
+            let mut sorted: Vec<u64> = samples.iter().cloned().collect();
+            sorted.sort_unstable();
+            for percent in PERCENTILES.iter() {
+                self.metrics.set(
+                    &format!("buttonLatency.{}.p{}Millis", stage, percent),
+                    percentile(&sorted, *percent),
+                );
+            }
+        }
+        self.metrics.increment("buttonLatency.presses");
+    }
+}
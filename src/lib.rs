@@ -1,14 +1,61 @@
+mod analysis;
 mod appstate;
 mod assets;
+mod audio;
+mod auth;
+mod backup;
+mod client;
+mod clock;
+mod dashboard;
+mod device;
 mod error;
 mod events;
+mod group;
+mod guest;
 mod hardware;
+mod hooks;
 mod hw_config;
+mod i18n;
+mod import;
+mod keypad;
+mod latency;
+mod library;
+mod logs;
+mod messages;
+mod metrics;
 mod musicbox;
+mod network;
+#[cfg(feature = "desktop")]
+mod notifications;
+mod nowplaying;
+mod oneshot;
+mod options;
+mod panic;
 mod player;
 mod playlist;
+mod plays;
+mod profile;
+mod provisioning;
+mod resume;
+mod schedule;
+mod secrets;
+mod selftest;
 mod server;
+mod signals;
+mod sinks;
+mod sounds;
+mod sources;
+mod speech;
+mod status;
+mod storage;
+mod surprise;
 mod term_logger;
 mod track;
+mod update;
+mod watchdog;
 
+pub use import::ImportOptions;
 pub use musicbox::MusicBox;
+pub use options::Options;
+pub use panic::install_hook;
+pub use plays::{ExportFormat, ExportOptions};
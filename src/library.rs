@@ -0,0 +1,346 @@
+use std::collections::HashMap;
+use std::fs::Metadata;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use gstreamer::tags::{Album, Artist, Title};
+use gstreamer::ClockTime;
+use gstreamer_pbutils::Discoverer;
+use musicbox_protocol::{Bookmark, LibraryStats, TrackInfo};
+use serde::{Deserialize, Serialize};
+use serde_json::{from_slice, to_vec};
+use tokio::fs::{read, write};
+use tokio::task::spawn_blocking;
+use tracing::{debug, warn};
+
+use crate::error::{ErrorExt, MusicResult, VoidResult};
+use crate::track::Track;
+
+const LIBRARY_FILE: &str = "library.json";
+const DISCOVER_TIMEOUT: u64 = 5;
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TrackMetadata {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub duration: Option<Duration>,
+}
+
+impl TrackMetadata {
+    fn probe(path: &Path) -> MusicResult<TrackMetadata> {
+        gstreamer::init().prefix("Unable to initialize gstreamer")?;
+
+        let discoverer = Discoverer::new(ClockTime::from_seconds(DISCOVER_TIMEOUT))
+            .prefix("Unable to create discoverer")?;
+        let info = discoverer
+            .discover_uri(&format!("file://{}", path.display()))
+            .prefix("Unable to probe track")?;
+
+        let mut metadata = TrackMetadata {
+            duration: info.get_duration().nseconds().map(Duration::from_nanos),
+            ..Default::default()
+        };
+
+        if let Some(tags) = info.get_tags() {
+            metadata.title = tags.get::<Title>().and_then(|v| v.get().map(str::to_owned));
+            metadata.artist = tags
+                .get::<Artist>()
+                .and_then(|v| v.get().map(str::to_owned));
+            metadata.album = tags.get::<Album>().and_then(|v| v.get().map(str::to_owned));
+        }
+
+        Ok(metadata)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CacheEntry {
+    modified: SystemTime,
+    size: u64,
+    metadata: TrackMetadata,
+    #[serde(default)]
+    favorite: bool,
+    // Blocked tracks are left out of playlists without deleting the file.
+    #[serde(default)]
+    blocked: bool,
+    // When the track was first probed. Entries from before this was recorded
+    // use the file's modification time instead.
+    #[serde(default)]
+    added: Option<SystemTime>,
+    // Why the file couldn't be probed, kept so it is reported until the file
+    // changes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    // Named positions in the track, such as the chapters of an audiobook,
+    // in order.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    bookmarks: Vec<Bookmark>,
+}
+
+fn same_text(expected: &Option<String>, actual: &Option<String>) -> bool {
+    match (expected, actual) {
+        (Some(expected), Some(actual)) => expected.to_lowercase() == actual.to_lowercase(),
+        (Some(_), None) => false,
+        (None, _) => true,
+    }
+}
+
+// Picks tracks from the library by what is known about them. Every condition
+// that is given has to match.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TrackQuery {
+    // Artist and album are matched ignoring case.
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    // Tracks added within this many days.
+    pub added_within: Option<u64>,
+    // Only tracks marked as favorites.
+    #[serde(default)]
+    pub favorites: bool,
+}
+
+impl TrackQuery {
+    fn matches(&self, entry: &CacheEntry, now: SystemTime) -> bool {
+        if self.favorites && !entry.favorite {
+            return false;
+        }
+
+        if let Some(days) = self.added_within {
+            let added = entry.added.unwrap_or(entry.modified);
+            match now.duration_since(added) {
+                Ok(age) if age.as_secs() > days * SECONDS_PER_DAY => return false,
+                _ => {}
+            }
+        }
+
+        same_text(&self.artist, &entry.metadata.artist)
+            && same_text(&self.album, &entry.metadata.album)
+    }
+}
+
+// Caches the results of probing track files. Entries are keyed by path and
+// are only considered valid while the file's modification time and size are
+// unchanged.
+pub struct Library {
+    file: PathBuf,
+    entries: HashMap<PathBuf, CacheEntry>,
+    dirty: bool,
+}
+
+impl Library {
+    pub async fn load(data_dir: &Path) -> Library {
+        let file = data_dir.join(LIBRARY_FILE);
+
+        let entries = match read(&file).await {
+            Ok(data) => match from_slice(&data) {
+                Ok(entries) => entries,
+                Err(e) => {
+                    warn!("Discarding unreadable library cache: {}", e);
+                    HashMap::new()
+                }
+            },
+            Err(_) => HashMap::new(),
+        };
+
+        Library {
+            file,
+            entries,
+            dirty: false,
+        }
+    }
+
+    pub async fn metadata(&mut self, path: &Path, file: &Metadata) -> TrackMetadata {
+        let modified = file.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+        let size = file.len();
+
+        let (mut favorite, mut blocked, mut added) = (false, false, SystemTime::now());
+        let mut bookmarks = Vec::new();
+        if let Some(entry) = self.entries.get(path) {
+            if entry.modified == modified && entry.size == size {
+                return entry.metadata.clone();
+            }
+            // Retagging a track shouldn't lose what is known about it.
+            favorite = entry.favorite;
+            blocked = entry.blocked;
+            added = entry.added.unwrap_or(entry.modified);
+            bookmarks = entry.bookmarks.clone();
+        }
+
+        debug!("Probing {}.", path.display());
+        let target = path.to_owned();
+        let (metadata, error) = match spawn_blocking(move || TrackMetadata::probe(&target)).await {
+            Ok(Ok(metadata)) => (metadata, None),
+            Ok(Err(e)) => {
+                warn!("Failed to probe {}: {}", path.display(), e);
+                (Default::default(), Some(e))
+            }
+            Err(e) => {
+                warn!("Failed to probe {}: {}", path.display(), e);
+                (Default::default(), Some(e.to_string()))
+            }
+        };
+
+        self.entries.insert(
+            path.to_owned(),
+            CacheEntry {
+                modified,
+                size,
+                metadata: metadata.clone(),
+                favorite,
+                blocked,
+                added: Some(added),
+                error,
+                bookmarks,
+            },
+        );
+        self.dirty = true;
+
+        metadata
+    }
+
+    // Why the file couldn't be probed when it was last looked at.
+    pub fn probe_error(&self, path: &Path) -> Option<String> {
+        self.entries.get(path)?.error.clone()
+    }
+
+    pub fn is_favorite(&self, path: &Path) -> bool {
+        matches!(self.entries.get(path), Some(entry) if entry.favorite)
+    }
+
+    // Returns false for tracks that haven't been probed.
+    pub fn set_favorite(&mut self, path: &Path, favorite: bool) -> bool {
+        match self.entries.get_mut(path) {
+            Some(entry) => {
+                if entry.favorite != favorite {
+                    entry.favorite = favorite;
+                    self.dirty = true;
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn bookmarks(&self, path: &Path) -> Vec<Bookmark> {
+        self.entries
+            .get(path)
+            .map(|entry| entry.bookmarks.clone())
+            .unwrap_or_default()
+    }
+
+    // Replaces any bookmark with the same name. Unnamed bookmarks are
+    // numbered. Returns the name used or None for tracks that haven't been
+    // probed.
+    pub fn set_bookmark(
+        &mut self,
+        path: &Path,
+        name: Option<String>,
+        position: Duration,
+    ) -> Option<String> {
+        let entry = self.entries.get_mut(path)?;
+        let name = name.unwrap_or_else(|| format!("Bookmark {}", entry.bookmarks.len() + 1));
+
+        entry.bookmarks.retain(|bookmark| bookmark.name != name);
+        entry.bookmarks.push(Bookmark {
+            name: name.clone(),
+            position,
+        });
+        entry.bookmarks.sort_by_key(|bookmark| bookmark.position);
+        self.dirty = true;
+
+        Some(name)
+    }
+
+    pub fn is_blocked(&self, path: &Path) -> bool {
+        matches!(self.entries.get(path), Some(entry) if entry.blocked)
+    }
+
+    // Returns false for tracks that haven't been probed.
+    pub fn set_blocked(&mut self, path: &Path, blocked: bool) -> bool {
+        match self.entries.get_mut(path) {
+            Some(entry) => {
+                if entry.blocked != blocked {
+                    entry.blocked = blocked;
+                    self.dirty = true;
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Unblocks a track by its id. Returns false if no blocked track has the
+    // id.
+    pub fn unblock(&mut self, id: &str) -> bool {
+        let path = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.blocked)
+            .map(|(path, entry)| Track::new(path, entry.metadata.clone()))
+            .find(|track| track.id() == id)
+            .map(|track| track.path());
+
+        match path {
+            Some(path) => self.set_blocked(&path, false),
+            None => false,
+        }
+    }
+
+    pub fn stats(&self) -> LibraryStats {
+        LibraryStats {
+            tracks: self.entries.len(),
+            favorites: self.entries.values().filter(|entry| entry.favorite).count(),
+            blocked: self.entries.values().filter(|entry| entry.blocked).count(),
+            unreadable: self
+                .entries
+                .values()
+                .filter(|entry| entry.error.is_some())
+                .count(),
+            ..Default::default()
+        }
+    }
+
+    pub fn blocked(&self) -> Vec<TrackInfo> {
+        self.entries
+            .iter()
+            .filter(|(_, entry)| entry.blocked)
+            .map(|(path, entry)| Track::new(path, entry.metadata.clone()).info())
+            .collect()
+    }
+
+    // The tracks that match the query, in path order. Blocked tracks and
+    // files that have gone are left out.
+    pub fn query(&self, query: &TrackQuery) -> Vec<Track> {
+        let now = SystemTime::now();
+        let mut tracks: Vec<Track> = self
+            .entries
+            .iter()
+            .filter(|(path, entry)| !entry.blocked && query.matches(entry, now) && path.exists())
+            .map(|(path, entry)| Track::new(path, entry.metadata.clone()))
+            .collect();
+        tracks.sort_by_key(Track::path);
+        tracks
+    }
+
+    pub async fn save(&mut self) -> VoidResult {
+        if !self.dirty {
+            return Ok(());
+        }
+
+        // Drop entries for files that no longer exist.
+        self.entries.retain(|path, _| path.exists());
+
+        let data = to_vec(&self.entries).prefix("Unable to serialize library cache")?;
+        write(&self.file, data)
+            .await
+            .prefix("Unable to write library cache")?;
+        self.dirty = false;
+
+        Ok(())
+    }
+}
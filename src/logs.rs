@@ -0,0 +1,320 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::fs::{create_dir_all, metadata, read_dir, rename, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use lazy_static::lazy_static;
+use log::Level;
+use serde::{Deserialize, Serialize};
+use serde_json::to_string;
+use time::OffsetDateTime;
+
+use crate::error::{ErrorExt, MusicResult, VoidResult};
+
+const LOG_DIR: &str = "logs";
+const LOG_FILE: &str = "musicbox.log";
+
+fn default_buffer() -> usize {
+    500
+}
+
+fn default_level() -> Level {
+    Level::Info
+}
+
+fn default_max_size() -> u64 {
+    1024
+}
+
+fn default_count() -> usize {
+    5
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum LogFormat {
+    // Readable lines like the terminal shows.
+    Text,
+    // One JSON object per line, for log collectors.
+    Json,
+}
+
+impl Default for LogFormat {
+    fn default() -> LogFormat {
+        LogFormat::Text
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LogFilesConfig {
+    // In kilobytes. A file this big is rotated out for a new one.
+    #[serde(default = "default_max_size")]
+    pub max_size: u64,
+
+    // The number of old files to keep.
+    #[serde(default = "default_count")]
+    pub count: usize,
+
+    #[serde(default)]
+    pub format: LogFormat,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LogConfig {
+    // The number of recent records kept in memory.
+    #[serde(default = "default_buffer")]
+    pub buffer: usize,
+
+    // The least severe records that are kept.
+    #[serde(default = "default_level")]
+    pub level: Level,
+
+    // Also writes records to files in the data directory.
+    pub files: Option<LogFilesConfig>,
+}
+
+impl Default for LogConfig {
+    fn default() -> LogConfig {
+        LogConfig {
+            buffer: default_buffer(),
+            level: default_level(),
+            files: None,
+        }
+    }
+}
+
+// A span that a record was logged in, such as the command being handled.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LogSpan {
+    pub name: String,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub fields: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LogRecord {
+    // Milliseconds since the unix epoch.
+    pub timestamp: u64,
+    pub level: Level,
+    pub target: String,
+    // The spans the record was logged in.
+    pub context: String,
+    pub message: String,
+    // The fields included in the message, by name.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub fields: BTreeMap<String, String>,
+    // The same spans as the context, outermost first.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub spans: Vec<LogSpan>,
+}
+
+impl LogRecord {
+    fn text(&self) -> String {
+        let time = OffsetDateTime::from_unix_timestamp((self.timestamp / 1000) as i64);
+        format!(
+            "[{} UTC {:5} {}] {}{}",
+            time.format("%F %T"),
+            self.level,
+            self.target,
+            self.context,
+            self.message
+        )
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LogFileInfo {
+    pub name: String,
+    pub size: u64,
+}
+
+struct LogFile {
+    dir: PathBuf,
+    config: LogFilesConfig,
+    file: File,
+    size: u64,
+}
+
+impl LogFile {
+    fn open(dir: &Path, config: &LogFilesConfig) -> MusicResult<LogFile> {
+        create_dir_all(dir).prefix("Unable to create log directory")?;
+        let path = dir.join(LOG_FILE);
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .prefix("Unable to open log file")?;
+
+        Ok(LogFile {
+            dir: dir.to_owned(),
+            config: config.clone(),
+            size: metadata(&path).map(|m| m.len()).unwrap_or_default(),
+            file,
+        })
+    }
+
+    // Moves each file along one place and starts a new one.
+    fn rotate(&mut self) -> VoidResult {
+        let name = |index: usize| match index {
+            0 => self.dir.join(LOG_FILE),
+            _ => self.dir.join(format!("{}.{}", LOG_FILE, index)),
+        };
+
+        for index in (0..self.config.count).rev() {
+            let from = name(index);
+            if from.exists() {
+                rename(&from, name(index + 1)).prefix("Unable to rotate log file")?;
+            }
+        }
+
+        *self = LogFile::open(&self.dir, &self.config)?;
+        Ok(())
+    }
+
+    fn write(&mut self, record: &LogRecord) -> VoidResult {
+        let mut line = match self.config.format {
+            LogFormat::Text => record.text(),
+            LogFormat::Json => to_string(record).prefix("Unable to serialize log record")?,
+        };
+        line.push('\n');
+        self.file
+            .write_all(line.as_bytes())
+            .prefix("Unable to write log file")?;
+        self.size += line.len() as u64;
+
+        if self.size >= self.config.max_size * 1024 {
+            self.rotate()?;
+        }
+        Ok(())
+    }
+}
+
+struct Logs {
+    config: LogConfig,
+    records: VecDeque<LogRecord>,
+    file: Option<LogFile>,
+}
+
+lazy_static! {
+    // Recent log records, for looking at what a music box has been doing
+    // without access to its terminal.
+    static ref LOGS: Mutex<Logs> = Mutex::new(Logs {
+        config: Default::default(),
+        records: VecDeque::new(),
+        file: None,
+    });
+}
+
+fn log_dir(data_dir: &Path) -> PathBuf {
+    data_dir.join(LOG_DIR)
+}
+
+// Applies the configuration once it has been loaded. Records from before
+// this are kept in memory.
+pub fn configure(config: &LogConfig, data_dir: &Path) -> VoidResult {
+    let mut logs = LOGS.lock().unwrap();
+    logs.config = config.clone();
+    while logs.records.len() > config.buffer {
+        logs.records.pop_front();
+    }
+
+    logs.file = match config.files {
+        Some(ref files) => {
+            let mut file = LogFile::open(&log_dir(data_dir), files)?;
+            for record in logs.records.iter() {
+                file.write(record)?;
+            }
+            Some(file)
+        }
+        None => None,
+    };
+
+    Ok(())
+}
+
+pub fn record(
+    level: Level,
+    target: &str,
+    context: &str,
+    message: &str,
+    fields: BTreeMap<String, String>,
+    spans: Vec<LogSpan>,
+) {
+    let mut logs = LOGS.lock().unwrap();
+    if level > logs.config.level {
+        return;
+    }
+
+    let record = LogRecord {
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_millis() as u64)
+            .unwrap_or_default(),
+        level,
+        target: target.to_owned(),
+        context: context.to_owned(),
+        message: message.to_owned(),
+        fields,
+        spans,
+    };
+
+    // Failing to write the log can't be logged.
+    if let Some(ref mut file) = logs.file {
+        if file.write(&record).is_err() {
+            logs.file = None;
+        }
+    }
+
+    if logs.config.buffer == 0 {
+        return;
+    }
+    if logs.records.len() >= logs.config.buffer {
+        logs.records.pop_front();
+    }
+    logs.records.push_back(record);
+}
+
+// The records in memory that are at least as severe as the level.
+pub fn recent(level: Level) -> Vec<LogRecord> {
+    LOGS.lock()
+        .unwrap()
+        .records
+        .iter()
+        .filter(|record| record.level <= level)
+        .cloned()
+        .collect()
+}
+
+pub fn files(data_dir: &Path) -> Vec<LogFileInfo> {
+    let mut files: Vec<LogFileInfo> = match read_dir(log_dir(data_dir)) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_name().to_string_lossy().starts_with(LOG_FILE))
+            .filter_map(|entry| {
+                let metadata = entry.metadata().ok()?;
+                Some(LogFileInfo {
+                    name: entry.file_name().to_string_lossy().to_string(),
+                    size: metadata.len(),
+                })
+            })
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    files.sort_by(|a, b| a.name.cmp(&b.name));
+    files
+}
+
+// The path of one of the log files, only if it is one of the files listed.
+pub fn file(data_dir: &Path, name: &str) -> Option<PathBuf> {
+    if files(data_dir).iter().any(|file| file.name == name) {
+        Some(log_dir(data_dir).join(name))
+    } else {
+        None
+    }
+}
@@ -1,12 +1,14 @@
 use std::env::current_dir;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process::exit;
 
 use clap::{load_yaml, App};
 
-use musicbox::MusicBox;
+use musicbox::{install_hook, ExportFormat, ExportOptions, ImportOptions, MusicBox, Options};
 
 fn main() {
+    install_hook();
+
     let yaml = load_yaml!("cli.yml");
     let matches = App::from_yaml(yaml).get_matches();
 
@@ -43,10 +45,64 @@ fn main() {
         },
     };
 
-    let result = if matches.is_present("daemonize") {
-        MusicBox::daemonize(&data_dir)
+    let options = Options {
+        data_dir,
+        record_gpio: matches.value_of("record-gpio").map(PathBuf::from),
+        replay_gpio: matches.value_of("replay-gpio").map(PathBuf::from),
+        dry_run: matches.is_present("dry-run"),
+    };
+
+    let result = if matches.subcommand_matches("doctor").is_some() {
+        MusicBox::doctor()
+    } else if let Some(backup) = matches.subcommand_matches("backup") {
+        MusicBox::backup(&options, Path::new(backup.value_of("file").unwrap()))
+    } else if let Some(export) = matches.subcommand_matches("export-history") {
+        let format = match ExportFormat::parse(export.value_of("format").unwrap()) {
+            Ok(format) => format,
+            Err(e) => {
+                println!("{}", e);
+                exit(1);
+            }
+        };
+
+        MusicBox::export_history(
+            &options,
+            &ExportOptions {
+                from: export.value_of("from").map(String::from),
+                to: export.value_of("to").map(String::from),
+                format,
+                stats: export.is_present("stats"),
+            },
+            export.value_of("file").map(Path::new),
+        )
+    } else if let Some(import) = matches.subcommand_matches("import") {
+        let bitrate = match import.value_of("bitrate").unwrap().parse() {
+            Ok(bitrate) => bitrate,
+            Err(e) => {
+                println!("Invalid bitrate: {}", e);
+                exit(1);
+            }
+        };
+
+        MusicBox::import(
+            &options,
+            &ImportOptions {
+                source: PathBuf::from(import.value_of("source").unwrap()),
+                playlist: import.value_of("playlist").unwrap().to_owned(),
+                transcode: import.is_present("transcode"),
+                bitrate,
+            },
+        )
+    } else if let Some(play) = matches.subcommand_matches("play-dir") {
+        MusicBox::play_dir(Path::new(play.value_of("dir").unwrap()))
+    } else if let Some(rescan) = matches.subcommand_matches("rescan") {
+        MusicBox::rescan(&options, rescan.value_of("playlist").unwrap())
+    } else if let Some(restore) = matches.subcommand_matches("restore") {
+        MusicBox::restore(&options, Path::new(restore.value_of("file").unwrap()))
+    } else if matches.is_present("daemonize") {
+        MusicBox::daemonize(&options)
     } else {
-        MusicBox::block(&data_dir)
+        MusicBox::block(&options)
     };
 
     if let Err(e) = result {
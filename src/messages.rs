@@ -0,0 +1,211 @@
+use std::fs::{create_dir_all, remove_file};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use glib::object::{Cast, ObjectExt};
+use glib::value::Value;
+use gstreamer::message::MessageView;
+use gstreamer::{
+    parse_launch, Bin, ClockTime, Element, ElementExt, Event as GstEvent, GstBinExt, MessageType,
+    State,
+};
+use serde::Deserialize;
+use time::OffsetDateTime;
+use tracing::info;
+
+use crate::error::{ErrorExt, MusicResult, VoidResult};
+#[cfg(feature = "rpi")]
+use crate::hardware::gpio::is_available;
+#[cfg(feature = "rpi")]
+use crate::hardware::gpio::led::{LEDConfig, LED};
+
+// How long to wait for the end of a recording to be written.
+const FINISH_TIMEOUT: u64 = 5;
+
+fn default_max_length() -> u64 {
+    60
+}
+
+fn default_min_length() -> u64 {
+    1000
+}
+
+// Records messages from a microphone into one of the stored playlists.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MessagesConfig {
+    pub playlist: String,
+    // A gstreamer source for the microphone, the system default is used when
+    // this is omitted.
+    pub source: Option<String>,
+    // Recording stops after this many seconds.
+    #[serde(default = "default_max_length")]
+    pub max_length: u64,
+    // Shorter recordings, in milliseconds, are thrown away as accidental
+    // presses.
+    #[serde(default = "default_min_length")]
+    pub min_length: u64,
+    // Lit while recording.
+    #[cfg(feature = "rpi")]
+    pub led: Option<LEDConfig>,
+}
+
+struct Recording {
+    pipeline: Element,
+    file: PathBuf,
+    started: Instant,
+}
+
+pub struct MessageRecorder {
+    playlist: String,
+    dir: PathBuf,
+    source: String,
+    max_length: Duration,
+    min_length: Duration,
+    recording: Option<Recording>,
+    #[cfg(feature = "rpi")]
+    led: Option<LED>,
+}
+
+impl MessageRecorder {
+    pub fn new(data_dir: &Path, config: &MessagesConfig) -> MusicResult<MessageRecorder> {
+        Ok(MessageRecorder {
+            playlist: config.playlist.clone(),
+            dir: data_dir.join("playlists").join(&config.playlist),
+            source: config
+                .source
+                .clone()
+                .unwrap_or_else(|| String::from("autoaudiosrc")),
+            max_length: Duration::from_secs(config.max_length),
+            min_length: Duration::from_millis(config.min_length),
+            recording: None,
+            #[cfg(feature = "rpi")]
+            led: match config.led {
+                Some(ref led) if is_available() => Some(LED::new(led)?),
+                _ => None,
+            },
+        })
+    }
+
+    pub fn playlist(&self) -> &str {
+        &self.playlist
+    }
+
+    pub fn max_length(&self) -> Duration {
+        self.max_length
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recording.is_some()
+    }
+
+    #[cfg(feature = "rpi")]
+    fn show_recording(&self, recording: bool) {
+        if let Some(ref led) = self.led {
+            if recording {
+                led.on();
+            } else {
+                led.off();
+            }
+        }
+    }
+
+    #[cfg(not(feature = "rpi"))]
+    fn show_recording(&self, _recording: bool) {}
+
+    pub fn start(&mut self) -> VoidResult {
+        if self.recording.is_some() {
+            return Ok(());
+        }
+
+        create_dir_all(&self.dir).prefix("Unable to create the messages playlist")?;
+        let now = OffsetDateTime::now_local();
+        let file = self
+            .dir
+            .join(format!("message-{}.mp3", now.format("%Y%m%d-%H%M%S")));
+
+        let pipeline = parse_launch(&format!(
+            "{} ! audioconvert ! audioresample ! taginject name=tags ! lamemp3enc ! id3v2mux ! filesink name=sink",
+            self.source
+        ))
+        .prefix("Unable to create recording pipeline")?;
+        let bin = pipeline
+            .clone()
+            .dynamic_cast::<Bin>()
+            .map_err(|_| String::from("Recording pipeline is not a bin."))?;
+        bin.get_by_name("tags")
+            .ok_or_else(|| String::from("Unable to get recording tags."))?
+            .set_property(
+                "tags",
+                &Value::from(&format!(
+                    "title=\"Message {}\"",
+                    now.format("%Y-%m-%d %H:%M")
+                )),
+            )
+            .prefix("Unable to set recording tags")?;
+        bin.get_by_name("sink")
+            .ok_or_else(|| String::from("Unable to get recording sink."))?
+            .set_property("location", &Value::from(&file.display().to_string()))
+            .prefix("Unable to set recording file")?;
+
+        pipeline
+            .set_state(State::Playing)
+            .prefix("Unable to start recording")?;
+        info!("Recording a message to {}.", file.display());
+
+        self.recording = Some(Recording {
+            pipeline,
+            file,
+            started: Instant::now(),
+        });
+        self.show_recording(true);
+        Ok(())
+    }
+
+    // Finishes the recording, returning the file unless it was too short to
+    // keep. This blocks until the file is written.
+    pub fn stop(&mut self) -> MusicResult<Option<PathBuf>> {
+        let recording = match self.recording.take() {
+            Some(recording) => recording,
+            None => return Ok(None),
+        };
+        self.show_recording(false);
+
+        // The end of the stream has to reach the file for it to be complete.
+        let length = recording.started.elapsed();
+        recording.pipeline.send_event(GstEvent::new_eos().build());
+        let result = match recording.pipeline.get_bus() {
+            Some(bus) => match bus.timed_pop_filtered(
+                ClockTime::from_seconds(FINISH_TIMEOUT),
+                &[MessageType::Eos, MessageType::Error],
+            ) {
+                Some(message) => match message.view() {
+                    MessageView::Error(e) => Err(format!("Recording failed: {}", e.get_error())),
+                    _ => Ok(()),
+                },
+                None => Err(String::from("Recording did not finish in time.")),
+            },
+            None => Err(String::from("Unable to get recording bus.")),
+        };
+        recording
+            .pipeline
+            .set_state(State::Null)
+            .prefix("Unable to stop recording")
+            .log()
+            .drop();
+
+        if result.is_err() || length < self.min_length {
+            remove_file(&recording.file)
+                .prefix("Unable to remove recording")
+                .log()
+                .drop();
+            return result.map(|()| {
+                info!("Discarded a message that was too short.");
+                None
+            });
+        }
+
+        info!("Recorded a {} second message.", length.as_secs());
+        Ok(Some(recording.file))
+    }
+}
@@ -0,0 +1,42 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+// Named counters describing how the music box is behaving, served by the API
+// for diagnosing problems.
+#[derive(Clone, Default)]
+pub struct Metrics {
+    counters: Arc<Mutex<BTreeMap<String, u64>>>,
+}
+
+impl Metrics {
+    pub fn add(&self, name: &str, value: u64) {
+        *self
+            .counters
+            .lock()
+            .unwrap()
+            .entry(name.to_owned())
+            .or_default() += value;
+    }
+
+    pub fn increment(&self, name: &str) {
+        self.add(name, 1);
+    }
+
+    // Keeps the largest value seen.
+    pub fn maximum(&self, name: &str, value: u64) {
+        let mut counters = self.counters.lock().unwrap();
+        let counter = counters.entry(name.to_owned()).or_default();
+        if value > *counter {
+            *counter = value;
+        }
+    }
+
+    // Replaces the value, for measurements rather than counts.
+    pub fn set(&self, name: &str, value: u64) {
+        self.counters.lock().unwrap().insert(name.to_owned(), value);
+    }
+
+    pub fn snapshot(&self) -> BTreeMap<String, u64> {
+        self.counters.lock().unwrap().clone()
+    }
+}
@@ -1,38 +1,233 @@
-use std::path::Path;
+use std::env::current_exe;
+use std::fmt::Debug;
+use std::fs::{remove_file, write};
+use std::mem::take;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::process::id;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
 
 use crossterm::terminal::{disable_raw_mode, enable_raw_mode};
 use daemonize::{Daemonize, DaemonizeError};
-use futures::compat::*;
-use futures::future::{ready, TryFutureExt};
+use futures::future::{abortable, ready, AbortHandle, Future, TryFutureExt};
 use futures::select;
 use futures::stream::{Stream, StreamExt};
-use log::{error, info, trace};
-use signal_hook::iterator::Signals;
+use musicbox_protocol::{DeviceInfo, QueueChange};
 use tokio::net::TcpListener;
 use tokio::runtime::Runtime;
+use tokio::sync::Mutex;
+use tokio::task::{spawn_blocking, JoinHandle};
+use tokio::time::{delay_for, timeout};
+use tracing::{debug_span, error, info, info_span, trace, warn, Instrument, Span};
 
+use crate::analysis::Analyzer;
 use crate::appstate::MutableAppState;
+use crate::audio::{AudioConfig, VolumeFeedback};
+use crate::auth::{is_authorized, AuthConfig};
+#[cfg(feature = "rpi")]
+use crate::auth::{Authorization, Authorizer};
+use crate::backup::{export, import, restore_staged};
+use crate::client::LocalClient;
+use crate::clock::{monitor_clock, Clock};
+use crate::dashboard::Dashboard;
+use crate::device;
 use crate::error::{ErrorExt, MusicResult, VoidResult};
-use crate::events::{Command, Event, Message, MessageReceiver, MessageSender};
+use crate::events::{Command, Event, Message, MessageReceiver, MessageSender, Origin};
+use crate::group::Group;
+use crate::guest::GuestMode;
 #[cfg(feature = "rpi")]
 use crate::hardware::gpio::button::Buttons;
+#[cfg(feature = "rpi")]
+use crate::hardware::gpio::encoder::Encoders;
+#[cfg(feature = "rpi")]
+use crate::hardware::gpio::key_switch::KeySwitch;
+#[cfg(feature = "rpi")]
+use crate::hardware::gpio::led::PlaylistLEDs;
+#[cfg(feature = "rpi")]
+use crate::hardware::gpio::power::PowerLoss;
+#[cfg(feature = "rpi")]
+use crate::hardware::gpio::usage::ButtonCounters;
+#[cfg(feature = "rpi")]
+use crate::hardware::gpio::{is_available, set_defaults as set_gpio_defaults};
+#[cfg(feature = "input")]
+use crate::hardware::input::InputDevices;
+#[cfg(not(feature = "rpi"))]
+use crate::hardware::is_raspberry_pi;
 use crate::hardware::keyboard::Keyboard;
+use crate::hooks::Hooks;
 use crate::hw_config::HwConfig;
-use crate::player::Player;
-use crate::playlist::StoredPlaylist;
+use crate::i18n::{set_language, tr_with};
+use crate::import::{import_tracks, ImportOptions};
+use crate::keypad::{Keypad, KeypadAction};
+use crate::latency::LatencyTracker;
+use crate::library::Library;
+use crate::logs::configure as configure_logs;
+use crate::messages::MessageRecorder;
+use crate::metrics::Metrics;
+use crate::network::{local_address, monitor_connectivity, Advertisement, AnnounceConfig};
+#[cfg(feature = "desktop")]
+use crate::notifications::DesktopNotifications;
+use crate::oneshot::play_dir;
+use crate::options::Options;
+use crate::panic::persist_on_panic;
+use crate::player::{beep_at, play_file, speaker_test, Player};
+use crate::playlist::{is_playable_file, monitor_media, EndOfPlaylist, StoredPlaylist};
+use crate::plays::{export as export_plays, ExportOptions, PlayLog};
+use crate::profile::Profiles;
+use crate::provisioning::Provisioning;
+use crate::resume::{mark_running, mark_stopped, CrashRecoveryConfig, ResumeState};
+use crate::schedule::{SyncWindows, TimeOfDay};
+use crate::selftest::SelfTest;
 use crate::server::{serve, ClientInfo};
+use crate::signals::Signals;
+use crate::sinks::{spawn_sink, EventSink};
+use crate::sounds::{Sound, Sounds};
+use crate::sources::CommandSource;
+use crate::speech::Speech;
+use crate::status::write_report;
+use crate::storage::TrackCache;
+#[cfg(feature = "rpi")]
+use crate::storage::CACHE_LED;
+use crate::surprise::SurpriseConfig;
 use crate::term_logger::TermLogger;
+use crate::track::Track;
+use crate::update::{check_boot, confirm_boot, pending_executable, restart, update, UpdateConfig};
+use crate::watchdog::{StallAction, Watchdog};
 
 const VOLUME_INTERVAL: f64 = 0.1;
+// The volume tone rises over two octaves from silent to full volume.
+const VOLUME_TONE_LOW: u32 = 440;
+const VOLUME_TONE_RANGE: u32 = 1320;
+const VOLUME_ANNOUNCEMENT_DELAY: Duration = Duration::from_millis(750);
+const DEFAULT_POWER_LOSS_GRACE: u64 = 2000;
+const UPDATE_CONFIRM_DELAY: Duration = Duration::from_secs(120);
+// Keeps a sync at the start of a window from being repeated straight away.
+const SYNC_REPEAT_DELAY: Duration = Duration::from_secs(60);
+// Events kept for web clients that reconnect. Position updates are coalesced
+// so this covers several minutes of playback.
+const EVENT_HISTORY: usize = 1000;
+// Blinked when a playlist can't be played, a zero shows as ten blinks.
+#[cfg(feature = "rpi")]
+const UNAVAILABLE_CODE: u8 = 0;
+#[cfg(feature = "rpi")]
+const IDENTIFY_DURATION: Duration = Duration::from_secs(5);
+
+enum QueueEdit {
+    Add(Box<Track>),
+    Move(String),
+    Remove(String),
+}
+
+// A task forwarding commands from a stream to the music box.
+struct Forwarder {
+    abort: AbortHandle,
+    task: JoinHandle<()>,
+}
+
+impl Forwarder {
+    fn cancel(&self) {
+        self.abort.abort();
+    }
+
+    // Cancels the task and waits for the stream to be dropped.
+    async fn stop(self) {
+        self.abort.abort();
+        self.task.await.drop();
+    }
+}
+
+struct RegisteredSource {
+    source: Box<dyn CommandSource>,
+    // Missing if the source failed to restart after a reload.
+    forwarder: Option<Forwarder>,
+}
 
 pub struct MusicBox {
     server: Option<TcpListener>,
+    control_server: Option<TcpListener>,
+    read_only: bool,
+    webapp_dir: Option<PathBuf>,
     events: MessageReceiver<Event>,
     commands: MessageReceiver<Command>,
     event_listeners: MessageSender<Event>,
     player: Player,
     state: MutableAppState,
+    library: Arc<Mutex<Library>>,
+    cache: TrackCache,
+    analyzer: Analyzer,
+    advertisement: Option<Advertisement>,
+    group: Option<Group>,
+    sources: Vec<RegisteredSource>,
+    forwarders: Vec<Forwarder>,
+    auth: AuthConfig,
+    speech: Speech,
+    data_dir: PathBuf,
+    resume: Option<ResumeState>,
+    pending_seek: Option<Duration>,
+    power_loss_grace: Duration,
+    audio: AudioConfig,
+    sounds: Sounds,
+    // The number of one-shot files currently playing.
+    interruptions: usize,
+    // Whether the playlist should continue once the interruptions end.
+    resume_after_interruption: bool,
+    // Whether the playlist volume was lowered for the interruptions.
+    ducked: bool,
+    default_end_of_playlist: EndOfPlaylist,
+    end_of_playlist: EndOfPlaylist,
+    fallback_playlist: Option<String>,
+    previous_track_threshold: Duration,
+    watchdog: Watchdog,
+    surprises: Vec<SurpriseConfig>,
+    favorites_playlist: String,
+    announce_titles: bool,
+    // Synthesized titles that are deleted once they have played.
+    spoken_titles: Vec<String>,
+    idle_timer: Option<AbortHandle>,
+    messages: Option<MessageRecorder>,
+    // Stops a recording at its maximum length.
+    recording_timer: Option<AbortHandle>,
+    // Waits for the volume to settle before it is spoken.
+    volume_announcement: Option<AbortHandle>,
+    provisioning: Provisioning,
+    // Starts the setup access point if the network stays down.
+    provisioning_timer: Option<AbortHandle>,
+    update: Option<UpdateConfig>,
+    sync_windows: SyncWindows,
+    clock: Clock,
+    // Rescheduled whenever the clock changes.
+    sync_timer: Option<AbortHandle>,
+    update_timer: Option<AbortHandle>,
+    // Whether an update is being downloaded.
+    updating: bool,
+    // Set when an update has been installed and the new binary should start
+    // after shutting down.
+    restart: Option<PathBuf>,
+    // Playback resumes straight away when starting after an update.
+    resume_on_start: bool,
+    crash_recovery: CrashRecoveryConfig,
+    // When the playback position was last saved in case of a crash.
+    resume_saved: Instant,
+    power_off_command: Vec<String>,
+    // Taken when the music box starts running.
+    self_test: Option<SelfTest>,
+    metrics: Metrics,
+    handler_budget: Duration,
+    latency: LatencyTracker,
+    // The playlist last started and when, to detect double presses.
+    last_playlist_start: Option<(String, Instant)>,
+    keypad: Keypad,
+    guest: GuestMode,
+    // Set by an admin to allow full volume during the current quiet hours.
+    quiet_override: bool,
+    profiles: Profiles,
+    dry_run: bool,
+    // Running in the background without a terminal.
+    daemon: bool,
+    device: DeviceInfo,
+    config_hash: String,
 }
 
 impl MusicBox {
@@ -40,22 +235,1142 @@ impl MusicBox {
     where
         S: Stream<Item = Message<Command>> + 'static,
     {
-        tokio::spawn(
+        let forwarder = self.forward(stream);
+        self.forwarders.push(forwarder);
+    }
+
+    fn forward<S: Send>(&self, stream: S) -> Forwarder
+    where
+        S: Stream<Item = Message<Command>> + 'static,
+    {
+        let (forward, abort) = abortable(
             stream
-                .map(|message| Ok(message))
+                .map(|mut message: Message<Command>| {
+                    message.emitted.get_or_insert_with(Instant::now);
+                    Ok(message)
+                })
                 .forward(self.commands.sender()),
         );
+        let forward = tokio::spawn(forward);
+
+        // Nothing else waits on the forwarding task so watch it here to find
+        // out if it panics.
+        let events = self.events.sender();
+        let task = tokio::spawn(async move {
+            if let Err(e) = forward.await {
+                events.send(
+                    Event::Error {
+                        message: format!("Command forwarder failed: {}", e),
+                    }
+                    .into(),
+                );
+            }
+        });
+
+        Forwarder { abort, task }
+    }
+
+    fn forward_source(&self, source: &mut dyn CommandSource) -> MusicResult<Forwarder> {
+        let commands = source
+            .commands()
+            .prefix(format!("Unable to start {}", source.name()))?;
+        let name = source.name().to_owned();
+        let authorizer = source.authorizer();
+        Ok(self.forward(commands.filter(move |command| {
+            ready(is_authorized(
+                &name,
+                authorizer.authorization(),
+                &command.payload,
+            ))
+        })))
+    }
+
+    pub fn add_command_source<S>(&mut self, mut source: S) -> VoidResult
+    where
+        S: CommandSource + 'static,
+    {
+        let forwarder = self.forward_source(&mut source)?;
+        self.sources.push(RegisteredSource {
+            source: Box::new(source),
+            forwarder: Some(forwarder),
+        });
+
+        Ok(())
+    }
+
+    // Restarts each source's commands from its reloaded configuration.
+    async fn reload_sources(&mut self) {
+        let mut sources = take(&mut self.sources);
+        for registered in &mut sources {
+            let name = registered.source.name().to_owned();
+            // The old stream may hold hardware that the new one needs.
+            if let Some(forwarder) = registered.forwarder.take() {
+                forwarder.stop().await;
+            }
+
+            registered
+                .source
+                .reload()
+                .format_log(|e| format!("Failed to reload {}: {}", name, e))
+                .drop();
+
+            registered.forwarder = self.forward_source(&mut *registered.source).log().ok();
+        }
+        self.sources = sources;
+    }
+
+    pub fn add_event_sink<S>(&mut self, sink: S)
+    where
+        S: EventSink,
+    {
+        spawn_sink(sink, self.event_listeners.receiver());
+    }
+
+    fn load_playlist(&mut self, playlist: &StoredPlaylist) {
+        self.resume = None;
+        self.player
+            .set_filters(self.audio.filters(playlist.pipeline()));
+        self.end_of_playlist = playlist
+            .end_of_playlist()
+            .unwrap_or(&self.default_end_of_playlist)
+            .clone();
+        self.announce_titles = playlist.announce_titles();
+        let gain = playlist.gain(&self.analyzer);
+        if gain != 0.0 {
+            info!("Playing {} with a gain of {:.1}dB.", playlist.name(), gain);
+        }
+        self.player.set_gain(gain);
+        self.state.set_playlist(playlist.tracks());
+        self.dispatch_event(
+            Event::PlaylistUpdated {
+                changes: Vec::new(),
+            }
+            .into(),
+        );
+    }
+
+    async fn play(&mut self, mut position: usize) {
+        let length = self.state.playlist().len();
+        if position > 0 && position >= length {
+            match self.end_of_playlist.clone() {
+                EndOfPlaylist::Repeat => position = 0,
+                EndOfPlaylist::Playlist { name } => match self.state.stored_playlist(&name) {
+                    Some(playlist) => {
+                        self.load_playlist(&playlist);
+                        position = 0;
+                    }
+                    None => error!("Cannot continue with unknown playlist {}.", name),
+                },
+                _ => {}
+            }
+        }
+
+        let playlist = self.state.playlist();
+        if let Some(track) = playlist.get(position) {
+            if let Some(timer) = self.idle_timer.take() {
+                timer.abort();
+            }
+            // Tracks from storage backends have to be downloaded before they
+            // can play. Normally they were already prefetched.
+            self.cache.fetch(track).await.log().drop();
+            let title = if self.announce_titles {
+                self.title_clip(track).await
+            } else {
+                None
+            };
+
+            self.player.start(&track.path()).log().drop();
+            self.cache.prefetch(&playlist[position + 1..]);
+            self.state.set_playback_position(Some(position));
+
+            // The track waits for its title to finish.
+            if let Some(title) = title {
+                self.interrupt(title, false);
+            }
+        } else {
+            self.state.set_playback_position(None);
+            self.player.stop().log().drop();
+
+            if self.end_of_playlist != EndOfPlaylist::Stop {
+                self.state.set_playlist(Default::default());
+                self.dispatch_event(
+                    Event::PlaylistUpdated {
+                        changes: Vec::new(),
+                    }
+                    .into(),
+                );
+            }
+
+            if let EndOfPlaylist::PowerOff { idle } = self.end_of_playlist {
+                self.start_idle_timer(Duration::from_secs(idle));
+            }
+        }
+    }
+
+    // A recording of the track's title, spoken if there isn't one already.
+    async fn title_clip(&mut self, track: &Track) -> Option<String> {
+        let recording = track.path().with_extension("wav");
+        if recording.exists() {
+            return Some(recording.display().to_string());
+        }
+
+        let file = self.speech.announcement_file();
+        match self.speech.synthesize(&track.title(), &file).await {
+            Ok(()) => {
+                let path = file.display().to_string();
+                self.spoken_titles.push(path.clone());
+                Some(path)
+            }
+            Err(e) => {
+                warn!("Unable to speak the title of {}: {}", track, e);
+                None
+            }
+        }
+    }
+
+    async fn recover_stalled_playback(&mut self) {
+        let position = match self.state.playback_position() {
+            Some(position) if self.watchdog.stalled() => position,
+            _ => return,
+        };
+
+        let message = match self.watchdog.position() {
+            Some(offset) => format!("Playback stalled at {}s.", offset.as_secs()),
+            None => String::from("Playback stalled before it started."),
+        };
+        self.dispatch_event(Event::Error { message }.into());
+
+        match self.watchdog.recover() {
+            StallAction::Restart => {
+                warn!("Restarting the stalled track.");
+                self.pending_seek = self.watchdog.position();
+                self.play(position).await;
+            }
+            StallAction::Skip => {
+                warn!("Skipping the stalled track.");
+                self.play(position + 1).await;
+            }
+        }
+    }
+
+    // Restarts the track on the next working output from where it stopped.
+    async fn fail_over_output(&mut self) {
+        self.player.fail_over();
+
+        if let (Some(position), Some(false)) = (self.state.playback_position(), self.state.paused())
+        {
+            self.pending_seek = self.watchdog.position();
+            self.play(position).await;
+        }
+    }
+
+    // Pauses playback so the microphone doesn't pick it up. The recording
+    // stops by itself once it reaches the maximum length.
+    fn start_recording(&mut self) {
+        let recorder = match self.messages {
+            Some(ref mut recorder) if !recorder.is_recording() => recorder,
+            Some(_) => return,
+            None => {
+                warn!("Recording messages is not configured.");
+                return;
+            }
+        };
+
+        if self.state.paused() == Some(false) {
+            self.player.pause().log().drop();
+        }
+        if recorder.start().log().is_err() {
+            self.sounds.play(Sound::Error);
+            return;
+        }
+
+        let (delay, handle) = abortable(delay_for(recorder.max_length()));
+        let sender = self.commands.sender();
+        tokio::spawn(async move {
+            if delay.await.is_ok() {
+                sender.send(Command::StopRecording.into());
+            }
+        });
+
+        if let Some(timer) = self.recording_timer.replace(handle) {
+            timer.abort();
+        }
+    }
+
+    // Recordings that are kept show up once the playlist has been scanned.
+    fn stop_recording(&mut self) {
+        if let Some(timer) = self.recording_timer.take() {
+            timer.abort();
+        }
+
+        let recorder = match self.messages {
+            Some(ref mut recorder) if recorder.is_recording() => recorder,
+            _ => return,
+        };
+
+        match recorder.stop().log() {
+            Ok(Some(_)) => {
+                self.sounds.play(Sound::Accept);
+                let playlist = recorder.playlist().to_owned();
+                self.scan_playlists(vec![playlist], false);
+            }
+            Ok(None) => (),
+            Err(_) => self.sounds.play(Sound::Error),
+        }
+    }
+
+    fn start_idle_timer(&mut self, idle: Duration) {
+        let (delay, handle) = abortable(delay_for(idle));
+        let sender = self.commands.sender();
+        tokio::spawn(async move {
+            if delay.await.is_ok() {
+                sender.send(Command::PowerOff.into());
+            }
+        });
+
+        if let Some(timer) = self.idle_timer.replace(handle) {
+            timer.abort();
+        }
+    }
+
+    fn confirm_update(&self) {
+        // An update that keeps running this long is kept.
+        let data_dir = self.data_dir.clone();
+        tokio::spawn(async move {
+            delay_for(UPDATE_CONFIRM_DELAY).await;
+            confirm_boot(&data_dir).log().drop();
+        });
+    }
+
+    // Downloads happen in the background and report back with an event.
+    fn start_update(&mut self) {
+        let config = match self.update {
+            Some(ref config) => config.clone(),
+            None => {
+                warn!("Updates are not configured.");
+                return;
+            }
+        };
+
+        if self.updating {
+            info!("Already checking for updates.");
+            return;
+        }
+        self.updating = true;
+
+        let data_dir = self.data_dir.clone();
+        let sender = self.events.sender();
+        tokio::spawn(
+            async move {
+                match update(&config, &data_dir).await {
+                    Ok(installed) => sender.send(Event::UpdateFinished { installed }.into()),
+                    Err(e) => sender.send_all(vec![
+                        Event::Error {
+                            message: format!("Update failed: {}", e),
+                        }
+                        .into(),
+                        Event::UpdateFinished { installed: None }.into(),
+                    ]),
+                }
+            }
+            .instrument(Span::current()),
+        );
+    }
+
+    fn schedule_updates(&mut self) {
+        let hours = match self.update.as_ref().and_then(|config| config.interval) {
+            Some(hours) => hours,
+            None => return,
+        };
+
+        let sender = self.commands.sender();
+        let windows = self.sync_windows.clone();
+        let clock = self.clock.clone();
+        let (updates, handle) = abortable(async move {
+            loop {
+                delay_for(Duration::from_secs(hours * 60 * 60)).await;
+                clock.synchronized().await;
+                delay_for(windows.wait(TimeOfDay::now())).await;
+                sender.send(Command::Update.into());
+            }
+        });
+        tokio::spawn(updates);
+
+        if let Some(timer) = self.update_timer.replace(handle) {
+            timer.abort();
+        }
+    }
+
+    // Playlists from storage backends aren't synced outside of the sync
+    // windows so they are synced at the start of each window instead.
+    fn schedule_syncs(&mut self) {
+        if self.sync_windows.is_empty() {
+            return;
+        }
+
+        let sender = self.commands.sender();
+        let windows = self.sync_windows.clone();
+        let clock = self.clock.clone();
+        let (syncs, handle) = abortable(async move {
+            clock.synchronized().await;
+            loop {
+                delay_for(windows.next_start(TimeOfDay::now())).await;
+                sender.send(Command::Reload.into());
+                delay_for(SYNC_REPEAT_DELAY).await;
+            }
+        });
+        tokio::spawn(syncs);
+
+        if let Some(timer) = self.sync_timer.replace(handle) {
+            timer.abort();
+        }
+    }
+
+    fn update_provisioning(&mut self, online: bool) {
+        if let Some(timer) = self.provisioning_timer.take() {
+            timer.abort();
+        }
+
+        let provisioning = self.provisioning.clone();
+        if online {
+            tokio::spawn(async move { provisioning.stop().await.log().drop() });
+        } else if let Some(delay) = provisioning.delay() {
+            let (delay, handle) = abortable(delay_for(Duration::from_secs(delay)));
+            tokio::spawn(async move {
+                if delay.await.is_ok() {
+                    provisioning.start().await.log().drop();
+                }
+            });
+            self.provisioning_timer = Some(handle);
+        }
+    }
+
+    async fn resume(&mut self, resume: ResumeState) {
+        let app_state = self.state.as_immutable();
+        let tracks: Vec<Track> = resume
+            .tracks
+            .iter()
+            .filter_map(|id| app_state.track(id))
+            .collect();
+
+        if tracks.len() != resume.tracks.len() {
+            warn!("Some tracks from the last session are missing, not resuming.");
+            self.play(0).await;
+            return;
+        }
+
+        info!(
+            "Resuming track {} at {}s.",
+            resume.position,
+            resume.offset.as_secs()
+        );
+        self.state.set_playlist(tracks);
+        self.dispatch_event(
+            Event::PlaylistUpdated {
+                changes: Vec::new(),
+            }
+            .into(),
+        );
+        self.ramp_volume();
+        self.pending_seek = Some(resume.offset);
+        self.play(resume.position).await;
+    }
+
+    // Returns false if neither the playlist nor a fallback could be started.
+    async fn start_playlist(&mut self, name: &str, force: bool, instant: Instant) -> bool {
+        if !self.profiles.allows(name) {
+            warn!("The current profile cannot play playlist {}.", name);
+            return false;
+        }
+
+        if let Some(surprise) = self.surprises.iter().find(|surprise| surprise.name == name) {
+            let surprise = surprise.clone();
+            return self.start_surprise(&surprise).await;
+        }
+
+        if name == self.favorites_playlist {
+            return self.start_favorites().await;
+        }
+
+        let playlist = match self.state.stored_playlist(name) {
+            Some(playlist) if !playlist.is_empty() => playlist,
+            requested => match self.unavailable_playlist(name, requested) {
+                Some(playlist) => playlist,
+                None => return false,
+            },
+        };
+
+        let restart = self.is_double_press(&playlist, instant) || force;
+        if self.state.is_playing_playlist(name) && !restart {
+            if self.state.paused() == Some(true) {
+                self.player.play().log().drop();
+            }
+            return true;
+        }
+
+        self.load_playlist(&playlist);
+        self.ramp_volume();
+        self.play(0).await;
+        true
+    }
+
+    // Starts the first playlist, by name, that the track is in from that
+    // track.
+    async fn play_track(&mut self, id: &str) -> bool {
+        let mut names = self.state.stored_playlist_names();
+        names.sort();
+
+        let found = names
+            .iter()
+            .filter(|name| self.profiles.allows(name))
+            .find_map(|name| {
+                let playlist = self.state.stored_playlist(name)?;
+                let position = playlist
+                    .tracks()
+                    .iter()
+                    .position(|track| track.id() == id)?;
+                Some((playlist, position))
+            });
+
+        match found {
+            Some((playlist, position)) => {
+                self.load_playlist(&playlist);
+                self.ramp_volume();
+                self.play(position).await;
+                true
+            }
+            None => {
+                warn!("Cannot play unknown track {}.", id);
+                false
+            }
+        }
+    }
+
+    #[cfg(feature = "rpi")]
+    fn identify_led(&self, name: &str) -> bool {
+        let led = match self.state.as_immutable().led(name) {
+            Some(led) => Some(led),
+            None if name == CACHE_LED => self.cache.led().cloned(),
+            None => None,
+        };
+
+        match led {
+            Some(led) => {
+                info!("Identifying the LED for {}.", name);
+                led.identify(IDENTIFY_DURATION);
+                true
+            }
+            None => {
+                warn!("Cannot identify unknown LED {}.", name);
+                false
+            }
+        }
+    }
+
+    #[cfg(not(feature = "rpi"))]
+    fn identify_led(&self, name: &str) -> bool {
+        warn!("Cannot identify LED {} without GPIO support.", name);
+        false
+    }
+
+    // Changes the queue without interrupting the playing track, unless it is
+    // the one removed. Tracks are found by ID, the first match is used if a
+    // track is queued more than once.
+    async fn edit_queue(&mut self, edit: QueueEdit, before: Option<String>) -> bool {
+        let mut tracks = self.state.playlist();
+        let find = |tracks: &[Track], id: &str| tracks.iter().position(|track| track.id() == id);
+
+        let change = match edit {
+            QueueEdit::Add(track) => {
+                let index = match before {
+                    Some(ref before) => match find(&tracks, before) {
+                        Some(index) => index,
+                        None => return false,
+                    },
+                    None => tracks.len(),
+                };
+                let info = track.info();
+                tracks.insert(index, *track);
+                QueueChange::Inserted { index, track: info }
+            }
+            QueueEdit::Move(id) => {
+                let from = match find(&tracks, &id) {
+                    Some(from) => from,
+                    None => return false,
+                };
+                let track = tracks.remove(from);
+                let to = match before {
+                    Some(ref before) => match find(&tracks, before) {
+                        Some(to) => to,
+                        None => return false,
+                    },
+                    None => tracks.len(),
+                };
+                tracks.insert(to, track);
+                QueueChange::Moved { from, to }
+            }
+            QueueEdit::Remove(id) => match find(&tracks, &id) {
+                Some(index) => {
+                    tracks.remove(index);
+                    QueueChange::Removed { index }
+                }
+                None => return false,
+            },
+        };
+
+        let playing = self.state.playback_position();
+        let position = playing.and_then(|position| match change {
+            QueueChange::Inserted { index, .. } if position >= index => Some(position + 1),
+            QueueChange::Removed { index } if position == index => None,
+            QueueChange::Removed { index } if position > index => Some(position - 1),
+            QueueChange::Moved { from, to } if position == from => Some(to),
+            QueueChange::Moved { from, to } => {
+                let position = if from < position {
+                    position - 1
+                } else {
+                    position
+                };
+                Some(if to <= position {
+                    position + 1
+                } else {
+                    position
+                })
+            }
+            _ => Some(position),
+        });
+
+        if let Some(position) = position {
+            self.cache.prefetch(&tracks[position + 1..]);
+        }
+        self.state.set_queue(tracks, position);
+        self.dispatch_event(
+            Event::PlaylistUpdated {
+                changes: vec![change],
+            }
+            .into(),
+        );
+
+        // The track that followed the removed one takes its place.
+        if let (Some(removed), None) = (playing, position) {
+            self.play(removed).await;
+        }
+
+        true
+    }
+
+    // Starts a new random queue, even if the surprise playlist is already
+    // playing.
+    async fn start_surprise(&mut self, surprise: &SurpriseConfig) -> bool {
+        let tracks = surprise.pick(|name| {
+            self.state
+                .stored_playlist(name)
+                .filter(|_| self.profiles.allows(name))
+        });
+        if tracks.is_empty() {
+            error!(
+                "Surprise playlist {} has no tracks to pick from.",
+                surprise.name
+            );
+            return false;
+        }
+
+        info!(
+            "Starting surprise playlist {} with {} tracks.",
+            surprise.name,
+            tracks.len()
+        );
+        self.start_generated(tracks).await
+    }
+
+    // Takes the playing track out of the queue and every playlist it is in,
+    // then plays whatever came after it.
+    async fn block_track(&mut self) {
+        let mut tracks = self.state.playlist();
+        let position = match self.state.playback_position() {
+            Some(position) if position < tracks.len() => position,
+            _ => {
+                self.sounds.play(Sound::Error);
+                return;
+            }
+        };
+        let track = tracks.remove(position);
+
+        {
+            let mut library = self.library.lock().await;
+            if !library.set_blocked(&track.path(), true) {
+                drop(library);
+                self.sounds.play(Sound::Error);
+                return;
+            }
+            library.save().await.log().drop();
+        }
+        info!("Blocked {}.", track.title());
+
+        let names = self
+            .state
+            .stored_playlist_names()
+            .into_iter()
+            .filter(|name| match self.state.stored_playlist(name) {
+                Some(playlist) => playlist.track(&track.id()).is_some(),
+                None => false,
+            })
+            .collect();
+        self.scan_playlists(names, false);
+
+        self.state.set_playlist(tracks);
+        self.dispatch_event(
+            Event::PlaylistUpdated {
+                changes: Vec::new(),
+            }
+            .into(),
+        );
+        self.play(position).await;
+    }
+
+    // Plays the favorite tracks from every playlist the current profile can
+    // play.
+    async fn start_favorites(&mut self) -> bool {
+        let mut names = self.state.stored_playlist_names();
+        names.sort();
+
+        let library = self.library.clone();
+        let library = library.lock().await;
+        let mut tracks: Vec<Track> = Vec::new();
+        for name in names.iter().filter(|name| self.profiles.allows(name)) {
+            if let Some(playlist) = self.state.stored_playlist(name) {
+                for track in playlist.tracks() {
+                    if library.is_favorite(&track.path()) && !tracks.contains(&track) {
+                        tracks.push(track);
+                    }
+                }
+            }
+        }
+        drop(library);
+
+        if tracks.is_empty() {
+            error!("There are no favorite tracks to play.");
+            return false;
+        }
+
+        info!("Starting the favorites with {} tracks.", tracks.len());
+        self.start_generated(tracks).await
+    }
+
+    // Starts a queue of tracks that isn't one of the stored playlists.
+    async fn start_generated(&mut self, tracks: Vec<Track>) -> bool {
+        self.resume = None;
+        self.player.set_filters(self.audio.filters(None));
+        self.player.set_gain(0.0);
+        self.end_of_playlist = self.default_end_of_playlist.clone();
+        self.announce_titles = false;
+        self.state.set_playlist(tracks);
+        self.dispatch_event(
+            Event::PlaylistUpdated {
+                changes: Vec::new(),
+            }
+            .into(),
+        );
+
+        self.ramp_volume();
+        self.play(0).await;
+        true
+    }
+
+    // Reports a playlist that can't be played and finds the fallback
+    // playlist to play instead.
+    fn unavailable_playlist(
+        &mut self,
+        name: &str,
+        requested: Option<StoredPlaylist>,
+    ) -> Option<StoredPlaylist> {
+        match requested {
+            Some(ref playlist) if playlist.is_media_missing() => {
+                error!("Cannot start playlist {} as its media is missing.", name);
+                let message = tr_with("media-missing", &[("name", name)]);
+                self.dispatch_event(Event::Error { message }.into());
+            }
+            Some(_) => error!("Cannot start playlist {} as it has no tracks.", name),
+            None => error!(
+                "Received a request to start playlist {} but that list does not exist.",
+                name
+            ),
+        }
+
+        let fallback = self
+            .fallback_playlist
+            .as_deref()
+            .filter(|fallback| *fallback != name && self.profiles.allows(fallback))
+            .and_then(|fallback| self.state.stored_playlist(fallback))
+            .filter(|playlist| !playlist.is_empty());
+
+        if let Some(ref playlist) = fallback {
+            info!("Starting fallback playlist {}.", playlist.name());
+        }
+
+        #[cfg(feature = "rpi")]
+        {
+            if let Some(playlist) = requested.as_ref().or(fallback.as_ref()) {
+                playlist.blink_code(vec![UNAVAILABLE_CODE]);
+            }
+        }
+
+        self.dispatch_event(
+            Event::PlaylistUnavailable {
+                name: name.to_owned(),
+                fallback: fallback.as_ref().map(StoredPlaylist::name),
+            }
+            .into(),
+        );
+        fallback
+    }
+
+    // Whether this start of the playlist is the second press of a double
+    // press.
+    fn is_double_press(&mut self, playlist: &StoredPlaylist, instant: Instant) -> bool {
+        let last = self.last_playlist_start.take();
+        let double = match (playlist.double_press(), last) {
+            (Some(window), Some((name, last))) => {
+                name == playlist.name() && instant.saturating_duration_since(last) <= window
+            }
+            _ => false,
+        };
+
+        // A third press starts a new double press.
+        if !double {
+            self.last_playlist_start = Some((playlist.name(), instant));
+        }
+        double
+    }
+
+    // Starts playback quietly so a playlist started early in the morning
+    // doesn't wake the house.
+    fn ramp_volume(&mut self) {
+        if let Some(ref ramp) = self.audio.ramp {
+            self.player
+                .ramp_volume(ramp.start, Duration::from_millis(ramp.duration));
+        }
+    }
+
+    // Plays a file over the playlist, either pausing the playlist or ducking
+    // its volume until the file has finished.
+    fn interrupt(&mut self, path: String, duck: bool) {
+        if self.interruptions == 0 {
+            let playing = self.state.paused() == Some(false);
+            self.ducked = playing && duck;
+            self.resume_after_interruption = playing && !duck;
+
+            if self.ducked {
+                self.player
+                    .set_volume(self.state.volume() * self.audio.duck_volume);
+            } else if self.resume_after_interruption {
+                self.player.pause().log().drop();
+            }
+            self.dispatch_event(Event::InterruptionStarted.into());
+        }
+        self.interruptions += 1;
+
+        let volume = self.state.volume();
+        let sender = self.events.sender();
+        thread::spawn(move || {
+            play_file(Path::new(&path), volume).log().drop();
+            sender.send(Event::FileFinished { path }.into());
+        });
+    }
+
+    fn end_interruption(&mut self) {
+        self.interruptions -= 1;
+        if self.interruptions > 0 {
+            return;
+        }
+
+        if self.ducked {
+            self.player.set_volume(self.state.volume());
+        } else if self.resume_after_interruption {
+            self.player.play().log().drop();
+        }
+        self.dispatch_event(Event::InterruptionEnded.into());
+    }
+
+    // The loudest the volume may be right now, if quiet hours or the profile
+    // limit it.
+    fn volume_limit(&mut self) -> Option<f64> {
+        let quiet = self.audio.max_volume(TimeOfDay::now());
+        if quiet.is_none() {
+            // Overrides only last until the quiet hours they were made in end.
+            self.quiet_override = false;
+        }
+
+        let limit = match (
+            quiet.filter(|_| !self.quiet_override),
+            self.profiles.max_volume(),
+        ) {
+            (Some(quiet), Some(profile)) => Some(quiet.min(profile)),
+            (quiet, profile) => quiet.or(profile),
+        };
+        self.state.set_max_volume(limit);
+        limit
+    }
+
+    // Turns the volume down when quiet hours start or the profile changes.
+    fn apply_volume_limit(&mut self) {
+        match self.volume_limit() {
+            Some(limit) if self.state.volume() > limit => {
+                info!("Lowering the volume to {}.", limit);
+                self.state.set_volume(limit);
+                if self.ducked {
+                    self.player.set_volume(limit * self.audio.duck_volume);
+                } else {
+                    self.player.set_volume(limit);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Counts playback against the guest mode allowance, stopping playback
+    // once it runs out and warning shortly before.
+    fn enforce_guest_mode(&mut self) {
+        let playing = self.state.paused() == Some(false);
+        self.guest.count(playing);
+
+        if playing && self.guest.is_blocked() {
+            info!("Guest mode does not allow playback now.");
+            self.player.pause().log().drop();
+            self.sounds.play(Sound::Error);
+        } else if playing && self.guest.should_warn() {
+            self.warn_guest();
+        }
+
+        self.state.set_guest(self.guest.state());
+    }
+
+    fn warn_guest(&mut self) {
+        // Counting the minute that has started.
+        let minutes = self.guest.time_left().unwrap_or_default().as_secs() / 60 + 1;
+        info!("Guest mode will stop playback in {} minutes.", minutes);
+
+        #[cfg(feature = "rpi")]
+        {
+            if let Some(name) = self.guest.config().and_then(|config| config.led.clone()) {
+                match self.state.stored_playlist(&name) {
+                    Some(playlist) => playlist.blink_code(vec![minutes.min(9) as u8]),
+                    None => error!(
+                        "Cannot blink the guest mode warning on unknown playlist {}.",
+                        name
+                    ),
+                }
+            }
+        }
+
+        if !matches!(self.guest.config(), Some(config) if config.announce) {
+            return;
+        }
+
+        let text = tr_with("guest-warning", &[("minutes", &minutes.to_string())]);
+        tokio::spawn(self.announce(text));
+    }
+
+    fn volume_feedback(&mut self, volume: f64) {
+        match self.audio.volume_feedback {
+            Some(VolumeFeedback::Tone) => {
+                let frequency = VOLUME_TONE_LOW + (volume * f64::from(VOLUME_TONE_RANGE)) as u32;
+                thread::spawn(move || beep_at(frequency, 0.1 + volume * 0.4).log().drop());
+            }
+            Some(VolumeFeedback::Speech) => {
+                let percent = format!("{:.0}", volume * 100.0);
+                let announcement = self.announce(tr_with("volume-level", &[("percent", &percent)]));
+                let (delay, handle) = abortable(delay_for(VOLUME_ANNOUNCEMENT_DELAY));
+                tokio::spawn(async move {
+                    if delay.await.is_ok() {
+                        announcement.await;
+                    }
+                });
+
+                if let Some(previous) = self.volume_announcement.replace(handle) {
+                    previous.abort();
+                }
+            }
+            None => (),
+        }
+    }
+
+    // Synthesizes announcements that are likely to be needed in the
+    // background so they play without waiting for the synthesizer.
+    fn prepare_announcements(&self) {
+        let mut texts = Vec::new();
+
+        if matches!(self.audio.volume_feedback, Some(VolumeFeedback::Speech)) {
+            let steps = (1.0 / VOLUME_INTERVAL).round() as u32;
+            texts.extend((0..=steps).map(|step| {
+                let percent = format!("{:.0}", f64::from(step) * VOLUME_INTERVAL * 100.0);
+                tr_with("volume-level", &[("percent", &percent)])
+            }));
+        }
+
+        if let Some(config) = self.guest.config().filter(|config| config.announce) {
+            texts.extend(
+                (1..=config.warning + 1)
+                    .map(|minutes| tr_with("guest-warning", &[("minutes", &minutes.to_string())])),
+            );
+        }
+
+        for name in self.state.stored_playlist_names() {
+            match self.state.stored_playlist(&name) {
+                Some(playlist) if playlist.announce_titles() => texts.extend(
+                    playlist
+                        .tracks()
+                        .iter()
+                        .filter(|track| !track.path().with_extension("wav").exists())
+                        .map(Track::title),
+                ),
+                _ => {}
+            }
+        }
+
+        let speech = self.speech.clone();
+        tokio::spawn(async move { speech.prepare(texts).await });
+    }
+
+    // Speaks over the playlist, lowering its volume until the announcement
+    // has finished.
+    fn announce(&self, text: String) -> impl Future<Output = ()> + Send + 'static {
+        let speech = self.speech.clone();
+        let sender = self.commands.sender();
+        let mut events = self.event_listeners.receiver();
+        async move {
+            let file = speech.announcement_file();
+            if let Err(e) = speech.synthesize(&text, &file).await {
+                warn!("Failed to synthesize announcement: {}", e);
+                return;
+            }
+
+            sender.send(
+                Command::PlayFile {
+                    path: file.display().to_string(),
+                    duck: true,
+                }
+                .into(),
+            );
+            while let Some(event) = events.next().await {
+                if let Event::FileFinished { ref path } = event.payload {
+                    if Path::new(path) == file {
+                        break;
+                    }
+                }
+            }
+
+            remove_file(&file).log().drop();
+        }
+    }
+
+    fn current_resume_state(&self) -> Option<ResumeState> {
+        self.state.playback_position().map(|position| ResumeState {
+            tracks: self
+                .state
+                .playlist()
+                .iter()
+                .map(|track| track.id().to_owned())
+                .collect(),
+            position,
+            offset: self.state.playback_duration().unwrap_or_default(),
+        })
+    }
+
+    fn save_resume_state(&self) {
+        ResumeState::save(&self.data_dir, self.current_resume_state().as_ref())
+            .log()
+            .drop();
+    }
+
+    // Stops playback and switches profile, ready to resume wherever the new
+    // profile left off.
+    fn select_profile(&mut self, name: Option<String>) -> bool {
+        if self.profiles.current_name() == name {
+            return true;
+        }
+
+        // Playback that was never resumed still belongs to the old profile.
+        let resume = match self.current_resume_state() {
+            Some(resume) => Some(resume),
+            None => self.resume.take(),
+        };
+
+        let resume = match self.profiles.select(name.as_deref(), resume) {
+            Ok(resume) => resume,
+            Err(e) => {
+                warn!("{}", e);
+                return false;
+            }
+        };
+
+        self.state.set_playback_position(None);
+        self.player.stop().log().drop();
+        self.state.set_playlist(Default::default());
+        self.dispatch_event(
+            Event::PlaylistUpdated {
+                changes: Vec::new(),
+            }
+            .into(),
+        );
+
+        self.resume = resume;
+        self.state.set_profile(name);
+        self.apply_volume_limit();
+        true
     }
 
-    async fn play(&mut self, position: usize) {
-        if let Some(track) = self.state.playlist().get(position) {
-            self.player.start(&track.path()).log().drop();
-            self.state.set_playback_position(Some(position))
-        } else {
-            self.state.set_playback_position(None);
-            self.player.stop().log().drop();
-            self.state.set_playlist(Default::default());
-            self.dispatch_event(Event::PlaylistUpdated.into());
+    // Waits for the shutdown sound so it isn't cut off as the music box exits.
+    async fn play_shutdown_sound(&self) {
+        let sounds = self.sounds.clone();
+        spawn_blocking(move || sounds.play_to_end(Sound::Shutdown))
+            .await
+            .as_err()
+            .and_then(|result| result)
+            .log()
+            .drop();
+    }
+
+    fn shutdown(&mut self) {
+        self.save_resume_state();
+        mark_stopped(&self.data_dir).log().drop();
+        self.guest.count(false);
+        self.guest.save().log().drop();
+        self.player.stop().log().drop();
+        for forwarder in &self.forwarders {
+            forwarder.cancel();
+        }
+        for registered in &mut self.sources {
+            if let Some(ref forwarder) = registered.forwarder {
+                forwarder.cancel();
+            }
+            registered.source.shutdown();
+        }
+        self.dispatch_event(Event::Shutdown.into());
+    }
+
+    // Logs and counts messages that held up the event loop, either by taking
+    // too long to handle or by waiting too long to be handled.
+    fn record_timing<T: Debug>(&self, kind: &str, message: &Message<T>, started: Instant) {
+        let taken = started.elapsed();
+        let waited = started.saturating_duration_since(message.instant);
+        let millis = taken.as_millis() as u64;
+
+        self.metrics.increment(&format!("{}.handled", kind));
+        self.metrics.add(&format!("{}.millis", kind), millis);
+        self.metrics.maximum(&format!("{}.maxMillis", kind), millis);
+
+        if taken > self.handler_budget {
+            warn!("Handling {:?} took {}ms.", message.payload, millis);
+            self.metrics.increment(&format!("{}.slow", kind));
+        }
+
+        if waited > self.handler_budget {
+            warn!(
+                "{:?} waited {}ms to be handled.",
+                message.payload,
+                waited.as_millis()
+            );
+            self.metrics.increment(&format!("{}.late", kind));
         }
     }
 
@@ -63,8 +1378,37 @@ impl MusicBox {
         self.event_listeners.send(event);
     }
 
+    // In dry run mode commands from hardware controls are only reported so
+    // new mappings can be tried out without disturbing playback.
+    fn is_dry_run(&self, command: &Message<Command>) -> bool {
+        self.dry_run && matches!(command.origin, Origin::Gpio { .. } | Origin::Input { .. })
+    }
+
     async fn handle_command(&mut self, command: Message<Command>) {
-        info!("Saw command {:?}", command.payload);
+        if self.is_dry_run(&command) {
+            info!(
+                "Dry run, skipping command {:?} from {}",
+                command.payload, command.origin
+            );
+            self.dispatch_event(
+                Event::CommandSkipped {
+                    command: command.payload,
+                    origin: command.origin,
+                }
+                .into(),
+            );
+            return;
+        }
+
+        info!("Saw command {:?} from {}", command.payload, command.origin);
+        self.state.record_command(&command.payload, &command.origin);
+        self.dispatch_event(
+            Event::CommandHandled {
+                command: command.payload.clone(),
+                origin: command.origin.clone(),
+            }
+            .into(),
+        );
 
         match command.payload {
             Command::PreviousTrack => {
@@ -73,7 +1417,7 @@ impl MusicBox {
                     self.state.playback_duration(),
                 ) {
                     (Some(position), Some(duration)) => {
-                        if position > 0 && duration.as_secs() < 2 {
+                        if position > 0 && duration < self.previous_track_threshold {
                             position - 1
                         } else {
                             position
@@ -83,6 +1427,11 @@ impl MusicBox {
                 };
                 self.play(position).await;
             }
+            Command::RestartTrack => {
+                if let Some(position) = self.state.playback_position() {
+                    self.play(position).await;
+                }
+            }
             Command::NextTrack => {
                 let position = match self.state.playback_position() {
                     Some(position) => position + 1,
@@ -99,17 +1448,21 @@ impl MusicBox {
                         trace!("Pause");
                         self.player.pause().log().drop();
                     }
+                } else if let Some(resume) = self.resume.take() {
+                    self.resume(resume).await;
                 } else {
                     self.play(0).await;
                 }
             }
             Command::VolumeUp => {
+                let limit = self.volume_limit().unwrap_or(1.0);
                 let mut volume = self.state.volume() + VOLUME_INTERVAL;
-                if volume > 1.0 {
-                    volume = 1.0;
+                if volume > limit {
+                    volume = limit;
                 }
                 self.state.set_volume(volume);
                 self.player.set_volume(volume);
+                self.volume_feedback(volume);
             }
             Command::VolumeDown => {
                 let mut volume = self.state.volume() - VOLUME_INTERVAL;
@@ -118,173 +1471,899 @@ impl MusicBox {
                 }
                 self.state.set_volume(volume);
                 self.player.set_volume(volume);
+                self.volume_feedback(volume);
+            }
+            Command::SetBalance { balance } => {
+                let balance = f64::from(balance.clamp(-100, 100)) / 100.0;
+                self.state.set_balance(balance);
+                self.player.set_balance(balance);
+            }
+            Command::Seek { millis } => {
+                self.player.seek(millis).log().drop();
             }
             Command::Shutdown => {
                 info!("Music box clean shutdown.");
-                self.player.stop().log().drop();
-                self.dispatch_event(Event::Shutdown.into());
+                self.play_shutdown_sound().await;
+                self.shutdown();
+            }
+            Command::Restart => match current_exe() {
+                Ok(executable) => {
+                    info!("Music box restarting.");
+                    self.restart = Some(executable);
+                    self.shutdown();
+                }
+                Err(e) => {
+                    error!("Unable to find the running executable: {}", e);
+                    self.shutdown();
+                }
+            },
+            Command::Update => self.start_update(),
+            Command::PowerOff => {
+                info!("Music box powering off.");
+                self.play_shutdown_sound().await;
+                self.shutdown();
+
+                if let Some((program, args)) = self.power_off_command.split_first() {
+                    std::process::Command::new(program)
+                        .args(args)
+                        .spawn()
+                        .prefix("Unable to power off")
+                        .log()
+                        .drop();
+                }
+            }
+            Command::PowerLost => {
+                warn!("Power lost, shutting down.");
+                self.player.pause().log().drop();
+
+                // Anything that doesn't make it to disk in time is lost.
+                let library = self.library.clone();
+                let save = async move { library.lock().await.save().await };
+                if timeout(self.power_loss_grace, save).await.is_err() {
+                    warn!("Ran out of time to save the library.");
+                }
+                self.shutdown();
+            }
+            Command::SetGuestMode { enabled } => {
+                // Playback so far counts against whichever mode it was in.
+                self.guest.count(self.state.paused() == Some(false));
+                self.guest.set_enabled(enabled);
+                self.enforce_guest_mode();
+            }
+            Command::OverrideQuietHours { enabled } => {
+                self.quiet_override = enabled;
+                self.apply_volume_limit();
+            }
+            Command::IdentifyLed { name } => {
+                if !self.identify_led(&name) {
+                    self.sounds.play(Sound::Error);
+                }
+            }
+            Command::RescanPlaylist { name } => self.scan_playlists(vec![name], false),
+            Command::FavoriteTrack => {
+                let track = self
+                    .state
+                    .playback_position()
+                    .and_then(|position| self.state.playlist().get(position).cloned());
+                let marked = match track {
+                    Some(track) => {
+                        let mut library = self.library.lock().await;
+                        let marked = library.set_favorite(&track.path(), true);
+                        if marked {
+                            info!("Marked {} as a favorite.", track.title());
+                            library.save().await.log().drop();
+                        }
+                        marked
+                    }
+                    None => false,
+                };
+
+                let sound = if marked { Sound::Accept } else { Sound::Error };
+                self.sounds.play(sound);
+            }
+            Command::SetBookmark { name } => {
+                let track = self
+                    .state
+                    .playback_position()
+                    .and_then(|position| self.state.playlist().get(position).cloned());
+                let marked = match (track, self.state.playback_duration()) {
+                    (Some(track), Some(position)) => {
+                        let mut library = self.library.lock().await;
+                        match library.set_bookmark(&track.path(), name, position) {
+                            Some(name) => {
+                                info!("Bookmarked {} in {}.", name, track.title());
+                                library.save().await.log().drop();
+                                true
+                            }
+                            None => false,
+                        }
+                    }
+                    _ => false,
+                };
+
+                let sound = if marked { Sound::Accept } else { Sound::Error };
+                self.sounds.play(sound);
+            }
+            Command::JumpToBookmark { name } => {
+                let track = self
+                    .state
+                    .playback_position()
+                    .and_then(|position| self.state.playlist().get(position).cloned());
+                let bookmark = match track {
+                    Some(track) => self
+                        .library
+                        .lock()
+                        .await
+                        .bookmarks(&track.path())
+                        .into_iter()
+                        .find(|bookmark| bookmark.name == name),
+                    None => None,
+                };
+
+                match bookmark {
+                    Some(bookmark) => self.player.seek_to(bookmark.position).log().drop(),
+                    None => self.sounds.play(Sound::Error),
+                }
+            }
+            Command::StartRecording => self.start_recording(),
+            Command::StopRecording => self.stop_recording(),
+            Command::Unknown => warn!("Ignoring a command this music box doesn't recognise."),
+            Command::BlockTrack => self.block_track().await,
+            Command::UnblockTrack { id } => {
+                let unblocked = {
+                    let mut library = self.library.lock().await;
+                    let unblocked = library.unblock(&id);
+                    if unblocked {
+                        library.save().await.log().drop();
+                    }
+                    unblocked
+                };
+
+                if unblocked {
+                    info!("Unblocked track {}.", id);
+                    self.scan_playlists(self.state.stored_playlist_names(), false);
+                } else {
+                    warn!("Track {} is not blocked.", id);
+                }
+            }
+            Command::SelectProfile { name } => {
+                let sound = if self.select_profile(name) {
+                    Sound::Accept
+                } else {
+                    Sound::Error
+                };
+                self.sounds.play(sound);
+            }
+            Command::StartPlaylist { name, force } => {
+                if let Some(ref group) = self.group {
+                    if group.mirrors() && !group.is_member(&command.origin) {
+                        group.send(&Command::StartPlaylist {
+                            name: name.clone(),
+                            force,
+                        });
+                    }
+                }
+                self.start_playlist(&name, force, command.instant).await;
+            }
+            Command::PlayTrack { id } => {
+                if !self.play_track(&id).await {
+                    self.sounds.play(Sound::Error);
+                }
+            }
+            Command::AddToQueue { id, before } => {
+                let track = self.state.as_immutable().track(&id);
+                let edited = match track {
+                    Some(track) => {
+                        self.edit_queue(QueueEdit::Add(Box::new(track)), before)
+                            .await
+                    }
+                    None => false,
+                };
+                if !edited {
+                    warn!("Cannot queue unknown track {}.", id);
+                }
+            }
+            Command::MoveInQueue { id, before } => {
+                if !self.edit_queue(QueueEdit::Move(id.clone()), before).await {
+                    warn!("Cannot move {} as it isn't queued.", id);
+                }
+            }
+            Command::RemoveFromQueue { id } => {
+                if !self.edit_queue(QueueEdit::Remove(id.clone()), None).await {
+                    warn!("Cannot remove {} as it isn't queued.", id);
+                }
             }
-            Command::StartPlaylist { name, force: _ } => {
-                if self.state.is_playing_playlist(&name) {
+            Command::GroupCommand { command: inner } => {
+                if matches!(command.origin, Origin::Group { .. })
+                    || matches!(*inner, Command::GroupCommand { .. })
+                {
+                    warn!("Ignoring a group command that would be passed on again.");
                     return;
                 }
 
-                if let Some(playlist) = self.state.stored_playlist(&name) {
-                    self.state.set_playlist(playlist.tracks());
-                    self.dispatch_event(Event::PlaylistUpdated.into());
-                    self.play(0).await;
+                // Run here as though it came from the group so that it isn't
+                // mirrored to the members that were just sent it.
+                let origin = match self.group {
+                    Some(ref group) => {
+                        group.send(&inner);
+                        group.origin()
+                    }
+                    None => {
+                        warn!("There is no group configured to send commands to.");
+                        command.origin.clone()
+                    }
+                };
+                self.commands
+                    .sender()
+                    .send(Message::new(command.instant, *inner).with_origin(origin));
+            }
+            Command::Keypad { key } => {
+                let sound = match self.keypad.press(key, command.instant) {
+                    KeypadAction::Digit | KeypadAction::Cleared => Sound::Click,
+                    KeypadAction::StartPlaylist(name) => {
+                        if self.start_playlist(&name, false, command.instant).await {
+                            Sound::Accept
+                        } else {
+                            Sound::Error
+                        }
+                    }
+                    KeypadAction::PlayTrack(position) if position < self.state.playlist().len() => {
+                        self.play(position).await;
+                        Sound::Accept
+                    }
+                    KeypadAction::PlayTrack(_) | KeypadAction::Invalid => Sound::Error,
+                };
+                self.sounds.play(sound);
+            }
+            Command::PlayFile { path, duck } => {
+                if is_playable_file(&self.data_dir, &self.speech, Path::new(&path)) {
+                    self.interrupt(path, duck);
                 } else {
-                    error!(
-                        "Received a request to start playlist {} but that list does not exist.",
-                        name
-                    );
+                    warn!("Refusing to play {} from outside the playlists.", path);
+                    let message = tr_with("unplayable-file", &[("path", &path)]);
+                    self.dispatch_event(Event::Error { message }.into());
+                }
+            }
+            Command::PlayTestSound => {
+                self.player.pause().log().drop();
+                thread::spawn(|| speaker_test().log().drop());
+            }
+            Command::Reload => {
+                self.scan_playlists(self.state.stored_playlist_names(), false);
+                self.reload_sources().await;
+            }
+            Command::Status => {
+                // A daemon has nowhere to show its state so it is written to
+                // a file for collecting problem reports.
+                if self.daemon {
+                    let events = self
+                        .event_listeners
+                        .history()
+                        .into_iter()
+                        .map(|message| (message.sequence, message.payload))
+                        .collect();
+                    if let Ok(file) = write_report(
+                        &self.data_dir,
+                        &self.state.as_immutable(),
+                        &self.metrics,
+                        events,
+                    )
+                    .log()
+                    {
+                        info!("Wrote status report to {}.", file.display());
+                    }
                 }
             }
-            Command::Reload => {}
-            Command::Status => {}
         }
     }
 
     async fn handle_event(&mut self, event: Message<Event>) {
         match &event.payload {
             Event::PlaybackPosition { duration: _ } => {}
+            Event::Error { message } => error!("{}", message),
             payload => info!("Saw event {:?}", payload),
         };
 
         match event.payload {
+            Event::PlaybackStarted => {
+                self.watchdog.start();
+                if let Some(offset) = self.pending_seek.take() {
+                    self.player.seek_to(offset).log().drop();
+                }
+            }
+            Event::PlaybackPosition { duration } => {
+                self.state.set_playback_duration(duration);
+                if self.state.paused() == Some(false) {
+                    self.watchdog.progress(duration);
+                }
+                self.player.update_ramp();
+
+                let interval = Duration::from_secs(self.crash_recovery.save_interval);
+                if self.resume_saved.elapsed() >= interval {
+                    self.save_resume_state();
+                    self.resume_saved = Instant::now();
+                }
+            }
+            Event::FileFinished { ref path } => {
+                if let Some(index) = self.spoken_titles.iter().position(|title| title == path) {
+                    remove_file(self.spoken_titles.swap_remove(index))
+                        .log()
+                        .drop();
+                }
+
+                // Let listeners see the file finish before the interruption
+                // ends.
+                self.dispatch_event(event);
+                self.end_interruption();
+                return;
+            }
             Event::PlaybackPaused => {
                 self.state.set_paused(true);
+                self.watchdog.stop();
             }
             Event::PlaybackUnpaused => {
                 self.state.set_paused(false);
+                self.watchdog.resume();
             }
+            Event::PlaybackStalled => self.recover_stalled_playback().await,
             Event::PlaybackEnded => {
+                self.watchdog.stop();
                 if let Some(pos) = self.state.playback_position() {
                     self.play(pos + 1).await;
                 }
             }
+            Event::ConnectivityChanged { online } => {
+                self.state.set_offline(!online);
+                self.cache.set_offline(!online);
+                self.update_provisioning(online);
+                self.sync_storage(online);
+            }
+            Event::AudioOutputChanged { ref name } => self.state.set_audio_output(name.clone()),
+            Event::AudioOutputFailed => self.fail_over_output().await,
+            Event::PlaylistMediaChanged { ref name, .. } => {
+                self.scan_playlists(vec![name.clone()], false)
+            }
+            Event::UpdateFinished { ref installed } => {
+                self.updating = false;
+                if installed.is_some() {
+                    match pending_executable(&self.data_dir) {
+                        Ok(executable) => {
+                            self.restart = Some(executable);
+                            self.commands.sender().send(Command::Shutdown.into());
+                        }
+                        Err(e) => error!("Unable to restart into the update: {}", e),
+                    }
+                }
+            }
+            Event::ClockChanged { .. } => {
+                // Anything waiting for a time of day was waiting for the
+                // wrong length of time.
+                self.schedule_updates();
+                self.schedule_syncs();
+                self.apply_volume_limit();
+            }
             _ => {}
         }
 
+        if matches!(
+            event.payload,
+            Event::PlaybackStarted
+                | Event::PlaybackPosition { .. }
+                | Event::PlaybackPaused
+                | Event::PlaybackUnpaused
+        ) {
+            self.enforce_guest_mode();
+            self.apply_volume_limit();
+        }
+
+        self.analyzer
+            .set_playing(self.state.paused() == Some(false));
         self.dispatch_event(event);
     }
 
+    // Rescans playlists on another task so commands are still handled while
+    // tracks are probed. Each playlist is updated as soon as it is scanned.
+    // When offline, playlists from storage backends are limited to their
+    // cached tracks instead.
+    fn scan_playlists(&self, mut names: Vec<String>, offline: bool) {
+        // Playlists made from queries pick up the changes to the others.
+        names.sort_by_key(|name| {
+            matches!(self.state.stored_playlist(name), Some(playlist) if playlist.has_query())
+        });
+
+        let mut state = self.state.clone();
+        let library = self.library.clone();
+        let cache = self.cache.clone();
+        let mut analyzer = self.analyzer.clone();
+        let sender = self.events.sender();
+
+        tokio::spawn(
+            async move {
+                for name in names {
+                    let mut playlist = match state.stored_playlist(&name) {
+                        Some(playlist) => playlist,
+                        None => continue,
+                    };
+
+                    {
+                        let mut library = library.lock().await;
+                        if offline {
+                            playlist.use_cache(&mut library, &cache).await;
+                        } else {
+                            playlist.rescan(&mut library, &cache).await.log().drop();
+                        }
+                    }
+
+                    if !offline {
+                        analyzer.queue(&playlist.tracks());
+                    }
+                    let result = playlist.scan_result();
+                    state.set_stored_playlist(playlist);
+                    sender.send_all(vec![
+                        Event::PlaylistUpdated {
+                            changes: Vec::new(),
+                        }
+                        .into(),
+                        Event::PlaylistScanned { name, result }.into(),
+                    ]);
+                }
+
+                library.lock().await.save().await.log().drop();
+            }
+            .instrument(Span::current()),
+        );
+    }
+
+    // Lists playlists from storage backends again when the network comes back
+    // and falls back to their cached tracks when it goes away.
+    fn sync_storage(&mut self, online: bool) {
+        // The sync windows mean nothing until the clock is right.
+        if online
+            && !self.sync_windows.is_empty()
+            && (!self.clock.is_synchronized() || !self.sync_windows.allows(TimeOfDay::now()))
+        {
+            info!("Leaving the storage sync for the next sync window.");
+            return;
+        }
+
+        let names = self
+            .state
+            .stored_playlist_names()
+            .into_iter()
+            .filter(|name| {
+                matches!(
+                    self.state.stored_playlist(name),
+                    Some(playlist) if playlist.has_storage() || playlist.has_query()
+                )
+            })
+            .collect();
+        self.scan_playlists(names, !online);
+    }
+
+    fn queue_analysis(&mut self) {
+        for name in self.state.stored_playlist_names() {
+            if let Some(playlist) = self.state.stored_playlist(&name) {
+                self.analyzer.queue(&playlist.tracks());
+            }
+        }
+    }
+
+    fn announce_address(&mut self, config: &AnnounceConfig, server: &SocketAddr) {
+        let address = local_address(server);
+        match address {
+            Some(ip) => info!(
+                "Web interface available at http://{}:{}/.",
+                ip,
+                server.port()
+            ),
+            None => error!("Unable to determine the local network address."),
+        }
+
+        if config.mdns {
+            self.advertisement = Advertisement::new(server, &self.device).log().ok();
+        }
+
+        #[cfg(feature = "rpi")]
+        {
+            if let (Some(std::net::IpAddr::V4(ip)), Some(name)) = (address, &config.led) {
+                let codes = ip
+                    .octets()
+                    .iter()
+                    .map(|octet| octet.to_string().bytes().map(|b| b - b'0').collect())
+                    .collect();
+
+                match self.state.stored_playlist(name) {
+                    Some(playlist) => playlist.blink_codes(codes),
+                    None => error!("Cannot blink the address on unknown playlist {}.", name),
+                }
+            }
+        }
+    }
+
     pub fn get_event_stream(&mut self) -> MessageReceiver<Event> {
         self.event_listeners.receiver()
     }
 
+    async fn run_self_test(&mut self, self_test: SelfTest) {
+        let failures = self_test.run().await;
+        if failures.is_empty() {
+            info!("Self test passed.");
+        }
+
+        for failure in failures.iter() {
+            error!("Self test failed: {}", failure);
+            self.dispatch_event(
+                Event::Error {
+                    message: failure.clone(),
+                }
+                .into(),
+            );
+        }
+        self.state.set_self_test_failures(failures);
+    }
+
     async fn run(mut self) -> VoidResult {
         info!("Music box startup. Running as process {}.", id());
+        if self.dry_run {
+            warn!("Dry run, commands from buttons and input devices will not run.");
+        }
 
-        if let Some(listener) = self.server.take() {
+        let info = ClientInfo {
+            app_state: self.state.as_immutable(),
+            event_sender: self.event_listeners.clone(),
+            command_sender: self.commands.sender(),
+            auth: self.auth.clone(),
+            speech: self.speech.clone(),
+            analyzer: self.analyzer.clone(),
+            data_dir: self.data_dir.clone(),
+            provisioning: self.provisioning.clone(),
+            metrics: self.metrics.clone(),
+            library: self.library.clone(),
+            webapp_dir: self.webapp_dir.clone(),
+            read_only: self.read_only,
+            device: self.device.clone(),
+            config_hash: self.config_hash.clone(),
+            group: self.group.as_ref().map(Group::key),
+        };
+
+        if let Some(listener) = self.control_server.take() {
             serve(
                 listener,
                 ClientInfo {
-                    app_state: self.state.as_immutable(),
-                    event_receiver: self.event_listeners.receiver(),
-                    command_sender: self.commands.sender(),
+                    read_only: false,
+                    ..info.clone()
                 },
             );
         }
 
+        if let Some(listener) = self.server.take() {
+            serve(listener, info);
+        }
+
+        if let Some(self_test) = self.self_test.take() {
+            self.run_self_test(self_test).await;
+        }
+
+        self.confirm_update();
+        self.prepare_announcements();
+        self.schedule_updates();
+        self.schedule_syncs();
+        if let Some(ref group) = self.group {
+            group.start();
+        }
+
+        if self.resume_on_start {
+            if let Some(resume) = self.resume.take() {
+                self.resume(resume).await;
+            }
+        }
+
         loop {
             select! {
                 c = self.commands.next() => if let Some(command) = c {
-                    self.handle_command(command.clone()).await;
-                    if command.payload == Command::Shutdown {
+                    let span = info_span!(
+                        "command",
+                        sequence = command.sequence,
+                        origin = %command.origin
+                    );
+                    let started = Instant::now();
+                    self.handle_command(command.clone()).instrument(span).await;
+                    self.record_timing("commands", &command, started);
+                    // Commands that don't change playback are done when
+                    // handled.
+                    let acted = self
+                        .player
+                        .last_action()
+                        .filter(|action| *action >= started)
+                        .unwrap_or_else(Instant::now);
+                    self.latency.record(&command, started, acted);
+                    if !self.is_dry_run(&command)
+                        && (command.payload == Command::Shutdown
+                            || command.payload == Command::Restart
+                            || command.payload == Command::PowerLost
+                            || command.payload == Command::PowerOff)
+                    {
                         break;
                     }
                 },
                 e = self.events.next() => if let Some(event) = e {
-                    self.handle_event(event).await
+                    let span = debug_span!("event", sequence = event.sequence);
+                    let started = Instant::now();
+                    self.handle_event(event.clone()).instrument(span).await;
+                    self.record_timing("events", &event, started);
                 },
                 complete => break,
             }
         }
 
-        Ok(())
+        match self.restart.take() {
+            Some(executable) => restart(&executable),
+            None => Ok(()),
+        }
     }
 
     // Should perform any privileged actions before the daemon reduces
     // privileges.
-    async fn init(data_dir: &Path, has_console: bool) -> MusicResult<MusicBox> {
-        let hw_config = HwConfig::load()?;
+    async fn init(options: &Options, has_console: bool) -> MusicResult<MusicBox> {
+        let data_dir = &options.data_dir;
+        let resume_on_start = check_boot(data_dir).log().unwrap_or(false);
+        restore_staged(data_dir)
+            .format_log(|e| format!("Unable to restore backup: {}", e))
+            .drop();
+        let hw_config = HwConfig::load(data_dir)?;
+        set_language(&hw_config.language);
+        #[cfg(feature = "rpi")]
+        set_gpio_defaults(hw_config.gpio);
+        configure_logs(&hw_config.logging, data_dir)
+            .format_log(|e| format!("Unable to set up log files: {}", e))
+            .drop();
+
+        let mut resume = ResumeState::load(data_dir);
+        let mut resume_on_start = resume_on_start;
+        if mark_running(data_dir) {
+            warn!("The music box did not shut down cleanly.");
+            if let Some(ref mut resume) = resume {
+                resume.rewind(Duration::from_secs(hw_config.crash_recovery.rewind));
+                resume_on_start = resume_on_start || hw_config.crash_recovery.auto_resume;
+            }
+        }
 
-        let app_state =
-            MutableAppState::new(StoredPlaylist::init(data_dir, hw_config.playlists).await?);
+        let mut library = Library::load(data_dir).await;
+        let cache = TrackCache::new(data_dir, &hw_config.cache)?;
+        let mut app_state = MutableAppState::new(
+            StoredPlaylist::init(data_dir, hw_config.playlists, &mut library, &cache).await?,
+        );
+        app_state.set_balance(hw_config.audio.balance);
+        persist_on_panic(data_dir, app_state.as_immutable());
+        cache.report_to(app_state.clone());
+        let analyzer = Analyzer::new(data_dir, &hw_config.analysis, app_state.clone()).await;
 
         let events = MessageReceiver::new();
 
+        let clock = monitor_clock(hw_config.clock.clone(), events.sender());
+        let metrics = Metrics::default();
+
         let mut music_box = MusicBox {
             server: Some(
                 TcpListener::bind(hw_config.server)
                     .await
                     .prefix("Unable to bind to server socket")?,
             ),
-            player: Player::new(events.sender(), 0.5)?,
+            control_server: match hw_config.control_server {
+                Some(addr) => Some(
+                    TcpListener::bind(addr)
+                        .await
+                        .prefix("Unable to bind to control server socket")?,
+                ),
+                None => None,
+            },
+            read_only: hw_config.read_only,
+            webapp_dir: hw_config.webapp_dir.clone(),
+            player: Player::new(
+                events.sender(),
+                0.5,
+                hw_config.audio.balance,
+                hw_config.audio.filters(None),
+                hw_config.audio.outputs.clone(),
+            )?,
+            watchdog: Watchdog::new(hw_config.watchdog.clone(), events.sender()),
             events,
             commands: Default::default(),
-            event_listeners: MessageSender::new(),
+            event_listeners: MessageSender::with_history(EVENT_HISTORY),
             state: app_state,
+            analyzer,
+            library: Arc::new(Mutex::new(library)),
+            cache,
+            advertisement: None,
+            group: None,
+            sources: Vec::new(),
+            forwarders: Vec::new(),
+            data_dir: data_dir.to_owned(),
+            resume,
+            pending_seek: None,
+            power_loss_grace: Duration::from_millis(DEFAULT_POWER_LOSS_GRACE),
+            auth: hw_config.auth.clone(),
+            speech: hw_config.speech.synth(data_dir)?,
+            audio: hw_config.audio.clone(),
+            sounds: Sounds::new(&hw_config.audio.sounds),
+            interruptions: 0,
+            resume_after_interruption: false,
+            ducked: false,
+            default_end_of_playlist: hw_config.end_of_playlist.clone(),
+            end_of_playlist: hw_config.end_of_playlist.clone(),
+            fallback_playlist: hw_config.fallback_playlist.clone(),
+            previous_track_threshold: Duration::from_secs(hw_config.previous_track_threshold),
+            surprises: hw_config.surprises.clone(),
+            favorites_playlist: hw_config.favorites_playlist.clone(),
+            announce_titles: false,
+            spoken_titles: Vec::new(),
+            idle_timer: None,
+            messages: match hw_config.messages {
+                Some(ref config) => Some(MessageRecorder::new(data_dir, config)?),
+                None => None,
+            },
+            recording_timer: None,
+            volume_announcement: None,
+            provisioning: Provisioning::new(hw_config.provisioning.clone()),
+            provisioning_timer: None,
+            update: hw_config.update.clone(),
+            sync_windows: hw_config.sync_windows.clone(),
+            clock,
+            sync_timer: None,
+            update_timer: None,
+            updating: false,
+            restart: None,
+            resume_on_start,
+            crash_recovery: hw_config.crash_recovery.clone(),
+            resume_saved: Instant::now(),
+            power_off_command: hw_config.power_off_command.clone(),
+            self_test: if hw_config.self_test {
+                Some(Default::default())
+            } else {
+                None
+            },
+            metrics: metrics.clone(),
+            handler_budget: Duration::from_millis(hw_config.handler_budget),
+            latency: LatencyTracker::new(metrics, hw_config.debug_latency),
+            last_playlist_start: None,
+            keypad: Keypad::new(hw_config.keypad.clone()),
+            guest: GuestMode::new(hw_config.guest.clone(), data_dir),
+            quiet_override: false,
+            profiles: Profiles::new(hw_config.profiles.clone(), data_dir),
+            dry_run: options.dry_run,
+            daemon: !has_console,
+            device: device::load(data_dir, hw_config.name.clone())?,
+            config_hash: hw_config.hash.clone(),
         };
 
+        if let Some(ref config) = hw_config.messages {
+            if music_box.state.stored_playlist(&config.playlist).is_none() {
+                warn!(
+                    "Messages are recorded into {} which is not a playlist.",
+                    config.playlist
+                );
+            }
+        }
+
+        let state = music_box.state.as_immutable();
+        music_box.add_event_sink(PlayLog::new(state, data_dir));
+
+        if !hw_config.hooks.hooks.is_empty() {
+            let state = music_box.state.as_immutable();
+            music_box.add_event_sink(Hooks::new(
+                hw_config.hooks.clone(),
+                state,
+                music_box.device.clone(),
+            )?);
+        }
+
+        music_box.announce_address(&hw_config.announce, &hw_config.server);
+        if let Some(ref config) = hw_config.group {
+            music_box.group = Group::new(config.clone(), &hw_config.server, &music_box.device)
+                .log()
+                .ok();
+        }
+        monitor_connectivity(hw_config.connectivity.clone(), music_box.events.sender());
+        monitor_media(music_box.state.clone(), music_box.events.sender());
+        music_box.queue_analysis();
+
         #[cfg(feature = "rpi")]
-        Buttons::init(&mut music_box, &hw_config.buttons)?;
+        {
+            let state = music_box.state.as_immutable();
+            music_box.add_event_sink(PlaylistLEDs::new(state));
+        }
 
-        if has_console {
-            music_box.add_command_stream(Keyboard::init(hw_config.keyboard));
-        }
-
-        match Signals::new(&[
-            signal_hook::SIGHUP,
-            signal_hook::SIGTERM,
-            signal_hook::SIGINT,
-            signal_hook::SIGQUIT,
-            signal_hook::SIGUSR1,
-            signal_hook::SIGUSR2,
-        ])
-        .and_then(|s| s.into_async())
+        #[cfg(feature = "rpi")]
         {
-            Ok(signals) => {
-                music_box.add_command_stream(signals.compat().filter_map(|r| {
-                    match r {
-                        Ok(signal_hook::SIGHUP) => ready(Some(Command::Reload.into())),
-                        Ok(signal_hook::SIGTERM) => ready(Some(Command::Shutdown.into())),
-                        Ok(signal_hook::SIGINT) => ready(Some(Command::Shutdown.into())),
-                        Ok(signal_hook::SIGQUIT) => ready(Some(Command::Shutdown.into())),
-                        Ok(signal_hook::SIGUSR1) => ready(Some(Command::Status.into())),
-                        Ok(signal_hook::SIGUSR2) => ready(Some(
-                            Command::StartPlaylist {
-                                name: String::from("red"),
-                                force: true,
-                            }
-                            .into(),
-                        )),
-                        Ok(signal) => {
-                            error!("Received unexpected signal {}.", signal);
-                            ready(None)
-                        }
-                        Err(e) => {
-                            error!("Received unknown error: {}", e);
-                            ready(None)
-                        }
-                    }
-                }));
+            let hardware: Arc<dyn Authorizer> = match hw_config.key_switch {
+                Some(ref config) if is_available() => {
+                    Arc::new(KeySwitch::new(config, hw_config.auth.elevated())?)
+                }
+                _ => Arc::new(Authorization::Playback),
+            };
+            music_box.guest.set_switch(hardware.clone());
+
+            // Without GPIO there is no hardware to test.
+            if let Some(self_test) = music_box.self_test.as_mut().filter(|_| is_available()) {
+                for button in hw_config.buttons.iter() {
+                    self_test.add_button(button.pin, button.on());
+                }
+                for led in music_box.state.as_immutable().leds() {
+                    self_test.add_led(led);
+                }
+                if let Some(led) = music_box.cache.led() {
+                    self_test.add_led(led.clone());
+                }
             }
-            Err(e) => {
-                error!("Unable to attach signal handler: {}", e);
+
+            let counters = ButtonCounters::load(
+                &options.data_dir,
+                music_box.state.clone(),
+                music_box.metrics.clone(),
+            );
+            music_box.add_command_source(Buttons::new(
+                hw_config.buttons,
+                options,
+                hardware.clone(),
+                counters,
+            ))?;
+            music_box.add_command_source(Encoders::new(hw_config.encoders, hardware))?;
+
+            if let Some(config) = hw_config.power_loss {
+                music_box.power_loss_grace = Duration::from_millis(config.grace);
+                music_box.add_command_source(PowerLoss::new(config))?;
+            }
+        }
+
+        #[cfg(not(feature = "rpi"))]
+        {
+            if is_raspberry_pi() {
+                warn!("Running on a Raspberry Pi without GPIO support, hardware controls are disabled.");
+            }
+        }
+
+        if has_console {
+            let mut keyboard = Keyboard::new(
+                hw_config.keyboard,
+                Duration::from_millis(hw_config.key_sequence_timeout),
+            );
+            if let Some(ref config) = hw_config.dashboard {
+                let dashboard = Dashboard::new(music_box.state.as_immutable());
+                if config.show {
+                    dashboard.toggle().log().drop();
+                }
+                keyboard.set_dashboard(config.key, dashboard.clone());
+                music_box.add_event_sink(dashboard);
+            }
+            music_box.add_command_source(keyboard)?;
+
+            #[cfg(feature = "desktop")]
+            {
+                let state = music_box.state.as_immutable();
+                music_box.add_event_sink(DesktopNotifications::new(state));
             }
         }
 
+        #[cfg(feature = "input")]
+        music_box.add_command_source(InputDevices::new(hw_config.input_devices))?;
+
+        music_box.add_command_source(Signals).log().drop();
+
+        let guest = music_box.guest.state();
+        music_box.state.set_guest(guest);
+        let profile = music_box.profiles.current_name();
+        music_box.state.set_profile(profile);
+        music_box.apply_volume_limit();
+
         Ok(music_box)
     }
 
-    async fn init_and_run(data_dir: &Path) -> VoidResult {
+    async fn init_and_run(options: &Options) -> VoidResult {
         // This is a non-daemonized run, set up the terminal for interactive use.
         enable_raw_mode().unwrap();
         TermLogger::init().unwrap();
 
-        let result = MusicBox::init(data_dir, true)
+        let result = MusicBox::init(options, true)
             .and_then(|music_box| music_box.run())
             .await;
 
@@ -294,14 +2373,78 @@ impl MusicBox {
         result
     }
 
-    pub fn block(data_dir: &Path) -> VoidResult {
+    pub fn doctor() -> VoidResult {
+        TermLogger::init()?;
+        speaker_test()
+    }
+
+    pub fn import(options: &Options, import: &ImportOptions) -> VoidResult {
+        TermLogger::init()?;
+        let mut runtime = Runtime::new().map_err(|e| e.to_string())?;
+
+        runtime.block_on(import_tracks(&options.data_dir, import))
+    }
+
+    pub fn play_dir(dir: &Path) -> VoidResult {
+        let mut runtime = Runtime::new().map_err(|e| e.to_string())?;
+
+        enable_raw_mode().unwrap();
+        TermLogger::init().unwrap();
+
+        let result = runtime.block_on(play_dir(dir));
+
+        disable_raw_mode().unwrap();
+        println!();
+
+        result
+    }
+
+    // Asks the running music box to scan a playlist and prints the result.
+    pub fn rescan(options: &Options, playlist: &str) -> VoidResult {
+        let hw_config = HwConfig::load(&options.data_dir)?;
+        let client = LocalClient::new(&hw_config)?;
+        let mut runtime = Runtime::new().map_err(|e| e.to_string())?;
+
+        let result = runtime.block_on(client.rescan(playlist))?;
+        println!("{} has {} tracks.", playlist, result.tracks);
+        for error in result.errors {
+            println!("{}: {}", error.path, error.message);
+        }
+        Ok(())
+    }
+
+    pub fn backup(options: &Options, file: &Path) -> VoidResult {
+        export(&options.data_dir, file)
+    }
+
+    pub fn export_history(
+        options: &Options,
+        export: &ExportOptions,
+        file: Option<&Path>,
+    ) -> VoidResult {
+        let data = export_plays(&options.data_dir, export)?;
+        match file {
+            Some(file) => write(file, data).prefix("Unable to write the export"),
+            None => {
+                print!("{}", data);
+                Ok(())
+            }
+        }
+    }
+
+    pub fn restore(options: &Options, file: &Path) -> VoidResult {
+        TermLogger::init()?;
+        import(&options.data_dir, file)
+    }
+
+    pub fn block(options: &Options) -> VoidResult {
         let mut runtime = Runtime::new().map_err(|e| e.to_string())?;
 
-        runtime.block_on(MusicBox::init_and_run(data_dir))
+        runtime.block_on(MusicBox::init_and_run(options))
     }
 
-    pub fn daemonize(data_dir: &Path) -> VoidResult {
-        let path = data_dir.to_owned();
+    pub fn daemonize(options: &Options) -> VoidResult {
+        let options = options.clone();
 
         // If forking fails we still run in the parent process. If it succeeds
         // the parent process exits immediately and any other results are being
@@ -309,10 +2452,11 @@ impl MusicBox {
         let result = Daemonize::new()
             .privileged_action(move || {
                 // This runs in the forked process.
+                TermLogger::init_detached().drop();
                 let mut runtime = Runtime::new().unwrap();
                 info!("Music box initialization.");
                 runtime
-                    .block_on(MusicBox::init(&path, false))
+                    .block_on(MusicBox::init(&options, false))
                     .format_log(|e| format!("Music box initialization failed: {}", e))
                     .expect("Initialization failed.")
             })
@@ -0,0 +1,140 @@
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::time::Duration;
+
+use libmdns::{Responder, Service};
+use musicbox_protocol::DeviceInfo;
+use serde::Deserialize;
+use tokio::net::TcpStream;
+use tokio::time::{delay_for, timeout};
+use tracing::{info, warn};
+
+use crate::error::{ErrorExt, MusicResult};
+use crate::events::{Event, MessageSender};
+
+const SERVICE_TYPE: &str = "_http._tcp";
+
+// Any routable address works here, connecting a UDP socket sends no packets.
+const ROUTE_PROBE: &str = "8.8.8.8:80";
+
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+fn default_interval() -> u64 {
+    10000
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AnnounceConfig {
+    // Advertise the web server over mDNS.
+    #[serde(default)]
+    pub mdns: bool,
+
+    // Blink the address on this playlist's LED, one octet at a time.
+    pub led: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConnectivityConfig {
+    // How often to check, in milliseconds.
+    #[serde(default = "default_interval")]
+    pub interval: u64,
+
+    // A "host:port" that must accept connections for the network to be
+    // considered up. Otherwise having a route off the device is enough.
+    pub check: Option<String>,
+}
+
+impl Default for ConnectivityConfig {
+    fn default() -> ConnectivityConfig {
+        ConnectivityConfig {
+            interval: default_interval(),
+            check: None,
+        }
+    }
+}
+
+// The address of the interface that traffic off the device goes through.
+fn routed_address() -> Option<IpAddr> {
+    let socket = UdpSocket::bind("0.0.0.0:0").ok()?;
+    socket.connect(ROUTE_PROBE).ok()?;
+    socket.local_addr().ok().map(|addr| addr.ip())
+}
+
+// Finds the address that other devices on the network can reach the server on.
+pub fn local_address(server: &SocketAddr) -> Option<IpAddr> {
+    if !server.ip().is_unspecified() {
+        return Some(server.ip());
+    }
+
+    routed_address()
+}
+
+async fn is_online(config: &ConnectivityConfig) -> bool {
+    match config.check {
+        Some(ref check) => matches!(
+            timeout(CHECK_TIMEOUT, TcpStream::connect(check.as_str())).await,
+            Ok(Ok(_))
+        ),
+        None => routed_address().is_some(),
+    }
+}
+
+// Periodically checks the network and sends an event whenever it goes down or
+// comes back up. The network is assumed to be up to begin with.
+pub fn monitor_connectivity(config: ConnectivityConfig, sender: MessageSender<Event>) {
+    tokio::spawn(async move {
+        let mut online = true;
+        loop {
+            delay_for(Duration::from_millis(config.interval)).await;
+
+            let now_online = is_online(&config).await;
+            if now_online != online {
+                online = now_online;
+                if online {
+                    info!("Network connection restored.");
+                } else {
+                    warn!("Network connection lost.");
+                }
+                sender.send(Event::ConnectivityChanged { online }.into());
+            }
+        }
+    });
+}
+
+// Keeps the mDNS service registered for as long as it is alive.
+pub struct Advertisement {
+    _service: Service,
+    _responder: Responder,
+}
+
+impl Advertisement {
+    pub fn new(server: &SocketAddr, device: &DeviceInfo) -> MusicResult<Advertisement> {
+        let responder = Responder::new().prefix("Unable to start mDNS responder")?;
+
+        let mut txt = vec![
+            String::from("path=/"),
+            format!("id={}", device.id),
+            format!("name={}", device.name),
+        ];
+        match local_address(server) {
+            Some(ip) => txt.push(format!("ip={}", ip)),
+            None => warn!("Unable to determine the local address for mDNS."),
+        }
+        let txt: Vec<&str> = txt.iter().map(String::as_str).collect();
+
+        let service = responder.register(
+            SERVICE_TYPE.to_owned(),
+            // Dots would split the name into labels.
+            device.name.replace('.', "-"),
+            server.port(),
+            &txt,
+        );
+        info!("Advertising webserver over mDNS.");
+
+        Ok(Advertisement {
+            _service: service,
+            _responder: responder,
+        })
+    }
+}
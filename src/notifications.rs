@@ -0,0 +1,68 @@
+use futures::future::{ready, BoxFuture, FutureExt};
+use notify_rust::Notification;
+use tokio::task::spawn_blocking;
+
+use crate::appstate::AppState;
+use crate::error::{ErrorExt, VoidResult};
+use crate::events::{Event, Message};
+use crate::i18n::tr;
+use crate::sinks::EventSink;
+
+const APP_NAME: &str = "Music Box";
+
+// Shows a desktop notification. This blocks while talking to the
+// notification daemon.
+pub fn notify(summary: &str, body: &str) -> VoidResult {
+    Notification::new()
+        .appname(APP_NAME)
+        .summary(summary)
+        .body(body)
+        .show()
+        .map(|_| ())
+        .prefix("Unable to show notification")
+}
+
+// Pops up a desktop notification whenever a new track starts playing.
+pub struct DesktopNotifications {
+    state: AppState,
+}
+
+impl DesktopNotifications {
+    pub fn new(state: AppState) -> DesktopNotifications {
+        DesktopNotifications { state }
+    }
+}
+
+impl EventSink for DesktopNotifications {
+    fn name(&self) -> &str {
+        "desktop notifications"
+    }
+
+    fn accepts(&self, event: &Event) -> bool {
+        *event == Event::PlaybackStarted
+    }
+
+    fn handle<'a>(&'a mut self, _event: &'a Message<Event>) -> BoxFuture<'a, VoidResult> {
+        let snapshot = self.state.snapshot();
+        let track = match snapshot
+            .play_state
+            .as_ref()
+            .and_then(|play_state| snapshot.playlist.get(play_state.position).cloned())
+        {
+            Some(track) => track,
+            None => return ready(Ok(())).boxed(),
+        };
+
+        let body = match track.artist {
+            Some(artist) => format!("{}\n{}", track.title, artist),
+            None => track.title,
+        };
+
+        async move {
+            spawn_blocking(move || notify(&tr("now-playing"), &body))
+                .await
+                .as_err()?
+        }
+        .boxed()
+    }
+}
@@ -0,0 +1,110 @@
+use std::env::temp_dir;
+use std::fs::{read, remove_file};
+use std::process::id;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use glib::object::{Cast, ObjectExt};
+use glib::value::Value;
+use gstreamer::{parse_launch, Bin, GstBinExt};
+use musicbox_protocol::PlayState;
+
+use crate::error::{ErrorExt, MusicResult};
+use crate::i18n::tr;
+use crate::player::play_to_end;
+use crate::track::Track;
+
+pub const DEFAULT_WIDTH: u32 = 400;
+pub const DEFAULT_HEIGHT: u32 = 300;
+pub const MAX_SIZE: u32 = 2000;
+
+const BAR_LENGTH: usize = 20;
+// Opaque black as ARGB.
+const TEXT_COLOR: u32 = 0xff00_0000;
+
+static NEXT_CARD: AtomicUsize = AtomicUsize::new(0);
+
+fn format_time(time: Duration) -> String {
+    let seconds = time.as_secs();
+    format!("{}:{:02}", seconds / 60, seconds % 60)
+}
+
+// The lines of text on the card. The playlist is missing for queues that
+// aren't one of the stored playlists.
+pub fn card_text(playing: Option<(Track, Option<String>, PlayState)>) -> String {
+    let (track, playlist, play_state) = match playing {
+        Some(playing) => playing,
+        None => return tr("nothing-playing"),
+    };
+
+    let info = track.info();
+    let mut lines = vec![info.title];
+    lines.extend(info.artist);
+    lines.extend(playlist);
+
+    let mut progress = match info.duration {
+        Some(length) if length.as_millis() > 0 => {
+            let filled = ((play_state.duration.as_secs_f64() / length.as_secs_f64())
+                * BAR_LENGTH as f64)
+                .round() as usize;
+            let filled = filled.min(BAR_LENGTH);
+            format!(
+                "{}{} {} / {}",
+                "█".repeat(filled),
+                "░".repeat(BAR_LENGTH - filled),
+                format_time(play_state.duration),
+                format_time(length)
+            )
+        }
+        _ => format_time(play_state.duration),
+    };
+    if play_state.paused {
+        progress.push_str(&format!(" ({})", tr("paused")));
+    }
+    lines.push(progress);
+
+    lines.join("\n")
+}
+
+// Draws the text in black on white and encodes it as a PNG, simple enough
+// for e-ink displays. This blocks until the image is ready.
+pub fn render(text: &str, width: u32, height: u32) -> MusicResult<Vec<u8>> {
+    gstreamer::init().prefix("Unable to initialize gstreamer")?;
+
+    let file = temp_dir().join(format!(
+        "musicbox-card-{}-{}.png",
+        id(),
+        NEXT_CARD.fetch_add(1, Ordering::SeqCst)
+    ));
+
+    let pipeline = parse_launch(&format!(
+        "videotestsrc pattern=white num-buffers=1 ! video/x-raw,width={},height={} ! \
+        textoverlay name=overlay valignment=top halignment=left line-alignment=left \
+        shaded-background=false color={} font-desc=\"Sans {}px\" ! \
+        videoconvert ! pngenc ! filesink name=sink",
+        width,
+        height,
+        TEXT_COLOR,
+        (height / 12).max(8)
+    ))
+    .prefix("Unable to create now playing pipeline")?;
+
+    let bin = pipeline
+        .clone()
+        .dynamic_cast::<Bin>()
+        .map_err(|_| String::from("Now playing pipeline is not a bin."))?;
+    bin.get_by_name("overlay")
+        .ok_or_else(|| String::from("Unable to get now playing overlay."))?
+        .set_property("text", &Value::from(text))
+        .prefix("Unable to set now playing text")?;
+    bin.get_by_name("sink")
+        .ok_or_else(|| String::from("Unable to get now playing sink."))?
+        .set_property("location", &Value::from(&file.display().to_string()))
+        .prefix("Unable to set now playing file")?;
+
+    let result = play_to_end(&pipeline, "now playing")
+        .and_then(|()| read(&file).prefix("Unable to read now playing image"));
+    remove_file(&file).drop();
+
+    result
+}
@@ -0,0 +1,166 @@
+use std::fs::read_dir;
+use std::path::{Path, PathBuf};
+
+use crossterm::event::{Event as TermEvent, EventStream, KeyCode, KeyEvent, KeyModifiers};
+use futures::select;
+use futures::stream::StreamExt;
+use tracing::info;
+
+use crate::error::{ErrorExt, MusicResult, VoidResult};
+use crate::events::{Event, MessageReceiver};
+use crate::player::Player;
+use crate::storage::is_track;
+
+const START_VOLUME: f64 = 0.5;
+const VOLUME_STEP: f64 = 0.05;
+
+// What a key does while playing a directory.
+enum Key {
+    PlayPause,
+    Next,
+    Previous,
+    VolumeUp,
+    VolumeDown,
+    Quit,
+}
+
+impl Key {
+    fn from_event(event: KeyEvent) -> Option<Key> {
+        match event.code {
+            KeyCode::Char('c') if event.modifiers.contains(KeyModifiers::CONTROL) => {
+                Some(Key::Quit)
+            }
+            KeyCode::Char(' ') => Some(Key::PlayPause),
+            KeyCode::Right | KeyCode::Char('n') => Some(Key::Next),
+            KeyCode::Left | KeyCode::Char('p') => Some(Key::Previous),
+            KeyCode::Up | KeyCode::Char('+') => Some(Key::VolumeUp),
+            KeyCode::Down | KeyCode::Char('-') => Some(Key::VolumeDown),
+            KeyCode::Esc | KeyCode::Char('q') => Some(Key::Quit),
+            _ => None,
+        }
+    }
+}
+
+// The tracks in the directory in name order.
+fn tracks(dir: &Path) -> MusicResult<Vec<PathBuf>> {
+    let mut tracks: Vec<PathBuf> = read_dir(dir)
+        .prefix("Unable to read directory")?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().map(|t| t.is_file()).unwrap_or(false))
+        .filter(|entry| is_track(&entry.file_name().to_string_lossy()))
+        .map(|entry| entry.path())
+        .collect();
+    tracks.sort();
+
+    Ok(tracks)
+}
+
+struct DirectoryPlayer {
+    player: Player,
+    tracks: Vec<PathBuf>,
+    position: usize,
+    volume: f64,
+    paused: bool,
+}
+
+impl DirectoryPlayer {
+    // Returns false once there is nothing left to play.
+    fn play(&mut self, position: usize) -> bool {
+        let track = match self.tracks.get(position) {
+            Some(track) => track,
+            None => return false,
+        };
+
+        info!(
+            "Playing {} of {}: {}.",
+            position + 1,
+            self.tracks.len(),
+            track.display()
+        );
+        self.position = position;
+        self.paused = false;
+        self.player.start(track).log().drop();
+        true
+    }
+
+    fn set_volume(&mut self, volume: f64) {
+        self.volume = volume;
+        self.player.set_volume(self.volume);
+        info!("Volume {}%.", (self.volume * 100.0).round());
+    }
+
+    // Returns false when playback should end.
+    fn key(&mut self, key: Key) -> bool {
+        match key {
+            Key::PlayPause => {
+                self.paused = !self.paused;
+                if self.paused {
+                    self.player.pause().log().drop();
+                } else {
+                    self.player.play().log().drop();
+                }
+                true
+            }
+            Key::Next => self.play(self.position + 1),
+            Key::Previous => self.play(self.position.saturating_sub(1)),
+            Key::VolumeUp => {
+                self.set_volume((self.volume + VOLUME_STEP).min(1.0));
+                true
+            }
+            Key::VolumeDown => {
+                self.set_volume((self.volume - VOLUME_STEP).max(0.0));
+                true
+            }
+            Key::Quit => false,
+        }
+    }
+}
+
+// Plays the tracks in a directory with simple keyboard controls, without any
+// of the music box's configuration, hardware or servers. Returns once the
+// last track finishes.
+pub async fn play_dir(dir: &Path) -> VoidResult {
+    let tracks = tracks(dir)?;
+    if tracks.is_empty() {
+        return Err(format!("There are no tracks in {}.", dir.display()));
+    }
+
+    info!(
+        "Keys: space pauses, left and right change track, up and down change the volume, q quits."
+    );
+
+    let mut events: MessageReceiver<Event> = Default::default();
+    let mut player = DirectoryPlayer {
+        player: Player::new(events.sender(), START_VOLUME, 0.0, None, Vec::new())?,
+        tracks,
+        position: 0,
+        volume: START_VOLUME,
+        paused: false,
+    };
+    let mut keys = EventStream::new().fuse();
+
+    let mut playing = player.play(0);
+    while playing {
+        select! {
+            k = keys.next() => playing = match k {
+                Some(Ok(TermEvent::Key(key))) => match Key::from_event(key) {
+                    Some(key) => player.key(key),
+                    None => true,
+                },
+                Some(_) => true,
+                None => false,
+            },
+            e = events.next() => playing = match e.map(|event| event.payload) {
+                Some(Event::PlaybackEnded) => player.play(player.position + 1),
+                Some(Event::Error { message }) => {
+                    info!("{}", message);
+                    true
+                }
+                Some(_) => true,
+                None => false,
+            },
+        }
+    }
+
+    player.player.stop()
+}
@@ -0,0 +1,14 @@
+use std::path::PathBuf;
+
+// Options that control how the music box runs, generally from the command line.
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    pub data_dir: PathBuf,
+    // Writes every raw GPIO input event to this file.
+    pub record_gpio: Option<PathBuf>,
+    // Feeds the GPIO input events from this file through the button handling
+    // instead of using the real hardware.
+    pub replay_gpio: Option<PathBuf>,
+    // Commands from hardware controls are logged but not run.
+    pub dry_run: bool,
+}
@@ -0,0 +1,71 @@
+use std::backtrace::Backtrace;
+use std::panic::{set_hook, take_hook};
+use std::path::{Path, PathBuf};
+use std::process::abort;
+use std::sync::Mutex;
+
+use crossterm::terminal::disable_raw_mode;
+use lazy_static::lazy_static;
+use tracing::error;
+
+use crate::appstate::AppState;
+use crate::error::ErrorExt;
+#[cfg(feature = "rpi")]
+use crate::hardware::gpio::led;
+use crate::resume::ResumeState;
+
+struct Persisted {
+    data_dir: PathBuf,
+    state: AppState,
+}
+
+lazy_static! {
+    static ref PERSISTED: Mutex<Option<Persisted>> = Mutex::new(None);
+}
+
+// Saves where playback was if the music box panics.
+pub fn persist_on_panic(data_dir: &Path, state: AppState) {
+    *PERSISTED.lock().unwrap() = Some(Persisted {
+        data_dir: data_dir.to_owned(),
+        state,
+    });
+}
+
+fn save_state() {
+    let persisted = match PERSISTED.try_lock() {
+        Ok(persisted) => persisted,
+        Err(_) => return,
+    };
+
+    if let Some(ref persisted) = *persisted {
+        if let Some(snapshot) = persisted.state.try_snapshot() {
+            ResumeState::save(
+                &persisted.data_dir,
+                ResumeState::from_snapshot(&snapshot).as_ref(),
+            )
+            .log()
+            .drop();
+        }
+    }
+}
+
+// A panic can leave the terminal in raw mode and LEDs lit. This puts things
+// back as best it can, saves the playback state and then aborts rather than
+// carrying on half working.
+pub fn install_hook() {
+    let default_hook = take_hook();
+    set_hook(Box::new(move |info| {
+        disable_raw_mode().drop();
+        #[cfg(feature = "rpi")]
+        led::all_off();
+        save_state();
+
+        default_hook(info);
+        error!(
+            "Music box panicked: {}\n{}",
+            info,
+            Backtrace::force_capture()
+        );
+        abort();
+    }));
+}
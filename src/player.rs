@@ -1,6 +1,6 @@
 use std::path::Path;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use glib::error::Error;
 use glib::object::{Cast, ObjectExt};
@@ -8,16 +8,119 @@ use glib::value::Value;
 use gstreamer::message;
 use gstreamer::message::MessageView;
 use gstreamer::{
-    init, Bus, ClockTime, ElementExt, ElementExtManual, ElementFactory, GstBinExt, GstObjectExt,
-    Pipeline, State,
+    init, parse_bin_from_description, parse_launch, Bus, ClockTime, Element, ElementExt,
+    ElementExtManual, ElementFactory, GstBinExt, GstObjectExt, MessageType, Object, Pipeline,
+    SeekFlags, State,
 };
 use gstreamer_audio::{StreamVolume, StreamVolumeExt, StreamVolumeFormat};
-use log::{error, info, trace, warn};
+use tracing::{error, info, info_span, trace, warn, Span};
 
+use crate::audio::OutputConfig;
 use crate::error::{ErrorExt, MusicResult, VoidResult};
 use crate::events::{Event, Message, MessageSender};
 
 const BUS_POLL_TIMEOUT: u64 = 500;
+const BALANCE_ELEMENT: &str = "balance";
+// The loudest playbin allows, as a linear multiplier.
+const MAX_VOLUME: f64 = 10.0;
+
+// Roughly one second of audio at the test source's default buffer size.
+const TEST_TONE_BUFFERS: u32 = 44;
+const TEST_TONES: [(&str, f64, u32); 3] = [
+    ("left", -1.0, 440),
+    ("right", 1.0, 660),
+    ("center", 0.0, 550),
+];
+
+// Plays a pipeline through to the end, blocking until it completes.
+pub fn play_to_end(pipeline: &Element, name: &str) -> VoidResult {
+    let bus = pipeline
+        .get_bus()
+        .ok_or_else(|| format!("Unable to get {} bus.", name))?;
+
+    pipeline
+        .set_state(State::Playing)
+        .prefix(format!("Unable to play {}", name))?;
+    let result =
+        match bus.timed_pop_filtered(ClockTime::none(), &[MessageType::Eos, MessageType::Error]) {
+            Some(message) => match message.view() {
+                MessageView::Error(e) => Err(format!("{} failed: {}", name, e.get_error())),
+                _ => Ok(()),
+            },
+            None => Ok(()),
+        };
+    pipeline
+        .set_state(State::Null)
+        .prefix(format!("Unable to stop {}", name))?;
+
+    result
+}
+
+// About a tenth of a second.
+const BEEP_BUFFERS: u32 = 4;
+const BEEP_VOLUME: f64 = 0.3;
+
+// Plays a short tone over anything else playing. This blocks until the tone
+// has played.
+pub fn beep(frequency: u32) -> VoidResult {
+    beep_at(frequency, BEEP_VOLUME)
+}
+
+// Like beep but at a given volume between 0 and 1.
+pub fn beep_at(frequency: u32, volume: f64) -> VoidResult {
+    init().prefix("Unable to initialize gstreamer")?;
+
+    let pipeline = parse_launch(&format!(
+        "audiotestsrc wave=sine freq={} volume={} num-buffers={} ! audioconvert ! autoaudiosink",
+        frequency, volume, BEEP_BUFFERS
+    ))
+    .prefix("Unable to create beep pipeline")?;
+
+    play_to_end(&pipeline, "beep")
+}
+
+fn play_tone(panorama: f64, frequency: u32) -> VoidResult {
+    let pipeline = parse_launch(&format!(
+        "audiotestsrc wave=sine freq={} num-buffers={} ! audiopanorama panorama={} ! audioconvert ! autoaudiosink",
+        frequency, TEST_TONE_BUFFERS, panorama
+    ))
+    .prefix("Unable to create test tone pipeline")?;
+
+    play_to_end(&pipeline, "test tone")
+}
+
+// Plays a single file outside of the playlist. This blocks until the file has
+// finished playing.
+pub fn play_file(path: &Path, volume: f64) -> VoidResult {
+    init().prefix("Unable to initialize gstreamer")?;
+    info!("Playing {}.", path.display());
+
+    let playbin =
+        ElementFactory::make("playbin", None).prefix("Unable to create playback element")?;
+    playbin
+        .set_property("uri", &Value::from(&format!("file://{}", path.display())))
+        .prefix("Unable to load source file")?;
+    playbin
+        .clone()
+        .dynamic_cast::<StreamVolume>()
+        .map_err(|_| String::from("Unable to get volume controller."))?
+        .set_volume(StreamVolumeFormat::Cubic, volume);
+
+    play_to_end(&playbin, "file playback")
+}
+
+// Plays a tone through the left, right and then both speakers. This blocks
+// until all the tones have played.
+pub fn speaker_test() -> VoidResult {
+    init().prefix("Unable to initialize gstreamer")?;
+
+    for (name, panorama, frequency) in TEST_TONES.iter() {
+        info!("Playing test tone through the {} speaker.", name);
+        play_tone(*panorama, *frequency)?;
+    }
+
+    Ok(())
+}
 
 #[derive(Debug, PartialEq)]
 enum PlaybackState {
@@ -30,26 +133,112 @@ enum PlaybackState {
 struct Playback {
     pipeline: Pipeline,
     volume: StreamVolume,
+    balance: Element,
+}
+
+struct VolumeRamp {
+    started: Instant,
+    duration: Duration,
+    start: f64,
+}
+
+// Checks that an output can be opened, which fails if the device is missing.
+fn check_output(output: &OutputConfig) -> VoidResult {
+    let sink = parse_bin_from_description(&output.sink, true)
+        .prefix(format!("Unable to create audio output {}", output.name))?;
+    let result = sink
+        .set_state(State::Ready)
+        .map(|_| ())
+        .prefix(format!("Unable to open audio output {}", output.name));
+    sink.set_state(State::Null).drop();
+    result
 }
 
 pub struct Player {
     playback: Option<Playback>,
     event_sender: MessageSender<Event>,
     volume: f64,
+    ramp: Option<VolumeRamp>,
+    // Scales the volume for the playlist being played, as a linear multiplier.
+    gain: f64,
+    balance: f64,
+    filters: Option<String>,
+    outputs: Vec<OutputConfig>,
+    // The index of the output in use, the system default is used if missing.
+    output: Option<usize>,
+    // When playback was last changed, for measuring how long commands take to
+    // be heard.
+    last_action: Option<Instant>,
 }
 
 impl Player {
-    pub fn new(sender: MessageSender<Event>, vol: f64) -> MusicResult<Player> {
+    pub fn new(
+        sender: MessageSender<Event>,
+        vol: f64,
+        balance: f64,
+        filters: Option<String>,
+        outputs: Vec<OutputConfig>,
+    ) -> MusicResult<Player> {
         init().prefix("Unable to initialize gstreamer")?;
 
-        Ok(Player {
+        let mut player = Player {
             playback: None,
             event_sender: sender,
             volume: vol,
-        })
+            ramp: None,
+            gain: 1.0,
+            balance,
+            filters,
+            outputs,
+            output: None,
+            last_action: None,
+        };
+        if !player.outputs.is_empty() {
+            player.select_output(None);
+        }
+
+        Ok(player)
+    }
+
+    // Switches to the first output that works, skipping the one that failed.
+    fn select_output(&mut self, failed: Option<usize>) {
+        self.output = (0..self.outputs.len())
+            .filter(|index| Some(*index) != failed)
+            .find(|index| check_output(&self.outputs[*index]).log().is_ok());
+
+        let name = match self.output {
+            Some(index) => {
+                info!("Using audio output {}.", self.outputs[index].name);
+                Some(self.outputs[index].name.clone())
+            }
+            None => {
+                error!("None of the audio outputs work, using the system default.");
+                None
+            }
+        };
+        self.event_sender
+            .send(Event::AudioOutputChanged { name }.into());
+    }
+
+    // Moves on to another output after the one in use fails. Tracks started
+    // after this use the new output.
+    pub fn fail_over(&mut self) {
+        if self.outputs.is_empty() {
+            return;
+        }
+
+        if let Some(index) = self.output {
+            warn!("Audio output {} failed.", self.outputs[index].name);
+        }
+        self.select_output(self.output);
     }
 
     pub fn start(&mut self, path: &Path) -> VoidResult {
+        // Everything that happens while this track plays, including on the
+        // bus listener's thread, is logged in this span.
+        let span = info_span!("playback", track = %path.display());
+        let _entered = span.enter();
+
         info!("Starting playback of {}.", path.display());
         if let Some(playback) = self.playback.take() {
             playback
@@ -71,25 +260,67 @@ impl Player {
             .set_property("uri", &Value::from(&format!("file://{}", path.display())))
             .prefix("Unable to load source file")?;
 
+        // The balance is always the last filter so it can be changed while
+        // playing.
+        let filters = format!(
+            "{} ! audiopanorama name={} method=simple",
+            self.filters.as_deref().unwrap_or("audioconvert"),
+            BALANCE_ELEMENT
+        );
+        let bin =
+            parse_bin_from_description(&filters, true).prefix("Unable to create audio filters")?;
+        let balance = bin
+            .get_by_name(BALANCE_ELEMENT)
+            .ok_or_else(|| String::from("Unable to get balance controller."))?;
+        playbin
+            .set_property("audio-filter", &bin)
+            .prefix("Unable to add audio filters")?;
+
+        let sink = match self.output {
+            Some(index) => {
+                let sink = parse_bin_from_description(&self.outputs[index].sink, true)
+                    .prefix("Unable to create audio output")?;
+                playbin
+                    .set_property("audio-sink", &sink)
+                    .prefix("Unable to set audio output")?;
+                Some(sink.upcast::<Element>())
+            }
+            None => None,
+        };
+
         let volume = playbin
             .dynamic_cast::<StreamVolume>()
             .map_err(|_| String::from("Unable to get volume controller."))?;
         self.playback = Some(Playback {
             pipeline: pipeline.clone(),
             volume,
+            balance,
         });
-        self.set_volume(self.volume);
+        self.apply_volume();
+        self.set_balance(self.balance);
 
-        PlaybackListener::init(pipeline.clone(), self.event_sender.clone())?;
+        PlaybackListener::init(pipeline.clone(), sink, self.event_sender.clone())?;
 
         pipeline
             .set_state(State::Playing)
             .prefix("Unable to start playback")?;
+        self.last_action = Some(Instant::now());
 
         Ok(())
     }
 
+    pub fn last_action(&self) -> Option<Instant> {
+        self.last_action
+    }
+
+    // Sets the gstreamer description of the filters to use for tracks started
+    // after this.
+    pub fn set_filters(&mut self, filters: Option<String>) {
+        self.filters = filters;
+    }
+
     pub fn stop(&mut self) -> VoidResult {
+        self.last_action = Some(Instant::now());
         if let Some(playback) = self.playback.take() {
             playback
                 .pipeline
@@ -100,6 +331,7 @@ impl Player {
     }
 
     pub fn play(&mut self) -> VoidResult {
+        self.last_action = Some(Instant::now());
         if let Some(ref playback) = self.playback {
             playback
                 .pipeline
@@ -110,6 +342,7 @@ impl Player {
     }
 
     pub fn pause(&mut self) -> VoidResult {
+        self.last_action = Some(Instant::now());
         if let Some(ref playback) = self.playback {
             playback
                 .pipeline
@@ -119,12 +352,129 @@ impl Player {
         Ok(())
     }
 
+    // Moves the playback position by the given offset, clamped to the bounds
+    // of the current track.
+    pub fn seek(&mut self, millis: i64) -> VoidResult {
+        self.last_action = Some(Instant::now());
+        if let Some(ref playback) = self.playback {
+            let position = playback
+                .pipeline
+                .query_position::<ClockTime>()
+                .and_then(|c| c.nseconds())
+                .ok_or_else(|| String::from("Unable to get the playback position."))?;
+            let duration = playback
+                .pipeline
+                .query_duration::<ClockTime>()
+                .and_then(|c| c.nseconds())
+                .unwrap_or(u64::max_value());
+
+            let offset = millis.abs() as u64 * 1_000_000;
+            let target = if millis < 0 {
+                position.saturating_sub(offset)
+            } else {
+                position.saturating_add(offset).min(duration)
+            };
+
+            playback
+                .pipeline
+                .seek_simple(
+                    SeekFlags::FLUSH | SeekFlags::KEY_UNIT,
+                    ClockTime::from_nseconds(target),
+                )
+                .prefix("Unable to seek")?;
+        }
+        Ok(())
+    }
+
+    pub fn seek_to(&mut self, position: Duration) -> VoidResult {
+        self.last_action = Some(Instant::now());
+        if let Some(ref playback) = self.playback {
+            playback
+                .pipeline
+                .seek_simple(
+                    SeekFlags::FLUSH | SeekFlags::KEY_UNIT,
+                    ClockTime::from_nseconds(position.as_nanos() as u64),
+                )
+                .prefix("Unable to seek")?;
+        }
+        Ok(())
+    }
+
+    // The volume to play at right now, taking any ramp into account.
+    fn current_volume(&self) -> f64 {
+        match self.ramp {
+            Some(ref ramp) => {
+                let elapsed = ramp.started.elapsed();
+                if elapsed >= ramp.duration {
+                    self.volume
+                } else {
+                    let progress = elapsed.as_secs_f64() / ramp.duration.as_secs_f64();
+                    ramp.start + (self.volume - ramp.start) * progress
+                }
+            }
+            None => self.volume,
+        }
+    }
+
+    fn apply_volume(&mut self) {
+        // The volume is on a cubic scale, the gain has to be applied to the
+        // linear volume.
+        let volume = (self.current_volume().powi(3) * self.gain).min(MAX_VOLUME);
+        if let Some(ref playback) = self.playback {
+            playback
+                .volume
+                .set_volume(StreamVolumeFormat::Linear, volume);
+        }
+    }
+
+    // Sets the gain in dB for the tracks played from now on.
+    pub fn set_gain(&mut self, db: f64) {
+        self.gain = 10f64.powf(db / 20.0);
+        self.apply_volume();
+    }
+
     pub fn set_volume(&mut self, volume: f64) {
+        self.last_action = Some(Instant::now());
         self.volume = volume;
+        self.ramp = None;
+        self.apply_volume();
+    }
+
+    // Drops the volume to the start level and raises it back to the current
+    // volume over the duration. Call `update_ramp` periodically while this
+    // happens.
+    pub fn ramp_volume(&mut self, start: f64, duration: Duration) {
+        if start >= self.volume || duration == Duration::from_secs(0) {
+            return;
+        }
+
+        self.ramp = Some(VolumeRamp {
+            started: Instant::now(),
+            duration,
+            start,
+        });
+        self.apply_volume();
+    }
+
+    pub fn update_ramp(&mut self) {
+        if let Some(ref ramp) = self.ramp {
+            let finished = ramp.started.elapsed() >= ramp.duration;
+            self.apply_volume();
+            if finished {
+                self.ramp = None;
+            }
+        }
+    }
+
+    pub fn set_balance(&mut self, balance: f64) {
+        self.balance = balance;
         if let Some(ref playback) = self.playback {
             playback
-                .volume
-                .set_volume(StreamVolumeFormat::Cubic, volume);
+                .balance
+                .set_property("panorama", &(balance as f32))
+                .prefix("Unable to set balance")
+                .log()
+                .drop();
         }
     }
 }
@@ -132,14 +482,21 @@ impl Player {
 struct PlaybackListener {
     sender: MessageSender<Event>,
     pipeline: Pipeline,
+    // The configured output, if one is in use.
+    sink: Option<Element>,
     bus: Bus,
     state: PlaybackState,
 }
 
 impl PlaybackListener {
-    pub fn init(pipeline: Pipeline, sender: MessageSender<Event>) -> VoidResult {
+    pub fn init(
+        pipeline: Pipeline,
+        sink: Option<Element>,
+        sender: MessageSender<Event>,
+    ) -> VoidResult {
         let listener = PlaybackListener {
             sender,
+            sink,
             bus: pipeline
                 .get_bus()
                 .ok_or_else(|| String::from("Unable to get playback bus."))?,
@@ -147,7 +504,8 @@ impl PlaybackListener {
             pipeline,
         };
 
-        thread::spawn(move || listener.listen());
+        let span = Span::current();
+        thread::spawn(move || span.in_scope(|| listener.listen()));
 
         Ok(())
     }
@@ -162,9 +520,17 @@ impl PlaybackListener {
         None
     }
 
-    fn error(&self, error: Error) -> Option<Message<Event>> {
+    fn error(&self, error: Error, source: Option<Object>) -> Option<Message<Event>> {
         error!("Bus reported error: {}", error);
-        None
+
+        match (&self.sink, source) {
+            (Some(sink), Some(source))
+                if source == sink.clone().upcast::<Object>() || source.has_as_ancestor(sink) =>
+            {
+                Some(Event::AudioOutputFailed.into())
+            }
+            _ => None,
+        }
     }
 
     fn state_changed(&mut self, sc: message::StateChanged) -> Option<Message<Event>> {
@@ -229,7 +595,7 @@ impl PlaybackListener {
                 Some(message) => match message.view() {
                     MessageView::Info(m) => self.info(m.get_error()),
                     MessageView::Warning(m) => self.warning(m.get_error()),
-                    MessageView::Error(m) => self.error(m.get_error()),
+                    MessageView::Error(m) => self.error(m.get_error(), message.get_src()),
                     MessageView::StateChanged(sc) => self.state_changed(sc),
                     MessageView::Eos(eos) => self.end_of_stream(eos),
 
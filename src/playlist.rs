@@ -1,50 +1,180 @@
+use std::collections::HashMap;
 use std::io;
+use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 
 use futures::stream::StreamExt;
-use log::{debug, error, info};
-use serde::{Deserialize, Serialize};
-use tokio::fs::{create_dir_all, metadata, read_dir};
+use musicbox_protocol::{PlaylistInfo, ScanError, ScanResult};
+use serde::Deserialize;
+use tokio::fs::{canonicalize, create_dir_all, metadata, read_dir};
+use tokio::time::delay_for;
+use tracing::{debug, error, info, info_span, warn, Instrument};
 
-use crate::error::{MusicResult, VoidResult};
+use crate::analysis::Analyzer;
+use crate::appstate::MutableAppState;
+use crate::error::{ErrorExt, MusicResult, VoidResult};
+use crate::events::{Event, MessageSender};
+#[cfg(feature = "rpi")]
+use crate::hardware::gpio::is_available;
 #[cfg(feature = "rpi")]
 use crate::hardware::gpio::led::{LEDConfig, LED};
+use crate::library::{Library, TrackQuery};
+use crate::speech::Speech;
+use crate::storage::{is_track, StorageConfig, TrackCache, TrackStorage, CACHE_DIR};
 use crate::track::Track;
 
+const MEDIA_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+// Automatic gain never changes the volume by more than this many dB.
+const MAX_AUTO_GAIN: f64 = 12.0;
+
+fn default_target_loudness() -> f64 {
+    -18.0
+}
+
+// Only tracks in the playlists and speech the music box made itself can be
+// played by path, anything else on the filesystem is off limits.
+pub fn is_playable_file(data_dir: &Path, speech: &Speech, path: &Path) -> bool {
+    let path = match std::fs::canonicalize(path) {
+        Ok(path) => path,
+        Err(_) => return false,
+    };
+
+    let in_roots = [data_dir.join("playlists"), data_dir.join(CACHE_DIR)]
+        .iter()
+        .filter_map(|root| std::fs::canonicalize(root).ok())
+        .any(|root| path.starts_with(root));
+    in_roots || speech.is_own_file(&path)
+}
+
+fn scan_error(path: &Path, message: &str) -> ScanError {
+    ScanError {
+        path: path.display().to_string(),
+        message: message.to_owned(),
+    }
+}
+
+// What to do when the last track of a playlist finishes.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "type")]
+pub enum EndOfPlaylist {
+    // Stop playing but keep the playlist queued.
+    Stop,
+    // Stop playing and clear the playlist.
+    Clear,
+    Repeat,
+    // Start playing another stored playlist.
+    Playlist { name: String },
+    // Clear the playlist and power off if nothing else is played within this
+    // many seconds.
+    PowerOff { idle: u64 },
+}
+
+impl Default for EndOfPlaylist {
+    fn default() -> EndOfPlaylist {
+        EndOfPlaylist::Clear
+    }
+}
+
+// Raises or lowers the volume of a whole playlist so that switching between
+// playlists of deliberately different loudness doesn't need the volume
+// changing.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "type")]
+pub enum PlaylistGain {
+    // A fixed change in dB.
+    Fixed {
+        db: f64,
+    },
+    // Brings the average loudness of the analysed tracks to the target level
+    // in dB.
+    Auto {
+        #[serde(default = "default_target_loudness")]
+        target: f64,
+    },
+}
+
 #[derive(Debug, Clone, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct PlaylistConfig {
     pub name: String,
     pub title: String,
+    // The audio pipeline to play this playlist through.
+    pub pipeline: Option<String>,
+    // Overrides the default end of playlist behaviour.
+    pub end_of_playlist: Option<EndOfPlaylist>,
+    // Starting the playlist twice within this many milliseconds restarts it
+    // from the first track. Otherwise starting it again just resumes it.
+    pub double_press: Option<u64>,
+    // Lists the tracks from a storage backend instead of the playlist
+    // directory.
+    pub storage: Option<StorageConfig>,
+    // Picks the tracks from everything in the library instead of having a
+    // directory of its own.
+    pub query: Option<TrackQuery>,
+    // Speaks each track's title before it plays. A wav file with the same
+    // name as the track is played instead if there is one.
+    #[serde(default)]
+    pub announce_titles: bool,
+    // The playlist directory is, or links to, a directory on removable media
+    // such as a USB stick. The playlist is unavailable while the media is
+    // missing and is scanned again when it comes back.
+    #[serde(default)]
+    pub removable: bool,
+    pub gain: Option<PlaylistGain>,
     #[cfg(feature = "rpi")]
-    #[serde(skip)]
-    pub led: LEDConfig,
+    #[serde(default)]
+    pub led: Option<LEDConfig>,
 }
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone)]
 pub struct StoredPlaylist {
-    #[serde(skip)]
     root: PathBuf,
     name: String,
     tracks: Vec<Track>,
+    pipeline: Option<String>,
+    end_of_playlist: Option<EndOfPlaylist>,
+    double_press: Option<Duration>,
+    storage: Option<Arc<dyn TrackStorage>>,
+    query: Option<TrackQuery>,
+    announce_titles: bool,
+    gain: Option<PlaylistGain>,
+    removable: bool,
+    // Whether the removable media was there at the last scan.
+    media_present: bool,
+    // What went wrong in the last scan.
+    errors: Vec<ScanError>,
     #[cfg(feature = "rpi")]
-    pub led: LED,
+    led: Option<LED>,
 }
 
 impl StoredPlaylist {
     pub async fn init(
         data_dir: &Path,
         configs: Vec<PlaylistConfig>,
+        library: &mut Library,
+        cache: &TrackCache,
     ) -> MusicResult<Vec<StoredPlaylist>> {
+        // Queries can only find tracks once the other playlists are scanned.
+        let mut configs = configs;
+        configs.sort_by_key(|config| config.query.is_some());
+
         let mut collection = Vec::with_capacity(configs.len());
         for config in configs {
-            let playlist = StoredPlaylist::new(data_dir, &config).await?;
+            let playlist = StoredPlaylist::new(data_dir, &config, library, cache).await?;
             collection.push(playlist);
         }
+        library.save().await.log().drop();
         Ok(collection)
     }
 
-    pub async fn new(data_dir: &Path, config: &PlaylistConfig) -> MusicResult<StoredPlaylist> {
+    pub async fn new(
+        data_dir: &Path,
+        config: &PlaylistConfig,
+        library: &mut Library,
+        cache: &TrackCache,
+    ) -> MusicResult<StoredPlaylist> {
         let mut root = data_dir.to_owned();
         root.push("playlists".parse::<PathBuf>().map_err(|e| e.to_string())?);
         root.push(config.name.parse::<PathBuf>().map_err(|e| e.to_string())?);
@@ -55,7 +185,16 @@ impl StoredPlaylist {
             root.display(),
         );
 
-        if let Err(e) = metadata(&root).await {
+        if config.query.is_some() && config.storage.is_some() {
+            return Err(format!(
+                "Playlist {} cannot have both storage and a query.",
+                config.name
+            ));
+        }
+
+        // Playlists made from queries don't need a directory and the
+        // directory for removable media must not be created in its place.
+        if let (None, false, Err(e)) = (&config.query, config.removable, metadata(&root).await) {
             if e.kind() == io::ErrorKind::NotFound {
                 if let Err(e) = create_dir_all(&root).await {
                     error!(
@@ -78,59 +217,292 @@ impl StoredPlaylist {
             root,
             name: config.name.clone(),
             tracks: Vec::new(),
+            pipeline: config.pipeline.clone(),
+            end_of_playlist: config.end_of_playlist.clone(),
+            double_press: config.double_press.map(Duration::from_millis),
+            storage: match config.storage {
+                Some(ref storage) => Some(storage.storage()?),
+                None => None,
+            },
+            query: config.query.clone(),
+            announce_titles: config.announce_titles,
+            gain: config.gain.clone(),
+            removable: config.removable,
+            media_present: true,
+            errors: Vec::new(),
             #[cfg(feature = "rpi")]
-            led: LED::new(&config.led)?,
+            led: match config.led {
+                Some(ref led) if is_available() => Some(LED::new(led)?),
+                _ => None,
+            },
         };
-        playlist.rescan().await?;
+        playlist.rescan(library, cache).await?;
 
         Ok(playlist)
     }
 
-    pub async fn rescan(&mut self) -> VoidResult {
-        self.tracks = read_dir(self.root.clone())
+    async fn scan_directory(
+        &self,
+        library: &mut Library,
+        errors: &mut Vec<ScanError>,
+    ) -> MusicResult<Vec<Track>> {
+        let root = canonicalize(&self.root)
             .await
-            .map_err(|e| e.to_string())?
-            .filter_map(|r| async {
-                let entry = match r {
-                    Ok(r) => r,
-                    _ => return None,
-                };
+            .prefix("Unable to resolve playlist directory")?;
 
-                let metadata = match entry.metadata().await {
-                    Ok(m) => m,
-                    _ => return None,
-                };
+        let mut entries = read_dir(root.clone())
+            .await
+            .prefix("Unable to read playlist directory")?;
 
-                if !metadata.is_file() {
-                    return None;
+        let mut tracks = Vec::new();
+        while let Some(r) = entries.next().await {
+            let entry = match r {
+                Ok(r) => r,
+                Err(e) => {
+                    errors.push(scan_error(&root, &e.to_string()));
+                    continue;
                 }
+            };
 
-                if let Some(extension) = entry.path().extension() {
-                    if extension == "mp3" {
-                        Some(Track::new(&entry.path()))
-                    } else {
-                        None
-                    }
-                } else {
-                    None
+            let metadata = match entry.metadata().await {
+                Ok(m) => m,
+                Err(e) => {
+                    errors.push(scan_error(&entry.path(), &e.to_string()));
+                    continue;
                 }
-            })
-            .collect::<Vec<Track>>()
-            .await;
+            };
+
+            if !metadata.is_file() {
+                continue;
+            }
+
+            match entry.path().extension() {
+                Some(extension) if extension == "mp3" => (),
+                _ => continue,
+            }
+
+            // Symlinks could point anywhere on the filesystem, only accept
+            // tracks that really live inside the playlist directory.
+            let path = match canonicalize(entry.path()).await {
+                Ok(p) => p,
+                Err(e) => {
+                    errors.push(scan_error(&entry.path(), &e.to_string()));
+                    continue;
+                }
+            };
+
+            if !path.starts_with(&root) {
+                warn!(
+                    "Ignoring {} as it is outside of the {} playlist.",
+                    path.display(),
+                    self.name
+                );
+                errors.push(scan_error(
+                    &entry.path(),
+                    "The track is outside of the playlist directory.",
+                ));
+                continue;
+            }
+
+            let track_metadata = library.metadata(&path, &metadata).await;
+            if let Some(e) = library.probe_error(&path) {
+                errors.push(scan_error(&path, &e));
+            }
+            if library.is_blocked(&path) {
+                continue;
+            }
+            tracks.push(Track::new(&path, track_metadata));
+        }
+
+        Ok(tracks)
+    }
+
+    async fn scan_storage(
+        &self,
+        storage: &dyn TrackStorage,
+        library: &mut Library,
+        cache: &TrackCache,
+        errors: &mut Vec<ScanError>,
+    ) -> MusicResult<Vec<Track>> {
+        let files = storage.list(cache.client()).await?;
+
+        let mut tracks = Vec::with_capacity(files.len());
+        for file in files {
+            let path = cache.path(&self.name, &file);
+            // Tracks that aren't cached yet are probed on the first rescan
+            // after they are downloaded.
+            let track_metadata = match metadata(&path).await {
+                Ok(metadata) => library.metadata(&path, &metadata).await,
+                Err(_) => Default::default(),
+            };
+            if let Some(e) = library.probe_error(&path) {
+                errors.push(scan_error(&path, &e));
+            }
+            if library.is_blocked(&path) {
+                continue;
+            }
+            tracks.push(Track::new(&path, track_metadata).with_remote(file));
+        }
+
+        Ok(tracks)
+    }
+
+    // The tracks that were downloaded from storage before.
+    async fn scan_cache(
+        &self,
+        library: &mut Library,
+        cache: &TrackCache,
+        errors: &mut Vec<ScanError>,
+    ) -> Vec<Track> {
+        let mut tracks = Vec::new();
+        let mut entries = match read_dir(cache.playlist_dir(&self.name)).await {
+            Ok(entries) => entries,
+            Err(_) => return tracks,
+        };
+
+        while let Some(Ok(entry)) = entries.next().await {
+            let metadata = match entry.metadata().await {
+                Ok(m) => m,
+                _ => continue,
+            };
+
+            if !metadata.is_file() || !is_track(&entry.file_name().to_string_lossy()) {
+                continue;
+            }
+
+            let path = entry.path();
+            let track_metadata = library.metadata(&path, &metadata).await;
+            if let Some(e) = library.probe_error(&path) {
+                errors.push(scan_error(&path, &e));
+            }
+            if library.is_blocked(&path) {
+                continue;
+            }
+            tracks.push(Track::new(&path, track_metadata));
+        }
+
+        tracks
+    }
+
+    fn set_tracks(&mut self, tracks: Vec<Track>) {
+        self.tracks = tracks;
 
         if self.tracks.is_empty() {
             info!("{} playlist has no tracks.", self.name);
-            #[cfg(feature = "rpi")]
-            self.led.off();
         } else {
             info!("{} playlist has {} tracks.", self.name, self.tracks.len());
-            #[cfg(feature = "rpi")]
-            self.led.on();
         }
 
+        #[cfg(feature = "rpi")]
+        self.update_led(false);
+    }
+
+    pub async fn rescan(&mut self, library: &mut Library, cache: &TrackCache) -> VoidResult {
+        let span = info_span!("scan", playlist = %self.name);
+        let mut errors = Vec::new();
+        let result = self
+            .scan(library, cache, &mut errors)
+            .instrument(span)
+            .await;
+        if let Err(ref e) = result {
+            errors.push(scan_error(&self.root, e));
+        }
+        self.errors = errors;
+        result
+    }
+
+    async fn scan(
+        &mut self,
+        library: &mut Library,
+        cache: &TrackCache,
+        errors: &mut Vec<ScanError>,
+    ) -> VoidResult {
+        let tracks = match self.storage {
+            Some(ref storage) => match self
+                .scan_storage(storage.as_ref(), library, cache, errors)
+                .await
+            {
+                Ok(tracks) => tracks,
+                Err(e) => {
+                    warn!(
+                        "Unable to list {} playlist storage, using cached tracks: {}",
+                        self.name, e
+                    );
+                    errors.push(scan_error(&self.root, &e));
+                    self.scan_cache(library, cache, errors).await
+                }
+            },
+            None => match self.query {
+                Some(ref query) => library.query(query),
+                None => {
+                    self.media_present = self.has_media().await;
+                    if self.media_present {
+                        self.scan_directory(library, errors).await?
+                    } else {
+                        warn!("The media for the {} playlist is missing.", self.name);
+                        Vec::new()
+                    }
+                }
+            },
+        };
+        self.set_tracks(tracks);
+
         Ok(())
     }
 
+    // Limits a playlist from a storage backend to the tracks that can play
+    // without the network.
+    pub async fn use_cache(&mut self, library: &mut Library, cache: &TrackCache) {
+        if self.storage.is_some() {
+            let mut errors = Vec::new();
+            let tracks = self.scan_cache(library, cache, &mut errors).await;
+            self.set_tracks(tracks);
+            self.errors = errors;
+        }
+    }
+
+    // The outcome of the last scan.
+    pub fn scan_result(&self) -> ScanResult {
+        ScanResult {
+            tracks: self.tracks.len(),
+            errors: self.errors.clone(),
+        }
+    }
+
+    // Removable media is present when the playlist directory exists on a
+    // different filesystem to the directory holding it, so an empty mount
+    // point or a dangling link counts as missing.
+    pub async fn has_media(&self) -> bool {
+        if !self.removable {
+            return true;
+        }
+
+        let parent = match self.root.parent() {
+            Some(parent) => parent,
+            None => return false,
+        };
+        match (metadata(&self.root).await, metadata(parent).await) {
+            (Ok(root), Ok(parent)) => root.is_dir() && root.dev() != parent.dev(),
+            _ => false,
+        }
+    }
+
+    pub fn is_removable(&self) -> bool {
+        self.removable
+    }
+
+    pub fn is_media_missing(&self) -> bool {
+        !self.media_present
+    }
+
+    pub fn has_storage(&self) -> bool {
+        self.storage.is_some()
+    }
+
+    pub fn has_query(&self) -> bool {
+        self.query.is_some()
+    }
+
     pub fn name(&self) -> String {
         self.name.clone()
     }
@@ -139,7 +511,129 @@ impl StoredPlaylist {
         self.tracks.clone()
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.tracks.is_empty()
+    }
+
+    pub fn pipeline(&self) -> Option<&str> {
+        self.pipeline.as_deref()
+    }
+
+    pub fn end_of_playlist(&self) -> Option<&EndOfPlaylist> {
+        self.end_of_playlist.as_ref()
+    }
+
+    pub fn double_press(&self) -> Option<Duration> {
+        self.double_press
+    }
+
+    pub fn announce_titles(&self) -> bool {
+        self.announce_titles
+    }
+
+    // The gain in dB to play this playlist at. Automatic gain uses whatever
+    // tracks have been analysed so far.
+    pub fn gain(&self, analyzer: &Analyzer) -> f64 {
+        match self.gain {
+            None => 0.0,
+            Some(PlaylistGain::Fixed { db }) => db,
+            Some(PlaylistGain::Auto { target }) => {
+                let levels: Vec<f64> = self
+                    .tracks
+                    .iter()
+                    .filter_map(|track| analyzer.analysis(&track.path()))
+                    .map(|analysis| 10f64.powf(analysis.loudness / 10.0))
+                    .collect();
+                if levels.is_empty() {
+                    return 0.0;
+                }
+
+                let loudness = 10.0 * (levels.iter().sum::<f64>() / levels.len() as f64).log10();
+                (target - loudness).clamp(-MAX_AUTO_GAIN, MAX_AUTO_GAIN)
+            }
+        }
+    }
+
+    pub fn info(&self) -> PlaylistInfo {
+        PlaylistInfo {
+            name: self.name.clone(),
+            tracks: self.tracks.iter().map(Track::info).collect(),
+            media_missing: !self.media_present,
+        }
+    }
+
+    pub fn track(&self, id: &str) -> Option<Track> {
+        self.tracks.iter().find(|track| track.id() == id).cloned()
+    }
+
     pub fn equals(&self, tracks: &[Track]) -> bool {
         self.tracks == tracks
     }
+
+    #[cfg(feature = "rpi")]
+    pub fn led(&self) -> Option<&LED> {
+        self.led.as_ref()
+    }
+
+    // The LED pulses while this playlist is playing and otherwise shows
+    // whether the playlist has any tracks.
+    #[cfg(feature = "rpi")]
+    pub fn update_led(&self, playing: bool) {
+        if let Some(ref led) = self.led {
+            if playing {
+                led.pulse();
+            } else if self.tracks.is_empty() {
+                led.off();
+            } else {
+                led.on();
+            }
+        }
+    }
+
+    // Blinks a code on the playlist's LED to report something about it.
+    #[cfg(feature = "rpi")]
+    pub fn blink_code(&self, digits: Vec<u8>) {
+        if let Some(ref led) = self.led {
+            led.blink_code(digits);
+        }
+    }
+
+    #[cfg(feature = "rpi")]
+    pub fn blink_codes(&self, codes: Vec<Vec<u8>>) {
+        if let Some(ref led) = self.led {
+            led.blink_codes(codes);
+        }
+    }
+}
+
+// Periodically checks the media of the removable playlists and sends an event
+// whenever it is removed or put back.
+pub fn monitor_media(state: MutableAppState, sender: MessageSender<Event>) {
+    tokio::spawn(async move {
+        let mut known: HashMap<String, bool> = HashMap::new();
+        loop {
+            delay_for(MEDIA_CHECK_INTERVAL).await;
+
+            for name in state.stored_playlist_names() {
+                let playlist = match state.stored_playlist(&name) {
+                    Some(playlist) if playlist.is_removable() => playlist,
+                    _ => continue,
+                };
+
+                let present = playlist.has_media().await;
+                let was_present = known
+                    .entry(name.clone())
+                    .or_insert_with(|| !playlist.is_media_missing());
+                if present != *was_present {
+                    *was_present = present;
+                    if present {
+                        info!("The media for the {} playlist is back.", name);
+                    } else {
+                        warn!("The media for the {} playlist was removed.", name);
+                    }
+                    sender.send(Event::PlaylistMediaChanged { name, present }.into());
+                }
+            }
+        }
+    });
 }
@@ -0,0 +1,314 @@
+use std::collections::HashMap;
+use std::fs::{read_to_string, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use futures::future::{ready, BoxFuture, FutureExt};
+use musicbox_protocol::StateSnapshot;
+use serde::{Deserialize, Serialize};
+use serde_json::{from_str, to_string, to_string_pretty};
+use time::{Date, OffsetDateTime};
+
+use crate::appstate::AppState;
+use crate::error::{ErrorExt, MusicResult, VoidResult};
+use crate::events::{Event, Message};
+use crate::sinks::EventSink;
+
+const PLAYS_FILE: &str = "plays.jsonl";
+
+// A track that was played.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Play {
+    // Milliseconds since the unix epoch when the track started.
+    pub timestamp: u64,
+    // The local date the track started, as YYYY-MM-DD.
+    pub date: String,
+    pub track: String,
+    pub title: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub artist: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub album: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub playlist: Option<String>,
+    // How far into the track playback got, in milliseconds.
+    pub played: u64,
+}
+
+// The plays of a single track added up.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TrackStats {
+    pub track: String,
+    pub title: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub artist: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub album: Option<String>,
+    pub plays: usize,
+    // In milliseconds.
+    pub played: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    Csv,
+    Json,
+}
+
+impl Default for ExportFormat {
+    fn default() -> ExportFormat {
+        ExportFormat::Json
+    }
+}
+
+impl ExportFormat {
+    pub fn parse(name: &str) -> MusicResult<ExportFormat> {
+        match name {
+            "csv" => Ok(ExportFormat::Csv),
+            "json" => Ok(ExportFormat::Json),
+            _ => Err(format!("Unknown export format {}.", name)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExportOptions {
+    // The first and last dates to include, as YYYY-MM-DD.
+    pub from: Option<String>,
+    pub to: Option<String>,
+    #[serde(default)]
+    pub format: ExportFormat,
+    // Exports the plays of each track added up rather than every play.
+    #[serde(default)]
+    pub stats: bool,
+}
+
+fn plays_file(data_dir: &Path) -> PathBuf {
+    data_dir.join(PLAYS_FILE)
+}
+
+fn parse_date(date: Option<&str>) -> MusicResult<Option<String>> {
+    match date {
+        Some(date) => Date::parse(date, "%F")
+            .map(|date| Some(date.to_string()))
+            .map_err(|e| format!("Invalid date {}: {}", date, e)),
+        None => Ok(None),
+    }
+}
+
+fn load_plays(data_dir: &Path) -> MusicResult<Vec<Play>> {
+    let data = match read_to_string(plays_file(data_dir)) {
+        Ok(data) => data,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(format!("Unable to read play history: {}", e)),
+    };
+
+    Ok(data
+        .lines()
+        .filter_map(|line| {
+            from_str(line)
+                .format_log(|e| format!("Skipping unreadable play: {}", e))
+                .ok()
+        })
+        .collect())
+}
+
+fn track_stats(plays: &[Play]) -> Vec<TrackStats> {
+    let mut tracks: HashMap<&str, TrackStats> = HashMap::new();
+    for play in plays {
+        let stats = tracks.entry(&play.track).or_insert_with(|| TrackStats {
+            track: play.track.clone(),
+            title: play.title.clone(),
+            artist: play.artist.clone(),
+            album: play.album.clone(),
+            plays: 0,
+            played: 0,
+        });
+        stats.plays += 1;
+        stats.played += play.played;
+    }
+
+    let mut tracks: Vec<TrackStats> = tracks.values().cloned().collect();
+    tracks.sort_by(|a, b| b.plays.cmp(&a.plays).then_with(|| a.title.cmp(&b.title)));
+    tracks
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains(&[',', '"', '\n', '\r'][..]) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_owned()
+    }
+}
+
+fn csv_row(fields: &[&str]) -> String {
+    let fields: Vec<String> = fields.iter().map(|field| csv_field(field)).collect();
+    format!("{}\n", fields.join(","))
+}
+
+fn plays_csv(plays: &[Play]) -> String {
+    let mut csv = csv_row(&[
+        "timestamp",
+        "date",
+        "track",
+        "title",
+        "artist",
+        "album",
+        "playlist",
+        "played",
+    ]);
+    for play in plays {
+        csv.push_str(&csv_row(&[
+            &play.timestamp.to_string(),
+            &play.date,
+            &play.track,
+            &play.title,
+            play.artist.as_deref().unwrap_or_default(),
+            play.album.as_deref().unwrap_or_default(),
+            play.playlist.as_deref().unwrap_or_default(),
+            &play.played.to_string(),
+        ]));
+    }
+    csv
+}
+
+fn stats_csv(tracks: &[TrackStats]) -> String {
+    let mut csv = csv_row(&["track", "title", "artist", "album", "plays", "played"]);
+    for stats in tracks {
+        csv.push_str(&csv_row(&[
+            &stats.track,
+            &stats.title,
+            stats.artist.as_deref().unwrap_or_default(),
+            stats.album.as_deref().unwrap_or_default(),
+            &stats.plays.to_string(),
+            &stats.played.to_string(),
+        ]));
+    }
+    csv
+}
+
+// The play history between the dates in the options, which include both the
+// first and last date.
+pub fn export(data_dir: &Path, options: &ExportOptions) -> MusicResult<String> {
+    let from = parse_date(options.from.as_deref())?;
+    let to = parse_date(options.to.as_deref())?;
+
+    let plays: Vec<Play> = load_plays(data_dir)?
+        .into_iter()
+        .filter(|play| match from {
+            Some(ref from) => &play.date >= from,
+            None => true,
+        })
+        .filter(|play| match to {
+            Some(ref to) => &play.date <= to,
+            None => true,
+        })
+        .collect();
+
+    match (options.format, options.stats) {
+        (ExportFormat::Csv, false) => Ok(plays_csv(&plays)),
+        (ExportFormat::Csv, true) => Ok(stats_csv(&track_stats(&plays))),
+        (ExportFormat::Json, false) => {
+            to_string_pretty(&plays).prefix("Unable to serialize play history")
+        }
+        (ExportFormat::Json, true) => {
+            to_string_pretty(&track_stats(&plays)).prefix("Unable to serialize play history")
+        }
+    }
+}
+
+// Records every track that is played to the history in the data directory.
+pub struct PlayLog {
+    state: AppState,
+    file: PathBuf,
+    current: Option<Play>,
+}
+
+impl PlayLog {
+    pub fn new(state: AppState, data_dir: &Path) -> PlayLog {
+        PlayLog {
+            state,
+            file: plays_file(data_dir),
+            current: None,
+        }
+    }
+
+    fn start(snapshot: &StateSnapshot) -> Option<Play> {
+        let play_state = snapshot.play_state.as_ref()?;
+        let track = snapshot.playlist.get(play_state.position)?;
+        let playlist = snapshot
+            .stored_playlists
+            .values()
+            .find(|playlist| playlist.tracks == snapshot.playlist)
+            .map(|playlist| playlist.name.clone());
+
+        Some(Play {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|duration| duration.as_millis() as u64)
+                .unwrap_or_default(),
+            date: OffsetDateTime::now_local().date().to_string(),
+            track: track.id.clone(),
+            title: track.title.clone(),
+            artist: track.artist.clone(),
+            album: track.album.clone(),
+            playlist,
+            played: 0,
+        })
+    }
+
+    fn finish(&mut self) -> VoidResult {
+        let play = match self.current.take() {
+            Some(play) => play,
+            None => return Ok(()),
+        };
+
+        let line = to_string(&play).prefix("Unable to serialize play")?;
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.file)
+            .prefix("Unable to open play history")?;
+        writeln!(file, "{}", line).prefix("Unable to write play history")
+    }
+}
+
+impl EventSink for PlayLog {
+    fn name(&self) -> &str {
+        "play log"
+    }
+
+    fn accepts(&self, event: &Event) -> bool {
+        matches!(
+            event,
+            Event::PlaybackStarted
+                | Event::PlaybackPosition { .. }
+                | Event::PlaybackEnded
+                | Event::Shutdown
+        )
+    }
+
+    fn handle<'a>(&'a mut self, event: &'a Message<Event>) -> BoxFuture<'a, VoidResult> {
+        let result = match event.payload {
+            Event::PlaybackStarted => {
+                let result = self.finish();
+                self.current = PlayLog::start(&self.state.snapshot());
+                result
+            }
+            Event::PlaybackPosition { duration } => {
+                if let Some(ref mut play) = self.current {
+                    play.played = play.played.max(duration.as_millis() as u64);
+                }
+                Ok(())
+            }
+            _ => self.finish(),
+        };
+
+        ready(result).boxed()
+    }
+}
@@ -0,0 +1,130 @@
+use std::collections::HashMap;
+use std::fs::{read, write};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{from_slice, to_vec};
+use tracing::{info, warn};
+
+use crate::error::{ErrorExt, MusicResult, VoidResult};
+use crate::resume::ResumeState;
+
+const PROFILES_FILE: &str = "profiles.json";
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProfileConfig {
+    pub name: String,
+
+    // The loudest the volume can be set while this profile is selected.
+    pub max_volume: Option<f64>,
+
+    // The playlists this profile can start, all playlists if missing.
+    pub playlists: Option<Vec<String>>,
+}
+
+// Persisted so each profile picks up where it left off.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ProfileState {
+    current: Option<String>,
+    // Where playback was when each profile was last switched away from.
+    resume: HashMap<String, ResumeState>,
+}
+
+// Lets the people sharing a music box keep their own settings and place in
+// their playlists.
+pub struct Profiles {
+    configs: Vec<ProfileConfig>,
+    file: PathBuf,
+    state: ProfileState,
+}
+
+impl Profiles {
+    pub fn new(configs: Vec<ProfileConfig>, data_dir: &Path) -> Profiles {
+        let file = data_dir.join(PROFILES_FILE);
+        let mut state: ProfileState = match read(&file) {
+            Ok(data) => from_slice(&data)
+                .format_log(|e| format!("Discarding unreadable profile state: {}", e))
+                .unwrap_or_default(),
+            Err(_) => Default::default(),
+        };
+
+        if let Some(ref name) = state.current {
+            if !configs.iter().any(|config| &config.name == name) {
+                warn!("Profile {} is no longer configured.", name);
+                state.current = None;
+            }
+        }
+
+        Profiles {
+            configs,
+            file,
+            state,
+        }
+    }
+
+    pub fn current(&self) -> Option<&ProfileConfig> {
+        let name = self.state.current.as_ref()?;
+        self.configs.iter().find(|config| &config.name == name)
+    }
+
+    pub fn current_name(&self) -> Option<String> {
+        self.state.current.clone()
+    }
+
+    pub fn max_volume(&self) -> Option<f64> {
+        self.current().and_then(|config| config.max_volume)
+    }
+
+    pub fn allows(&self, playlist: &str) -> bool {
+        match self.current().and_then(|config| config.playlists.as_ref()) {
+            Some(playlists) => playlists.iter().any(|name| name == playlist),
+            None => true,
+        }
+    }
+
+    // Switches to another profile, or to no profile, keeping the resume state
+    // of the current one. Returns where the new profile left off.
+    pub fn select(
+        &mut self,
+        name: Option<&str>,
+        resume: Option<ResumeState>,
+    ) -> MusicResult<Option<ResumeState>> {
+        if let Some(name) = name {
+            if !self.configs.iter().any(|config| config.name == name) {
+                return Err(format!("Unknown profile {}.", name));
+            }
+        }
+
+        // Playback without a profile is kept under an empty name.
+        let key = |name: Option<&str>| name.unwrap_or_default().to_owned();
+        match resume {
+            Some(resume) => {
+                self.state
+                    .resume
+                    .insert(key(self.state.current.as_deref()), resume);
+            }
+            None => {
+                self.state
+                    .resume
+                    .remove(&key(self.state.current.as_deref()));
+            }
+        }
+
+        match name {
+            Some(name) => info!("Selected profile {}.", name),
+            None => info!("Cleared the profile."),
+        }
+        self.state.current = name.map(ToOwned::to_owned);
+        let resume = self.state.resume.remove(&key(name));
+        self.save().log().drop();
+
+        Ok(resume)
+    }
+
+    pub fn save(&self) -> VoidResult {
+        let data = to_vec(&self.state).prefix("Unable to serialize profile state")?;
+        write(&self.file, data).prefix("Unable to write profile state")
+    }
+}
@@ -0,0 +1,223 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use serde::Deserialize;
+use tokio::process::Command;
+use tracing::{debug, info, warn};
+
+use crate::error::{ErrorExt, VoidResult};
+
+fn default_delay() -> u64 {
+    60
+}
+
+fn default_ssid() -> String {
+    String::from("Music Box Setup")
+}
+
+fn default_password() -> String {
+    String::from("musicbox")
+}
+
+fn default_start_command() -> Vec<String> {
+    vec![
+        String::from("nmcli"),
+        String::from("device"),
+        String::from("wifi"),
+        String::from("hotspot"),
+        String::from("con-name"),
+        String::from("MusicBoxSetup"),
+        String::from("ssid"),
+        String::from("{ssid}"),
+        String::from("password"),
+        String::from("{password}"),
+    ]
+}
+
+fn default_stop_command() -> Vec<String> {
+    vec![
+        String::from("nmcli"),
+        String::from("connection"),
+        String::from("down"),
+        String::from("MusicBoxSetup"),
+    ]
+}
+
+fn default_connect_command() -> Vec<String> {
+    vec![
+        String::from("nmcli"),
+        String::from("device"),
+        String::from("wifi"),
+        String::from("connect"),
+        String::from("{ssid}"),
+        String::from("password"),
+        String::from("{password}"),
+    ]
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProvisioningConfig {
+    // How long the network has to be down, in seconds, before the access
+    // point starts.
+    #[serde(default = "default_delay")]
+    pub delay: u64,
+
+    // The name of the access point.
+    #[serde(default = "default_ssid")]
+    pub ssid: String,
+
+    // The password for the access point, this should be printed somewhere on
+    // the music box. WPA needs at least 8 characters.
+    #[serde(default = "default_password")]
+    pub password: String,
+
+    // Brings up the access point. "{ssid}" and "{password}" in the arguments
+    // are replaced.
+    #[serde(default = "default_start_command")]
+    pub start_command: Vec<String>,
+
+    // Takes down the access point.
+    #[serde(default = "default_stop_command")]
+    pub stop_command: Vec<String>,
+
+    // Joins a WiFi network. "{ssid}" and "{password}" in the arguments are
+    // replaced.
+    #[serde(default = "default_connect_command")]
+    pub connect_command: Vec<String>,
+}
+
+// Replaces the placeholders in one pass so that a value containing a
+// placeholder is left as it is.
+fn fill(arg: &str, values: &[(&str, &str)]) -> String {
+    let mut filled = String::with_capacity(arg.len());
+    let mut rest = arg;
+    while let Some(start) = rest.find('{') {
+        filled.push_str(&rest[..start]);
+        let placeholder = &rest[start..];
+        match placeholder.find('}').and_then(|end| {
+            values
+                .iter()
+                .find(|(name, _)| *name == &placeholder[1..end])
+                .map(|(_, value)| (end, value))
+        }) {
+            Some((end, value)) => {
+                filled.push_str(value);
+                rest = &placeholder[end + 1..];
+            }
+            None => {
+                filled.push('{');
+                rest = &placeholder[1..];
+            }
+        }
+    }
+    filled.push_str(rest);
+    filled
+}
+
+async fn run(command: &[String], values: &[(&str, &str)]) -> VoidResult {
+    let args: Vec<String> = command.iter().map(|arg| fill(arg, values)).collect();
+
+    let (program, args) = args
+        .split_first()
+        .ok_or_else(|| String::from("No provisioning command is configured."))?;
+    debug!("Running {}.", program);
+
+    let status = Command::new(program)
+        .args(args)
+        .status()
+        .await
+        .prefix("Unable to run provisioning command")?;
+
+    if status.success() {
+        Ok(())
+    } else {
+        Err(format!("Provisioning command {} failed.", program))
+    }
+}
+
+// Brings up a temporary access point when the music box has no network so
+// WiFi credentials can be entered from the setup page.
+#[derive(Clone)]
+pub struct Provisioning {
+    config: Option<Arc<ProvisioningConfig>>,
+    active: Arc<AtomicBool>,
+}
+
+impl Provisioning {
+    pub fn new(config: Option<ProvisioningConfig>) -> Provisioning {
+        Provisioning {
+            config: config.map(Arc::new),
+            active: Default::default(),
+        }
+    }
+
+    // How long to wait without a network before starting the access point,
+    // or None if provisioning is not configured.
+    pub fn delay(&self) -> Option<u64> {
+        self.config.as_ref().map(|config| config.delay)
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active.load(Ordering::SeqCst)
+    }
+
+    pub async fn start(&self) -> VoidResult {
+        let config = match self.config {
+            Some(ref config) => config,
+            None => return Ok(()),
+        };
+
+        if self.active.swap(true, Ordering::SeqCst) {
+            return Ok(());
+        }
+
+        info!("Starting the setup access point.");
+        let values = [("ssid", &*config.ssid), ("password", &*config.password)];
+        if let Err(e) = run(&config.start_command, &values).await {
+            self.active.store(false, Ordering::SeqCst);
+            return Err(e);
+        }
+
+        info!(
+            "Connect to the access point {} and visit /setup to configure WiFi.",
+            config.ssid
+        );
+        Ok(())
+    }
+
+    pub async fn stop(&self) -> VoidResult {
+        let config = match self.config {
+            Some(ref config) => config,
+            None => return Ok(()),
+        };
+
+        if !self.active.swap(false, Ordering::SeqCst) {
+            return Ok(());
+        }
+
+        info!("Stopping the setup access point.");
+        run(&config.stop_command, &[]).await
+    }
+
+    // Leaves the access point to join the network. The access point comes
+    // back if that fails.
+    pub async fn connect(&self, ssid: &str, password: &str) -> VoidResult {
+        let config = match self.config {
+            Some(ref config) => config,
+            None => return Err(String::from("WiFi provisioning is not configured.")),
+        };
+
+        self.stop().await.log().drop();
+
+        info!("Connecting to WiFi network {}.", ssid);
+        let values = [("ssid", ssid), ("password", password)];
+        if let Err(e) = run(&config.connect_command, &values).await {
+            warn!("Unable to connect to WiFi network {}: {}", ssid, e);
+            self.start().await.log().drop();
+            return Err(e);
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,136 @@
+use std::fs::{read, read_to_string, remove_file, write, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::id;
+use std::time::Duration;
+
+use musicbox_protocol::StateSnapshot;
+use serde::{Deserialize, Serialize};
+use serde_json::{from_slice, to_vec};
+use tracing::warn;
+
+use crate::error::{ErrorExt, VoidResult};
+
+const RESUME_FILE: &str = "resume.json";
+const PID_FILE: &str = "musicbox.pid";
+
+fn default_save_interval() -> u64 {
+    15
+}
+
+fn default_rewind() -> u64 {
+    10
+}
+
+// What to do after the music box stops without shutting down cleanly.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CrashRecoveryConfig {
+    // Seconds between saving the playback position while playing.
+    #[serde(default = "default_save_interval")]
+    pub save_interval: u64,
+
+    // Starts playing again straight away.
+    #[serde(default)]
+    pub auto_resume: bool,
+
+    // Seconds to go back from the last saved position.
+    #[serde(default = "default_rewind")]
+    pub rewind: u64,
+}
+
+impl Default for CrashRecoveryConfig {
+    fn default() -> CrashRecoveryConfig {
+        CrashRecoveryConfig {
+            save_interval: default_save_interval(),
+            auto_resume: false,
+            rewind: default_rewind(),
+        }
+    }
+}
+
+fn pid_file(data_dir: &Path) -> PathBuf {
+    data_dir.join(PID_FILE)
+}
+
+// Records that the music box is running. Returns true if the last run didn't
+// shut down cleanly, which is when its pid file is still around but that
+// process is gone.
+pub fn mark_running(data_dir: &Path) -> bool {
+    let crashed = match read_to_string(pid_file(data_dir)) {
+        Ok(pid) => match pid.trim().parse::<u32>() {
+            Ok(pid) if pid != id() && Path::new(&format!("/proc/{}", pid)).exists() => {
+                warn!("Process {} may still be running a music box.", pid);
+                false
+            }
+            _ => true,
+        },
+        Err(_) => false,
+    };
+
+    write(pid_file(data_dir), id().to_string())
+        .prefix("Unable to write pid file")
+        .log()
+        .drop();
+    crashed
+}
+
+// Called on a clean shutdown.
+pub fn mark_stopped(data_dir: &Path) -> VoidResult {
+    remove_file(pid_file(data_dir)).prefix("Unable to remove pid file")
+}
+
+// Where playback was when the music box last shut down.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ResumeState {
+    pub tracks: Vec<String>,
+    pub position: usize,
+    pub offset: Duration,
+}
+
+fn resume_file(data_dir: &Path) -> PathBuf {
+    data_dir.join(RESUME_FILE)
+}
+
+impl ResumeState {
+    pub fn from_snapshot(snapshot: &StateSnapshot) -> Option<ResumeState> {
+        snapshot.play_state.as_ref().map(|play_state| ResumeState {
+            tracks: snapshot
+                .playlist
+                .iter()
+                .map(|track| track.id.clone())
+                .collect(),
+            position: play_state.position,
+            offset: play_state.duration,
+        })
+    }
+
+    // Goes back a little to make up for the position being saved only every
+    // so often.
+    pub fn rewind(&mut self, by: Duration) {
+        self.offset = self.offset.checked_sub(by).unwrap_or_default();
+    }
+
+    pub fn load(data_dir: &Path) -> Option<ResumeState> {
+        let data = read(resume_file(data_dir)).ok()?;
+        match from_slice(&data) {
+            Ok(state) => state,
+            Err(e) => {
+                warn!("Discarding unreadable resume state: {}", e);
+                None
+            }
+        }
+    }
+
+    // This may be running with power already lost so it writes synchronously
+    // and waits for the data to reach the disk.
+    pub fn save(data_dir: &Path, state: Option<&ResumeState>) -> VoidResult {
+        let data = to_vec(&state).prefix("Unable to serialize resume state")?;
+        let mut file =
+            File::create(resume_file(data_dir)).prefix("Unable to create resume state")?;
+        file.write_all(&data)
+            .prefix("Unable to write resume state")?;
+        file.sync_all().prefix("Unable to write resume state")
+    }
+}
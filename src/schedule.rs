@@ -0,0 +1,112 @@
+use std::convert::TryFrom;
+use std::time::Duration;
+
+use serde::Deserialize;
+use time::OffsetDateTime;
+
+const SECONDS_PER_DAY: u32 = 24 * 60 * 60;
+
+// A time of day in the local timezone, written as "HH:MM" in the config.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(try_from = "String")]
+pub struct TimeOfDay {
+    // Seconds since midnight.
+    seconds: u32,
+}
+
+impl TimeOfDay {
+    pub fn now() -> TimeOfDay {
+        let now = OffsetDateTime::now_local();
+        TimeOfDay {
+            seconds: u32::from(now.hour()) * 3600
+                + u32::from(now.minute()) * 60
+                + u32::from(now.second()),
+        }
+    }
+}
+
+impl TryFrom<String> for TimeOfDay {
+    type Error = String;
+
+    fn try_from(text: String) -> Result<TimeOfDay, String> {
+        let invalid = || format!("Invalid time of day {}, expected HH:MM.", text);
+
+        let mut parts = text.splitn(2, ':');
+        let hours: u32 = parts
+            .next()
+            .and_then(|part| part.parse().ok())
+            .ok_or_else(invalid)?;
+        let minutes: u32 = parts
+            .next()
+            .and_then(|part| part.parse().ok())
+            .ok_or_else(invalid)?;
+
+        if hours > 23 || minutes > 59 {
+            return Err(invalid());
+        }
+
+        Ok(TimeOfDay {
+            seconds: hours * 3600 + minutes * 60,
+        })
+    }
+}
+
+// A daily period of time. Windows that end before they start run past
+// midnight.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TimeWindow {
+    pub start: TimeOfDay,
+    pub end: TimeOfDay,
+}
+
+impl TimeWindow {
+    pub fn contains(&self, time: TimeOfDay) -> bool {
+        if self.start <= self.end {
+            time >= self.start && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+
+    // How long until the window next starts.
+    pub fn starts_in(&self, time: TimeOfDay) -> Duration {
+        let seconds = (self.start.seconds + SECONDS_PER_DAY - time.seconds) % SECONDS_PER_DAY;
+        Duration::from_secs(u64::from(seconds))
+    }
+}
+
+// The times of day when the music box may use the network in the background,
+// for syncing storage and checking for updates. Any time is allowed when no
+// windows are configured.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(transparent)]
+pub struct SyncWindows(Vec<TimeWindow>);
+
+impl SyncWindows {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn allows(&self, time: TimeOfDay) -> bool {
+        self.0.is_empty() || self.0.iter().any(|window| window.contains(time))
+    }
+
+    // How long until a window next starts.
+    pub fn next_start(&self, time: TimeOfDay) -> Duration {
+        self.0
+            .iter()
+            .map(|window| window.starts_in(time))
+            .min()
+            .unwrap_or_default()
+    }
+
+    // How long to wait before the network can be used.
+    pub fn wait(&self, time: TimeOfDay) -> Duration {
+        if self.allows(time) {
+            Duration::from_secs(0)
+        } else {
+            self.next_start(time)
+        }
+    }
+}
@@ -0,0 +1,86 @@
+use std::collections::HashMap;
+use std::fs::{metadata, read};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use serde_json::{from_slice, Map, Value};
+
+use crate::error::{ErrorExt, MusicResult, VoidResult};
+
+const SECRETS_FILE: &str = "secrets.json";
+
+fn secrets_file(data_dir: &Path) -> PathBuf {
+    data_dir.join(SECRETS_FILE)
+}
+
+// The name of the secret if the value is a reference to one, written as
+// {"secret": "name"} in the config.
+fn reference(map: &Map<String, Value>) -> Option<&str> {
+    if map.len() != 1 {
+        return None;
+    }
+    map.get("secret").and_then(Value::as_str)
+}
+
+fn has_references(value: &Value) -> bool {
+    match value {
+        Value::Object(map) => reference(map).is_some() || map.values().any(has_references),
+        Value::Array(values) => values.iter().any(has_references),
+        _ => false,
+    }
+}
+
+// Secrets live in their own file so the config can be shared without them.
+// Anyone else being able to read the file defeats that so it is refused.
+fn load(data_dir: &Path) -> MusicResult<HashMap<String, String>> {
+    let file = secrets_file(data_dir);
+    let mode = metadata(&file)
+        .prefix("Unable to read secrets")?
+        .permissions()
+        .mode();
+    if mode & 0o077 != 0 {
+        return Err(format!(
+            "{} can be read by other users, it should only be readable by its owner.",
+            file.display()
+        ));
+    }
+
+    let data = read(&file).prefix("Unable to read secrets")?;
+    from_slice(&data).prefix("Unable to parse secrets")
+}
+
+fn replace(value: &mut Value, secrets: &HashMap<String, String>) -> VoidResult {
+    match value {
+        Value::Object(map) => {
+            if let Some(name) = reference(map) {
+                let secret = secrets
+                    .get(name)
+                    .ok_or_else(|| format!("Unknown secret {}.", name))?;
+                *value = Value::String(secret.clone());
+                return Ok(());
+            }
+
+            for value in map.values_mut() {
+                replace(value, secrets)?;
+            }
+        }
+        Value::Array(values) => {
+            for value in values {
+                replace(value, secrets)?;
+            }
+        }
+        _ => {}
+    }
+
+    Ok(())
+}
+
+// Replaces references to secrets in the config with the secrets themselves.
+pub fn resolve(config: &mut Value, data_dir: &Path) -> VoidResult {
+    if !has_references(config) {
+        return Ok(());
+    }
+
+    let secrets = load(data_dir)?;
+    replace(config, &secrets)
+}
@@ -0,0 +1,127 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+#[cfg(feature = "rpi")]
+use rppal::gpio::Level;
+use tokio::net::TcpStream;
+use tokio::task::spawn_blocking;
+#[cfg(feature = "rpi")]
+use tokio::time::delay_for;
+use tokio::time::timeout;
+
+#[cfg(feature = "rpi")]
+use crate::hardware::gpio::led::LED;
+#[cfg(feature = "rpi")]
+use crate::hardware::gpio::monitor;
+use crate::player::beep;
+
+const TEST_TONE: u32 = 660;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+#[cfg(feature = "rpi")]
+const LED_FLASH: Duration = Duration::from_millis(300);
+
+// Checks the hardware when the music box starts so problems like a loose wire
+// are noticed before anyone tries to use it.
+#[derive(Default)]
+pub struct SelfTest {
+    server: Option<SocketAddr>,
+    #[cfg(feature = "rpi")]
+    leds: Vec<LED>,
+    // Each button's pin and the level it reads while pressed.
+    #[cfg(feature = "rpi")]
+    buttons: Vec<(u8, Level)>,
+}
+
+impl SelfTest {
+    pub fn set_server(&mut self, mut server: SocketAddr) {
+        // A server listening on every interface can be reached locally.
+        if server.ip().is_unspecified() {
+            server.set_ip(match server.ip() {
+                IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+                IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+            });
+        }
+        self.server = Some(server);
+    }
+
+    #[cfg(feature = "rpi")]
+    pub fn add_led(&mut self, led: LED) {
+        self.leds.push(led);
+    }
+
+    #[cfg(feature = "rpi")]
+    pub fn add_button(&mut self, pin: u8, on: Level) {
+        self.buttons.push((pin, on));
+    }
+
+    // Flashes each LED in turn for someone to watch. There is no way to tell
+    // whether they lit.
+    #[cfg(feature = "rpi")]
+    async fn flash_leds(&self) {
+        for led in &self.leds {
+            led.flash(LED_FLASH);
+            delay_for(LED_FLASH * 2).await;
+        }
+    }
+
+    // A button that reads as pressed when nobody is touching it is probably
+    // miswired or shorted.
+    #[cfg(feature = "rpi")]
+    fn check_buttons(&self) -> Vec<String> {
+        let pins = monitor::snapshot();
+        self.buttons
+            .iter()
+            .filter_map(
+                |(pin, on)| match pins.iter().find(|status| status.pin == *pin) {
+                    Some(status) if status.high == (*on == Level::High) => Some(format!(
+                        "The button on pin {} reads as pressed while at rest.",
+                        pin
+                    )),
+                    Some(_) => None,
+                    None => Some(format!("The button on pin {} could not be read.", pin)),
+                },
+            )
+            .collect()
+    }
+
+    async fn check_audio() -> Option<String> {
+        match spawn_blocking(|| beep(TEST_TONE)).await {
+            Ok(Ok(())) => None,
+            Ok(Err(e)) => Some(format!("Unable to play the test tone: {}", e)),
+            Err(e) => Some(format!("Unable to play the test tone: {}", e)),
+        }
+    }
+
+    async fn check_server(server: SocketAddr) -> Option<String> {
+        match timeout(CONNECT_TIMEOUT, TcpStream::connect(server)).await {
+            Ok(Ok(_)) => None,
+            Ok(Err(e)) => Some(format!(
+                "Unable to connect to the web server on {}: {}",
+                server, e
+            )),
+            Err(_) => Some(format!(
+                "Timed out connecting to the web server on {}.",
+                server
+            )),
+        }
+    }
+
+    // Returns a description of each problem found.
+    pub async fn run(&self) -> Vec<String> {
+        let mut failures = Vec::new();
+
+        #[cfg(feature = "rpi")]
+        {
+            self.flash_leds().await;
+            failures.extend(self.check_buttons());
+        }
+
+        failures.extend(SelfTest::check_audio().await);
+
+        if let Some(server) = self.server {
+            failures.extend(SelfTest::check_server(server).await);
+        }
+
+        failures
+    }
+}
@@ -1,41 +1,104 @@
+use std::convert::Infallible;
+use std::fs::remove_file;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::pin::Pin;
 use std::str;
+use std::sync::Arc;
 use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use futures::stream::Stream;
-use log::info;
+use futures::future::{Fuse, FutureExt};
+use futures::select;
+use futures::sink::SinkExt;
+use futures::stream::{self, BoxStream, Stream, StreamExt};
+use lazy_static::lazy_static;
+use musicbox_protocol::{
+    CommandResult, DeviceInfo, MessageFromClient, MessageToClient, PinStatus, SearchResult,
+    SearchResults, SystemReport, VersionInfo, PROTOCOL_VERSION,
+};
 use serde::Deserialize;
-use serde_json::Value;
+use serde_json::{from_slice, from_str, to_string};
+use tokio::fs;
 use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Mutex;
+use tokio::task::spawn_blocking;
+use tokio::time::{delay_until, interval_at, timeout, Instant};
+use tracing::{info, info_span, warn, Instrument, Span};
+use warp::http::StatusCode;
 use warp::reject::{not_found, Rejection};
-use warp::reply::{json, with_header};
+use warp::reply::{html, json, with_header, with_status, Response};
+use warp::sse;
+use warp::ws::{Message as WsMessage, WebSocket, Ws};
 use warp::{path::FullPath, Filter, Reply};
 
+use crate::analysis::{resample_waveform, Analyzer};
 use crate::appstate::AppState;
 use crate::assets::Webapp;
-use crate::events::{Command, Event, MessageReceiver, MessageSender};
+use crate::auth::{is_authorized, AuthConfig, Authorization};
+use crate::backup::{self, Backup};
+use crate::error::ErrorExt;
+use crate::events::{Command, Event, Message, MessageReceiver, MessageSender, Origin};
+use crate::group::{GroupKey, DEVICE_HEADER, SIGNATURE_HEADER, TIMESTAMP_HEADER};
+#[cfg(feature = "rpi")]
+use crate::hardware::gpio::monitor;
+use crate::i18n::{tr, tr_with};
+use crate::library::Library;
+use crate::logs;
+use crate::metrics::Metrics;
+use crate::nowplaying;
+use crate::playlist::is_playable_file;
+use crate::plays::{self, ExportFormat, ExportOptions};
+use crate::provisioning::Provisioning;
+use crate::speech::Speech;
+#[cfg(feature = "rpi")]
+use crate::storage::CACHE_LED;
 
-#[derive(Deserialize)]
-#[serde(tag = "type")]
-enum Request {}
+#[derive(Clone)]
+pub struct ClientInfo {
+    pub app_state: AppState,
+    pub command_sender: MessageSender<Command>,
+    pub event_sender: MessageSender<Event>,
+    pub auth: AuthConfig,
+    pub speech: Speech,
+    pub analyzer: Analyzer,
+    pub provisioning: Provisioning,
+    pub metrics: Metrics,
+    pub library: Arc<Mutex<Library>>,
+    pub data_dir: PathBuf,
+    // Served in place of the built in web app where files exist.
+    pub webapp_dir: Option<PathBuf>,
+    // Commands are refused when this is set.
+    pub read_only: bool,
+    pub device: DeviceInfo,
+    pub config_hash: String,
+    // Checks commands from the other members of the group.
+    pub group: Option<GroupKey>,
+}
 
 #[derive(Deserialize)]
-#[serde(tag = "type")]
-enum MessageFromClient {
-    Command { command: Command },
-    Request { id: u32, request: Request },
+struct ClientParams {
+    token: Option<String>,
 }
 
-enum MessageToClient {
-    Event { event: Event },
-    Response { id: u32, response: Value },
+#[derive(Deserialize)]
+struct ConnectParams {
+    token: Option<String>,
+    // The session and last event sequence number a reconnecting client saw.
+    session: Option<String>,
+    since: Option<u64>,
 }
 
-#[derive(Clone)]
-pub struct ClientInfo {
-    pub app_state: AppState,
-    pub command_sender: MessageSender<Command>,
-    pub event_receiver: MessageReceiver<Event>,
+lazy_static! {
+    // Sequence numbers restart with the server so clients must know when it
+    // has restarted.
+    static ref SESSION: String = format!(
+        "{:x}",
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_millis())
+            .unwrap_or_default()
+    );
 }
 
 struct Incoming {
@@ -52,23 +115,13 @@ impl Stream for Incoming {
     }
 }
 
-async fn static_content(path: FullPath) -> Result<impl Reply, Rejection> {
-    let mut target = &path.as_str()[1..];
-    if target.is_empty() {
-        target = "index.html";
-    }
-
-    let data = match Webapp::get(target) {
-        Some(data) => str::from_utf8(&data).unwrap().to_owned(),
-        None => return Err(not_found()),
-    };
-
+fn content_type(target: &str) -> &'static str {
     let last_part = match target.rfind('/') {
         Some(pos) => &target[pos + 1..],
         None => target,
     };
 
-    let content_type = match last_part.rfind('.') {
+    match last_part.rfind('.') {
         Some(0) => "text/plain",
         Some(pos) => match &last_part[pos + 1..] {
             "html" => "text/html",
@@ -77,17 +130,102 @@ async fn static_content(path: FullPath) -> Result<impl Reply, Rejection> {
             _ => "text/plain",
         },
         None => "text/plain",
+    }
+}
+
+// Identifies a version of a file on disk so browsers notice when it changes.
+fn file_etag(modified: SystemTime, len: u64) -> String {
+    let modified = modified
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    format!("\"{:x}-{:x}\"", modified, len)
+}
+
+// Serves a file from the web app directory on disk, if it is there.
+async fn disk_content(dir: &Path, target: &str, if_none_match: Option<String>) -> Option<Response> {
+    // Never serve anything from outside of the directory.
+    if target
+        .split('/')
+        .any(|part| part.is_empty() || part == "." || part == "..")
+    {
+        return None;
+    }
+
+    let file = dir.join(target);
+    let metadata = fs::metadata(&file).await.ok()?;
+    if !metadata.is_file() {
+        return None;
+    }
+
+    let etag = file_etag(metadata.modified().ok()?, metadata.len());
+    if if_none_match.as_ref() == Some(&etag) {
+        return Some(with_status("", StatusCode::NOT_MODIFIED).into_response());
+    }
+
+    let data = match fs::read(&file).await {
+        Ok(data) => data,
+        Err(e) => {
+            warn!("Unable to read {}: {}", file.display(), e);
+            return None;
+        }
     };
 
-    Ok(with_header(data, "content-type", content_type))
+    // Files on disk may be replaced at any time so must always be revalidated.
+    let reply = with_header(data, "content-type", content_type(target));
+    let reply = with_header(reply, "etag", etag);
+    Some(with_header(reply, "cache-control", "no-cache").into_response())
 }
 
-fn static_content_route() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
-    warp::path::full().and_then(static_content)
+// Files in the web app directory take precedence over the built in web app.
+async fn static_content(
+    webapp_dir: Option<PathBuf>,
+    path: FullPath,
+    if_none_match: Option<String>,
+) -> Result<Response, Rejection> {
+    let mut target = &path.as_str()[1..];
+    if target.is_empty() {
+        target = "index.html";
+    }
+
+    if let Some(ref dir) = webapp_dir {
+        if let Some(response) = disk_content(dir, target, if_none_match).await {
+            return Ok(response);
+        }
+    }
+
+    let data = match Webapp::get(target) {
+        Some(data) => data.into_owned(),
+        None => return Err(not_found()),
+    };
+
+    Ok(with_header(data, "content-type", content_type(target)).into_response())
 }
 
-async fn state(info: ClientInfo) -> Result<impl Reply, Rejection> {
-    Ok(json(&info.app_state))
+fn static_content_route(
+    info: &ClientInfo,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let webapp_dir = info.webapp_dir.clone();
+    warp::path::full()
+        .and(warp::header::optional::<String>("if-none-match"))
+        .and_then(move |path, if_none_match| {
+            static_content(webapp_dir.clone(), path, if_none_match)
+        })
+}
+
+fn etag(version: u64) -> String {
+    format!("\"{}\"", version)
+}
+
+// Polling clients can pass the ETag from the last response to avoid being sent
+// the same state again.
+async fn state(info: ClientInfo, if_none_match: Option<String>) -> Result<Response, Rejection> {
+    if if_none_match == Some(etag(info.app_state.version())) {
+        return Ok(with_status("", StatusCode::NOT_MODIFIED).into_response());
+    }
+
+    let (version, snapshot) = info.app_state.versioned_snapshot();
+    Ok(with_header(json(&snapshot), "etag", etag(version)).into_response())
 }
 
 fn state_route(
@@ -95,24 +233,1161 @@ fn state_route(
 ) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
     warp::path("state")
         .and(warp::path::end())
-        .and_then(move || state(info.clone()))
+        .and(warp::header::optional::<String>("if-none-match"))
+        .and_then(move |if_none_match| state(info.clone(), if_none_match))
+}
+
+async fn track(info: ClientInfo, id: String) -> Result<impl Reply, Rejection> {
+    match info.app_state.track(&id) {
+        Some(track) => Ok(json(&track.info())),
+        None => Err(not_found()),
+    }
+}
+
+fn track_route(
+    info: ClientInfo,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("track" / String).and_then(move |id| track(info.clone(), id))
+}
+
+async fn analysis(info: ClientInfo, id: String) -> Result<impl Reply, Rejection> {
+    match info
+        .app_state
+        .track(&id)
+        .and_then(|track| info.analyzer.analysis(&track.path()))
+    {
+        Some(analysis) => Ok(json(&analysis)),
+        None => Err(not_found()),
+    }
+}
+
+fn analysis_route(
+    info: ClientInfo,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("analysis" / String).and_then(move |id| analysis(info.clone(), id))
+}
+
+async fn bookmarks(info: ClientInfo, id: String) -> Result<impl Reply, Rejection> {
+    match info.app_state.track(&id) {
+        Some(track) => Ok(json(&info.library.lock().await.bookmarks(&track.path()))),
+        None => Err(not_found()),
+    }
+}
+
+fn bookmarks_route(
+    info: ClientInfo,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("bookmarks" / String).and_then(move |id| bookmarks(info.clone(), id))
+}
+
+const MAX_WAVEFORM_POINTS: usize = 1000;
+
+#[derive(Deserialize)]
+struct WaveformParams {
+    points: Option<usize>,
+}
+
+// The waveform as raw bytes, one per point, each the peak level of that
+// section of the track from 0 to 255.
+async fn waveform(
+    info: ClientInfo,
+    id: String,
+    params: WaveformParams,
+) -> Result<impl Reply, Rejection> {
+    let analysis = match info
+        .app_state
+        .track(&id)
+        .and_then(|track| info.analyzer.analysis(&track.path()))
+    {
+        Some(analysis) => analysis,
+        None => return Err(not_found()),
+    };
+
+    let data = match params.points {
+        Some(points) => resample_waveform(&analysis.waveform, points.clamp(1, MAX_WAVEFORM_POINTS)),
+        None => analysis.waveform,
+    };
+
+    Ok(with_header(
+        with_header(data, "content-type", "application/octet-stream"),
+        "x-track-duration",
+        analysis.duration.as_millis().to_string(),
+    ))
+}
+
+fn waveform_route(
+    info: ClientInfo,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("waveform" / String)
+        .and(warp::query::<WaveformParams>())
+        .and_then(move |id, params| waveform(info.clone(), id, params))
+}
+
+const DEFAULT_SEARCH_LIMIT: usize = 50;
+const MAX_SEARCH_LIMIT: usize = 200;
+
+#[derive(Deserialize)]
+struct SearchParams {
+    q: String,
+    #[serde(default)]
+    offset: usize,
+    limit: Option<usize>,
+}
+
+async fn search(info: ClientInfo, params: SearchParams) -> Result<impl Reply, Rejection> {
+    let matches = info.app_state.search(&params.q);
+    let limit = params
+        .limit
+        .unwrap_or(DEFAULT_SEARCH_LIMIT)
+        .min(MAX_SEARCH_LIMIT);
+
+    Ok(json(&SearchResults {
+        total: matches.len(),
+        offset: params.offset,
+        results: matches
+            .into_iter()
+            .skip(params.offset)
+            .take(limit)
+            .map(|(playlist, track)| SearchResult {
+                playlist,
+                track: track.info(),
+            })
+            .collect(),
+    }))
+}
+
+// Searches the titles and artists of the tracks in every playlist a page at a
+// time. A result is played with the PlayTrack command.
+fn search_route(
+    info: ClientInfo,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("search")
+        .and(warp::get())
+        .and(warp::query::<SearchParams>())
+        .and_then(move |params| search(info.clone(), params))
+}
+
+#[derive(Deserialize)]
+struct NowPlayingParams {
+    width: Option<u32>,
+    height: Option<u32>,
+}
+
+async fn now_playing(info: ClientInfo, params: NowPlayingParams) -> Result<Response, Rejection> {
+    let size =
+        |size: Option<u32>, default: u32| size.unwrap_or(default).max(1).min(nowplaying::MAX_SIZE);
+    let width = size(params.width, nowplaying::DEFAULT_WIDTH);
+    let height = size(params.height, nowplaying::DEFAULT_HEIGHT);
+    let text = nowplaying::card_text(info.app_state.now_playing());
+
+    let error = match spawn_blocking(move || nowplaying::render(&text, width, height)).await {
+        Ok(Ok(data)) => {
+            return Ok(with_header(
+                with_header(data, "content-type", "image/png"),
+                "cache-control",
+                "no-cache",
+            )
+            .into_response())
+        }
+        Ok(Err(e)) => e,
+        Err(e) => e.to_string(),
+    };
+
+    warn!("Failed to render the now playing card: {}", error);
+    Ok(with_status(error, StatusCode::INTERNAL_SERVER_ERROR).into_response())
+}
+
+// A picture of what is playing for displays that can only show images.
+fn now_playing_route(
+    info: ClientInfo,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("nowplaying.png")
+        .and(warp::get())
+        .and(warp::query::<NowPlayingParams>())
+        .and_then(move |params| now_playing(info.clone(), params))
+}
+
+// The queue is edited with the AddToQueue, MoveInQueue and RemoveFromQueue
+// commands.
+fn queue_route(
+    info: ClientInfo,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("queue")
+        .and(warp::get())
+        .map(move || json(&info.app_state.queue()))
+}
+
+async fn history(info: ClientInfo) -> Result<impl Reply, Rejection> {
+    Ok(json(&info.app_state.history()))
+}
+
+fn history_route(
+    info: ClientInfo,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path("history")
+        .and(warp::path::end())
+        .and_then(move || history(info.clone()))
+}
+
+async fn export_history(info: ClientInfo, options: ExportOptions) -> Result<Response, Rejection> {
+    let data_dir = info.data_dir.clone();
+    let format = options.format;
+    let error = match spawn_blocking(move || plays::export(&data_dir, &options)).await {
+        Ok(Ok(data)) => {
+            let (content_type, extension) = match format {
+                ExportFormat::Csv => ("text/csv", "csv"),
+                ExportFormat::Json => ("application/json", "json"),
+            };
+            return Ok(with_header(
+                with_header(data, "content-type", content_type),
+                "content-disposition",
+                format!("attachment; filename=\"musicbox-history.{}\"", extension),
+            )
+            .into_response());
+        }
+        Ok(Err(e)) => e,
+        Err(e) => e.to_string(),
+    };
+
+    Ok(with_status(error, StatusCode::BAD_REQUEST).into_response())
+}
+
+fn export_history_route(
+    info: ClientInfo,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("history" / "export")
+        .and(warp::get())
+        .and(warp::query::<ExportOptions>())
+        .and_then(move |options| export_history(info.clone(), options))
+}
+
+async fn blocked(info: ClientInfo) -> Result<impl Reply, Rejection> {
+    Ok(json(&info.library.lock().await.blocked()))
+}
+
+// The tracks that have been blocked. They are unblocked with the UnblockTrack
+// command.
+fn blocked_route(
+    info: ClientInfo,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("blocked")
+        .and(warp::get())
+        .and_then(move || blocked(info.clone()))
+}
+
+#[derive(Deserialize)]
+struct LogParams {
+    token: Option<String>,
+    // The least severe records to include.
+    level: Option<log::Level>,
+}
+
+// Logs can include anything the music box knows so need admin authorization.
+fn logs_authorized(info: &ClientInfo, token: Option<&str>) -> bool {
+    info.auth.for_token(token) >= Authorization::Admin
+}
+
+async fn recent_logs(info: ClientInfo, params: LogParams) -> Result<Response, Rejection> {
+    if !logs_authorized(&info, params.token.as_deref()) {
+        return Ok(with_status(tr("not-authorized"), StatusCode::FORBIDDEN).into_response());
+    }
+
+    let level = params.level.unwrap_or(log::Level::Trace);
+    Ok(json(&logs::recent(level)).into_response())
+}
+
+async fn log_files(info: ClientInfo, params: ClientParams) -> Result<Response, Rejection> {
+    if !logs_authorized(&info, params.token.as_deref()) {
+        return Ok(with_status(tr("not-authorized"), StatusCode::FORBIDDEN).into_response());
+    }
+
+    Ok(json(&logs::files(&info.data_dir)).into_response())
+}
+
+async fn log_file(
+    info: ClientInfo,
+    name: String,
+    params: ClientParams,
+) -> Result<Response, Rejection> {
+    if !logs_authorized(&info, params.token.as_deref()) {
+        return Ok(with_status(tr("not-authorized"), StatusCode::FORBIDDEN).into_response());
+    }
+
+    let path = logs::file(&info.data_dir, &name).ok_or_else(not_found)?;
+    let data = fs::read(path).await.map_err(|_| not_found())?;
+    Ok(with_header(
+        with_header(data, "content-type", "text/plain; charset=utf-8"),
+        "content-disposition",
+        format!("attachment; filename=\"{}\"", name),
+    )
+    .into_response())
+}
+
+// Recent log records kept in memory, and the log files if they are written.
+fn logs_route(info: ClientInfo) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let recent_info = info.clone();
+    let recent = warp::path!("logs")
+        .and(warp::get())
+        .and(warp::query::<LogParams>())
+        .and_then(move |params| recent_logs(recent_info.clone(), params));
+
+    let files_info = info.clone();
+    let files = warp::path!("logs" / "files")
+        .and(warp::get())
+        .and(warp::query::<ClientParams>())
+        .and_then(move |params| log_files(files_info.clone(), params));
+
+    let file = warp::path!("logs" / "files" / String)
+        .and(warp::get())
+        .and(warp::query::<ClientParams>())
+        .and_then(move |name, params| log_file(info.clone(), name, params));
+
+    recent.or(files).or(file)
+}
+
+fn metrics_route(
+    info: ClientInfo,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("metrics").map(move || json(&info.metrics.snapshot()))
+}
+
+#[cfg(feature = "rpi")]
+fn gpio_pins() -> Vec<PinStatus> {
+    monitor::snapshot()
+}
+
+#[cfg(not(feature = "rpi"))]
+fn gpio_pins() -> Vec<PinStatus> {
+    Vec::new()
+}
+
+#[cfg(feature = "rpi")]
+fn gpio_changes() -> BoxStream<'static, PinStatus> {
+    monitor::subscribe().boxed()
+}
+
+#[cfg(not(feature = "rpi"))]
+fn gpio_changes() -> BoxStream<'static, PinStatus> {
+    stream::pending().boxed()
+}
+
+// The state of the GPIO pins in use, either once or as a stream of server sent
+// events as each pin changes.
+fn gpio_route() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let pins = warp::path!("debug" / "gpio").map(|| json(&gpio_pins()));
+    let changes = warp::path!("debug" / "gpio" / "stream").map(|| {
+        let events = gpio_changes().map(|status| Ok::<_, Infallible>(sse::json(status)));
+        sse::reply(sse::keep_alive().stream(events))
+    });
+
+    pins.or(changes)
+}
+
+fn web_client(addr: Option<SocketAddr>) -> Origin {
+    Origin::WebClient {
+        address: addr.map(|addr| addr.to_string()),
+    }
+}
+
+// Tags anything logged while handling a request that changes something with
+// the route and client.
+fn request_span(route: &str, origin: &Origin) -> Span {
+    info_span!("request", route, origin = %origin)
+}
+
+fn version_route(
+    info: ClientInfo,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("version").map(move || {
+        json(&VersionInfo {
+            protocol: PROTOCOL_VERSION,
+            server: env!("CARGO_PKG_VERSION").to_owned(),
+            device: info.device.clone(),
+        })
+    })
+}
+
+// The optional features this binary was built with.
+fn features() -> Vec<String> {
+    let mut features = Vec::new();
+    if cfg!(feature = "rpi") {
+        features.push(String::from("rpi"));
+    }
+    if cfg!(feature = "desktop") {
+        features.push(String::from("desktop"));
+    }
+    if cfg!(feature = "input") {
+        features.push(String::from("input"));
+    }
+    features
+}
+
+async fn system_report(info: ClientInfo) -> Result<impl Reply, Rejection> {
+    let mut library = info.library.lock().await.stats();
+    library.playlists = info.app_state.stored_playlist_count();
+
+    Ok(json(&SystemReport {
+        protocol: PROTOCOL_VERSION,
+        server: env!("CARGO_PKG_VERSION").to_owned(),
+        device: info.device.clone(),
+        features: features(),
+        config_hash: info.config_hash.clone(),
+        library,
+    }))
+}
+
+fn system_report_route(
+    info: ClientInfo,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("report")
+        .and(warp::get())
+        .and_then(move || system_report(info.clone()))
+}
+
+const MAX_ANNOUNCEMENT: u64 = 4096;
+// Requests that wait for the music box give up after this long in case it
+// never reports that it has finished.
+const ANNOUNCE_TIMEOUT: Duration = Duration::from_secs(300);
+const RESCAN_TIMEOUT: Duration = Duration::from_secs(600);
+
+// Speaks the text over whatever is playing, responding once it has finished.
+async fn announce<B>(
+    info: ClientInfo,
+    params: ClientParams,
+    origin: Origin,
+    body: B,
+) -> Result<impl Reply, Rejection>
+where
+    B: AsRef<[u8]>,
+{
+    let text = match str::from_utf8(body.as_ref()) {
+        Ok(text) => text.trim().to_owned(),
+        Err(_) => return Ok(with_status(tr("text-not-utf8"), StatusCode::BAD_REQUEST)),
+    };
+
+    if info.read_only {
+        return Ok(with_status(tr("read-only"), StatusCode::FORBIDDEN));
+    }
+
+    let file = info.speech.announcement_file();
+    let command = Command::PlayFile {
+        path: file.display().to_string(),
+        duck: true,
+    };
+
+    let granted = info.auth.for_token(params.token.as_deref());
+    if !is_authorized(&origin.to_string(), granted, &command) {
+        return Ok(with_status(tr("not-authorized"), StatusCode::FORBIDDEN));
+    }
+
+    if let Err(e) = info.speech.synthesize(&text, &file).await {
+        warn!("Failed to synthesize announcement: {}", e);
+        return Ok(with_status(
+            tr("speech-failed"),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        ));
+    }
+
+    // Listen before sending the command so the end can't be missed.
+    let mut events = info.event_sender.receiver();
+    info.command_sender
+        .send(Message::from(command.clone()).with_origin(origin));
+    let finished = timeout(ANNOUNCE_TIMEOUT, async {
+        while let Some(event) = events.next().await {
+            match event.payload {
+                Event::FileFinished { ref path } if Path::new(path) == file => return true,
+                // In dry run mode the file is never played.
+                Event::CommandSkipped {
+                    command: ref skipped,
+                    ..
+                } if *skipped == command => return true,
+                _ => {}
+            }
+        }
+        false
+    })
+    .await
+    .unwrap_or(false);
+
+    remove_file(&file).drop();
+    if !finished {
+        warn!("Gave up waiting for the announcement to play.");
+        return Ok(with_status(
+            String::new(),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        ));
+    }
+    Ok(with_status(String::new(), StatusCode::OK))
+}
+
+fn announce_route(
+    info: ClientInfo,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("announce")
+        .and(warp::post())
+        .and(warp::query::<ClientParams>())
+        .and(warp::addr::remote())
+        .and(warp::body::content_length_limit(MAX_ANNOUNCEMENT))
+        .and(warp::body::bytes())
+        .and_then(move |params, addr, body| {
+            let origin = web_client(addr);
+            let span = request_span("announce", &origin);
+            announce(info.clone(), params, origin, body).instrument(span)
+        })
+}
+
+const MAX_BATCH: u64 = 16384;
+
+#[cfg(feature = "rpi")]
+fn has_led(info: &ClientInfo, name: &str) -> bool {
+    name == CACHE_LED || info.app_state.led(name).is_some()
+}
+
+#[cfg(not(feature = "rpi"))]
+fn has_led(_info: &ClientInfo, _name: &str) -> bool {
+    false
+}
+
+// Checks whether a command from a batch can be run.
+fn validate(
+    info: &ClientInfo,
+    origin: &Origin,
+    granted: Authorization,
+    command: &Command,
+) -> CommandResult {
+    if info.read_only {
+        return CommandResult::Rejected {
+            reason: tr("read-only"),
+        };
+    }
+
+    if *command == Command::Unknown {
+        return CommandResult::Rejected {
+            reason: tr("unknown-command"),
+        };
+    }
+
+    if !is_authorized(&origin.to_string(), granted, command) {
+        return CommandResult::Rejected {
+            reason: tr("not-authorized"),
+        };
+    }
+
+    match command {
+        Command::StartPlaylist { name, force: _ } | Command::RescanPlaylist { name }
+            if !info.app_state.has_stored_playlist(name) =>
+        {
+            CommandResult::Rejected {
+                reason: tr_with("unknown-playlist", &[("name", name)]),
+            }
+        }
+        Command::PlayTrack { id } if info.app_state.track(id).is_none() => {
+            CommandResult::Rejected {
+                reason: tr_with("unknown-track", &[("id", id)]),
+            }
+        }
+        Command::AddToQueue { id, .. } if info.app_state.track(id).is_none() => {
+            CommandResult::Rejected {
+                reason: tr_with("unknown-track", &[("id", id)]),
+            }
+        }
+        Command::MoveInQueue { id, .. } | Command::RemoveFromQueue { id }
+            if !info.app_state.is_queued(id) =>
+        {
+            CommandResult::Rejected {
+                reason: tr_with("not-queued", &[("id", id)]),
+            }
+        }
+        Command::AddToQueue {
+            before: Some(before),
+            ..
+        }
+        | Command::MoveInQueue {
+            before: Some(before),
+            ..
+        } if !info.app_state.is_queued(before) => CommandResult::Rejected {
+            reason: tr_with("not-queued", &[("id", before)]),
+        },
+        Command::IdentifyLed { name } if !has_led(info, name) => CommandResult::Rejected {
+            reason: tr_with("unknown-led", &[("name", name)]),
+        },
+        // Group commands are only passed on once.
+        Command::GroupCommand { command }
+            if matches!(origin, Origin::Group { .. })
+                || matches!(**command, Command::GroupCommand { .. }) =>
+        {
+            CommandResult::Rejected {
+                reason: tr("group-relay"),
+            }
+        }
+        Command::PlayFile { path, .. }
+            if !is_playable_file(&info.data_dir, &info.speech, Path::new(path)) =>
+        {
+            CommandResult::Rejected {
+                reason: tr_with("unplayable-file", &[("path", path)]),
+            }
+        }
+        _ => CommandResult::Accepted,
+    }
+}
+
+// Runs a list of commands in order with nothing else in between them. If any
+// command is invalid then none of them are run.
+async fn commands(
+    info: ClientInfo,
+    granted: Authorization,
+    origin: Origin,
+    commands: Vec<Command>,
+) -> Result<impl Reply, Rejection> {
+    let results: Vec<CommandResult> = commands
+        .iter()
+        .map(|command| validate(&info, &origin, granted, command))
+        .collect();
+
+    if results
+        .iter()
+        .all(|result| *result == CommandResult::Accepted)
+    {
+        info.command_sender.send_all(
+            commands
+                .into_iter()
+                .map(|command| Message::from(command).with_origin(origin.clone()))
+                .collect(),
+        );
+        return Ok(with_status(json(&results), StatusCode::OK));
+    }
+
+    let results: Vec<CommandResult> = results
+        .into_iter()
+        .map(|result| match result {
+            CommandResult::Accepted => CommandResult::Skipped,
+            result => result,
+        })
+        .collect();
+    Ok(with_status(json(&results), StatusCode::BAD_REQUEST))
+}
+
+fn commands_route(
+    info: ClientInfo,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("commands")
+        .and(warp::post())
+        .and(warp::query::<ClientParams>())
+        .and(warp::addr::remote())
+        .and(warp::body::content_length_limit(MAX_BATCH))
+        .and(warp::body::json())
+        .and_then(move |params: ClientParams, addr, batch| {
+            let origin = web_client(addr);
+            let span = request_span("commands", &origin);
+            let granted = info.auth.for_token(params.token.as_deref());
+            commands(info.clone(), granted, origin, batch).instrument(span)
+        })
+}
+
+// Runs commands sent by another member of the group. They are signed with the
+// group's secret rather than carrying a token.
+async fn group_commands<B>(
+    info: ClientInfo,
+    sender: String,
+    timestamp: String,
+    signature: String,
+    body: B,
+) -> Result<Response, Rejection>
+where
+    B: AsRef<[u8]>,
+{
+    let verified = match info.group {
+        Some(ref key) => key.verify(&sender, &timestamp, &signature, body.as_ref()),
+        None => false,
+    };
+    if !verified {
+        warn!(
+            "Rejected commands claiming to be from group member {}.",
+            sender
+        );
+        return Ok(with_status(tr("not-authorized"), StatusCode::FORBIDDEN).into_response());
+    }
+
+    let batch: Vec<Command> = match from_slice(body.as_ref()) {
+        Ok(batch) => batch,
+        Err(e) => return Ok(with_status(e.to_string(), StatusCode::BAD_REQUEST).into_response()),
+    };
+
+    let origin = Origin::Group { device: sender };
+    commands(info, Authorization::Admin, origin, batch)
+        .await
+        .map(Reply::into_response)
+}
+
+fn group_commands_route(
+    info: ClientInfo,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("group" / "commands")
+        .and(warp::post())
+        .and(warp::header::<String>(DEVICE_HEADER))
+        .and(warp::header::<String>(TIMESTAMP_HEADER))
+        .and(warp::header::<String>(SIGNATURE_HEADER))
+        .and(warp::body::content_length_limit(MAX_BATCH))
+        .and(warp::body::bytes())
+        .and_then(move |sender: String, timestamp, signature, body| {
+            let span = request_span(
+                "group",
+                &Origin::Group {
+                    device: sender.clone(),
+                },
+            );
+            group_commands(info.clone(), sender, timestamp, signature, body).instrument(span)
+        })
+}
+
+// Blinks an LED for a few seconds to find which one it is.
+async fn identify_led(
+    info: ClientInfo,
+    name: String,
+    params: ClientParams,
+    origin: Origin,
+) -> Result<impl Reply, Rejection> {
+    let granted = info.auth.for_token(params.token.as_deref());
+    let command = Command::IdentifyLed { name };
+    let result = validate(&info, &origin, granted, &command);
+
+    if result == CommandResult::Accepted {
+        info.command_sender
+            .send(Message::from(command).with_origin(origin));
+        return Ok(with_status(json(&result), StatusCode::OK));
+    }
+
+    Ok(with_status(json(&result), StatusCode::BAD_REQUEST))
+}
+
+fn identify_led_route(
+    info: ClientInfo,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("leds" / String / "identify")
+        .and(warp::post())
+        .and(warp::query::<ClientParams>())
+        .and(warp::addr::remote())
+        .and_then(move |name, params, addr| {
+            let origin = web_client(addr);
+            let span = request_span("identify", &origin);
+            identify_led(info.clone(), name, params, origin).instrument(span)
+        })
+}
+
+// Scans a single playlist, responding with the number of tracks found and
+// anything that couldn't be scanned once it has finished.
+async fn rescan_playlist(
+    info: ClientInfo,
+    name: String,
+    params: ClientParams,
+    origin: Origin,
+) -> Result<Response, Rejection> {
+    let granted = info.auth.for_token(params.token.as_deref());
+    let command = Command::RescanPlaylist { name: name.clone() };
+    let result = validate(&info, &origin, granted, &command);
+    if result != CommandResult::Accepted {
+        return Ok(with_status(json(&result), StatusCode::BAD_REQUEST).into_response());
+    }
+
+    // Listen before sending the command so the end can't be missed.
+    let mut events = info.event_sender.receiver();
+    info.command_sender
+        .send(Message::from(command.clone()).with_origin(origin));
+    let scanned = timeout(RESCAN_TIMEOUT, async {
+        while let Some(event) = events.next().await {
+            match event.payload {
+                Event::PlaylistScanned {
+                    name: ref scanned,
+                    ref result,
+                } if *scanned == name => return Some(json(result).into_response()),
+                Event::CommandSkipped {
+                    command: ref skipped,
+                    ..
+                } if *skipped == command => break,
+                _ => {}
+            }
+        }
+        None
+    })
+    .await;
+
+    match scanned {
+        Ok(Some(response)) => Ok(response),
+        Ok(None) => {
+            Ok(with_status(String::new(), StatusCode::INTERNAL_SERVER_ERROR).into_response())
+        }
+        Err(_) => {
+            warn!("Gave up waiting for playlist {} to be scanned.", name);
+            Ok(with_status(String::new(), StatusCode::INTERNAL_SERVER_ERROR).into_response())
+        }
+    }
+}
+
+fn rescan_route(
+    info: ClientInfo,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("playlists" / String / "rescan")
+        .and(warp::post())
+        .and(warp::query::<ClientParams>())
+        .and(warp::addr::remote())
+        .and_then(move |name, params, addr| {
+            let origin = web_client(addr);
+            let span = request_span("rescan", &origin);
+            rescan_playlist(info.clone(), name, params, origin).instrument(span)
+        })
+}
+
+// Backups include the admin token so need admin authorization.
+async fn backup(info: ClientInfo, params: ClientParams) -> Result<Response, Rejection> {
+    if info.auth.for_token(params.token.as_deref()) < Authorization::Admin {
+        return Ok(with_status(tr("not-authorized"), StatusCode::FORBIDDEN).into_response());
+    }
+
+    let data_dir = info.data_dir.clone();
+    let error = match spawn_blocking(move || backup::create(&data_dir)).await {
+        Ok(Ok(backup)) => {
+            return Ok(with_header(
+                json(&backup),
+                "content-disposition",
+                "attachment; filename=\"musicbox-backup.json\"",
+            )
+            .into_response())
+        }
+        Ok(Err(e)) => e,
+        Err(e) => e.to_string(),
+    };
+
+    warn!("Unable to create backup: {}", error);
+    Ok(with_status(error, StatusCode::INTERNAL_SERVER_ERROR).into_response())
+}
+
+fn backup_route(
+    info: ClientInfo,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("backup")
+        .and(warp::get())
+        .and(warp::query::<ClientParams>())
+        .and_then(move |params| backup(info.clone(), params))
+}
+
+const MAX_BACKUP: u64 = 16 * 1024 * 1024;
+
+// The backup is restored when the music box restarts so nothing running can
+// overwrite it.
+async fn restore(
+    info: ClientInfo,
+    params: ClientParams,
+    origin: Origin,
+    backup: Backup,
+) -> Result<impl Reply, Rejection> {
+    if info.read_only {
+        return Ok(with_status(tr("read-only"), StatusCode::FORBIDDEN));
+    }
+
+    let command = Command::Restart;
+    let granted = info.auth.for_token(params.token.as_deref());
+    if !is_authorized(&origin.to_string(), granted, &command) {
+        return Ok(with_status(tr("not-authorized"), StatusCode::FORBIDDEN));
+    }
+
+    if let Err(e) = backup::stage(&info.data_dir, &backup) {
+        warn!("{}", e);
+        return Ok(with_status(e, StatusCode::INTERNAL_SERVER_ERROR));
+    }
+
+    info.command_sender
+        .send(Message::from(command).with_origin(origin));
+    Ok(with_status(String::new(), StatusCode::OK))
+}
+
+fn restore_route(
+    info: ClientInfo,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("restore")
+        .and(warp::post())
+        .and(warp::query::<ClientParams>())
+        .and(warp::addr::remote())
+        .and(warp::body::content_length_limit(MAX_BACKUP))
+        .and(warp::body::json())
+        .and_then(move |params, addr, backup| {
+            let origin = web_client(addr);
+            let span = request_span("restore", &origin);
+            restore(info.clone(), params, origin, backup).instrument(span)
+        })
 }
 
 fn api_routes(
     info: &ClientInfo,
 ) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
-    warp::path("api").and(state_route(info.clone()))
+    warp::path("api").and(
+        state_route(info.clone())
+            .or(track_route(info.clone()))
+            .or(analysis_route(info.clone()))
+            .or(bookmarks_route(info.clone()))
+            .or(waveform_route(info.clone()))
+            .or(search_route(info.clone()))
+            .or(queue_route(info.clone()))
+            .or(now_playing_route(info.clone()))
+            .or(history_route(info.clone()))
+            .or(export_history_route(info.clone()))
+            .or(blocked_route(info.clone()))
+            .or(metrics_route(info.clone()))
+            .or(logs_route(info.clone()))
+            .or(gpio_route())
+            .or(version_route(info.clone()))
+            .or(system_report_route(info.clone()))
+            .or(announce_route(info.clone()))
+            .or(commands_route(info.clone()))
+            .or(group_commands_route(info.clone()))
+            .or(identify_led_route(info.clone()))
+            .or(rescan_route(info.clone()))
+            .or(backup_route(info.clone()))
+            .or(restore_route(info.clone())),
+    )
+}
+
+const MAX_SETUP: u64 = 1024;
+
+const SETUP_PAGE: &str = include_str!("setup.html");
+const SETUP_CONNECTING: &str = "<!DOCTYPE html><html><body><p>Connecting to the network. \
+The setup access point will come back if this fails.</p></body></html>";
+
+#[derive(Deserialize)]
+struct WifiCredentials {
+    ssid: String,
+    #[serde(default)]
+    password: String,
+}
+
+// The setup page is only available while the setup access point is up.
+async fn setup_page(info: ClientInfo) -> Result<impl Reply, Rejection> {
+    if !info.provisioning.is_active() {
+        return Err(not_found());
+    }
+
+    Ok(html(SETUP_PAGE))
+}
+
+async fn setup_wifi(
+    info: ClientInfo,
+    credentials: WifiCredentials,
+) -> Result<impl Reply, Rejection> {
+    if !info.provisioning.is_active() {
+        return Err(not_found());
+    }
+
+    // The client loses its connection when the access point goes down so this
+    // responds before connecting.
+    tokio::spawn(async move {
+        info.provisioning
+            .connect(&credentials.ssid, &credentials.password)
+            .await
+            .drop()
+    });
+
+    Ok(html(SETUP_CONNECTING))
+}
+
+fn setup_route(
+    info: ClientInfo,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let page_info = info.clone();
+    let page = warp::path!("setup")
+        .and(warp::get())
+        .and_then(move || setup_page(page_info.clone()));
+
+    let submit = warp::path!("setup")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(MAX_SETUP))
+        .and(warp::body::form())
+        .and_then(move |credentials| setup_wifi(info.clone(), credentials));
+
+    page.or(submit)
+}
+
+async fn send_to_client(socket: &mut WebSocket, message: &MessageToClient) -> bool {
+    match to_string(message) {
+        Ok(text) => socket.send(WsMessage::text(text)).await.is_ok(),
+        Err(e) => {
+            warn!("Unable to serialize message for client: {}", e);
+            true
+        }
+    }
+}
+
+async fn send_event(socket: &mut WebSocket, message: Message<Event>) -> bool {
+    send_to_client(
+        socket,
+        &MessageToClient::Event {
+            event: message.payload,
+            sequence: message.sequence,
+        },
+    )
+    .await
+}
+
+// Each client is sent position updates at most this often so a slow
+// connection doesn't fall behind. Other events are sent immediately.
+const POSITION_INTERVAL: Duration = Duration::from_secs(1);
+
+fn is_throttled(event: &Event) -> bool {
+    matches!(event, Event::PlaybackPosition { .. })
+}
+
+// Clients are pinged this often. Phones on WiFi can go to sleep without
+// closing the connection so clients that haven't sent anything, including
+// replies to pings, for the idle timeout are disconnected.
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+const IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+
+async fn client_connected(
+    mut socket: WebSocket,
+    info: ClientInfo,
+    granted: Authorization,
+    origin: Origin,
+    params: ConnectParams,
+) {
+    let name = origin.to_string();
+
+    // A client reconnecting to the same server is sent the events it missed,
+    // if they are still kept, otherwise it has to fetch the state again.
+    let resumed = match (params.session, params.since) {
+        (Some(ref session), Some(since)) if *session == *SESSION => {
+            info.event_sender.receiver_since(since)
+        }
+        _ => None,
+    };
+    let is_resumed = resumed.is_some();
+    let mut events: MessageReceiver<Event> =
+        resumed.unwrap_or_else(|| info.event_sender.receiver());
+
+    if !send_to_client(
+        &mut socket,
+        &MessageToClient::Connected {
+            session: SESSION.clone(),
+            resumed: is_resumed,
+            device: info.device.clone(),
+        },
+    )
+    .await
+    {
+        return;
+    }
+
+    // The latest throttled event and when it can be sent.
+    let mut pending: Option<Message<Event>> = None;
+    let mut flush = Fuse::terminated();
+    let mut last_sent: Option<Instant> = None;
+
+    let mut pings = interval_at(Instant::now() + PING_INTERVAL, PING_INTERVAL);
+    let mut last_heard = Instant::now();
+
+    loop {
+        select! {
+            e = events.next() => if let Some(event) = e {
+                if is_throttled(&event.payload) {
+                    match last_sent {
+                        Some(last) if last.elapsed() < POSITION_INTERVAL => {
+                            if pending.is_none() {
+                                flush = delay_until(last + POSITION_INTERVAL).fuse();
+                            }
+                            pending = Some(event);
+                            continue;
+                        }
+                        _ => last_sent = Some(Instant::now()),
+                    }
+                } else if let Some(position) = pending.take() {
+                    // Keeps events in order.
+                    flush = Fuse::terminated();
+                    last_sent = Some(Instant::now());
+                    if !send_event(&mut socket, position).await {
+                        break;
+                    }
+                }
+
+                if !send_event(&mut socket, event).await {
+                    break;
+                }
+            },
+            _ = flush => if let Some(position) = pending.take() {
+                last_sent = Some(Instant::now());
+                if !send_event(&mut socket, position).await {
+                    break;
+                }
+            },
+            _ = pings.tick().fuse() => {
+                if last_heard.elapsed() >= IDLE_TIMEOUT {
+                    info!("Disconnecting {} as it has stopped responding.", name);
+                    break;
+                }
+
+                if socket.send(WsMessage::ping(Vec::new())).await.is_err() {
+                    break;
+                }
+            },
+            m = socket.next().fuse() => match m {
+                Some(Ok(message)) => {
+                    last_heard = Instant::now();
+                    if message.is_close() {
+                        break;
+                    }
+
+                    let text = match message.to_str() {
+                        Ok(text) => text,
+                        Err(_) => continue,
+                    };
+
+                    match from_str::<MessageFromClient>(text) {
+                        Ok(MessageFromClient::Command { command: _ }) if info.read_only => {
+                            warn!("Ignoring a command from {} as the API is read only.", name);
+                        }
+                        Ok(MessageFromClient::Command { command }) => {
+                            if is_authorized(&name, granted, &command) {
+                                info.command_sender
+                                    .send(Message::from(command).with_origin(origin.clone()));
+                            }
+                        }
+                        Ok(MessageFromClient::Request { id: _, request }) => match request {},
+                        Err(e) => warn!("Received an invalid message from a client: {}", e),
+                    }
+                }
+                _ => break,
+            },
+        }
+    }
+}
+
+fn ws_route(info: ClientInfo) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("ws")
+        .and(warp::ws())
+        .and(warp::query::<ConnectParams>())
+        .and(warp::addr::remote())
+        .map(move |ws: Ws, params: ConnectParams, addr| {
+            let info = info.clone();
+            let granted = info.auth.for_token(params.token.as_deref());
+            let origin = web_client(addr);
+            let span = request_span("ws", &origin);
+            ws.on_upgrade(move |socket| {
+                client_connected(socket, info, granted, origin, params).instrument(span)
+            })
+        })
 }
 
 pub fn serve(listener: TcpListener, info: ClientInfo) {
     let server = warp::serve(
         api_routes(&info)
-            .or(static_content_route())
+            .or(ws_route(info.clone()))
+            .or(setup_route(info.clone()))
+            .or(static_content_route(&info))
             .with(warp::log("musicbox::server")),
     );
 
     if let Ok(addr) = listener.local_addr() {
-        info!("Starting webserver, listening on {}.", addr);
+        if info.read_only {
+            info!("Starting read only webserver, listening on {}.", addr);
+        } else {
+            info!("Starting webserver, listening on {}.", addr);
+        }
     }
 
     tokio::spawn(server.serve_incoming(Incoming { listener }));
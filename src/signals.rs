@@ -0,0 +1,68 @@
+use std::sync::Arc;
+
+use futures::compat::*;
+use futures::future::ready;
+use futures::stream::StreamExt;
+use signal_hook::iterator::Signals as SignalIterator;
+use tracing::error;
+
+use crate::auth::{Authorization, Authorizer};
+use crate::error::{ErrorExt, MusicResult};
+use crate::events::{Command, Message, Origin};
+use crate::sources::{CommandSource, CommandStream};
+
+// Maps process signals to commands.
+pub struct Signals;
+
+impl CommandSource for Signals {
+    fn name(&self) -> &str {
+        "signals"
+    }
+
+    fn authorizer(&self) -> Arc<dyn Authorizer> {
+        Arc::new(Authorization::System)
+    }
+
+    fn commands(&mut self) -> MusicResult<CommandStream> {
+        let signals = SignalIterator::new(&[
+            signal_hook::SIGHUP,
+            signal_hook::SIGTERM,
+            signal_hook::SIGINT,
+            signal_hook::SIGQUIT,
+            signal_hook::SIGUSR1,
+            signal_hook::SIGUSR2,
+        ])
+        .and_then(|s| s.into_async())
+        .prefix("Unable to attach signal handler")?;
+
+        Ok(Box::pin(signals.compat().filter_map(|r| {
+            let signal = match r {
+                Ok(signal) => signal,
+                Err(e) => {
+                    error!("Received unknown error: {}", e);
+                    return ready(None);
+                }
+            };
+
+            let command = match signal {
+                signal_hook::SIGHUP => Command::Reload,
+                signal_hook::SIGTERM => Command::Shutdown,
+                signal_hook::SIGINT => Command::Shutdown,
+                signal_hook::SIGQUIT => Command::Shutdown,
+                signal_hook::SIGUSR1 => Command::Status,
+                signal_hook::SIGUSR2 => Command::StartPlaylist {
+                    name: String::from("red"),
+                    force: true,
+                },
+                _ => {
+                    error!("Received unexpected signal {}.", signal);
+                    return ready(None);
+                }
+            };
+
+            ready(Some(
+                Message::from(command).with_origin(Origin::Signal { signal }),
+            ))
+        })))
+    }
+}
@@ -0,0 +1,41 @@
+use futures::future::BoxFuture;
+use futures::stream::StreamExt;
+use tracing::warn;
+
+use crate::error::VoidResult;
+use crate::events::{Event, Message, MessageReceiver};
+
+// An output backend that reacts to events. Sinks are registered with
+// `MusicBox::add_event_sink`.
+pub trait EventSink: Send + 'static {
+    // Used when logging about the sink.
+    fn name(&self) -> &str;
+
+    // The sink is only passed events that this returns true for.
+    fn accepts(&self, _event: &Event) -> bool {
+        true
+    }
+
+    fn handle<'a>(&'a mut self, event: &'a Message<Event>) -> BoxFuture<'a, VoidResult>;
+}
+
+// Each sink runs in its own task with its own queue of events so a sink that
+// is slow or failing cannot hold up the music box or any other sink.
+pub fn spawn_sink<S>(mut sink: S, mut events: MessageReceiver<Event>)
+where
+    S: EventSink,
+{
+    tokio::spawn(async move {
+        while let Some(event) = events.next().await {
+            if sink.accepts(&event.payload) {
+                if let Err(e) = sink.handle(&event).await {
+                    warn!("Event sink {} failed: {}", sink.name(), e);
+                }
+            }
+
+            if event.payload == Event::Shutdown {
+                break;
+            }
+        }
+    });
+}
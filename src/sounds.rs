@@ -0,0 +1,148 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use gstreamer::message::MessageView;
+use gstreamer::{init, parse_launch, ClockTime, Element, ElementExt, MessageType, State};
+use serde::Deserialize;
+use tracing::warn;
+
+use crate::error::{ErrorExt, VoidResult};
+use crate::player::beep;
+
+// About a tenth of a second.
+const TONE_BUFFERS: u32 = 4;
+const TONE_VOLUME: f64 = 0.3;
+
+// Feedback that has to be heard as soon as a control is used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+pub enum Sound {
+    Click,
+    Accept,
+    Error,
+    Shutdown,
+}
+
+const SOUNDS: [Sound; 4] = [Sound::Click, Sound::Accept, Sound::Error, Sound::Shutdown];
+
+impl Sound {
+    // The tone played when there is no file for this sound.
+    fn tone(self) -> Option<u32> {
+        match self {
+            Sound::Click => Some(880),
+            Sound::Accept => Some(1320),
+            Sound::Error => Some(220),
+            Sound::Shutdown => None,
+        }
+    }
+
+    fn description(self, file: Option<&PathBuf>) -> Option<String> {
+        match file {
+            Some(file) => Some(format!(
+                "filesrc location=\"{}\" ! decodebin ! audioconvert ! audioresample ! autoaudiosink",
+                file.display()
+            )),
+            None => self.tone().map(|frequency| {
+                format!(
+                    "audiotestsrc wave=sine freq={} volume={} num-buffers={} ! audioconvert ! autoaudiosink",
+                    frequency, TONE_VOLUME, TONE_BUFFERS
+                )
+            }),
+        }
+    }
+}
+
+// Brings the pipeline back to its start and leaves it paused, which makes
+// gstreamer decode the start of the sound and open the output so it can play
+// straight away.
+fn preroll(pipeline: &Element) -> VoidResult {
+    pipeline
+        .set_state(State::Ready)
+        .prefix("Unable to reset sound")?;
+    pipeline
+        .set_state(State::Paused)
+        .prefix("Unable to preroll sound")?;
+    Ok(())
+}
+
+fn play_prerolled(pipeline: &Element, sound: Sound) -> VoidResult {
+    let bus = pipeline
+        .get_bus()
+        .ok_or_else(|| format!("Unable to get {:?} sound bus.", sound))?;
+
+    pipeline
+        .set_state(State::Playing)
+        .prefix(format!("Unable to play {:?} sound", sound))?;
+    let result = match bus
+        .timed_pop_filtered(ClockTime::none(), &[MessageType::Eos, MessageType::Error])
+    {
+        Some(message) => match message.view() {
+            MessageView::Error(e) => Err(format!("{:?} sound failed: {}", sound, e.get_error())),
+            _ => Ok(()),
+        },
+        None => Ok(()),
+    };
+    preroll(pipeline)?;
+
+    result
+}
+
+// Keeps a pipeline for each sound waiting in the paused state rather than
+// building one each time a sound is needed.
+#[derive(Clone)]
+pub struct Sounds {
+    pipelines: Arc<HashMap<Sound, Mutex<Element>>>,
+}
+
+impl Sounds {
+    pub fn new(files: &HashMap<Sound, PathBuf>) -> Sounds {
+        let mut pipelines = HashMap::new();
+        if let Err(e) = init() {
+            warn!("Unable to initialize gstreamer for sounds: {}", e);
+        } else {
+            for sound in SOUNDS.iter().copied() {
+                let description = match sound.description(files.get(&sound)) {
+                    Some(description) => description,
+                    None => continue,
+                };
+
+                let pipeline = match parse_launch(&description) {
+                    Ok(pipeline) => pipeline,
+                    Err(e) => {
+                        warn!("Unable to create {:?} sound: {}", sound, e);
+                        continue;
+                    }
+                };
+
+                if preroll(&pipeline).log().is_ok() {
+                    pipelines.insert(sound, Mutex::new(pipeline));
+                }
+            }
+        }
+
+        Sounds {
+            pipelines: Arc::new(pipelines),
+        }
+    }
+
+    pub fn play(&self, sound: Sound) {
+        let sounds = self.clone();
+        thread::spawn(move || sounds.play_to_end(sound).log().drop());
+    }
+
+    // Blocks until the sound has played. A sound that is already playing is
+    // not played again.
+    pub fn play_to_end(&self, sound: Sound) -> VoidResult {
+        match self.pipelines.get(&sound) {
+            Some(pipeline) => match pipeline.try_lock() {
+                Ok(pipeline) => play_prerolled(&pipeline, sound),
+                Err(_) => Ok(()),
+            },
+            None => match sound.tone() {
+                Some(frequency) => beep(frequency),
+                None => Ok(()),
+            },
+        }
+    }
+}
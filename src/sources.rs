@@ -0,0 +1,34 @@
+use std::pin::Pin;
+use std::sync::Arc;
+
+use futures::stream::Stream;
+
+use crate::auth::{Authorization, Authorizer};
+use crate::error::{MusicResult, VoidResult};
+use crate::events::{Command, Message};
+
+pub type CommandStream = Pin<Box<dyn Stream<Item = Message<Command>> + Send>>;
+
+// An input backend that generates commands. Sources are registered with
+// `MusicBox::add_command_source` which forwards their commands to the music
+// box and lets them know when the music box reloads or shuts down.
+pub trait CommandSource {
+    // Used when logging about the source.
+    fn name(&self) -> &str;
+
+    // Called once when the source is registered.
+    fn commands(&mut self) -> MusicResult<CommandStream>;
+
+    // Commands that need more authorization than this grants are dropped.
+    fn authorizer(&self) -> Arc<dyn Authorizer> {
+        Arc::new(Authorization::Playback)
+    }
+
+    // Called when the music box handles a reload command.
+    fn reload(&mut self) -> VoidResult {
+        Ok(())
+    }
+
+    // Called before the music box shuts down.
+    fn shutdown(&mut self) {}
+}
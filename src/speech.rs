@@ -0,0 +1,292 @@
+use std::env::temp_dir;
+use std::fmt;
+use std::fs::canonicalize;
+use std::path::{Path, PathBuf};
+use std::process::id;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use futures::future::{BoxFuture, FutureExt};
+use reqwest::{Client, Url};
+use ring::digest::{digest, SHA256};
+use serde::Deserialize;
+use serde_json::json;
+use tokio::fs::{copy, create_dir_all, remove_file, rename, write};
+use tokio::process::Command;
+use tracing::{debug, warn};
+
+use crate::error::{ErrorExt, MusicResult, VoidResult};
+use crate::i18n::language;
+
+const SPEECH_CACHE_DIR: &str = "speech";
+
+static NEXT_ANNOUNCEMENT: AtomicUsize = AtomicUsize::new(0);
+
+fn default_command() -> Vec<String> {
+    vec![
+        String::from("espeak-ng"),
+        String::from("-v"),
+        String::from("{language}"),
+        String::from("-w"),
+        String::from("{file}"),
+        String::from("{text}"),
+    ]
+}
+
+// How announcements are turned into speech.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type")]
+pub enum SpeechConfig {
+    // Runs a command that synthesizes speech to a wav file, espeak-ng by
+    // default, which works offline. "{file}", "{text}" and "{language}" in
+    // the arguments are replaced.
+    #[serde(rename_all = "camelCase")]
+    Espeak {
+        #[serde(default = "default_command")]
+        command: Vec<String>,
+    },
+    // A web service that returns audio for text POSTed to it as JSON.
+    #[serde(rename_all = "camelCase")]
+    Cloud {
+        url: String,
+        // Sent as a bearer token.
+        api_key: Option<String>,
+        voice: Option<String>,
+        // Used when the service can't be reached. Its speech isn't cached so
+        // the service is tried again next time.
+        #[serde(default = "default_command")]
+        fallback: Vec<String>,
+    },
+}
+
+impl Default for SpeechConfig {
+    fn default() -> SpeechConfig {
+        SpeechConfig::Espeak {
+            command: default_command(),
+        }
+    }
+}
+
+impl SpeechConfig {
+    pub fn synth(&self, data_dir: &Path) -> MusicResult<Speech> {
+        let cache = data_dir.join(SPEECH_CACHE_DIR);
+        match self {
+            SpeechConfig::Espeak { command } => Ok(Speech {
+                synth: Arc::new(CommandSynth {
+                    command: command.clone(),
+                }),
+                fallback: None,
+                voice: command.join(" "),
+                cache,
+            }),
+            SpeechConfig::Cloud {
+                url,
+                api_key,
+                voice,
+                fallback,
+            } => Ok(Speech {
+                synth: Arc::new(CloudSynth {
+                    client: Client::new(),
+                    url: Url::parse(url).prefix("Invalid speech service url")?,
+                    api_key: api_key.clone(),
+                    voice: voice.clone(),
+                }),
+                fallback: Some(Arc::new(CommandSynth {
+                    command: fallback.clone(),
+                })),
+                voice: format!("{} {}", url, voice.as_deref().unwrap_or_default()),
+                cache,
+            }),
+        }
+    }
+}
+
+// Something that can write speech for some text to an audio file.
+pub trait SpeechSynth: fmt::Debug + Send + Sync {
+    fn synthesize<'a>(&'a self, text: &'a str, file: &'a Path) -> BoxFuture<'a, VoidResult>;
+}
+
+#[derive(Debug)]
+struct CommandSynth {
+    command: Vec<String>,
+}
+
+impl CommandSynth {
+    async fn run(&self, text: &str, file: &Path) -> VoidResult {
+        let args: Vec<String> = self
+            .command
+            .iter()
+            .map(|arg| {
+                arg.replace("{file}", &file.display().to_string())
+                    .replace("{text}", text)
+                    .replace("{language}", language())
+            })
+            .collect();
+
+        let (program, args) = args
+            .split_first()
+            .ok_or_else(|| String::from("No speech command is configured."))?;
+        debug!("Synthesizing speech with {}.", program);
+
+        let status = Command::new(program)
+            .args(args)
+            .status()
+            .await
+            .prefix("Unable to run speech command")?;
+
+        if status.success() {
+            Ok(())
+        } else {
+            Err(format!("Speech command {} failed.", program))
+        }
+    }
+}
+
+impl SpeechSynth for CommandSynth {
+    fn synthesize<'a>(&'a self, text: &'a str, file: &'a Path) -> BoxFuture<'a, VoidResult> {
+        self.run(text, file).boxed()
+    }
+}
+
+#[derive(Debug)]
+struct CloudSynth {
+    client: Client,
+    url: Url,
+    api_key: Option<String>,
+    voice: Option<String>,
+}
+
+impl CloudSynth {
+    async fn fetch(&self, text: &str, file: &Path) -> VoidResult {
+        let mut request = self.client.post(self.url.clone()).json(&json!({
+            "text": text,
+            "language": language(),
+            "voice": self.voice,
+        }));
+        if let Some(ref key) = self.api_key {
+            request = request.bearer_auth(key);
+        }
+
+        let audio = request
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .prefix("Unable to reach speech service")?
+            .bytes()
+            .await
+            .prefix("Unable to download speech")?;
+
+        write(file, &audio).await.prefix("Unable to write speech")
+    }
+}
+
+impl SpeechSynth for CloudSynth {
+    fn synthesize<'a>(&'a self, text: &'a str, file: &'a Path) -> BoxFuture<'a, VoidResult> {
+        self.fetch(text, file).boxed()
+    }
+}
+
+// The configured speech synthesizer, shared by announcements and the announce
+// API. Everything it says is kept in the data directory so repeating an
+// announcement doesn't run the synthesizer again, which is slow on a Pi Zero.
+#[derive(Debug, Clone)]
+pub struct Speech {
+    synth: Arc<dyn SpeechSynth>,
+    fallback: Option<Arc<dyn SpeechSynth>>,
+    // Identifies the synthesizer and its settings in the cache.
+    voice: String,
+    cache: PathBuf,
+}
+
+impl Speech {
+    // A new file to synthesize an announcement into.
+    pub fn announcement_file(&self) -> PathBuf {
+        temp_dir().join(format!(
+            "musicbox-{}-{}.wav",
+            id(),
+            NEXT_ANNOUNCEMENT.fetch_add(1, Ordering::SeqCst)
+        ))
+    }
+
+    // Whether a canonical path is an announcement or cached speech.
+    pub fn is_own_file(&self, path: &Path) -> bool {
+        if let Ok(cache) = canonicalize(&self.cache) {
+            if path.starts_with(cache) {
+                return true;
+            }
+        }
+
+        let announcement = path
+            .file_name()
+            .map(|name| {
+                name.to_string_lossy()
+                    .starts_with(&format!("musicbox-{}-", id()))
+            })
+            .unwrap_or(false);
+        announcement && path.parent() == canonicalize(temp_dir()).ok().as_deref()
+    }
+
+    // Entries are named after a hash of everything that affects the speech.
+    fn cached_file(&self, text: &str) -> PathBuf {
+        let key = format!("{}\n{}\n{}", self.voice, language(), text);
+        let name: String = digest(&SHA256, key.as_bytes())
+            .as_ref()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        self.cache.join(name).with_extension("wav")
+    }
+
+    // Synthesizes the text into the cache unless it is already there.
+    async fn cache(&self, text: &str) -> MusicResult<PathBuf> {
+        let cached = self.cached_file(text);
+        if cached.exists() {
+            return Ok(cached);
+        }
+
+        // Synthesized under a temporary name so a partial file is never used.
+        create_dir_all(&self.cache)
+            .await
+            .prefix("Unable to create speech cache")?;
+        let partial = cached.with_extension(format!(
+            "{}.part",
+            NEXT_ANNOUNCEMENT.fetch_add(1, Ordering::SeqCst)
+        ));
+        if let Err(e) = self.synth.synthesize(text, &partial).await {
+            remove_file(&partial).await.drop();
+            return Err(e);
+        }
+        rename(&partial, &cached)
+            .await
+            .prefix("Unable to write speech cache")?;
+
+        Ok(cached)
+    }
+
+    pub async fn synthesize(&self, text: &str, file: &Path) -> VoidResult {
+        match (self.cache(text).await, &self.fallback) {
+            (Ok(cached), _) => copy(&cached, file)
+                .await
+                .map(|_| ())
+                .prefix("Unable to copy cached speech"),
+            (Err(e), Some(fallback)) => {
+                warn!("{}", e);
+                fallback.synthesize(text, file).await
+            }
+            (Err(e), None) => Err(e),
+        }
+    }
+
+    // Fills the cache with announcements that are likely to be needed so they
+    // play straight away. Stops at the first failure.
+    pub async fn prepare(&self, texts: Vec<String>) {
+        let count = texts.len();
+        for text in texts {
+            if let Err(e) = self.cache(&text).await {
+                warn!("Unable to prepare announcements: {}", e);
+                return;
+            }
+        }
+        debug!("Prepared {} announcements.", count);
+    }
+}
@@ -0,0 +1,70 @@
+use std::collections::BTreeMap;
+use std::fs::write;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::Level;
+use musicbox_protocol::{Event, HistoryEntry, StateSnapshot};
+use serde::Serialize;
+use serde_json::to_vec_pretty;
+use time::OffsetDateTime;
+
+use crate::appstate::AppState;
+use crate::error::{ErrorExt, MusicResult};
+use crate::logs::{self, LogRecord};
+use crate::metrics::Metrics;
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct RecentEvent {
+    sequence: u64,
+    event: Event,
+}
+
+// Everything useful for working out what a headless music box has been doing.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct StatusReport {
+    version: String,
+    // Milliseconds since the unix epoch.
+    timestamp: u64,
+    state: StateSnapshot,
+    history: Vec<HistoryEntry>,
+    events: Vec<RecentEvent>,
+    metrics: BTreeMap<String, u64>,
+    logs: Vec<LogRecord>,
+}
+
+// Writes a report to a new file in the data directory, returning its path.
+// Events are the recently sent events with their sequence numbers.
+pub fn write_report(
+    data_dir: &Path,
+    state: &AppState,
+    metrics: &Metrics,
+    events: Vec<(u64, Event)>,
+) -> MusicResult<PathBuf> {
+    let report = StatusReport {
+        version: env!("CARGO_PKG_VERSION").to_owned(),
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_millis() as u64)
+            .unwrap_or_default(),
+        state: state.snapshot(),
+        history: state.history(),
+        events: events
+            .into_iter()
+            .map(|(sequence, event)| RecentEvent { sequence, event })
+            .collect(),
+        metrics: metrics.snapshot(),
+        logs: logs::recent(Level::Trace),
+    };
+
+    let file = data_dir.join(format!(
+        "status-{}.json",
+        OffsetDateTime::now_local().format("%Y%m%d-%H%M%S")
+    ));
+    let data = to_vec_pretty(&report).prefix("Unable to serialize status report")?;
+    write(&file, data).prefix("Unable to write status report")?;
+
+    Ok(file)
+}
@@ -0,0 +1,820 @@
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use futures::future::{BoxFuture, FutureExt};
+use musicbox_protocol::{CurrentDownload, DownloadProgress};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use reqwest::{Client, Method, RequestBuilder, Url};
+use ring::digest::{digest, SHA256};
+use ring::hmac;
+use roxmltree::{Document, Node};
+use serde::Deserialize;
+use time::OffsetDateTime;
+use tokio::fs::{create_dir_all, metadata, rename, File};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+use tokio::task::spawn_blocking;
+use tokio::time::delay_for;
+use tracing::{debug, info, warn};
+
+use crate::appstate::MutableAppState;
+use crate::error::{ErrorExt, MusicResult, VoidResult};
+#[cfg(feature = "rpi")]
+use crate::hardware::gpio::is_available;
+#[cfg(feature = "rpi")]
+use crate::hardware::gpio::led::{LEDConfig, LED};
+use crate::track::Track;
+
+pub const CACHE_DIR: &str = "cache";
+// In milliseconds, multiplied by the number of attempts so far.
+const RETRY_DELAY: u64 = 5000;
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+#[cfg(feature = "rpi")]
+const FAILED_CODE: u8 = 2;
+// The name the cache LED is identified by.
+pub const CACHE_LED: &str = "cache";
+
+// Only downloads and listings are signed for S3 so the payload is always
+// empty.
+const EMPTY_PAYLOAD_HASH: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+const S3_SIGNED_HEADERS: &str = "host;x-amz-content-sha256;x-amz-date";
+// Everything but the unreserved characters is encoded when signing.
+const S3_ENCODE: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+const PROPFIND_BODY: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<propfind xmlns="DAV:"><prop><resourcetype/><getcontentlength/></prop></propfind>"#;
+
+fn default_prefetch() -> usize {
+    2
+}
+
+fn default_retries() -> u32 {
+    3
+}
+
+fn default_region() -> String {
+    String::from("us-east-1")
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Credentials {
+    Basic {
+        username: String,
+        password: Option<String>,
+    },
+    // Requests are signed with AWS signature version 4.
+    S3 {
+        access_key: String,
+        secret_key: String,
+        region: String,
+    },
+}
+
+// Where a playlist's tracks are stored when they aren't in the playlist
+// directory.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type")]
+pub enum StorageConfig {
+    // A WebDAV collection. Only the files directly inside it are used.
+    #[serde(rename_all = "camelCase")]
+    WebDav {
+        url: String,
+        username: Option<String>,
+        password: Option<String>,
+    },
+    // An S3 compatible bucket. Without keys the bucket must allow anonymous
+    // listing and reads.
+    #[serde(rename_all = "camelCase")]
+    S3 {
+        endpoint: String,
+        bucket: String,
+        #[serde(default)]
+        prefix: String,
+        access_key: Option<String>,
+        secret_key: Option<String>,
+        #[serde(default = "default_region")]
+        region: String,
+    },
+}
+
+impl StorageConfig {
+    pub fn storage(&self) -> MusicResult<Arc<dyn TrackStorage>> {
+        match self {
+            StorageConfig::WebDav {
+                url,
+                username,
+                password,
+            } => Ok(Arc::new(WebDavStorage {
+                url: Url::parse(url).prefix("Invalid WebDAV url")?,
+                credentials: username.as_ref().map(|username| Credentials::Basic {
+                    username: username.clone(),
+                    password: password.clone(),
+                }),
+            })),
+            StorageConfig::S3 {
+                endpoint,
+                bucket,
+                prefix,
+                access_key,
+                secret_key,
+                region,
+            } => Ok(Arc::new(S3Storage {
+                endpoint: Url::parse(endpoint).prefix("Invalid S3 endpoint")?,
+                bucket: bucket.clone(),
+                prefix: prefix.clone(),
+                credentials: match (access_key, secret_key) {
+                    (Some(access_key), Some(secret_key)) => Some(Credentials::S3 {
+                        access_key: access_key.clone(),
+                        secret_key: secret_key.clone(),
+                        region: region.clone(),
+                    }),
+                    (None, None) => None,
+                    _ => {
+                        return Err(String::from(
+                            "S3 storage needs both an access key and a secret key.",
+                        ))
+                    }
+                },
+            })),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CacheConfig {
+    // How many of the following tracks to download while a track plays.
+    #[serde(default = "default_prefetch")]
+    pub prefetch: usize,
+
+    // In megabytes. The oldest downloads are removed to stay below this.
+    pub max_size: Option<u64>,
+
+    // How many times a failed download is retried before giving up.
+    #[serde(default = "default_retries")]
+    pub retries: u32,
+
+    // In kilobytes per second.
+    pub bandwidth_limit: Option<u64>,
+
+    // Pulses while tracks are downloading. Identified as CACHE_LED.
+    #[cfg(feature = "rpi")]
+    #[serde(default)]
+    pub led: Option<LEDConfig>,
+}
+
+impl Default for CacheConfig {
+    fn default() -> CacheConfig {
+        CacheConfig {
+            prefetch: default_prefetch(),
+            max_size: None,
+            retries: default_retries(),
+            bandwidth_limit: None,
+            #[cfg(feature = "rpi")]
+            led: None,
+        }
+    }
+}
+
+// A track file held by a storage backend.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RemoteFile {
+    pub name: String,
+    pub url: String,
+    pub size: Option<u64>,
+    pub credentials: Option<Credentials>,
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn s3_encode(value: &str) -> String {
+    utf8_percent_encode(value, S3_ENCODE).to_string()
+}
+
+// The request as it is signed for AWS signature version 4.
+fn s3_canonical_request(method: &Method, url: &Url, timestamp: &str) -> String {
+    let path: Vec<String> = url
+        .path()
+        .split('/')
+        .map(|segment| s3_encode(&percent_decode_str(segment).decode_utf8_lossy()))
+        .collect();
+    let mut query: Vec<String> = url
+        .query_pairs()
+        .map(|(name, value)| format!("{}={}", s3_encode(&name), s3_encode(&value)))
+        .collect();
+    query.sort();
+    let host = url.host_str().unwrap_or_default();
+    let host = match url.port() {
+        Some(port) => format!("{}:{}", host, port),
+        None => host.to_owned(),
+    };
+
+    format!(
+        "{}\n{}\n{}\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{}\n{}",
+        method,
+        path.join("/"),
+        query.join("&"),
+        host,
+        EMPTY_PAYLOAD_HASH,
+        timestamp,
+        S3_SIGNED_HEADERS,
+        EMPTY_PAYLOAD_HASH
+    )
+}
+
+fn s3_signature(secret_key: &str, scope: &[&str], timestamp: &str, canonical: &str) -> String {
+    let signed = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+        timestamp,
+        scope.join("/"),
+        to_hex(digest(&SHA256, canonical.as_bytes()).as_ref())
+    );
+
+    // The key is derived from the secret and each part of the scope.
+    let mut key = hmac::Key::new(hmac::HMAC_SHA256, format!("AWS4{}", secret_key).as_bytes());
+    for part in scope {
+        key = hmac::Key::new(
+            hmac::HMAC_SHA256,
+            hmac::sign(&key, part.as_bytes()).as_ref(),
+        );
+    }
+    to_hex(hmac::sign(&key, signed.as_bytes()).as_ref())
+}
+
+fn request(
+    client: &Client,
+    method: Method,
+    url: Url,
+    credentials: &Option<Credentials>,
+) -> RequestBuilder {
+    match credentials {
+        Some(Credentials::Basic { username, password }) => client
+            .request(method, url)
+            .basic_auth(username, password.as_ref()),
+        Some(Credentials::S3 {
+            access_key,
+            secret_key,
+            region,
+        }) => {
+            let now = OffsetDateTime::from(SystemTime::now());
+            let date = now.format("%Y%m%d");
+            let timestamp = now.format("%Y%m%dT%H%M%SZ");
+            let scope = [date.as_str(), region, "s3", "aws4_request"];
+            let canonical = s3_canonical_request(&method, &url, &timestamp);
+            let signature = s3_signature(secret_key, &scope, &timestamp, &canonical);
+
+            client
+                .request(method, url)
+                .header("x-amz-date", timestamp)
+                .header("x-amz-content-sha256", EMPTY_PAYLOAD_HASH)
+                .header(
+                    "Authorization",
+                    format!(
+                        "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+                        access_key,
+                        scope.join("/"),
+                        S3_SIGNED_HEADERS,
+                        signature
+                    ),
+                )
+        }
+        None => client.request(method, url),
+    }
+}
+
+pub fn is_track(name: &str) -> bool {
+    Path::new(name)
+        .extension()
+        .map(|extension| extension == "mp3")
+        .unwrap_or(false)
+}
+
+// Listed names are joined onto the cache directory so anything that could
+// reach outside of it is refused.
+fn is_file_name(name: &str) -> bool {
+    name != "." && name != ".." && Path::new(name).file_name() == Some(name.as_ref())
+}
+
+// XML responses are matched on local names only as servers disagree on
+// namespaces.
+fn child<'a, 'input>(node: Node<'a, 'input>, name: &str) -> Option<Node<'a, 'input>> {
+    node.descendants()
+        .find(|child| child.tag_name().name() == name)
+}
+
+fn child_text<'a>(node: Node<'a, '_>, name: &str) -> Option<&'a str> {
+    child(node, name).and_then(|child| child.text())
+}
+
+// A backend that playlist tracks can be listed from. Listed files are
+// downloaded into the track cache before they play.
+pub trait TrackStorage: fmt::Debug + Send + Sync {
+    fn list<'a>(&'a self, client: &'a Client) -> BoxFuture<'a, MusicResult<Vec<RemoteFile>>>;
+}
+
+#[derive(Debug)]
+struct WebDavStorage {
+    url: Url,
+    credentials: Option<Credentials>,
+}
+
+impl WebDavStorage {
+    async fn propfind(&self, client: &Client) -> MusicResult<Vec<RemoteFile>> {
+        let method = Method::from_bytes(b"PROPFIND").as_err()?;
+        let text = request(client, method, self.url.clone(), &self.credentials)
+            .header("Depth", "1")
+            .header("Content-Type", "application/xml")
+            .body(PROPFIND_BODY)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .prefix("Unable to list WebDAV collection")?
+            .text()
+            .await
+            .prefix("Unable to list WebDAV collection")?;
+
+        let document = Document::parse(&text).prefix("Unable to parse WebDAV listing")?;
+        let mut files = Vec::new();
+        for response in document
+            .descendants()
+            .filter(|node| node.tag_name().name() == "response")
+        {
+            if child(response, "collection").is_some() {
+                continue;
+            }
+
+            let url = match child_text(response, "href").map(|href| self.url.join(href.trim())) {
+                Some(Ok(url)) => url,
+                _ => continue,
+            };
+
+            let name = match url
+                .path_segments()
+                .and_then(|mut segments| segments.next_back())
+            {
+                Some(segment) => percent_decode_str(segment).decode_utf8_lossy().to_string(),
+                None => continue,
+            };
+
+            if !is_file_name(&name) {
+                warn!("Ignoring WebDAV file with an invalid name: {}", name);
+                continue;
+            }
+
+            if !is_track(&name) {
+                continue;
+            }
+
+            files.push(RemoteFile {
+                name,
+                url: url.to_string(),
+                size: child_text(response, "getcontentlength").and_then(|size| size.parse().ok()),
+                credentials: self.credentials.clone(),
+            });
+        }
+
+        Ok(files)
+    }
+}
+
+impl TrackStorage for WebDavStorage {
+    fn list<'a>(&'a self, client: &'a Client) -> BoxFuture<'a, MusicResult<Vec<RemoteFile>>> {
+        self.propfind(client).boxed()
+    }
+}
+
+#[derive(Debug)]
+struct S3Storage {
+    endpoint: Url,
+    bucket: String,
+    prefix: String,
+    credentials: Option<Credentials>,
+}
+
+impl S3Storage {
+    fn object_url(&self, key: &str) -> MusicResult<Url> {
+        let mut url = self.endpoint.clone();
+        url.path_segments_mut()
+            .map_err(|_| String::from("Invalid S3 endpoint."))?
+            .pop_if_empty()
+            .push(&self.bucket)
+            .extend(key.split('/'));
+        Ok(url)
+    }
+
+    async fn list_objects(&self, client: &Client) -> MusicResult<Vec<RemoteFile>> {
+        let mut files = Vec::new();
+        let mut continuation: Option<String> = None;
+
+        loop {
+            // Encoded the same way as when signing so nothing is decoded
+            // differently by the server.
+            let mut query = vec![
+                String::from("list-type=2"),
+                format!("prefix={}", s3_encode(&self.prefix)),
+            ];
+            if let Some(ref token) = continuation {
+                query.push(format!("continuation-token={}", s3_encode(token)));
+            }
+            let mut url = self.object_url("")?;
+            url.set_query(Some(&query.join("&")));
+
+            let text = request(client, Method::GET, url, &self.credentials)
+                .send()
+                .await
+                .and_then(|response| response.error_for_status())
+                .prefix("Unable to list S3 bucket")?
+                .text()
+                .await
+                .prefix("Unable to list S3 bucket")?;
+
+            let document = Document::parse(&text).prefix("Unable to parse S3 listing")?;
+            let root = document.root_element();
+            for contents in root
+                .children()
+                .filter(|node| node.tag_name().name() == "Contents")
+            {
+                let key = match child_text(contents, "Key") {
+                    Some(key) => key,
+                    None => continue,
+                };
+
+                // Only the objects directly inside the prefix are used.
+                if !key.starts_with(&self.prefix) {
+                    continue;
+                }
+                let name = &key[self.prefix.len()..];
+                if !is_file_name(name) || !is_track(name) {
+                    continue;
+                }
+
+                files.push(RemoteFile {
+                    name: name.to_owned(),
+                    url: self.object_url(key)?.to_string(),
+                    size: child_text(contents, "Size").and_then(|size| size.parse().ok()),
+                    credentials: self.credentials.clone(),
+                });
+            }
+
+            match child_text(root, "NextContinuationToken") {
+                Some(token) if child_text(root, "IsTruncated") == Some("true") => {
+                    continuation = Some(token.to_owned())
+                }
+                _ => break,
+            }
+        }
+
+        Ok(files)
+    }
+}
+
+impl TrackStorage for S3Storage {
+    fn list<'a>(&'a self, client: &'a Client) -> BoxFuture<'a, MusicResult<Vec<RemoteFile>>> {
+        self.list_objects(client).boxed()
+    }
+}
+
+async fn is_cached(path: &Path, size: Option<u64>) -> bool {
+    match metadata(path).await {
+        Ok(metadata) => size.map(|size| size == metadata.len()).unwrap_or(true),
+        Err(_) => false,
+    }
+}
+
+// Removes the oldest downloads until the cache fits in the maximum size.
+fn evict(dir: &Path, max_size: u64, keep: &Path) -> VoidResult {
+    let mut files: Vec<(PathBuf, u64, SystemTime)> = Vec::new();
+    for playlist in fs::read_dir(dir).prefix("Unable to read track cache")? {
+        let playlist = playlist.as_err()?.path();
+        if !playlist.is_dir() {
+            continue;
+        }
+
+        for entry in fs::read_dir(&playlist).prefix("Unable to read track cache")? {
+            let entry = entry.as_err()?;
+            let metadata = entry.metadata().as_err()?;
+            if metadata.is_file() {
+                let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+                files.push((entry.path(), metadata.len(), modified));
+            }
+        }
+    }
+
+    let mut total: u64 = files.iter().map(|(_, size, _)| size).sum();
+    files.sort_by_key(|(_, _, modified)| *modified);
+    for (path, size, _) in files {
+        if total <= max_size {
+            break;
+        }
+
+        if path != keep {
+            debug!("Evicting {} from the track cache.", path.display());
+            fs::remove_file(&path).prefix("Unable to remove cached track")?;
+            total -= size;
+        }
+    }
+
+    Ok(())
+}
+
+struct Downloads {
+    progress: DownloadProgress,
+    state: Option<MutableAppState>,
+}
+
+// Holds local copies of tracks from storage backends.
+#[derive(Clone)]
+pub struct TrackCache {
+    client: Client,
+    dir: PathBuf,
+    prefetch: usize,
+    max_size: Option<u64>,
+    retries: u32,
+    bandwidth_limit: Option<u64>,
+    // Downloads happen one at a time, in the order they were requested, so
+    // the same file is never written twice.
+    lock: Arc<Mutex<()>>,
+    downloads: Arc<StdMutex<Downloads>>,
+    // Downloads fail immediately while the network is down.
+    offline: Arc<AtomicBool>,
+    #[cfg(feature = "rpi")]
+    led: Option<LED>,
+}
+
+impl TrackCache {
+    pub fn new(data_dir: &Path, config: &CacheConfig) -> MusicResult<TrackCache> {
+        Ok(TrackCache {
+            client: Client::new(),
+            dir: data_dir.join(CACHE_DIR),
+            prefetch: config.prefetch,
+            max_size: config.max_size.map(|size| size * 1024 * 1024),
+            retries: config.retries,
+            bandwidth_limit: config.bandwidth_limit.map(|limit| limit * 1024),
+            lock: Default::default(),
+            downloads: Arc::new(StdMutex::new(Downloads {
+                progress: Default::default(),
+                state: None,
+            })),
+            offline: Default::default(),
+            #[cfg(feature = "rpi")]
+            led: match config.led {
+                Some(ref led) if is_available() => Some(LED::new(led)?),
+                _ => None,
+            },
+        })
+    }
+
+    // The state is created after the playlists are listed so download
+    // progress is only reported from then on.
+    pub fn report_to(&self, mut state: MutableAppState) {
+        let mut downloads = self.downloads.lock().unwrap();
+        state.set_download_progress(downloads.progress.clone());
+        downloads.state = Some(state);
+    }
+
+    #[cfg(feature = "rpi")]
+    pub fn led(&self) -> Option<&LED> {
+        self.led.as_ref()
+    }
+
+    pub fn set_offline(&self, offline: bool) {
+        self.offline.store(offline, Ordering::SeqCst);
+    }
+
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    pub fn playlist_dir(&self, playlist: &str) -> PathBuf {
+        self.dir.join(playlist)
+    }
+
+    pub fn path(&self, playlist: &str, file: &RemoteFile) -> PathBuf {
+        self.playlist_dir(playlist).join(&file.name)
+    }
+
+    fn update<F>(&self, f: F)
+    where
+        F: FnOnce(&mut DownloadProgress),
+    {
+        let mut downloads = self.downloads.lock().unwrap();
+        f(&mut downloads.progress);
+
+        // The LED pulses while anything is downloading.
+        #[cfg(feature = "rpi")]
+        {
+            if let Some(ref led) = self.led {
+                if downloads.progress.queued > 0 {
+                    led.pulse();
+                } else {
+                    led.off();
+                }
+            }
+        }
+
+        let progress = downloads.progress.clone();
+        if let Some(ref mut state) = downloads.state {
+            state.set_download_progress(progress);
+        }
+    }
+
+    // Downloads the track if it is from a storage backend and not already
+    // cached.
+    pub async fn fetch(&self, track: &Track) -> VoidResult {
+        let remote = match track.remote() {
+            Some(remote) => remote,
+            None => return Ok(()),
+        };
+
+        let path = track.path();
+        if is_cached(&path, remote.size).await {
+            return Ok(());
+        }
+
+        if self.offline.load(Ordering::SeqCst) {
+            return Err(format!(
+                "Unable to download {} while the network is down.",
+                remote.name
+            ));
+        }
+
+        self.update(|progress| progress.queued += 1);
+        let result = self.queued_download(remote, &path).await;
+        self.update(|progress| {
+            progress.queued -= 1;
+            progress.current = None;
+            match result {
+                Ok(true) => progress.completed += 1,
+                Ok(false) => (),
+                Err(_) => progress.failed += 1,
+            }
+        });
+
+        // Blinks twice when a download is given up on.
+        #[cfg(feature = "rpi")]
+        {
+            if let (Err(_), Some(led)) = (&result, &self.led) {
+                led.blink_code(vec![FAILED_CODE]);
+            }
+        }
+
+        result.map(|_| ())
+    }
+
+    // Waits for earlier downloads to finish then downloads the file, retrying
+    // if it fails. Returns false if the file was cached in the meantime.
+    async fn queued_download(&self, remote: &RemoteFile, path: &Path) -> MusicResult<bool> {
+        let _guard = self.lock.lock().await;
+        if is_cached(path, remote.size).await {
+            return Ok(false);
+        }
+
+        let mut attempt: u32 = 0;
+        loop {
+            match self.download(remote, path).await {
+                Ok(()) => break,
+                Err(e) if attempt < self.retries => {
+                    attempt += 1;
+                    warn!("Download of {} failed, retrying: {}", remote.name, e);
+                    delay_for(Duration::from_millis(RETRY_DELAY) * attempt).await;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        if let Some(max_size) = self.max_size {
+            let dir = self.dir.clone();
+            let keep = path.to_owned();
+            spawn_blocking(move || evict(&dir, max_size, &keep))
+                .await
+                .as_err()??;
+        }
+
+        Ok(true)
+    }
+
+    async fn download(&self, remote: &RemoteFile, path: &Path) -> VoidResult {
+        info!("Downloading {}.", remote.url);
+        if let Some(parent) = path.parent() {
+            create_dir_all(parent)
+                .await
+                .prefix("Unable to create track cache")?;
+        }
+
+        let url = Url::parse(&remote.url).prefix("Invalid track url")?;
+        let mut response = request(&self.client, Method::GET, url, &remote.credentials)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .prefix("Unable to download track")?;
+
+        let size = response.content_length().or(remote.size);
+        self.update(|progress| {
+            progress.current = Some(CurrentDownload {
+                name: remote.name.clone(),
+                received: 0,
+                size,
+            })
+        });
+
+        // Written under a different name so a partial download is never
+        // mistaken for the track.
+        let partial = path.with_extension("part");
+        let mut file = File::create(&partial)
+            .await
+            .prefix("Unable to create cached track")?;
+
+        let start = Instant::now();
+        let mut last_update = start;
+        let mut received: u64 = 0;
+        while let Some(chunk) = response.chunk().await.prefix("Unable to download track")? {
+            file.write_all(&chunk)
+                .await
+                .prefix("Unable to write cached track")?;
+            received += chunk.len() as u64;
+
+            if last_update.elapsed() >= PROGRESS_INTERVAL {
+                last_update = Instant::now();
+                self.update(|progress| {
+                    if let Some(ref mut current) = progress.current {
+                        current.received = received;
+                    }
+                });
+            }
+
+            // Sleeps whenever the download gets ahead of the limit.
+            if let Some(limit) = self.bandwidth_limit {
+                let expected = Duration::from_secs_f64(received as f64 / limit as f64);
+                let elapsed = start.elapsed();
+                if expected > elapsed {
+                    delay_for(expected - elapsed).await;
+                }
+            }
+        }
+
+        file.sync_all()
+            .await
+            .prefix("Unable to write cached track")?;
+        rename(&partial, path)
+            .await
+            .prefix("Unable to write cached track")
+    }
+
+    // Downloads the tracks that are likely to play next in the background.
+    pub fn prefetch(&self, tracks: &[Track]) {
+        if self.offline.load(Ordering::SeqCst) {
+            return;
+        }
+
+        let tracks: Vec<Track> = tracks
+            .iter()
+            .filter(|track| track.remote().is_some())
+            .take(self.prefetch)
+            .cloned()
+            .collect();
+        if tracks.is_empty() {
+            return;
+        }
+
+        let cache = self.clone();
+        tokio::spawn(async move {
+            for track in tracks {
+                cache.fetch(&track).await.log().drop();
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The example from the S3 documentation for signing a bucket listing.
+    #[test]
+    fn s3_signature_matches_documentation() {
+        let url =
+            Url::parse("https://examplebucket.s3.amazonaws.com/?max-keys=2&prefix=J").unwrap();
+        let timestamp = "20130524T000000Z";
+        let canonical = s3_canonical_request(&Method::GET, &url, timestamp);
+
+        assert_eq!(
+            s3_signature(
+                "wJalrXUtnFEMI/K7MDENG/bPxRfiCYEXAMPLEKEY",
+                &["20130524", "us-east-1", "s3", "aws4_request"],
+                timestamp,
+                &canonical
+            ),
+            "34b48302e7b5fa45bde8084f4b7868a86f0a534bc59db6670ed5711ef69dc6f7"
+        );
+    }
+}
@@ -0,0 +1,95 @@
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::Deserialize;
+
+use crate::playlist::StoredPlaylist;
+use crate::track::Track;
+
+fn default_weight() -> u32 {
+    1
+}
+
+fn default_length() -> usize {
+    20
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SurpriseSource {
+    pub playlist: String,
+
+    // How likely each track is to come from this playlist compared to the
+    // other sources.
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+}
+
+// A playlist that doesn't have tracks of its own. Starting it plays a random
+// selection of tracks from the source playlists.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SurpriseConfig {
+    pub name: String,
+
+    pub sources: Vec<SurpriseSource>,
+
+    // The number of tracks to pick.
+    #[serde(default = "default_length")]
+    pub length: usize,
+}
+
+// A random number from 0 up to but not including the bound.
+fn random_below(random: &SystemRandom, bound: u64) -> u64 {
+    let mut bytes = [0; 8];
+    if random.fill(&mut bytes).is_err() {
+        return 0;
+    }
+    u64::from_ne_bytes(bytes) % bound
+}
+
+impl SurpriseConfig {
+    // Picks the tracks for a new queue. Each track is only picked once.
+    pub fn pick<F>(&self, lookup: F) -> Vec<Track>
+    where
+        F: Fn(&str) -> Option<StoredPlaylist>,
+    {
+        let mut sources: Vec<(u32, Vec<Track>)> = self
+            .sources
+            .iter()
+            .filter(|source| source.weight > 0)
+            .filter_map(|source| {
+                lookup(&source.playlist).map(|playlist| (source.weight, playlist.tracks()))
+            })
+            .collect();
+
+        let random = SystemRandom::new();
+        let mut picked = Vec::with_capacity(self.length);
+        while picked.len() < self.length {
+            sources.retain(|(_, tracks)| !tracks.is_empty());
+            let total: u64 = sources.iter().map(|(weight, _)| u64::from(*weight)).sum();
+            if total == 0 {
+                break;
+            }
+
+            let mut choice = random_below(&random, total);
+            let tracks = match sources.iter_mut().find(|(weight, _)| {
+                if choice < u64::from(*weight) {
+                    true
+                } else {
+                    choice -= u64::from(*weight);
+                    false
+                }
+            }) {
+                Some((_, tracks)) => tracks,
+                None => break,
+            };
+
+            let index = random_below(&random, tracks.len() as u64) as usize;
+            let track = tracks.swap_remove(index);
+            if !picked.contains(&track) {
+                picked.push(track);
+            }
+        }
+
+        picked
+    }
+}
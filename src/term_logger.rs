@@ -1,101 +1,229 @@
+use std::collections::BTreeMap;
+use std::fmt::{self, Write as _};
 use std::io::{stdout, Stdout, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Mutex;
+#[cfg(feature = "desktop")]
+use std::thread;
 
 use crossterm::cursor::MoveToColumn;
 use crossterm::style::{style, Color, Print, PrintStyledContent};
 use crossterm::QueueableCommand;
-use log::{Level, LevelFilter, Log, Metadata, Record};
 use time::Time;
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Level, Metadata, Subscriber};
+use tracing_log::{AsLog, LogTracer, NormalizeEvent};
+use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Registry;
 
-use crate::error::{ErrorExt, MusicResult, VoidResult};
+use crate::error::{ErrorExt, VoidResult};
+#[cfg(feature = "desktop")]
+use crate::i18n::tr;
+use crate::logs::{self, LogSpan};
+#[cfg(feature = "desktop")]
+use crate::notifications::notify;
 
-struct Logger {
-    output: Stdout,
+// Set while something else, like the dashboard, is using the terminal.
+static PAUSED: AtomicBool = AtomicBool::new(false);
+
+pub fn pause(paused: bool) {
+    PAUSED.store(paused, Ordering::SeqCst);
 }
 
-impl Logger {
-    fn enabled(&self, metadata: &Metadata) -> MusicResult<bool> {
-        let target = metadata.target();
-        if target.starts_with("musicbox::") || target.starts_with("rpi_futures::") {
-            Ok(true)
-        } else {
-            Ok(false)
+fn is_enabled(metadata: &Metadata) -> bool {
+    let target = metadata.target();
+    target.starts_with("musicbox::") || target.starts_with("rpi_futures::")
+}
+
+// Collects the fields of a span or event as text, keeping the message apart.
+// The fields are also kept by name for structured logs.
+#[derive(Default)]
+struct Fields {
+    message: String,
+    fields: String,
+    values: BTreeMap<String, String>,
+}
+
+impl Visit for Fields {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.record_debug(field, &format_args!("{}", value));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        // Records from the log crate carry their source location as fields.
+        if field.name() == "message" {
+            write!(self.message, "{:?}", value).drop();
+        } else if !field.name().starts_with("log.") {
+            if !self.fields.is_empty() {
+                self.fields.push(' ');
+            }
+            write!(self.fields, "{}={:?}", field.name(), value).drop();
+            self.values
+                .insert(field.name().to_owned(), format!("{:?}", value));
         }
     }
+}
 
-    fn log(&mut self, record: &Record) -> VoidResult {
-        if !self.enabled(record.metadata())? {
-            return Ok(());
+struct Logger {
+    // Missing when running as a daemon.
+    output: Option<Stdout>,
+}
+
+impl Logger {
+    fn log(
+        &mut self,
+        level: &Level,
+        target: &str,
+        context: &str,
+        fields: Fields,
+        spans: Vec<LogSpan>,
+    ) -> VoidResult {
+        let mut message = fields.message;
+        if !fields.fields.is_empty() {
+            write!(message, " {}", fields.fields).drop();
         }
+        logs::record(
+            level.as_log(),
+            target,
+            context,
+            &message,
+            fields.values,
+            spans,
+        );
+
+        let output = match self.output {
+            Some(ref mut output) if !PAUSED.load(Ordering::SeqCst) => output,
+            _ => return Ok(()),
+        };
 
         let time = Time::now();
-        self.output
+        output
             .queue(Print(format!("[{} ", time.format("%H:%M:%S"))))
             .as_err()?;
 
-        self.output
-            .queue(PrintStyledContent(match record.level() {
-                Level::Error => style("ERROR").with(Color::Red),
-                Level::Warn => style(" WARN").with(Color::Yellow),
-                Level::Info => style(" INFO").with(Color::White),
-                Level::Debug => style("DEBUG").with(Color::Grey),
-                Level::Trace => style("TRACE").with(Color::DarkGrey),
+        output
+            .queue(PrintStyledContent(if *level == Level::ERROR {
+                style("ERROR").with(Color::Red)
+            } else if *level == Level::WARN {
+                style(" WARN").with(Color::Yellow)
+            } else if *level == Level::INFO {
+                style(" INFO").with(Color::White)
+            } else if *level == Level::DEBUG {
+                style("DEBUG").with(Color::Grey)
+            } else {
+                style("TRACE").with(Color::DarkGrey)
             }))
             .as_err()?;
 
-        self.output
-            .queue(Print(format!(" {}] {}\n", record.target(), record.args())))
+        output
+            .queue(Print(format!(" {}] {}{}\n", target, context, message)))
             .as_err()?;
 
-        self.output.queue(MoveToColumn(0)).as_err()?;
-        Ok(())
-    }
+        output.queue(MoveToColumn(0)).as_err()?;
+
+        // Errors are easy to miss in the scrolling log.
+        #[cfg(feature = "desktop")]
+        {
+            if *level == Level::ERROR {
+                let message = message.to_owned();
+                thread::spawn(move || notify(&tr("error"), &message).drop());
+            }
+        }
 
-    fn flush(&mut self) -> VoidResult {
-        self.output
+        output
             .flush()
-            .map_err(|_| String::from("Failed to flush output."))?;
-        Ok(())
+            .map_err(|_| String::from("Failed to flush output."))
     }
 }
 
+// Writes log messages to the terminal, prefixed by the spans they happened
+// in. Messages from crates still using the log crate are included. Every
+// message is also kept for the logs API.
 pub struct TermLogger {
     inner: Mutex<Logger>,
 }
 
 impl TermLogger {
+    fn install(output: Option<Stdout>) -> VoidResult {
+        LogTracer::init().map_err(|_| String::from("Logging already initialized."))?;
+
+        let subscriber = Registry::default().with(TermLogger {
+            inner: Mutex::new(Logger { output }),
+        });
+        tracing::subscriber::set_global_default(subscriber)
+            .map_err(|_| String::from("Logging already initialized."))
+    }
+
     pub fn init() -> VoidResult {
-        log::set_boxed_logger(Box::new(TermLogger {
-            inner: Mutex::new(Logger { output: stdout() }),
-        }))
-        .map_err(|_| String::from("Logging already initialized."))?;
-        log::set_max_level(LevelFilter::Trace);
-        Ok(())
+        TermLogger::install(Some(stdout()))
+    }
+
+    // Keeps log messages without writing them to the terminal, for when
+    // running as a daemon.
+    pub fn init_detached() -> VoidResult {
+        TermLogger::install(None)
     }
 }
 
-impl Log for TermLogger {
-    fn enabled(&self, metadata: &Metadata) -> bool {
-        self.inner
-            .lock()
-            .map_err(|_| String::from("Failed to lock logger."))
-            .and_then(|inner| inner.enabled(metadata))
-            .unwrap()
+impl<S> Layer<S> for TermLogger
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn enabled(&self, metadata: &Metadata, _ctx: Context<S>) -> bool {
+        is_enabled(metadata)
     }
 
-    fn log(&self, record: &Record) {
-        self.inner
-            .lock()
-            .map_err(|_| String::from("Failed to lock logger."))
-            .and_then(|mut inner| inner.log(record))
-            .unwrap();
+    fn new_span(&self, attrs: &Attributes, id: &Id, ctx: Context<S>) {
+        if let Some(span) = ctx.span(id) {
+            let mut fields = Fields::default();
+            attrs.record(&mut fields);
+            span.extensions_mut().insert(fields);
+        }
+    }
+
+    fn on_record(&self, id: &Id, values: &Record, ctx: Context<S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(fields) = span.extensions_mut().get_mut::<Fields>() {
+                values.record(fields);
+            }
+        }
     }
 
-    fn flush(&self) {
+    fn on_event(&self, event: &Event, ctx: Context<S>) {
+        let normalized = event.normalized_metadata();
+        let metadata = normalized.as_ref().unwrap_or_else(|| event.metadata());
+
+        let mut context = String::new();
+        let mut spans = Vec::new();
+        if let Some(scope) = ctx.event_scope(event) {
+            for span in scope.from_root() {
+                context.push_str(span.name());
+                let mut values = BTreeMap::new();
+                if let Some(fields) = span.extensions().get::<Fields>() {
+                    if !fields.fields.is_empty() {
+                        write!(context, "{{{}}}", fields.fields).drop();
+                    }
+                    values = fields.values.clone();
+                }
+                context.push_str(": ");
+                spans.push(LogSpan {
+                    name: span.name().to_owned(),
+                    fields: values,
+                });
+            }
+        }
+
+        let mut fields = Fields::default();
+        event.record(&mut fields);
+
         self.inner
             .lock()
             .map_err(|_| String::from("Failed to lock logger."))
-            .and_then(|mut inner| inner.flush())
+            .and_then(|mut inner| {
+                inner.log(metadata.level(), metadata.target(), &context, fields, spans)
+            })
             .unwrap();
     }
 }
@@ -1,43 +1,93 @@
 use std::fmt;
+use std::os::unix::ffi::OsStrExt;
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-use serde::{Serialize, Serializer};
-
-fn serialize_file_name<S>(path: &Path, serializer: S) -> Result<S::Ok, S::Error>
-where
-    S: Serializer,
-{
-    path.file_name()
-        .unwrap()
-        .to_os_string()
-        .into_string()
-        .unwrap()
-        .serialize(serializer)
+use musicbox_protocol::TrackInfo;
+
+use crate::library::TrackMetadata;
+use crate::storage::RemoteFile;
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0100_0000_01b3;
+
+// A stable identifier for a track derived from its canonical path. This uses
+// FNV-1a rather than the std hasher as the std hasher is not guaranteed to
+// produce the same results across Rust releases.
+fn track_id(path: &Path) -> String {
+    let hash = path
+        .as_os_str()
+        .as_bytes()
+        .iter()
+        .fold(FNV_OFFSET_BASIS, |hash, byte| {
+            (hash ^ u64::from(*byte)).wrapping_mul(FNV_PRIME)
+        });
+    format!("{:016x}", hash)
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Track {
-    #[serde(serialize_with = "serialize_file_name")]
+    id: String,
     path: PathBuf,
     title: String,
+    artist: Option<String>,
+    album: Option<String>,
+    duration: Option<Duration>,
+    // Set when the path is where this track is cached from a storage backend.
+    remote: Option<RemoteFile>,
 }
 
 impl Track {
-    pub fn new(path: &Path) -> Track {
-        let title = match path.file_stem() {
-            Some(name) => name.to_string_lossy().to_string(),
-            None => path.display().to_string(),
+    // The path should be canonical so that the same file always results in
+    // the same id.
+    pub fn new(path: &Path, metadata: TrackMetadata) -> Track {
+        let title = match (metadata.title, path.file_stem()) {
+            (Some(title), _) => title,
+            (None, Some(name)) => name.to_string_lossy().to_string(),
+            (None, None) => path.display().to_string(),
         };
 
         Track {
+            id: track_id(path),
             path: path.to_owned(),
             title,
+            artist: metadata.artist,
+            album: metadata.album,
+            duration: metadata.duration,
+            remote: None,
         }
     }
 
+    pub fn with_remote(mut self, remote: RemoteFile) -> Track {
+        self.remote = Some(remote);
+        self
+    }
+
+    pub fn id(&self) -> String {
+        self.id.clone()
+    }
+
     pub fn path(&self) -> PathBuf {
         self.path.clone()
     }
+
+    pub fn title(&self) -> String {
+        self.title.clone()
+    }
+
+    pub fn remote(&self) -> Option<&RemoteFile> {
+        self.remote.as_ref()
+    }
+
+    pub fn info(&self) -> TrackInfo {
+        TrackInfo {
+            id: self.id.clone(),
+            title: self.title.clone(),
+            artist: self.artist.clone(),
+            album: self.album.clone(),
+            duration: self.duration,
+        }
+    }
 }
 
 impl fmt::Display for Track {
@@ -0,0 +1,214 @@
+use std::env::{args_os, current_exe};
+use std::ffi::OsString;
+use std::fs::{read, remove_file, rename, write, Permissions};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::str;
+
+use reqwest::Client;
+use ring::signature::{UnparsedPublicKey, ED25519};
+use serde::{Deserialize, Serialize};
+use serde_json::{from_slice, to_vec};
+use tokio::fs;
+use tracing::{info, warn};
+
+use crate::error::{ErrorExt, MusicResult, VoidResult};
+
+const UPDATE_FILE: &str = "update.json";
+
+// An update is rolled back when it fails to start this many times in a row.
+const MAX_BOOT_ATTEMPTS: u32 = 2;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UpdateConfig {
+    // The url of a release manifest.
+    pub manifest: String,
+
+    // The hex encoded ed25519 key that releases are signed with.
+    pub public_key: String,
+
+    // Hours between checks. Without this updates only happen on command.
+    pub interval: Option<u64>,
+}
+
+// Describes the latest release.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Manifest {
+    version: String,
+    url: String,
+    // The hex encoded ed25519 signature of the version followed by a newline
+    // and then the binary, so an old release can't be passed off as new.
+    signature: String,
+}
+
+// Written when an update is installed and removed once the new binary has
+// started successfully.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PendingUpdate {
+    executable: PathBuf,
+    backup: PathBuf,
+    attempts: u32,
+}
+
+fn update_file(data_dir: &Path) -> PathBuf {
+    data_dir.join(UPDATE_FILE)
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+fn parse_version(version: &str) -> Vec<u64> {
+    version
+        .trim_start_matches('v')
+        .split('.')
+        .map(|part| part.parse().unwrap_or(0))
+        .collect()
+}
+
+fn signed_release(version: &str, binary: &[u8]) -> Vec<u8> {
+    let mut data = format!("{}\n", version).into_bytes();
+    data.extend_from_slice(binary);
+    data
+}
+
+fn decode_hex(text: &str) -> MusicResult<Vec<u8>> {
+    text.as_bytes()
+        .chunks(2)
+        .map(|pair| {
+            str::from_utf8(pair)
+                .ok()
+                .filter(|digits| digits.len() == 2)
+                .and_then(|digits| u8::from_str_radix(digits, 16).ok())
+                .ok_or_else(|| String::from("Invalid hex string."))
+        })
+        .collect()
+}
+
+fn save_pending(data_dir: &Path, pending: &PendingUpdate) -> VoidResult {
+    let data = to_vec(pending).prefix("Unable to serialize update state")?;
+    write(update_file(data_dir), data).prefix("Unable to write update state")
+}
+
+// Replaces the running process with a new instance of the executable.
+pub fn restart(executable: &Path) -> VoidResult {
+    info!("Restarting.");
+    let error = Command::new(executable).args(args_os().skip(1)).exec();
+    Err(format!("Unable to restart: {}", error))
+}
+
+// Called early on start up, returns true if this is the first start after an
+// update. If a recently installed update keeps failing to start the previous
+// binary is restored and started instead.
+pub fn check_boot(data_dir: &Path) -> MusicResult<bool> {
+    let mut pending: PendingUpdate = match read(update_file(data_dir)) {
+        Ok(data) => from_slice(&data).prefix("Unable to parse update state")?,
+        Err(_) => return Ok(false),
+    };
+
+    pending.attempts += 1;
+    if pending.attempts <= MAX_BOOT_ATTEMPTS {
+        save_pending(data_dir, &pending)?;
+        return Ok(pending.attempts == 1);
+    }
+
+    warn!("The update failed to start, rolling back.");
+    rename(&pending.backup, &pending.executable).prefix("Unable to roll back update")?;
+    remove_file(update_file(data_dir)).prefix("Unable to remove update state")?;
+    restart(&pending.executable)?;
+    Ok(false)
+}
+
+// The executable to start after an update has been installed.
+pub fn pending_executable(data_dir: &Path) -> MusicResult<PathBuf> {
+    let data = read(update_file(data_dir)).prefix("Unable to read update state")?;
+    let pending: PendingUpdate = from_slice(&data).prefix("Unable to parse update state")?;
+    Ok(pending.executable)
+}
+
+// Called once the music box has been running for a while after starting.
+pub fn confirm_boot(data_dir: &Path) -> VoidResult {
+    let pending: PendingUpdate = match read(update_file(data_dir)) {
+        Ok(data) => from_slice(&data).prefix("Unable to parse update state")?,
+        Err(_) => return Ok(()),
+    };
+
+    info!("Update started successfully.");
+    remove_file(&pending.backup).log().drop();
+    remove_file(update_file(data_dir)).prefix("Unable to remove update state")
+}
+
+// Checks for a newer release and installs it. Returns the version if an update
+// was installed.
+pub async fn update(config: &UpdateConfig, data_dir: &Path) -> MusicResult<Option<String>> {
+    let client = Client::new();
+    let manifest: Manifest = client
+        .get(&config.manifest)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .prefix("Unable to fetch release manifest")?
+        .json()
+        .await
+        .prefix("Unable to parse release manifest")?;
+
+    if parse_version(&manifest.version) <= parse_version(env!("CARGO_PKG_VERSION")) {
+        info!("Already running the latest release.");
+        return Ok(None);
+    }
+
+    info!("Downloading release {}.", manifest.version);
+    let binary = client
+        .get(&manifest.url)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .prefix("Unable to download release")?
+        .bytes()
+        .await
+        .prefix("Unable to download release")?;
+
+    let key = decode_hex(&config.public_key).prefix("Invalid update public key")?;
+    let signature = decode_hex(&manifest.signature).prefix("Invalid release signature")?;
+    UnparsedPublicKey::new(&ED25519, &key)
+        .verify(&signed_release(&manifest.version, &binary), &signature)
+        .map_err(|_| String::from("The release signature does not match."))?;
+
+    let executable = current_exe().prefix("Unable to find the running executable")?;
+    let staged = with_suffix(&executable, ".new");
+    let backup = with_suffix(&executable, ".old");
+
+    fs::write(&staged, &binary)
+        .await
+        .prefix("Unable to write release")?;
+    fs::set_permissions(&staged, Permissions::from_mode(0o755))
+        .await
+        .prefix("Unable to make release executable")?;
+
+    fs::rename(&executable, &backup)
+        .await
+        .prefix("Unable to back up the running executable")?;
+    if let Err(e) = fs::rename(&staged, &executable).await {
+        fs::rename(&backup, &executable).await.log().drop();
+        return Err(format!("Unable to install release: {}", e));
+    }
+
+    save_pending(
+        data_dir,
+        &PendingUpdate {
+            executable,
+            backup,
+            attempts: 0,
+        },
+    )?;
+
+    info!("Installed release {}.", manifest.version);
+    Ok(Some(manifest.version))
+}
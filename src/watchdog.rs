@@ -0,0 +1,148 @@
+use std::time::{Duration, Instant};
+
+use futures::future::{abortable, AbortHandle};
+use serde::Deserialize;
+use tokio::time::delay_for;
+
+use crate::events::{Event, MessageSender};
+
+fn default_timeout() -> u64 {
+    10
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum StallAction {
+    // Starts the track again from where it stalled. If it stalls again
+    // without getting any further the track is skipped.
+    Restart,
+    Skip,
+}
+
+impl Default for StallAction {
+    fn default() -> StallAction {
+        StallAction::Restart
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WatchdogConfig {
+    // Seconds that the position can stay still while playing before playback
+    // counts as stalled. 0 turns the watchdog off.
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+
+    #[serde(default)]
+    pub action: StallAction,
+}
+
+impl Default for WatchdogConfig {
+    fn default() -> WatchdogConfig {
+        WatchdogConfig {
+            timeout: default_timeout(),
+            action: Default::default(),
+        }
+    }
+}
+
+// Notices when the player says it is playing but the position stops moving,
+// which is what a wedged pipeline looks like.
+pub struct Watchdog {
+    config: WatchdogConfig,
+    sender: MessageSender<Event>,
+    timer: Option<AbortHandle>,
+    position: Option<Duration>,
+    progressed: Instant,
+    // Whether the track has been restarted since it last made progress.
+    restarted: bool,
+}
+
+impl Watchdog {
+    pub fn new(config: WatchdogConfig, sender: MessageSender<Event>) -> Watchdog {
+        Watchdog {
+            config,
+            sender,
+            timer: None,
+            position: None,
+            progressed: Instant::now(),
+            restarted: false,
+        }
+    }
+
+    fn timeout(&self) -> Duration {
+        Duration::from_secs(self.config.timeout)
+    }
+
+    fn arm(&mut self) {
+        self.progressed = Instant::now();
+        if self.config.timeout == 0 {
+            return;
+        }
+
+        let (delay, handle) = abortable(delay_for(self.timeout()));
+        let sender = self.sender.clone();
+        tokio::spawn(async move {
+            if delay.await.is_ok() {
+                sender.send(Event::PlaybackStalled.into());
+            }
+        });
+
+        if let Some(timer) = self.timer.replace(handle) {
+            timer.abort();
+        }
+    }
+
+    // A track has started playing.
+    pub fn start(&mut self) {
+        self.position = None;
+        self.arm();
+    }
+
+    pub fn resume(&mut self) {
+        self.arm();
+    }
+
+    pub fn stop(&mut self) {
+        if let Some(timer) = self.timer.take() {
+            timer.abort();
+        }
+    }
+
+    pub fn progress(&mut self, position: Duration) {
+        if self.position == Some(position) {
+            return;
+        }
+
+        if self.position.is_some() {
+            self.restarted = false;
+        }
+        self.position = Some(position);
+        self.arm();
+    }
+
+    // The last position playback reached.
+    pub fn position(&self) -> Option<Duration> {
+        self.position
+    }
+
+    // Checks that a stall the timer saw hasn't since been overtaken by
+    // progress or by playback stopping.
+    pub fn stalled(&self) -> bool {
+        self.timer.is_some() && self.progressed.elapsed() >= self.timeout()
+    }
+
+    // What to do about a stall.
+    pub fn recover(&mut self) -> StallAction {
+        self.stop();
+        match self.config.action {
+            StallAction::Restart if !self.restarted => {
+                self.restarted = true;
+                StallAction::Restart
+            }
+            _ => {
+                self.restarted = false;
+                StallAction::Skip
+            }
+        }
+    }
+}
@@ -1,4 +1,4 @@
-import { Handler, handler, MessageToServer } from "../types/api";
+import { Handler, handler, MessageFromServer, MessageToServer } from "../types/api";
 import { AppStateDecoder, AppState } from "../types/musicbox";
 
 type Resolve<T> = (value: T | Promise<T>) => void;
@@ -6,11 +6,11 @@ type Resolve<T> = (value: T | Promise<T>) => void;
 type ResponseHandler = (message: any) => void;
 type Rejecter = (error: Error) => void;
 
-function connect(): Promise<WebSocket> {
+function connect(url: string): Promise<WebSocket> {
   return new Promise((resolve: Resolve<WebSocket>) => {
-    let socket = new WebSocket("/ws");
+    let socket = new WebSocket(url);
     let errorHandler = (): void => {
-      resolve(connect());
+      resolve(connect(url));
     };
 
     socket.addEventListener("open", () => {
@@ -26,6 +26,11 @@ export class Connection {
   private requestId: number = 0;
   private socket: Promise<WebSocket>;
   private requests: Map<number, [ResponseHandler, Rejecter]> = new Map();
+  // Used to ask for the events missed while disconnected.
+  private session: string | null = null;
+  private sequence: number = 0;
+  // False when events were missed and the state must be fetched again.
+  public resumed: boolean = false;
 
   public getState: Handler<AppState> = handler("state", AppStateDecoder);
 
@@ -34,8 +39,13 @@ export class Connection {
   }
 
   private async connect(): Promise<WebSocket> {
-    let socket = await connect();
-    socket.addEventListener("message", () => this.onMessage());
+    let url = "/ws";
+    if (this.session) {
+      url += `?session=${encodeURIComponent(this.session)}&since=${this.sequence}`;
+    }
+
+    let socket = await connect(url);
+    socket.addEventListener("message", (event: MessageEvent) => this.onMessage(event));
     socket.addEventListener("error", () => this.onError());
     socket.addEventListener("close", () => this.onClose());
     return socket;
@@ -49,8 +59,25 @@ export class Connection {
     this.socket = this.connect();
   }
 
-  private onMessage(): void {
-
+  private onMessage(event: MessageEvent): void {
+    let message = JSON.parse(event.data) as MessageFromServer;
+    switch (message.type) {
+      case "Connected":
+        this.session = message.session;
+        this.resumed = message.resumed;
+        break;
+      case "Event":
+        this.sequence = message.sequence;
+        break;
+      case "Response": {
+        let request = this.requests.get(message.id);
+        if (request) {
+          this.requests.delete(message.id);
+          request[0](message.response);
+        }
+        break;
+      }
+    }
   }
 
   public request(path: string, data: any): Promise<any> {
@@ -2,3 +2,20 @@ import { AppStateDecoder } from "../types/musicbox";
 import { request } from "./helpers";
 
 export const fetchState = request("/api/state", AppStateDecoder);
+
+export interface Waveform {
+  duration: number;
+  peaks: Uint8Array;
+}
+
+export async function fetchWaveform(id: string, points: number): Promise<Waveform | null> {
+  let response = await fetch(`/api/waveform/${encodeURIComponent(id)}?points=${points}`);
+  if (!response.ok) {
+    return null;
+  }
+
+  return {
+    duration: Number(response.headers.get("x-track-duration")),
+    peaks: new Uint8Array(await response.arrayBuffer()),
+  };
+}
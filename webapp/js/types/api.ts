@@ -1,6 +1,7 @@
 import { JsonDecoder } from "ts.data.json";
 
 import { Connection } from "../api/connection";
+import { Track } from "./musicbox";
 
 export type Handler<S> = () => Promise<S>;
 export type ParamHandler<Q, S> = (data: Q) => Promise<S>;
@@ -19,21 +20,149 @@ export function paramHandler<Q, S>(path: string, decoder: JsonDecoder.Decoder<S>
 
 export type Command = {
   type: "PreviousTrack" |
+  "RestartTrack" |
+  "FavoriteTrack" |
+  "BlockTrack" |
   "NextTrack" |
   "PlayPause" |
   "VolumeUp" |
   "VolumeDown" |
   "Shutdown" |
   "Reload" |
-  "Status";
+  "Status" |
+  "PlayTestSound" |
+  "Update" |
+  "Restart";
+} | {
+  type: "Seek";
+  millis: number;
+} | {
+  type: "SetBalance";
+  balance: number;
+} | {
+  type: "PlayFile";
+  path: string;
+  duck?: boolean;
+} | {
+  type: "SetGuestMode";
+  enabled: boolean;
+} | {
+  type: "OverrideQuietHours";
+  enabled: boolean;
+} | {
+  type: "UnblockTrack";
+  id: string;
+} | {
+  type: "GroupCommand";
+  command: Command;
+} | {
+  type: "SelectProfile";
+  name?: string;
+} | {
+  type: "Keypad";
+  key: string;
 } | {
   type: "StartPlaylist";
   name: string;
   force: boolean;
+} | {
+  type: "PlayTrack";
+  id: string;
+} | {
+  type: "AddToQueue" | "MoveInQueue";
+  id: string;
+  before?: string;
+} | {
+  type: "RemoveFromQueue";
+  id: string;
+} | {
+  type: "IdentifyLed";
+  name: string;
+} | {
+  type: "RescanPlaylist";
+  name: string;
+} | {
+  type: "SetBookmark";
+  name?: string;
+} | {
+  type: "JumpToBookmark";
+  name: string;
+} | {
+  type: "StartRecording";
+} | {
+  type: "StopRecording";
 };
 
+export interface Bookmark {
+  name: string;
+  position: number;
+}
+
+export interface ScanError {
+  path: string;
+  message: string;
+}
+
+export interface ScanResult {
+  tracks: number;
+  errors?: ScanError[];
+}
+
+export type QueueChange = {
+  type: "Inserted";
+  index: number;
+  track: Track;
+} | {
+  type: "Moved";
+  from: number;
+  to: number;
+} | {
+  type: "Removed";
+  index: number;
+};
+
+export type Origin = {
+  type: "Keyboard" | "Internal";
+} | {
+  type: "Gpio";
+  pin: number;
+} | {
+  type: "Input";
+  device: string;
+} | {
+  type: "Signal";
+  signal: number;
+} | {
+  type: "WebClient";
+  address?: string;
+} | {
+  type: "Group";
+  device: string;
+};
+
+export type CommandResult = {
+  type: "Accepted" | "Skipped";
+} | {
+  type: "Rejected";
+  reason: string;
+};
+
+export interface DeviceInfo {
+  id: string;
+  name: string;
+}
+
+export interface HistoryEntry {
+  command: Command;
+  origin: Origin;
+  timestamp: number;
+}
+
 export type Event = {
   type: "PreviousTrack" |
+  "RestartTrack" |
+  "FavoriteTrack" |
+  "BlockTrack" |
   "NextTrack" |
   "PlayPause" |
   "VolumeUp" |
@@ -41,18 +170,62 @@ export type Event = {
   "Shutdown" |
   "Reload" |
   "Status";
+} | {
+  type: "PlaylistUpdated";
+  changes?: QueueChange[];
+} | {
+  type: "PlaylistScanned";
+  name: string;
+  result: ScanResult;
 } | {
   type: "PlaybackPosition";
   duration: number;
+} | {
+  type: "PlaybackStalled";
+} | {
+  type: "CommandHandled" | "CommandSkipped";
+  command: Command;
+  origin: Origin;
+} | {
+  type: "Error";
+  message: string;
+} | {
+  type: "ConnectivityChanged";
+  online: boolean;
+} | {
+  type: "ClockChanged";
+  offset: number;
+} | {
+  type: "AudioOutputChanged";
+  name?: string;
+} | {
+  type: "AudioOutputFailed";
+} | {
+  type: "PlaylistUnavailable";
+  name: string;
+  fallback?: string;
+} | {
+  type: "PlaylistMediaChanged";
+  name: string;
+  present: boolean;
+} | {
+  type: "UpdateFinished";
+  installed?: string;
 };
 
 export type MessageFromServer = {
+  type: "Connected";
+  session: string;
+  resumed: boolean;
+  device: DeviceInfo;
+} | {
   type: "Response";
   id: number;
   response: any;
 } | {
   type: "Event";
   event: Event;
+  sequence: number;
 };
 
 export type MessageToServer = {
@@ -1,25 +1,63 @@
 import { JsonDecoder } from "ts.data.json";
 
 export interface Track {
-  path: string;
+  id: string;
   title: string;
+  artist: string | undefined;
+  album: string | undefined;
 }
 
 export const TrackDecoder = JsonDecoder.object<Track>({
-  path: JsonDecoder.string,
+  id: JsonDecoder.string,
   title: JsonDecoder.string,
+  artist: JsonDecoder.optional(JsonDecoder.string),
+  album: JsonDecoder.optional(JsonDecoder.string),
 }, "Track");
 
 export interface StoredPlaylist {
   name: string;
   tracks: Track[];
+  mediaMissing: boolean;
 }
 
 export const StoredPlaylistDecoder = JsonDecoder.object<StoredPlaylist>({
   name: JsonDecoder.string,
   tracks: JsonDecoder.array(TrackDecoder, "Track[]"),
+  mediaMissing: JsonDecoder.boolean,
 }, "Track");
 
+export interface SearchResult {
+  playlist: string;
+  track: Track;
+}
+
+export const SearchResultDecoder = JsonDecoder.object<SearchResult>({
+  playlist: JsonDecoder.string,
+  track: TrackDecoder,
+}, "SearchResult");
+
+export interface SearchResults {
+  total: number;
+  offset: number;
+  results: SearchResult[];
+}
+
+export const SearchResultsDecoder = JsonDecoder.object<SearchResults>({
+  total: JsonDecoder.number,
+  offset: JsonDecoder.number,
+  results: JsonDecoder.array(SearchResultDecoder, "SearchResult[]"),
+}, "SearchResults");
+
+export interface Queue {
+  tracks: Track[];
+  position?: number;
+}
+
+export const QueueDecoder = JsonDecoder.object<Queue>({
+  tracks: JsonDecoder.array(TrackDecoder, "Track[]"),
+  position: JsonDecoder.optional(JsonDecoder.number),
+}, "Queue");
+
 export interface PlayState {
   position: number;
   duration: number;
@@ -32,11 +70,81 @@ export const PlayStateDecoder = JsonDecoder.object<PlayState>({
   paused: JsonDecoder.boolean,
 }, "Track");
 
+export interface AnalysisProgress {
+  queued: number;
+  completed: number;
+  paused: boolean;
+}
+
+export const AnalysisProgressDecoder = JsonDecoder.object<AnalysisProgress>({
+  queued: JsonDecoder.number,
+  completed: JsonDecoder.number,
+  paused: JsonDecoder.boolean,
+}, "AnalysisProgress");
+
+export interface CurrentDownload {
+  name: string;
+  received: number;
+  size?: number;
+}
+
+export const CurrentDownloadDecoder = JsonDecoder.object<CurrentDownload>({
+  name: JsonDecoder.string,
+  received: JsonDecoder.number,
+  size: JsonDecoder.optional(JsonDecoder.number),
+}, "CurrentDownload");
+
+export interface DownloadProgress {
+  queued: number;
+  completed: number;
+  failed: number;
+  current?: CurrentDownload;
+}
+
+export const DownloadProgressDecoder = JsonDecoder.object<DownloadProgress>({
+  queued: JsonDecoder.number,
+  completed: JsonDecoder.number,
+  failed: JsonDecoder.number,
+  current: JsonDecoder.optional(CurrentDownloadDecoder),
+}, "DownloadProgress");
+
+export interface GuestState {
+  remaining?: number;
+  quiet: boolean;
+}
+
+export const GuestStateDecoder = JsonDecoder.object<GuestState>({
+  remaining: JsonDecoder.optional(JsonDecoder.number),
+  quiet: JsonDecoder.boolean,
+}, "GuestState");
+
+export interface ButtonUsage {
+  presses: number;
+  bounces: number;
+  worn: boolean;
+}
+
+export const ButtonUsageDecoder = JsonDecoder.object<ButtonUsage>({
+  presses: JsonDecoder.number,
+  bounces: JsonDecoder.number,
+  worn: JsonDecoder.boolean,
+}, "ButtonUsage");
+
 export interface AppState {
   storedPlaylists: Record<string, StoredPlaylist>;
   playlist: Track[];
   playState: PlayState | undefined;
   volume: number;
+  balance: number;
+  analysis: AnalysisProgress;
+  downloads: DownloadProgress;
+  offline: boolean;
+  guest?: GuestState;
+  maxVolume?: number;
+  profile?: string;
+  selfTestFailures?: string[];
+  buttons?: Record<string, ButtonUsage>;
+  audioOutput?: string;
 }
 
 export const AppStateDecoder = JsonDecoder.object<AppState>({
@@ -44,4 +152,14 @@ export const AppStateDecoder = JsonDecoder.object<AppState>({
   playlist: JsonDecoder.array(TrackDecoder, "Track[]"),
   playState: JsonDecoder.optional(PlayStateDecoder),
   volume: JsonDecoder.number,
+  balance: JsonDecoder.number,
+  analysis: AnalysisProgressDecoder,
+  downloads: DownloadProgressDecoder,
+  offline: JsonDecoder.boolean,
+  guest: JsonDecoder.optional(GuestStateDecoder),
+  maxVolume: JsonDecoder.optional(JsonDecoder.number),
+  profile: JsonDecoder.optional(JsonDecoder.string),
+  selfTestFailures: JsonDecoder.optional(JsonDecoder.array(JsonDecoder.string, "string[]")),
+  buttons: JsonDecoder.optional(JsonDecoder.dictionary(ButtonUsageDecoder, "Dict<ButtonUsage>")),
+  audioOutput: JsonDecoder.optional(JsonDecoder.string),
 }, "Track");